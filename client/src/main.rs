@@ -1,19 +1,65 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::{CommandRequest, CommandResponse, Result};
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
+use kvs::{CommandRequest, CommandResponse, Error, Result, SizeHistogram, Stats, StreamId};
+use std::fs;
+use std::io;
 use std::net::TcpStream;
+use std::path::Path;
 use std::process;
+use std::time::Instant;
+use uuid::Uuid;
 
 fn main() -> Result<()> {
     let addr_arg = Arg::with_name("addr")
         .long("addr")
+        .env("KVS_ADDR")
         .takes_value(true)
         .value_name("IP-ADDR")
         .default_value("127.0.0.1:4000");
-    let matches = App::new(env!("CARGO_PKG_NAME"))
+    let sequence_arg = Arg::with_name("sequence")
+        .long("sequence")
+        .help("Print the write's sequence number");
+    let progress_arg = Arg::with_name("progress")
+        .long("progress")
+        .help("Draw a progress bar on stderr while the server works on it");
+    let mut app = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .setting(AppSettings::DisableHelpSubcommand)
+        .arg(
+            Arg::with_name("session")
+                .long("session")
+                .env("KVS_SESSION")
+                .takes_value(true)
+                .value_name("FILE")
+                .global(true)
+                .help("Only accept answers from servers that have applied this session's writes"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .env("KVS_TIMEOUT")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .global(true)
+                .help("Have the server give up on the request if it takes longer than this"),
+        )
+        .arg(
+            Arg::with_name("idempotency-token")
+                .long("idempotency-token")
+                .takes_value(true)
+                .value_name("TOKEN")
+                .global(true)
+                .help("Don't apply the request again if the server already applied one with TOKEN"),
+        )
+        .arg(
+            Arg::with_name("request-id")
+                .long("request-id")
+                .takes_value(true)
+                .value_name("ID")
+                .global(true)
+                .help("Log the request under ID on the server (defaults to a random UUID)"),
+        )
         .subcommand(
             SubCommand::with_name("get")
                 .arg(Arg::with_name("key").required(true))
@@ -22,20 +68,301 @@ fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("rm")
                 .arg(Arg::with_name("key").required(true))
+                .arg(&sequence_arg)
                 .arg(&addr_arg),
         )
         .subcommand(
             SubCommand::with_name("set")
                 .arg(Arg::with_name("key").required(true))
                 .arg(Arg::with_name("value").required(true))
+                .arg(&sequence_arg)
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("expected")
+                        .required(true)
+                        .help("The value the key must have (the new value, with --absent)"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .required_unless("absent")
+                        .conflicts_with("absent"),
+                )
+                .arg(
+                    Arg::with_name("absent")
+                        .long("absent")
+                        .help("Only set the key if it doesn't exist"),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("rename")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("new-key").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("hset")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("field").required(true))
+                .arg(Arg::with_name("value").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("hget")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("field").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("hgetall")
+                .arg(Arg::with_name("key").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("hdel")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("field").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("zadd")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("score").required(true))
+                .arg(Arg::with_name("member").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("zrangebyscore")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("min")
+                        .required(true)
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::with_name("max")
+                        .required(true)
+                        .allow_hyphen_values(true),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("zrank")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("member").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("xadd")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("payload").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("xrange")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("from")
+                        .required(true)
+                        .allow_hyphen_values(true),
+                )
+                .arg(Arg::with_name("to").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("enqueue")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("payload").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("dequeue")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("visibility-timeout")
+                        .long("visibility-timeout")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("30")
+                        .help("How long the item is hidden from other consumers unless acked"),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("ack")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("id").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("lock")
+                .arg(Arg::with_name("key").required(true))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("30")
+                        .help("How long the lock is held unless it's unlocked first"),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("unlock")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("token").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("setbit")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("offset").required(true))
+                .arg(
+                    Arg::with_name("value")
+                        .required(true)
+                        .possible_values(&["0", "1"]),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("getbit")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("offset").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("bitcount")
+                .arg(Arg::with_name("key").required(true))
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("eval")
+                .about("Run a script on the server, applying all of its writes or none of them")
+                .arg(Arg::with_name("script").required(true))
+                .arg(
+                    Arg::with_name("args")
+                        .multiple(true)
+                        .allow_hyphen_values(true)
+                        .help("The values of arg(0), arg(1), and so on in the script"),
+                )
                 .arg(&addr_arg),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Print the keys with string values in a range, with their values")
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .takes_value(true)
+                        .value_name("KEY")
+                        .help("The first key in the range (defaults to the first key)"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .takes_value(true)
+                        .value_name("KEY")
+                        .help("The key the range stops before (defaults to past the last key)"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Print at most N keys (the server sends at most 1000 at a time)"),
+                )
+                .arg(
+                    Arg::with_name("reverse")
+                        .long("reverse")
+                        .help("Go through the range in descending key order, from its end"),
+                )
+                .arg(
+                    Arg::with_name("cursor")
+                        .long("cursor")
+                        .takes_value(true)
+                        .value_name("CURSOR")
+                        .help("Carry on from where an earlier scan of the same range stopped"),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(SubCommand::with_name("health").arg(&addr_arg))
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("Have the server merge its pages, dropping overwritten and removed values")
+                .arg(&progress_arg)
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Have the server check its pages and data files for corruption")
+                .arg(&progress_arg)
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Have the server copy its live files into a new directory on its machine")
+                .arg(Arg::with_name("path").required(true))
+                .arg(&progress_arg)
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(&["table", "json"])
+                        .default_value("table"),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("ping")
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1")
+                        .help("How many pings to send, one after another"),
+                )
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .setting(AppSettings::Hidden)
+                .about("Print a shell completion script")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&["bash", "zsh", "fish"]),
+                ),
+        );
+    let matches = app.clone().get_matches();
 
     let (command, maybe_args) = matches.subcommand();
     let args = maybe_args.unwrap();
+
+    if command == "completions" {
+        let shell: Shell = args.value_of("shell").unwrap().parse().unwrap();
+        app.gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut io::stdout());
+        return Ok(());
+    }
     let addr = args.value_of("addr").unwrap();
 
+    if command == "ping" {
+        let count = args.value_of("count").unwrap();
+        let count = count
+            .parse()
+            .map_err(|_| Error::Message(format!("Invalid count: {}", count)))?;
+        return ping(addr, count);
+    }
+
     let mut stream = TcpStream::connect(addr)?;
 
     let request = match command {
@@ -60,22 +387,393 @@ fn main() -> Result<()> {
                 value: None,
             }
         }
+        "cas" => {
+            let key = args.value_of("key").unwrap().to_owned();
+            let expected = args.value_of("expected").unwrap().to_owned();
+            if args.is_present("absent") {
+                CommandRequest::CompareAndSwap {
+                    key,
+                    expected: None,
+                    value: expected,
+                }
+            } else {
+                CommandRequest::CompareAndSwap {
+                    key,
+                    expected: Some(expected),
+                    value: args.value_of("new").unwrap().to_owned(),
+                }
+            }
+        }
+        "eval" => CommandRequest::Eval {
+            script: args.value_of("script").unwrap().to_owned(),
+            args: args
+                .values_of("args")
+                .map(|values| values.map(str::to_owned).collect())
+                .unwrap_or_default(),
+        },
+        "rename" => CommandRequest::Rename {
+            key: args.value_of("key").unwrap().to_owned(),
+            new_key: args.value_of("new-key").unwrap().to_owned(),
+        },
+        "hset" => CommandRequest::HSet {
+            key: args.value_of("key").unwrap().to_owned(),
+            field: args.value_of("field").unwrap().to_owned(),
+            value: args.value_of("value").unwrap().to_owned(),
+        },
+        "hget" => CommandRequest::HGet {
+            key: args.value_of("key").unwrap().to_owned(),
+            field: args.value_of("field").unwrap().to_owned(),
+        },
+        "hgetall" => CommandRequest::HGetAll {
+            key: args.value_of("key").unwrap().to_owned(),
+        },
+        "hdel" => CommandRequest::HDel {
+            key: args.value_of("key").unwrap().to_owned(),
+            field: args.value_of("field").unwrap().to_owned(),
+        },
+        "zadd" => CommandRequest::ZAdd {
+            key: args.value_of("key").unwrap().to_owned(),
+            score: parse_score(args.value_of("score").unwrap())?,
+            member: args.value_of("member").unwrap().to_owned(),
+        },
+        "zrangebyscore" => CommandRequest::ZRangeByScore {
+            key: args.value_of("key").unwrap().to_owned(),
+            min: parse_score(args.value_of("min").unwrap())?,
+            max: parse_score(args.value_of("max").unwrap())?,
+        },
+        "zrank" => CommandRequest::ZRank {
+            key: args.value_of("key").unwrap().to_owned(),
+            member: args.value_of("member").unwrap().to_owned(),
+        },
+        "xadd" => CommandRequest::XAdd {
+            key: args.value_of("key").unwrap().to_owned(),
+            payload: args.value_of("payload").unwrap().to_owned(),
+        },
+        "xrange" => CommandRequest::XRange {
+            key: args.value_of("key").unwrap().to_owned(),
+            from: StreamId::parse_bound(args.value_of("from").unwrap(), false)?,
+            to: StreamId::parse_bound(args.value_of("to").unwrap(), true)?,
+        },
+        "enqueue" => CommandRequest::Enqueue {
+            key: args.value_of("key").unwrap().to_owned(),
+            payload: args.value_of("payload").unwrap().to_owned(),
+        },
+        "dequeue" => CommandRequest::Dequeue {
+            key: args.value_of("key").unwrap().to_owned(),
+            visibility_timeout_ms: parse_millis(args.value_of("visibility-timeout").unwrap())?,
+        },
+        "lock" => CommandRequest::Lock {
+            key: args.value_of("key").unwrap().to_owned(),
+            ttl_ms: parse_millis(args.value_of("ttl").unwrap())?,
+        },
+        "unlock" => {
+            let token = args.value_of("token").unwrap();
+            CommandRequest::Unlock {
+                key: args.value_of("key").unwrap().to_owned(),
+                token: token
+                    .parse()
+                    .map_err(|_| Error::Message(format!("Invalid token: {}", token)))?,
+            }
+        }
+        "ack" => CommandRequest::Ack {
+            key: args.value_of("key").unwrap().to_owned(),
+            id: args.value_of("id").unwrap().parse()?,
+        },
+        "setbit" => CommandRequest::SetBit {
+            key: args.value_of("key").unwrap().to_owned(),
+            offset: parse_offset(args.value_of("offset").unwrap())?,
+            value: args.value_of("value").unwrap() == "1",
+        },
+        "getbit" => CommandRequest::GetBit {
+            key: args.value_of("key").unwrap().to_owned(),
+            offset: parse_offset(args.value_of("offset").unwrap())?,
+        },
+        "bitcount" => CommandRequest::BitCount {
+            key: args.value_of("key").unwrap().to_owned(),
+        },
+        "scan" => CommandRequest::Scan {
+            start: args.value_of("start").map(str::to_owned),
+            end: args.value_of("end").map(str::to_owned),
+            limit: match args.value_of("limit") {
+                Some(limit) => Some(
+                    limit
+                        .parse()
+                        .map_err(|_| Error::Message(format!("Invalid limit: {}", limit)))?,
+                ),
+                None => None,
+            },
+            reverse: args.is_present("reverse"),
+            cursor: args.value_of("cursor").map(str::to_owned),
+        },
+        "health" => CommandRequest::Health,
+        "stats" => CommandRequest::Stats,
+        "compact" => CommandRequest::Compact,
+        "verify" => CommandRequest::Verify,
+        "snapshot" => CommandRequest::Snapshot {
+            path: args.value_of("path").unwrap().to_owned(),
+        },
         _ => unreachable!(),
     };
 
+    let token = matches
+        .value_of("idempotency-token")
+        .or_else(|| args.value_of("idempotency-token"));
+    let request = match token {
+        Some(token) => CommandRequest::Idempotent {
+            token: token.to_owned(),
+            request: Box::new(request),
+        },
+        None => request,
+    };
+    let session = matches
+        .value_of("session")
+        .or_else(|| args.value_of("session"));
+    let request = match session {
+        Some(session) => CommandRequest::Session {
+            min_sequence: read_session(Path::new(session))?,
+            request: Box::new(request),
+        },
+        None => request,
+    };
+    let progress = args.is_present("progress");
+    let request = if progress {
+        CommandRequest::Progress {
+            request: Box::new(request),
+        }
+    } else {
+        request
+    };
+    let timeout = matches
+        .value_of("timeout")
+        .or_else(|| args.value_of("timeout"));
+    let request = match timeout {
+        Some(timeout) => CommandRequest::Deadline {
+            timeout_ms: parse_millis(timeout)?,
+            request: Box::new(request),
+        },
+        None => request,
+    };
+
+    let request_id = matches
+        .value_of("request-id")
+        .or_else(|| args.value_of("request-id"))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let request = CommandRequest::Traced {
+        request_id,
+        request: Box::new(request),
+    };
+
     bincode::serialize_into(&mut stream, &request)?;
-    let response = bincode::deserialize_from::<&TcpStream, CommandResponse>(&stream)?;
+    let mut response = loop {
+        match bincode::deserialize_from::<&TcpStream, CommandResponse>(&stream)? {
+            CommandResponse::Progress(report) => eprint!("\r{:<80}", report.bar(80)),
+            response => break response,
+        }
+    };
+    if progress {
+        eprintln!();
+    }
+    let mut request_id = None;
+    if let CommandResponse::Traced {
+        request_id: id,
+        response: inner,
+    } = response
+    {
+        request_id = Some(id);
+        response = *inner;
+    }
+    if let (Some(session), CommandResponse::Session { sequence, .. }) = (session, &response) {
+        let session = Path::new(session);
+        if *sequence > read_session(session)? {
+            fs::write(session, sequence.to_string())?;
+        }
+    }
+    if let CommandResponse::Session {
+        response: inner, ..
+    } = response
+    {
+        response = *inner;
+    }
+    // Failures come with the request id, to look up the server's log lines for them
+    let report_request_id = || {
+        if let Some(request_id) = &request_id {
+            eprintln!("Request id: {}", request_id);
+        }
+    };
     match response {
         CommandResponse::Message(message) => {
             if message != "" {
                 println!("{}", message)
             }
         }
+        CommandResponse::Written { sequence } => {
+            if args.is_present("sequence") {
+                println!("{}", sequence)
+            }
+        }
+        CommandResponse::Error { .. } => {
+            println!("{}", response);
+            report_request_id();
+            process::exit(1)
+        }
         CommandResponse::KeyNotFound => {
             eprintln!("Key not found");
             process::exit(1)
         }
+        CommandResponse::DeadlineExceeded => {
+            eprintln!("Deadline exceeded");
+            report_request_id();
+            process::exit(1)
+        }
+        CommandResponse::Swap { swapped, .. } => {
+            println!("{}", response);
+            if !swapped {
+                process::exit(1)
+            }
+        }
+        CommandResponse::Stats(stats) => {
+            if args.value_of("output") == Some("json") {
+                let json = serde_json::to_string_pretty(&stats).map_err(Error::serialization)?;
+                println!("{}", json);
+            } else {
+                print_stats_table(&stats);
+            }
+        }
+        CommandResponse::ScanPage(page) => {
+            for (key, value) in page.pairs {
+                println!("{}: {}", key, value);
+            }
+            if let Some(cursor) = page.cursor {
+                eprintln!("More keys follow, scan again with --cursor {}", cursor);
+            }
+        }
+        CommandResponse::Health(status) => {
+            println!("{}", status);
+            if !status.healthy {
+                process::exit(1)
+            }
+        }
+        CommandResponse::Verify(report) => {
+            println!("{}", report);
+            if !report.is_ok() {
+                process::exit(1)
+            }
+        }
+        response => println!("{}", response),
     }
 
     Ok(())
 }
+
+/// Ping the server `count` times, printing the round trip time of each, then a summary if
+/// there was more than one.
+fn ping(addr: &str, count: u32) -> Result<()> {
+    let mut times = Vec::new();
+    for _ in 0..count {
+        let started = Instant::now();
+        let stream = TcpStream::connect(addr)?;
+        bincode::serialize_into(&stream, &CommandRequest::Ping)?;
+        let response = bincode::deserialize_from::<&TcpStream, CommandResponse>(&stream)?;
+        let millis = started.elapsed().as_micros() as f64 / 1000.0;
+        match response {
+            CommandResponse::Pong { version } => {
+                println!(
+                    "pong from {}: version={} time={:.3} ms",
+                    addr, version, millis
+                )
+            }
+            response => return Err(Error::Message(format!("Unexpected response: {}", response))),
+        }
+        times.push(millis);
+    }
+    if times.len() > 1 {
+        let min = times.iter().cloned().fold(std::f64::INFINITY, f64::min);
+        let max = times.iter().cloned().fold(0.0, f64::max);
+        let avg = times.iter().sum::<f64>() / times.len() as f64;
+        println!(
+            "{} pings, min/avg/max = {:.3}/{:.3}/{:.3} ms",
+            times.len(),
+            min,
+            avg,
+            max
+        );
+    }
+    Ok(())
+}
+
+/// Print the stats as two columns, with the numbers right-aligned. Each cache gets a row for
+/// each of its counts, and each size histogram gets a row for the biggest size and one for each
+/// bucket that isn't empty.
+fn print_stats_table(stats: &Stats) {
+    let mut rows = vec![
+        ("pages".to_owned(), stats.pages),
+        ("partial pages".to_owned(), stats.partial_pages),
+        ("memtable entries".to_owned(), stats.memtable_entries),
+        ("memtable bytes".to_owned(), stats.memtable_bytes),
+        ("disk bytes".to_owned(), stats.disk_bytes),
+        ("approximate keys".to_owned(), stats.approximate_keys),
+    ];
+    for (name, cache) in stats.caches.iter() {
+        rows.push((format!("{} cache hits", name), cache.hits));
+        rows.push((format!("{} cache misses", name), cache.misses));
+        rows.push((format!("{} cache evictions", name), cache.evictions));
+        rows.push((format!("{} cache entries", name), cache.entries));
+        rows.push((format!("{} cache bytes", name), cache.bytes));
+    }
+    for (name, sizes) in [("key", &stats.key_sizes), ("value", &stats.value_sizes)].iter() {
+        rows.push((format!("largest {}", name), sizes.max));
+        for (bucket, count) in sizes.buckets.iter().enumerate() {
+            if *count > 0 {
+                let (min, max) = SizeHistogram::bucket_range(bucket);
+                rows.push((format!("{}s of {}-{} bytes", name, min, max), *count));
+            }
+        }
+    }
+    let name_width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0) + 2;
+    let width = rows
+        .iter()
+        .map(|(_, value)| value.to_string().len())
+        .max()
+        .unwrap_or(0);
+    for (name, value) in rows.iter() {
+        println!(
+            "{:<name_width$}{:>width$}",
+            name,
+            value,
+            name_width = name_width,
+            width = width
+        );
+    }
+}
+
+/// The latest write sequence number seen in the session, or 0 for a new session.
+fn read_session(path: &Path) -> Result<u64> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .trim()
+            .parse()
+            .map_err(|_| Error::Message(format!("Invalid session file: {:?}", path))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(Error::IoError(e)),
+    }
+}
+
+/// Parse a sorted set score, which may also be `-inf` or `+inf`.
+fn parse_score(s: &str) -> Result<f64> {
+    s.parse()
+        .map_err(|_| Error::Message(format!("Invalid score: {}", s)))
+}
+
+/// Parse a number of seconds, which may be fractional, into milliseconds.
+fn parse_millis(s: &str) -> Result<u64> {
+    let seconds: f64 = s
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid number of seconds: {}", s)))?;
+    Ok((seconds * 1000.0) as u64)
+}
+
+fn parse_offset(s: &str) -> Result<u64> {
+    s.parse()
+        .map_err(|_| Error::Message(format!("Invalid bit offset: {}", s)))
+}
@@ -1,7 +1,12 @@
 use assert_cmd::prelude::*;
+use kvs::Engine;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
+use server::KvStore;
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::Read;
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -140,6 +145,33 @@ fn client_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `completions SHELL` should print a completion script that knows the subcommands
+#[test]
+fn cli_completions() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["completions", "bash"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("complete -F _client"))
+        .stdout(contains("stats"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["completions", "fish"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("complete -c kvs-admin"));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["completions", "powershell"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 // `server -V` should print the version
 #[test]
 fn server_cli_version() {
@@ -151,6 +183,21 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `server --systemd` should fail if systemd didn't pass in any sockets
+#[test]
+fn server_cli_systemd_without_sockets() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("server")
+        .unwrap()
+        .args(&["--systemd"])
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("No listening sockets were passed in by systemd"));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -163,6 +210,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -230,6 +278,33 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    // kvs numbers its writes from 1; sled doesn't number them
+    let sequence = if engine == "kvs" { "5\n" } else { "0\n" };
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key3", "value4", "--sequence", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(sequence);
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["rm", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["health", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("status: ok"))
+        .stdout(contains(engine));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -244,6 +319,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -261,6 +337,132 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("Key not found"));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["cas", "key4", "first", "--absent", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("swapped\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["cas", "key4", "wrong", "second", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("not swapped, current value: first\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["cas", "key4", "first", "second", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("second\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["rename", "key4", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("second\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["rename", "key4", "key6", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["rename", "key5", "__kvs_key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("keys starting with __kvs_ are reserved"));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2: value3\nkey5: second\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["scan", "--reverse", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key5: second\n")
+        .stderr("More keys follow, scan again with --cursor 6b657935\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["scan", "--reverse", "--cursor", "6b657935", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2: value3\n")
+        .stderr(is_empty());
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["scan", "--cursor", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("Invalid scan cursor"));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["scan", "--start", "key3", "--end", "key9", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key5: second\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["ping", "--count", "3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("pong from {}: version=", addr)))
+        .stdout(contains("3 pings, min/avg/max = "));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "__kvs_health__", "value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(
+            "Invalid key: keys starting with __kvs_ are reserved",
+        ));
+    if engine == "kvs" {
+        Command::cargo_bin("client")
+            .unwrap()
+            .args(&["stats", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains("memtable entries"))
+            .stdout(contains("largest value"));
+        Command::cargo_bin("client")
+            .unwrap()
+            .args(&["stats", "--output", "json", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains("\"memtable_entries\": "));
+    }
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -274,3 +476,622 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// A session only accepts answers from a server that has applied the writes it has seen.
+#[test]
+fn cli_session() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let session = temp_dir.path().join("session");
+    let mut server = Command::cargo_bin("server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr, "--session"])
+        .arg(&session)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let sequence: u64 = fs::read_to_string(&session).unwrap().parse().unwrap();
+    assert!(sequence > 0);
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .arg("--session")
+        .arg(&session)
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // A session that has seen later writes than the server has applied is refused
+    fs::write(&session, (sequence + 100).to_string()).unwrap();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--session"])
+        .arg(&session)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(format!(
+            "Error: Server has applied writes up to {}, but the session has seen {}",
+            sequence,
+            sequence + 100
+        )));
+    assert_eq!(
+        fs::read_to_string(&session).unwrap(),
+        (sequence + 100).to_string()
+    );
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// A server given several addresses serves the same store on all of them.
+#[test]
+fn server_multiple_addrs() {
+    let (first, second) = ("127.0.0.1:4007", "127.0.0.1:4008");
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", first, "--addr", second])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", first])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", second])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// On SIGTERM the server should flush buffered writes and exit cleanly.
+#[test]
+fn server_sigterm_flushes() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("server")
+        .unwrap()
+        .args(&[
+            "--engine",
+            "kvs",
+            "--durability",
+            "buffered",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let status = Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// A request whose deadline has already passed is refused with a timeout error.
+#[test]
+fn cli_timeout() {
+    let addr = "127.0.0.1:4010";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr, "--timeout", "5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--timeout", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Deadline exceeded"));
+
+    // Failures report the request id the server logged them under
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--timeout", "0"])
+        .args(&["--request-id", "timed-out-get"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Request id: timed-out-get"));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["--timeout", "5", "get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // A timeout too long to keep track of is as good as none
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&[
+            "get",
+            "key1",
+            "--addr",
+            addr,
+            "--timeout",
+            "10000000000000000",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Settings can come from KVS_* environment variables, with flags taking precedence.
+#[test]
+fn cli_env_config() {
+    let addr = "127.0.0.1:4011";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("server").unwrap();
+    let mut child = server
+        .env("KVS_ADDR", addr)
+        .env("KVS_ENGINE", "kvs")
+        .env("KVS_LOG_LEVEL", "warning")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .env("KVS_ADDR", addr)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .env("KVS_ADDR", "127.0.0.1:1")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// The server keeps its data in --dir, wherever it was started from.
+#[test]
+fn server_data_dir() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let mut child = Command::cargo_bin("server")
+        .unwrap()
+        .args(&[
+            "--engine",
+            "kvs",
+            "--durability",
+            "buffered",
+            "--addr",
+            addr,
+        ])
+        .arg("--dir")
+        .arg(&data_dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    let status = Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+
+    let store = KvStore::open(&data_dir).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// A request retried with the same idempotency token gets the first response and isn't
+// applied again.
+#[test]
+fn cli_idempotency_token() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let xadd = || {
+        let output = Command::cargo_bin("client")
+            .unwrap()
+            .args(&["xadd", "stream", "payload", "--addr", addr])
+            .args(&["--idempotency-token", "token1"])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let id = xadd();
+    assert_eq!(xadd(), id);
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["xrange", "stream", "-", "+", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}: payload\n", id.trim()));
+
+    // The token stands for the xadd, so it can't be reused for anything else
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(&["--idempotency-token", "token1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("different request"));
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_eval() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let incr = "let n = get(arg(0)); if n == nil { n = 0; } set(arg(0), int(n) + 1); return n;";
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["eval", incr, "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["eval", incr, "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&[
+            "eval",
+            "set(arg(0), 1); return 1 / 0;",
+            "counter",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("division by zero"));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_progress() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["compact", "--progress", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("compact: 1/1 pages"));
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["verify", "--progress", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("checked 1 pages"))
+        .stderr(contains("100%"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify", "--addr", addr, "--progress"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("verify: 1/1 pages"));
+    // Without --progress, only the response comes back
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["verify", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr("");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+// Connections are served by a pool of --threads workers, so a client that's slow to send its
+// request doesn't hold up anyone else's while a worker is free.
+#[test]
+fn cli_concurrent_connections() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let stalled = TcpStream::connect(addr).unwrap();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    drop(stalled);
+
+    // Concurrent health checks each touch a key of their own, so none sees another's write
+    let probes: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || {
+                Command::cargo_bin("client")
+                    .unwrap()
+                    .args(&["health", "--addr", addr])
+                    .assert()
+                    .success()
+                    .stdout(contains("status: ok"));
+            })
+        })
+        .collect();
+    for probe in probes {
+        probe.join().unwrap();
+    }
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+// A connection that never sends its request is dropped after --idle-timeout, so even the only
+// worker is freed for other clients
+#[test]
+fn cli_idle_timeout() {
+    let addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("server")
+        .unwrap()
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--threads",
+            "1",
+            "--idle-timeout",
+            "1",
+        ])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stalled = TcpStream::connect(addr).unwrap();
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let mut buf = Vec::new();
+    stalled.read_to_end(&mut buf).unwrap();
+    assert!(buf.is_empty());
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn dump_pages() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store
+        .set(
+            "key1".to_owned(),
+            "a value long enough to be cut off".to_owned(),
+        )
+        .unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live pages"))
+        .stdout(contains("count 2 partial"))
+        .stdout(contains("slot").not());
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(&["--values", "--preview", "7"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("key \"key1\" \"a value...\""))
+        .stdout(contains("key \"key2\" \"value2\""));
+
+    // A truncated page is reported, and the exit code says something is wrong
+    for entry in fs::read_dir(temp_dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map_or(false, |ext| ext == "log") {
+            File::create(&path).unwrap();
+        }
+    }
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(&["--dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(contains("PROBLEM: Could not read"))
+        .stderr(contains("problems"));
+}
+
+#[test]
+fn torture_recovers_acknowledged_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-torture")
+        .unwrap()
+        .args(&["--dir", "store", "--rounds", "3", "--max-delay", "300"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("round 2: killed after"));
+
+    // Leftover data would be taken for writes that were never acknowledged
+    Command::cargo_bin("kvs-torture")
+        .unwrap()
+        .args(&["--dir", "store", "--rounds", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
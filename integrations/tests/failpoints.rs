@@ -0,0 +1,163 @@
+//! Crash consistency and I/O fault tests, run with `cargo test --features failpoints`.
+//! Failpoints are global to the process, so these live in their own test binary and run one at
+//! a time.
+#![cfg(feature = "failpoints")]
+
+use fail::FailScenario;
+use kvs::{Engine, Error, Result};
+use server::{CompactionStrategy, IoFaults, KvStore, Options};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Copy the files in `from` into the new directory `to`, as a crash would have left them.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+// A write that fails at each step of saving is either entirely there or entirely missing
+// after a crash at that point, and the store that saw the failure can still save it later.
+#[test]
+fn crash_during_save() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let steps = [
+        ("write-page", None),
+        ("write-data", None),
+        ("write-manifest", None),
+        // The manifest is the commit point, so the index is rebuilt from it
+        ("write-index", Some("value2".to_owned())),
+    ];
+    for (step, expected) in steps.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store_dir = temp_dir.path().join("store");
+        let crash_dir = temp_dir.path().join("crash");
+        fs::create_dir(&store_dir)?;
+
+        let store = KvStore::open(&store_dir)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        fail::cfg(*step, "return").unwrap();
+        assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+        copy_dir(&store_dir, &crash_dir)?;
+        fail::remove(*step);
+        drop(store);
+
+        let store = KvStore::open(&crash_dir)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(
+            &store.get("key2".to_owned())?,
+            expected,
+            "crash at {}",
+            step
+        );
+        assert!(store.verify()?.is_ok(), "crash at {}", step);
+
+        let store = KvStore::open(&store_dir)?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert!(store.verify()?.is_ok(), "failure at {}", step);
+        // Nothing it committed after the failure was missing
+        assert!(
+            !store_dir.join("quarantine").exists(),
+            "failure at {}",
+            step
+        );
+    }
+    scenario.teardown();
+    Ok(())
+}
+
+// Reads that return a few bytes at a time and slow I/O don't change what the store reads back.
+#[test]
+fn short_reads_and_latency() -> Result<()> {
+    let faults = [
+        IoFaults {
+            max_read: Some(7),
+            ..IoFaults::default()
+        },
+        IoFaults {
+            latency: Duration::from_millis(1),
+            ..IoFaults::default()
+        },
+    ];
+    for faults in faults.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let logger = kvs::get_default_logger();
+        let options = Options {
+            io_faults: Some(faults.clone()),
+            ..Options::default()
+        };
+
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+        for i in 0..20 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+        for i in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", i)),
+                "{:?}",
+                faults
+            );
+        }
+        assert!(store.verify()?.is_ok(), "{:?}", faults);
+    }
+    Ok(())
+}
+
+// Transient I/O errors reach the caller, and retrying succeeds without leaving anything behind
+// that a later open would have to repair.
+#[test]
+fn transient_io_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let options = Options {
+        io_faults: Some(IoFaults {
+            error_every: Some(7),
+            ..IoFaults::default()
+        }),
+        // Failed merges are only logged, so they'd hide the errors from the caller
+        compaction: CompactionStrategy::Manual,
+        ..Options::default()
+    };
+
+    fn retry<T>(failures: &mut u64, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        loop {
+            match f() {
+                Err(Error::IoError(ref e)) if e.raw_os_error() == Some(5) => *failures += 1,
+                result => return result,
+            }
+        }
+    }
+
+    let mut failures = 0;
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    for i in 0..20 {
+        retry(&mut failures, || {
+            store.set(format!("key{}", i), format!("value{}", i))
+        })?;
+    }
+    for i in 0..20 {
+        let value = retry(&mut failures, || store.get(format!("key{}", i)))?;
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+    assert!(failures > 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(store.verify()?.is_ok());
+    assert!(!temp_dir.path().join("quarantine").exists());
+    Ok(())
+}
@@ -1,5 +1,16 @@
-use kvs::{Engine, Result};
-use server::KvStore;
+use kvs::{
+    CommandRequest, CommandResponse, Engine, Error, ErrorCode, Result, SizeHistogram, StreamId,
+    MAX_KEY_LEN,
+};
+use server::{
+    Checksums, CompactionStrategy, Durability, HashAlgorithm, HookMode, IdempotencyCache, KeyHash,
+    KvStore, Options, RotatingFile, Rotation, SecondaryIndex, Statsd, Telemetry, CHECKSUMS_FILE,
+    MAX_TRANSACTION_ATTEMPTS,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -7,7 +18,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -17,7 +28,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -28,7 +39,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -37,7 +48,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -49,14 +60,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -65,7 +76,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -73,19 +84,44 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+// Removes should see keys on disk and in memory, and can skip the check entirely.
+#[test]
+fn remove_key_existence_check() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert!(store.remove("key1".to_owned()).is_err());
+    drop(store);
+
+    let mut options = Options::default();
+    options.check_exists_on_remove = false;
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    assert!(store.remove("key3".to_owned()).is_ok());
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 // #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -115,7 +151,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -125,3 +161,2992 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Compaction should keep the newest value for each key and drop removed keys.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let options = Options {
+        compaction: CompactionStrategy::Manual,
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+
+    for iter in 0..3 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let pages_before = store.stats()?.pages;
+
+    store.compact()?;
+    assert!(store.stats()?.pages < pages_before);
+    assert!(store.verify()?.is_ok());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("2".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let snapshot_dir = temp_dir.path().join("snapshot");
+    let restore_dir = temp_dir.path().join("restore");
+    std::fs::create_dir(&store_dir)?;
+
+    let store = KvStore::open(&store_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.snapshot(&snapshot_dir)?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Every other file in the snapshot is listed in its checksums
+    let checksums = std::fs::read_to_string(snapshot_dir.join(CHECKSUMS_FILE))?;
+    let checksums = Checksums::from_ron(&checksums)?;
+    let files = std::fs::read_dir(&snapshot_dir)?.count();
+    assert_eq!(checksums.files.len(), files - 1);
+    for (name, checksum) in &checksums.files {
+        let len = std::fs::metadata(snapshot_dir.join(name))?.len();
+        assert_eq!(checksum.len, len);
+    }
+
+    KvStore::restore(&snapshot_dir, &restore_dir)?;
+    let store = KvStore::open(&restore_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Restoring over existing data is refused
+    assert!(KvStore::restore(&snapshot_dir, &store_dir).is_err());
+
+    Ok(())
+}
+
+// Snapshots on the same filesystem share the page files with the store.
+#[cfg(unix)]
+#[test]
+fn snapshot_links_pages() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let snapshot_dir = temp_dir.path().join("snapshot");
+    let restore_dir = temp_dir.path().join("restore");
+    std::fs::create_dir(&store_dir)?;
+
+    let store = KvStore::open(&store_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.snapshot(&snapshot_dir)?;
+    for entry in std::fs::read_dir(&snapshot_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().unwrap();
+        let linked = name.ends_with(".log") || name.ends_with(".data");
+        match std::fs::metadata(store_dir.join(&name)) {
+            Ok(metadata) => assert_eq!(metadata.ino() == entry.metadata()?.ino(), linked),
+            Err(_) => assert_eq!(name, CHECKSUMS_FILE),
+        }
+    }
+
+    // Compaction deletes the store's links, leaving the snapshot intact
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    drop(store);
+    assert!(KvStore::verify_backup(&snapshot_dir, 1)?.is_ok());
+    KvStore::restore(&snapshot_dir, &restore_dir)?;
+    let store = KvStore::open(&restore_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A second store can't open a directory that is already open.
+#[test]
+fn directory_is_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(Error::AlreadyLocked(pid)) => assert_eq!(pid, Some(std::process::id())),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a locked directory"),
+    }
+
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// Every durability level should persist writes across a clean close.
+#[test]
+fn durability_levels() -> Result<()> {
+    for durability in &[Durability::Buffered, Durability::Flush, Durability::Sync] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let logger = kvs::get_default_logger();
+        let mut options = Options::default();
+        options.durability = *durability;
+
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+fn log_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .collect()
+}
+
+// A truncated page should be quarantined on open instead of making the store unreadable.
+#[test]
+fn recover_truncated_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let old_pages = log_files(temp_dir.path());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let new_page = log_files(temp_dir.path())
+        .into_iter()
+        .find(|path| !old_pages.contains(path))
+        .unwrap();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&new_page)?
+        .set_len(100)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.verify()?.is_ok());
+    assert!(temp_dir
+        .path()
+        .join("quarantine")
+        .join(new_page.file_name().unwrap())
+        .exists());
+    Ok(())
+}
+
+// Pages checked on several threads at open are quarantined the same way, and only the broken
+// ones are.
+#[test]
+fn recover_pages_in_parallel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let mut pages = Vec::new();
+    for i in 0..10 {
+        let options = Options {
+            compaction: CompactionStrategy::Manual,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        drop(store);
+        let page = log_files(temp_dir.path())
+            .into_iter()
+            .find(|path| !pages.contains(path))
+            .unwrap();
+        pages.push(page);
+    }
+    let broken = [2, 5, 7];
+    for &i in broken.iter() {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&pages[i])?
+            .set_len(100)?;
+    }
+
+    let options = Options {
+        startup_threads: 3,
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    for (i, page) in pages.iter().enumerate() {
+        let quarantined = temp_dir
+            .path()
+            .join("quarantine")
+            .join(page.file_name().unwrap());
+        if broken.contains(&i) {
+            assert_eq!(store.get(format!("key{}", i))?, None);
+            assert!(quarantined.exists());
+        } else {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+            assert!(!quarantined.exists());
+        }
+    }
+    assert!(store.verify()?.is_ok());
+    Ok(())
+}
+
+// Files that aren't referenced by the index are moved aside, then deleted after a grace period.
+#[test]
+fn clean_up_orphaned_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let orphan = "a1a2a3a4-b1b2-11c1-8000-d1d2d3d4d5d6.log";
+    std::fs::write(temp_dir.path().join(orphan), b"garbage")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let lost_and_found = temp_dir.path().join("lost+found");
+    assert!(!temp_dir.path().join(orphan).exists());
+    assert!(lost_and_found.join(orphan).exists());
+
+    let mut options = Options::default();
+    options.orphan_grace_period = std::time::Duration::from_secs(0);
+    KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    assert!(!lost_and_found.join(orphan).exists());
+    Ok(())
+}
+
+// The index should be rebuilt from the pages the manifest lists if it's lost or stale.
+#[test]
+fn rebuild_index_from_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let stale_index = std::fs::read(temp_dir.path().join("index"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    // As if we crashed after writing the manifest but before writing the index
+    std::fs::write(temp_dir.path().join("index"), stale_index)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    std::fs::remove_file(temp_dir.path().join("index"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.verify()?.is_ok());
+    Ok(())
+}
+
+// An index that's damaged, or from an older generation than the manifest even if it lists the
+// same pages, is rebuilt rather than trusted. A read-only store refuses to open instead.
+#[test]
+fn index_checksum_and_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let index = temp_dir.path().join("index");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let old_generation = std::fs::read(&index)?;
+
+    // Opening with other options writes a new manifest and index for the same pages
+    let options = Options {
+        durability: Durability::Sync,
+        ..Options::default()
+    };
+    drop(KvStore::open_with_options(
+        temp_dir.path(),
+        &logger,
+        options,
+    )?);
+    std::fs::write(&index, &old_generation)?;
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(KvStore::open_read_only(temp_dir.path()).is_ok());
+
+    let mut contents = std::fs::read(&index)?;
+    contents[10] ^= 0xff;
+    std::fs::write(&index, &contents)?;
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.verify()?.is_ok());
+    Ok(())
+}
+
+// Hash fields are written as merge operands and folded together on read and compaction.
+#[test]
+fn hash_fields() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.hset("hash".to_owned(), "a".to_owned(), "1".to_owned())?;
+    store.hset("hash".to_owned(), "b".to_owned(), "2".to_owned())?;
+    store.hset("hash".to_owned(), "a".to_owned(), "3".to_owned())?;
+    store.hdel("hash".to_owned(), "b".to_owned())?;
+    assert!(store.hdel("hash".to_owned(), "b".to_owned()).is_err());
+    assert_eq!(
+        store.hget("hash".to_owned(), "a".to_owned())?,
+        Some("3".to_owned())
+    );
+    assert_eq!(store.hget("hash".to_owned(), "b".to_owned())?, None);
+
+    store.set("value".to_owned(), "v".to_owned())?;
+    match store.hset("value".to_owned(), "a".to_owned(), "1".to_owned()) {
+        Err(Error::WrongType) => {}
+        result => panic!("expected WrongType, got {:?}", result),
+    }
+    match store.get("hash".to_owned()) {
+        Err(Error::WrongType) => {}
+        result => panic!("expected WrongType, got {:?}", result),
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.hset("hash".to_owned(), "c".to_owned(), "4".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let fields = store.hgetall("hash".to_owned())?;
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields.get("a"), Some(&"3".to_owned()));
+    assert_eq!(fields.get("c"), Some(&"4".to_owned()));
+    assert!(store.verify()?.is_ok());
+
+    store.hdel("hash".to_owned(), "a".to_owned())?;
+    store.hdel("hash".to_owned(), "c".to_owned())?;
+    assert!(store.hgetall("hash".to_owned())?.is_empty());
+    Ok(())
+}
+
+// Sorted set members are kept in score order across pages, reopening, and compaction.
+#[test]
+fn sorted_set_members() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.zadd("board".to_owned(), 30.0, "carol".to_owned())?;
+    store.zadd("board".to_owned(), 10.0, "alice".to_owned())?;
+    store.zadd("board".to_owned(), 20.0, "bob".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.zadd("board".to_owned(), 5.0, "carol".to_owned())?;
+    assert_eq!(
+        store.zrank("board".to_owned(), "carol".to_owned())?,
+        Some(0)
+    );
+    assert_eq!(store.zrank("board".to_owned(), "bob".to_owned())?, Some(2));
+    assert_eq!(store.zrank("board".to_owned(), "dave".to_owned())?, None);
+    assert_eq!(
+        store.zrangebyscore("board".to_owned(), 10.0, std::f64::INFINITY)?,
+        vec![("alice".to_owned(), 10.0), ("bob".to_owned(), 20.0)]
+    );
+
+    store.hset("hash".to_owned(), "a".to_owned(), "1".to_owned())?;
+    match store.zadd("hash".to_owned(), 1.0, "a".to_owned()) {
+        Err(Error::WrongType) => {}
+        result => panic!("expected WrongType, got {:?}", result),
+    }
+    store.compact()?;
+    assert_eq!(
+        store.zrangebyscore("board".to_owned(), std::f64::NEG_INFINITY, 10.0)?,
+        vec![("carol".to_owned(), 5.0), ("alice".to_owned(), 10.0)]
+    );
+    Ok(())
+}
+
+// Stream ids keep increasing across restarts, and ranges are inclusive.
+#[test]
+fn stream_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let first = store.xadd("events".to_owned(), "one".to_owned())?;
+    let second = store.xadd("events".to_owned(), "two".to_owned())?;
+    assert!(second > first);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let third = store.xadd("events".to_owned(), "three".to_owned())?;
+    assert!(third > second);
+    assert_eq!(
+        store.xrange("events".to_owned(), second, StreamId::MAX)?,
+        vec![(second, "two".to_owned()), (third, "three".to_owned())]
+    );
+
+    store.compact()?;
+    let entries = store.xrange("events".to_owned(), StreamId::MIN, second)?;
+    assert_eq!(
+        entries,
+        vec![(first, "one".to_owned()), (second, "two".to_owned())]
+    );
+    assert_eq!(StreamId::parse_bound(&first.to_string(), false)?, first);
+    assert!(store
+        .xrange("missing".to_owned(), StreamId::MIN, StreamId::MAX)?
+        .is_empty());
+    Ok(())
+}
+
+// Dequeued items are hidden until their lease runs out, and removed once acked.
+#[test]
+fn queue_visibility_and_ack() -> Result<()> {
+    use std::time::Duration;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let first = store.enqueue("jobs".to_owned(), "one".to_owned())?;
+    let second = store.enqueue("jobs".to_owned(), "two".to_owned())?;
+
+    let long = Duration::from_secs(60);
+    assert_eq!(
+        store.dequeue("jobs".to_owned(), long)?,
+        Some((first, "one".to_owned()))
+    );
+    assert_eq!(
+        store.dequeue("jobs".to_owned(), Duration::from_millis(0))?,
+        Some((second, "two".to_owned()))
+    );
+    drop(store);
+
+    // The lease on the second item has already run out
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.dequeue("jobs".to_owned(), long)?,
+        Some((second, "two".to_owned()))
+    );
+    assert_eq!(store.dequeue("jobs".to_owned(), long)?, None);
+
+    store.ack("jobs".to_owned(), first)?;
+    store.ack("jobs".to_owned(), second)?;
+    assert!(store.ack("jobs".to_owned(), second).is_err());
+    store.compact()?;
+    let third = store.enqueue("jobs".to_owned(), "three".to_owned())?;
+    assert!(third > second);
+    assert_eq!(
+        store.dequeue("jobs".to_owned(), long)?,
+        Some((third, "three".to_owned()))
+    );
+
+    // A visibility timeout too long to count in milliseconds hides the item for good, rather
+    // than wrapping around and delivering it again
+    let fourth = store.enqueue("jobs".to_owned(), "four".to_owned())?;
+    let forever = Duration::from_millis(u64::max_value());
+    assert_eq!(
+        store.dequeue("jobs".to_owned(), forever)?,
+        Some((fourth, "four".to_owned()))
+    );
+    assert_eq!(store.dequeue("jobs".to_owned(), long)?, None);
+    Ok(())
+}
+
+// Locks can only be taken by one holder at a time, and fencing tokens keep increasing.
+#[test]
+fn lock_fencing_tokens() -> Result<()> {
+    use std::time::Duration;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let long = Duration::from_secs(60);
+    let first = store.lock("mutex".to_owned(), long)?;
+    match store.lock("mutex".to_owned(), long) {
+        Err(e @ Error::LockHeld) => assert_eq!(e.code(), ErrorCode::Busy),
+        result => panic!("expected LockHeld, got {:?}", result),
+    }
+    assert!(store.unlock("mutex".to_owned(), first + 1).is_err());
+    store.unlock("mutex".to_owned(), first)?;
+    assert!(store.unlock("mutex".to_owned(), first).is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let second = store.lock("mutex".to_owned(), Duration::from_millis(0))?;
+    assert!(second > first);
+    // The lease has already run out, so the lock can be taken again
+    let third = store.lock("mutex".to_owned(), long)?;
+    assert!(third > second);
+    assert!(store.unlock("mutex".to_owned(), second).is_err());
+
+    // A lease too long to count in milliseconds never runs out, rather than wrapping around
+    store.unlock("mutex".to_owned(), third)?;
+    let forever = Duration::from_millis(u64::max_value());
+    let fourth = store.lock("mutex".to_owned(), forever)?;
+    match store.lock("mutex".to_owned(), forever) {
+        Err(Error::LockHeld) => {}
+        result => panic!("expected LockHeld, got {:?}", result),
+    }
+    store.unlock("mutex".to_owned(), fourth)?;
+    Ok(())
+}
+
+// Leases and visibility timeouts run out by the store's clock, not the system's.
+#[test]
+fn expiry_follows_clock() -> Result<()> {
+    use server::MockClock;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let clock = Arc::new(MockClock::new(SystemTime::now()));
+    let mut options = Options::default();
+    options.clock = clock.clone();
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+
+    let ttl = Duration::from_secs(30);
+    let first = store.lock("mutex".to_owned(), ttl)?;
+    clock.advance(Duration::from_secs(29));
+    match store.lock("mutex".to_owned(), ttl) {
+        Err(Error::LockHeld) => {}
+        result => panic!("expected LockHeld, got {:?}", result),
+    }
+    clock.advance(Duration::from_secs(2));
+    assert!(store.unlock("mutex".to_owned(), first).is_err());
+    assert!(store.lock("mutex".to_owned(), ttl)? > first);
+
+    let id = store.enqueue("jobs".to_owned(), "one".to_owned())?;
+    let visibility = Duration::from_secs(10);
+    assert_eq!(
+        store.dequeue("jobs".to_owned(), visibility)?,
+        Some((id, "one".to_owned()))
+    );
+    clock.advance(Duration::from_secs(9));
+    assert_eq!(store.dequeue("jobs".to_owned(), visibility)?, None);
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(
+        store.dequeue("jobs".to_owned(), visibility)?,
+        Some((id, "one".to_owned()))
+    );
+    Ok(())
+}
+
+// Stores can be filled from and read out through ordinary iterators.
+#[test]
+fn collection_traits() -> Result<()> {
+    use kvs::WriteBatch;
+    use server::DumpValue;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.extend((0..3).map(|i| (format!("key{}", i), format!("value{}", i))));
+    let batch: WriteBatch = vec![("key3".to_owned(), "value3".to_owned())]
+        .into_iter()
+        .collect();
+    store.write_batch(batch)?;
+    store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+
+    let entries = store.into_iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 5);
+    assert_eq!(entries[0].0, "hash");
+    assert_eq!(
+        entries[1],
+        ("key0".to_owned(), DumpValue::String("value0".to_owned()))
+    );
+    assert_eq!(
+        entries[4],
+        ("key3".to_owned(), DumpValue::String("value3".to_owned()))
+    );
+
+    // The store's files are still there afterwards
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Sessions can be loaded until they expire, and stay expired until they're stored again.
+#[test]
+fn session_store_expiry() -> Result<()> {
+    use server::{MockClock, SessionStore};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(MockClock::new(SystemTime::now()));
+    let store = KvStore::open(temp_dir.path())?;
+    let sessions = SessionStore::with_clock(store, clock.clone());
+
+    let ttl = Duration::from_secs(60);
+    sessions.store("abc", "user=1:theme=dark", ttl)?;
+    sessions.store("def", "user=2", ttl)?;
+    assert_eq!(sessions.load("abc")?, Some("user=1:theme=dark".to_owned()));
+    assert_eq!(sessions.load("missing")?, None);
+
+    sessions.destroy("def")?;
+    sessions.destroy("def")?;
+    assert_eq!(sessions.load("def")?, None);
+
+    sessions.store("forever", "user=3", Duration::from_millis(u64::max_value()))?;
+
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(sessions.load("abc")?, None);
+    assert_eq!(sessions.load("abc")?, None);
+    assert_eq!(sessions.load("forever")?, Some("user=3".to_owned()));
+    sessions.store("abc", "user=1", ttl)?;
+    assert_eq!(sessions.load("abc")?, Some("user=1".to_owned()));
+    Ok(())
+}
+
+// The cache engine stays within its budget by evicting the least recently used keys.
+#[test]
+fn cache_engine_evicts_least_recently_used() -> Result<()> {
+    use server::CacheEngine;
+    let cache = CacheEngine::new(1000);
+    for i in 0..5 {
+        cache.set(format!("key{}", i), "x".repeat(100))?;
+    }
+    // Reading key0 makes key1 the least recently used
+    assert!(cache.get("key0".to_owned())?.is_some());
+    cache.set("key5".to_owned(), "x".repeat(100))?;
+    assert_eq!(cache.evictions(), 1);
+    assert!(cache.used_memory() <= 1000);
+    assert_eq!(cache.get("key1".to_owned())?, None);
+    assert!(cache.get("key0".to_owned())?.is_some());
+    assert!(cache.get("key5".to_owned())?.is_some());
+
+    // A value bigger than the whole budget isn't stored, and doesn't evict anything
+    cache.set("huge".to_owned(), "x".repeat(2000))?;
+    assert_eq!(cache.get("huge".to_owned())?, None);
+    assert_eq!(cache.stats()?.memtable_entries, 5);
+
+    cache.remove("key0".to_owned())?;
+    match cache.remove("key0".to_owned()) {
+        Err(Error::KeyNotFound) => {}
+        result => panic!("expected KeyNotFound, got {:?}", result),
+    }
+    Ok(())
+}
+
+// Bits at large offsets don't take up space for the bits before them.
+#[test]
+fn sparse_bitmap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let far = 1 << 40;
+    store.setbit("active".to_owned(), 3, true)?;
+    store.setbit("active".to_owned(), far, true)?;
+    store.setbit("active".to_owned(), 7, true)?;
+    store.setbit("active".to_owned(), 7, false)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    assert!(store.getbit("active".to_owned(), 3)?);
+    assert!(store.getbit("active".to_owned(), far)?);
+    assert!(!store.getbit("active".to_owned(), 7)?);
+    assert!(!store.getbit("missing".to_owned(), 0)?);
+    assert_eq!(store.bitcount("active".to_owned())?, 2);
+    assert!(store.stats()?.disk_bytes < 64 * 1024);
+    Ok(())
+}
+
+// Archived pages can be replayed on top of a snapshot up to a point in time.
+#[test]
+fn point_in_time_recovery() -> Result<()> {
+    use server::{Clock, MockClock, RecoveryTarget};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    let archive = temp_dir.path().join("archive");
+    let snapshot = temp_dir.path().join("snapshot");
+    std::fs::create_dir(&data)?;
+    let logger = kvs::get_default_logger();
+    let clock = Arc::new(MockClock::new(SystemTime::now()));
+    let mut options = Options::default();
+    options.archive_dir = Some(archive.clone());
+    options.clock = clock.clone();
+
+    let store = KvStore::open_with_options(&data, &logger, options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.snapshot(&snapshot)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    clock.advance(Duration::from_secs(1));
+    let before_delete = clock.now();
+    clock.advance(Duration::from_secs(1));
+    store.remove("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let restored = temp_dir.path().join("restored-time");
+    let target = RecoveryTarget::Time(before_delete);
+    assert_eq!(
+        KvStore::restore_to(&snapshot, &archive, &restored, target)?,
+        1
+    );
+    let store = KvStore::open(&restored)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+
+    let restored = temp_dir.path().join("restored-sequence");
+    let target = RecoveryTarget::Sequence(3);
+    assert_eq!(
+        KvStore::restore_to(&snapshot, &archive, &restored, target)?,
+        2
+    );
+    let store = KvStore::open(&restored)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+
+    let restored = temp_dir.path().join("restored-all");
+    let target = RecoveryTarget::Sequence(std::u64::MAX);
+    assert_eq!(
+        KvStore::restore_to(&snapshot, &archive, &restored, target)?,
+        3
+    );
+    let store = KvStore::open(&restored)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Compaction doesn't delete pages that couldn't be archived yet.
+#[test]
+fn archive_before_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    let archive = temp_dir.path().join("archive");
+    std::fs::create_dir(&data)?;
+    let logger = kvs::get_default_logger();
+    let mut options = Options::default();
+    options.archive_dir = Some(archive.clone());
+
+    let store = KvStore::open_with_options(&data, &logger, options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // Make the archive unwritable; writes still succeed
+    std::fs::remove_dir_all(&archive)?;
+    std::fs::write(&archive, "")?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.compact().is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    std::fs::remove_file(&archive)?;
+    std::fs::create_dir(&archive)?;
+    store.compact()?;
+    let archived = std::fs::read_dir(&archive)?
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("00000000000000000002-"))
+        .count();
+    assert_eq!(archived, 2);
+    Ok(())
+}
+
+// A snapshot can be checked without being changed, and corruption in it is found.
+#[test]
+fn verify_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    let snapshot = temp_dir.path().join("snapshot");
+    std::fs::create_dir(&data)?;
+
+    let store = KvStore::open(&data)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.snapshot(&snapshot)?;
+    drop(store);
+
+    let report = KvStore::verify_backup(&snapshot, 10)?;
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(report.keys_sampled, 10);
+    // Checking it didn't leave anything behind that isn't in the checksums
+    assert!(KvStore::verify_backup(&snapshot, 0)?.is_ok());
+
+    let store = KvStore::open_read_only(&snapshot)?;
+    assert_eq!(store.get("key7".to_owned())?, Some("value7".to_owned()));
+    assert!(store.set("key7".to_owned(), "other".to_owned()).is_err());
+    match store.compact() {
+        Err(Error::ReadOnly) => (),
+        result => panic!("expected Error::ReadOnly, got {:?}", result),
+    }
+    drop(store);
+
+    let data_file = std::fs::read_dir(&snapshot)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.extension()
+                .map_or(false, |ext| ext == "data" || ext == "segment")
+        })
+        .unwrap();
+    let mut contents = std::fs::read(&data_file)?;
+    let last = contents.len() - 1;
+    contents[last] ^= 0xff;
+    std::fs::write(&data_file, contents)?;
+    let report = KvStore::verify_backup(&snapshot, 0)?;
+    assert!(!report.is_ok());
+
+    std::fs::remove_file(snapshot.join(server::CHECKSUMS_FILE))?;
+    assert!(!KvStore::verify_backup(&snapshot, 0)?.is_ok());
+    Ok(())
+}
+
+// Keys can be moved to another store through an SST file.
+#[test]
+fn sst_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = temp_dir.path().join("source");
+    let dest = temp_dir.path().join("dest");
+    let sst = temp_dir.path().join("export.sst");
+    std::fs::create_dir(&source)?;
+    std::fs::create_dir(&dest)?;
+    let logger = kvs::get_default_logger();
+    let mut options = Options::default();
+    options.durability = Durability::Buffered;
+
+    let store = KvStore::open_with_options(&source, &logger, options.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key5".to_owned())?;
+    store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+    assert_eq!(store.export_sst(&sst)?, 1000);
+    // The file isn't overwritten
+    assert!(store.export_sst(&sst).is_err());
+    drop(store);
+
+    let store = KvStore::open_with_options(&dest, &logger, options)?;
+    store.set("key5".to_owned(), "old".to_owned())?;
+    assert_eq!(store.import_sst(&sst)?, 1000);
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("old".to_owned()));
+    assert_eq!(
+        store.hget("hash".to_owned(), "field".to_owned())?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// Stores with the same contents dump identically, whatever order they were written in.
+#[test]
+fn dump_is_deterministic() -> Result<()> {
+    use server::DumpFormat;
+    use std::time::Duration;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = temp_dir.path().join("first");
+    let second = temp_dir.path().join("second");
+    std::fs::create_dir(&first)?;
+    std::fs::create_dir(&second)?;
+
+    let store = KvStore::open(&first)?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.hset("h".to_owned(), "field".to_owned(), "value".to_owned())?;
+    store.zadd("z".to_owned(), 1.5, "member".to_owned())?;
+    store.setbit("bits".to_owned(), 100_000, true)?;
+    store.lock("l".to_owned(), Duration::from_secs(60))?;
+    let mut json = Vec::new();
+    assert_eq!(store.dump(&mut json, DumpFormat::Json)?, 6);
+    let json = String::from_utf8(json).unwrap();
+    let lines: Vec<&str> = json.lines().collect();
+    assert_eq!(lines[0], r#"{"key":"a","value":{"string":"1"}}"#);
+    assert_eq!(lines[1], r#"{"key":"b","value":{"string":"2"}}"#);
+    assert_eq!(lines[2], r#"{"key":"bits","value":{"bitmap":[100000]}}"#);
+    let mut ron = Vec::new();
+    store.dump(&mut ron, DumpFormat::Ron)?;
+    assert_eq!(String::from_utf8(ron).unwrap().lines().count(), 6);
+
+    let store = KvStore::open(&second)?;
+    store.set("a".to_owned(), "0".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    let mut dump = Vec::new();
+    store.dump(&mut dump, DumpFormat::Json)?;
+    assert_eq!(
+        String::from_utf8(dump).unwrap(),
+        lines[..2].join("\n") + "\n"
+    );
+    Ok(())
+}
+
+// A dump can be loaded back, with existing keys handled by the conflict policy.
+#[test]
+fn load_dump_conflicts() -> Result<()> {
+    use server::{ConflictPolicy, DumpFormat};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "old".to_owned())?;
+    store.xadd("s".to_owned(), "old entry".to_owned())?;
+
+    let dump = concat!(
+        r#"{"key":"a","value":{"string":"new"}}"#,
+        "\n",
+        r#"(key:"b",value:hash({"f":"v"}))"#,
+        "\n",
+        r#"{"key":"bad","value":{"zset":[["m","nope"]]}}"#,
+        "\n",
+        r#"{"key":"s","value":{"stream":[["99999999999999-0","new entry"]]}}"#,
+        "\n",
+    );
+    let report = store.load_dump(dump.as_bytes(), ConflictPolicy::Skip)?;
+    assert_eq!((report.loaded, report.skipped), (1, 2));
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].starts_with("line 3:"));
+    assert_eq!(store.get("a".to_owned())?, Some("old".to_owned()));
+    assert_eq!(
+        store.hget("b".to_owned(), "f".to_owned())?,
+        Some("v".to_owned())
+    );
+
+    let report = store.load_dump(dump.as_bytes(), ConflictPolicy::Fail)?;
+    assert_eq!(report.loaded, 0);
+    assert!(report.errors[0].starts_with("line 1:"));
+    // Nothing is loaded, even when the conflict comes pages into the dump
+    let mut long_dump = String::new();
+    for i in 0..4000 {
+        long_dump += &format!("{{\"key\":\"new{}\",\"value\":{{\"string\":\"v\"}}}}\n", i);
+    }
+    long_dump += dump;
+    let report = store.load_dump(long_dump.as_bytes(), ConflictPolicy::Fail)?;
+    assert_eq!(report.loaded, 0);
+    assert!(report.errors[0].starts_with("line 4001:"));
+    assert_eq!(store.get("new0".to_owned())?, None);
+
+    let report = store.load_dump(dump.as_bytes(), ConflictPolicy::Overwrite)?;
+    assert_eq!(report.loaded, 3);
+    assert_eq!(store.get("a".to_owned())?, Some("new".to_owned()));
+    // New ids come after the loaded ones
+    let id = store.xadd("s".to_owned(), "after".to_owned())?;
+    assert!(id > "99999999999999-0".parse().unwrap());
+
+    // A round trip through a dump changes nothing
+    let mut before = Vec::new();
+    store.dump(&mut before, DumpFormat::Ron)?;
+    let report = store.load_dump(&before[..], ConflictPolicy::Overwrite)?;
+    assert!(report.is_ok());
+    let mut after = Vec::new();
+    store.dump(&mut after, DumpFormat::Ron)?;
+    assert_eq!(before, after);
+    Ok(())
+}
+
+// Batches bigger than a page are written in a single commit.
+#[test]
+fn write_batch() -> Result<()> {
+    use kvs::WriteBatch;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let options = Options {
+        compaction: CompactionStrategy::Manual,
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    for i in 0..3000 {
+        batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    batch.remove("removed".to_owned());
+    batch.remove("missing".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.stats()?.pages, 3);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key2999".to_owned())?,
+        Some("value2999".to_owned())
+    );
+    assert_eq!(store.get("removed".to_owned())?, None);
+    Ok(())
+}
+
+// Renames in a batch move any kind of value, and see the batch's earlier writes.
+#[test]
+fn rename_batch() -> Result<()> {
+    use kvs::WriteBatch;
+    use server::CacheEngine;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "ann".to_owned())?;
+    store.hset(
+        "user:1:tags".to_owned(),
+        "admin".to_owned(),
+        "yes".to_owned(),
+    )?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .rename("user:1".to_owned(), "users/1".to_owned())
+        .rename("user:1:tags".to_owned(), "users/1/tags".to_owned())
+        .set("user:2".to_owned(), "bob".to_owned())
+        .rename("user:2".to_owned(), "users/2".to_owned());
+    store.write_batch(batch)?;
+    // Nothing is written if a key to rename doesn't exist
+    let mut batch = WriteBatch::new();
+    batch
+        .rename("users/1".to_owned(), "people/1".to_owned())
+        .rename("user:1".to_owned(), "people/2".to_owned());
+    match store.write_batch(batch) {
+        Err(Error::KeyNotFound) => {}
+        result => panic!("expected KeyNotFound, got {:?}", result),
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("user:1".to_owned())?, None);
+    assert_eq!(store.get("users/1".to_owned())?, Some("ann".to_owned()));
+    assert_eq!(store.get("users/2".to_owned())?, Some("bob".to_owned()));
+    assert_eq!(store.get("user:2".to_owned())?, None);
+    assert_eq!(store.get("people/1".to_owned())?, None);
+    assert_eq!(
+        store.hget("users/1/tags".to_owned(), "admin".to_owned())?,
+        Some("yes".to_owned())
+    );
+    store.rename("users/2".to_owned(), "users/1".to_owned())?;
+    assert_eq!(store.get("users/1".to_owned())?, Some("bob".to_owned()));
+    assert_eq!(store.get("users/2".to_owned())?, None);
+    assert!(store
+        .rename("users/2".to_owned(), "users/3".to_owned())
+        .is_err());
+
+    let cache = CacheEngine::new(1000);
+    cache.set("a".to_owned(), "1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .rename("a".to_owned(), "b".to_owned())
+        .rename("b".to_owned(), "c".to_owned());
+    cache.write_batch(batch)?;
+    assert_eq!(cache.get("a".to_owned())?, None);
+    assert_eq!(cache.get("c".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+// A transaction commits only if nothing it read was written in the meantime.
+#[test]
+fn optimistic_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "1".to_owned())?;
+
+    let mut txn = store.begin();
+    let value = txn.get(&store, "counter".to_owned())?.unwrap();
+    txn.set("counter".to_owned(), format!("{}1", value));
+    assert_eq!(
+        txn.get(&store, "counter".to_owned())?,
+        Some("11".to_owned())
+    );
+    // A blind write to a key the other transaction doesn't read doesn't conflict with it
+    let mut other = store.begin();
+    other.set("other".to_owned(), "value".to_owned());
+    store.set("counter".to_owned(), "2".to_owned())?;
+    store.commit_transaction(other)?;
+    match store.commit_transaction(txn) {
+        Err(Error::Conflict) => {}
+        result => panic!("expected a conflict, got {:?}", result),
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+
+    let value = store.transaction(|txn| {
+        let value = txn.get("counter".to_owned())?.unwrap();
+        txn.set("counter".to_owned(), format!("{}2", value));
+        txn.remove("other".to_owned());
+        Ok(value)
+    })?;
+    assert_eq!(value, "2");
+    assert_eq!(store.get("counter".to_owned())?, Some("22".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, None);
+
+    // Nothing is written if the closure fails
+    let result: Result<()> = store.transaction(|txn| {
+        txn.set("counter".to_owned(), "3".to_owned());
+        Err(Error::KeyNotFound)
+    });
+    assert!(result.is_err());
+    assert_eq!(store.get("counter".to_owned())?, Some("22".to_owned()));
+
+    // The store isn't locked while the closure runs, and a conflicting commit runs it again
+    let mut attempts = 0;
+    store.transaction(|txn| {
+        attempts += 1;
+        let value = txn.get("counter".to_owned())?.unwrap();
+        if attempts == 1 {
+            store.set("counter".to_owned(), "4".to_owned())?;
+        }
+        txn.set("counter".to_owned(), format!("{}4", value));
+        Ok(())
+    })?;
+    assert_eq!(attempts, 2);
+    assert_eq!(store.get("counter".to_owned())?, Some("44".to_owned()));
+
+    // It gives up once it has conflicted every time it was allowed to run
+    let mut attempts = 0;
+    let result = store.transaction(|txn| {
+        attempts += 1;
+        txn.get("counter".to_owned())?;
+        store.set("counter".to_owned(), attempts.to_string())?;
+        txn.set("counter".to_owned(), "lost".to_owned());
+        Ok(())
+    });
+    match result {
+        Err(Error::Conflict) => {}
+        result => panic!("expected a conflict, got {:?}", result),
+    }
+    assert_eq!(attempts, MAX_TRANSACTION_ATTEMPTS);
+    assert_eq!(
+        store.get("counter".to_owned())?,
+        Some(MAX_TRANSACTION_ATTEMPTS.to_string())
+    );
+    Ok(())
+}
+
+// A key locked by a transaction can't be locked or written by anyone else until it ends.
+#[test]
+fn transaction_key_locks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+
+    let mut first = store.begin();
+    let mut second = store.begin();
+    first.lock(&store, "a".to_owned())?;
+    first.lock(&store, "a".to_owned())?;
+    match second.lock(&store, "a".to_owned()) {
+        Err(Error::LockHeld) => {}
+        result => panic!("expected the lock to be held, got {:?}", result),
+    }
+    assert!(store.set("a".to_owned(), "2".to_owned()).is_err());
+    second.lock(&store, "b".to_owned())?;
+    second.set("a".to_owned(), "3".to_owned());
+    match store.commit_transaction(second) {
+        Err(Error::LockHeld) => {}
+        result => panic!("expected the lock to be held, got {:?}", result),
+    }
+    // The failed commit released the second transaction's lock
+    store.set("b".to_owned(), "1".to_owned())?;
+
+    first.set("a".to_owned(), "4".to_owned());
+    store.commit_transaction(first)?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+    store.set("a".to_owned(), "5".to_owned())?;
+
+    // Locks are released when a scoped transaction fails, too
+    let result: Result<()> = store.transaction(|txn| {
+        txn.lock("a".to_owned())?;
+        Err(Error::KeyNotFound)
+    });
+    assert!(result.is_err());
+    let mut txn = store.begin();
+    txn.lock(&store, "a".to_owned())?;
+    store.abort_transaction(txn);
+    store.set("a".to_owned(), "6".to_owned())?;
+    Ok(())
+}
+
+// Write sequence numbers keep increasing across restarts.
+#[test]
+fn applied_sequence_survives_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.applied_sequence()?, 2);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.applied_sequence()?, 2);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.applied_sequence()?, 3);
+    Ok(())
+}
+
+// Two transactions each waiting for the other's key: the one that closes the cycle is aborted.
+#[test]
+fn transaction_deadlock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut first = store.begin();
+    let mut second = store.begin();
+    let mut third = store.begin();
+    first.lock(&store, "a".to_owned())?;
+    second.lock(&store, "b".to_owned())?;
+    third.lock(&store, "c".to_owned())?;
+    // first waits for second, which waits for third
+    for (txn, key) in vec![(&mut first, "b"), (&mut second, "c")] {
+        match txn.lock(&store, key.to_owned()) {
+            Err(Error::LockHeld) => {}
+            result => panic!("expected the lock to be held, got {:?}", result),
+        }
+    }
+    match third.lock(&store, "a".to_owned()) {
+        Err(Error::Deadlock) => {}
+        result => panic!("expected a deadlock, got {:?}", result),
+    }
+    // The victim's locks are gone, so the transaction waiting for it can go on
+    third.set("c".to_owned(), "lost".to_owned());
+    match store.commit_transaction(third) {
+        Err(Error::Deadlock) => {}
+        result => panic!("expected a deadlock, got {:?}", result),
+    }
+    second.lock(&store, "c".to_owned())?;
+    second.set("c".to_owned(), "second".to_owned());
+    store.commit_transaction(second)?;
+    first.lock(&store, "b".to_owned())?;
+    store.commit_transaction(first)?;
+    assert_eq!(store.get("c".to_owned())?, Some("second".to_owned()));
+    Ok(())
+}
+
+// Compaction moves pages into the cold directory, and reads look in both tiers.
+#[test]
+fn cold_tier() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary cold directory");
+    let logger = kvs::get_default_logger();
+    let options = Options {
+        cold_dir: Some(cold_dir.path().to_owned()),
+        compaction: CompactionStrategy::Manual,
+        ..Options::default()
+    };
+    let page_count = |dir: &std::path::Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |ext| ext == "log" || ext == "segment")
+            })
+            .count()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "cold".to_owned())?;
+    }
+    store.save()?;
+    assert_eq!(page_count(cold_dir.path()), 0);
+
+    store.compact()?;
+    assert_eq!(page_count(temp_dir.path()), 0);
+    assert!(page_count(cold_dir.path()) > 0);
+
+    for key_id in 10..30 {
+        store.set(format!("key{}", key_id), "hot".to_owned())?;
+    }
+    store.save()?;
+    assert!(page_count(temp_dir.path()) > 0);
+    assert!(store.verify()?.is_ok());
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    for key_id in 0..30 {
+        let expected = if key_id < 10 { "cold" } else { "hot" };
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(expected.to_owned())
+        );
+    }
+    assert!(store.verify()?.is_ok());
+    Ok(())
+}
+
+// Once the deadline has passed, reads that need pages give up, but the memtable is still read.
+#[test]
+fn deadline_stops_page_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("saved".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("buffered".to_owned(), "value".to_owned())?;
+
+    store.set_deadline(Some(Instant::now()));
+    assert_eq!(store.get("buffered".to_owned())?, Some("value".to_owned()));
+    match store.get("saved".to_owned()) {
+        Err(Error::DeadlineExceeded) => {}
+        result => panic!("expected the deadline to pass, got {:?}", result),
+    }
+    match store.verify() {
+        Err(Error::DeadlineExceeded) => {}
+        result => panic!("expected the deadline to pass, got {:?}", result),
+    }
+
+    store.set_deadline(Some(Instant::now() + Duration::from_secs(60)));
+    assert_eq!(store.get("saved".to_owned())?, Some("value".to_owned()));
+    store.set_deadline(None);
+    assert!(store.verify()?.is_ok());
+    Ok(())
+}
+
+// A request waiting for another to release the store gives up at its deadline, and a panic
+// while the store is locked fails later requests rather than panicking them too
+#[test]
+fn locked_store() -> Result<()> {
+    use std::sync::mpsc;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let (locked, is_locked) = mpsc::channel();
+    let locked = std::sync::Mutex::new(locked);
+    store.on_set(HookMode::Sync, move |key, _| {
+        if key == "slow" {
+            locked.lock().unwrap().send(()).unwrap();
+            thread::sleep(Duration::from_secs(1));
+        } else if key == "panic" {
+            panic!("hook panicked");
+        }
+        Ok(())
+    });
+
+    let slow = store.clone();
+    let writer = thread::spawn(move || slow.set("slow".to_owned(), "value".to_owned()));
+    is_locked.recv().unwrap();
+    let start = Instant::now();
+    store.set_deadline(Some(start + Duration::from_millis(50)));
+    match store.get("slow".to_owned()) {
+        Err(Error::DeadlineExceeded) => {}
+        result => panic!("expected the deadline to pass, got {:?}", result),
+    }
+    assert!(start.elapsed() < Duration::from_millis(500));
+    store.set_deadline(None);
+    writer.join().unwrap()?;
+    assert_eq!(store.get("slow".to_owned())?, Some("value".to_owned()));
+
+    let panicking = store.clone();
+    assert!(
+        thread::spawn(move || panicking.set("panic".to_owned(), "value".to_owned()))
+            .join()
+            .is_err()
+    );
+    match store.get("slow".to_owned()) {
+        Err(Error::Message(message)) => assert!(message.contains("panic"), "{}", message),
+        result => panic!("expected the store to be unusable, got {:?}", result),
+    }
+    Ok(())
+}
+
+// Once the idempotency cache is full, each new token pushes out the oldest one.
+#[test]
+fn idempotency_cache_forgets_oldest() {
+    use server::Claim;
+    let mut cache = IdempotencyCache::new(2);
+    let get = CommandRequest::Get {
+        key: "key".to_owned(),
+    };
+    let applied = |cache: &mut IdempotencyCache, token: &str| match cache.claim("a", token, &get) {
+        Claim::Applied(response) => Some(format!("{}", response)),
+        Claim::New => None,
+        claim => panic!("expected the token to be applied or new, got {:?}", claim),
+    };
+    for (token, response) in &[("a", "1"), ("b", "2")] {
+        assert!(applied(&mut cache, token).is_none());
+        let response = CommandResponse::Message(response.to_string());
+        cache.finish("a", token, Some(response));
+    }
+    assert_eq!(applied(&mut cache, "a"), Some("1".to_owned()));
+    assert_eq!(cache.len(), 2);
+    assert!(applied(&mut cache, "c").is_none());
+    cache.finish("a", "c", Some(CommandResponse::Message("3".to_owned())));
+    assert_eq!(cache.len(), 2);
+    assert!(applied(&mut cache, "a").is_none());
+    assert_eq!(applied(&mut cache, "c"), Some("3".to_owned()));
+}
+
+// A token only replays for the client that sent it and the request it was sent with, is held
+// while its request is applied, and is forgotten if the request fails.
+#[test]
+fn idempotency_cache_claims() {
+    use server::Claim;
+    let mut cache = IdempotencyCache::new(10);
+    let set = CommandRequest::Set {
+        key: "key".to_owned(),
+        value: Some("value".to_owned()),
+    };
+    let remove = CommandRequest::Set {
+        key: "key".to_owned(),
+        value: None,
+    };
+    assert!(match cache.claim("a", "token", &set) {
+        Claim::New => true,
+        _ => false,
+    });
+    assert!(match cache.claim("a", "token", &set) {
+        Claim::InFlight => true,
+        _ => false,
+    });
+    let written = CommandResponse::Written { sequence: 1 };
+    cache.finish("a", "token", Some(written));
+    assert!(match cache.claim("a", "token", &set) {
+        Claim::Applied(CommandResponse::Written { sequence: 1 }) => true,
+        _ => false,
+    });
+    assert!(match cache.claim("a", "token", &remove) {
+        Claim::Mismatch => true,
+        _ => false,
+    });
+    assert!(match cache.claim("b", "token", &set) {
+        Claim::New => true,
+        _ => false,
+    });
+    cache.finish("b", "token", None);
+    assert!(match cache.claim("b", "token", &remove) {
+        Claim::New => true,
+        _ => false,
+    });
+}
+
+// Metrics are buffered until they're flushed, then pushed to the agent in statsd's format.
+#[test]
+fn statsd_sink() -> Result<()> {
+    let agent = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    agent.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let addr = agent.local_addr()?.to_string();
+    let mut statsd = Statsd::new(&addr, "test", Duration::from_secs(0))?;
+    assert!(statsd.is_due());
+
+    statsd.record_request("get", Duration::from_millis(3), false);
+    statsd.record_request("set", Duration::from_millis(5), true);
+    let stats = kvs::Stats {
+        pages: 2,
+        ..kvs::Stats::default()
+    };
+    statsd.record_metrics(&stats, 10);
+    statsd.flush()?;
+
+    let mut buf = [0; 2048];
+    let len = agent.recv(&mut buf)?;
+    let lines: Vec<&str> = std::str::from_utf8(&buf[..len]).unwrap().lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            "test.request.get:3|ms",
+            "test.request.set:5|ms",
+            "test.request.set.errors:1|c",
+            "test.pages:2|g",
+            "test.pages.partial:0|g",
+            "test.memtable.entries:0|g",
+            "test.memtable.usage:0|g",
+            "test.disk.usage:0|g",
+            "test.server.requests:10|c",
+        ]
+    );
+
+    // The request counter is sent as the increase since the last flush
+    statsd.record_metrics(&stats, 15);
+    statsd.flush()?;
+    let len = agent.recv(&mut buf)?;
+    assert!(std::str::from_utf8(&buf[..len])
+        .unwrap()
+        .ends_with("test.server.requests:5|c"));
+    Ok(())
+}
+
+// Finished spans are posted to the collector's /v1/traces as OTLP JSON, at the latest when the
+// telemetry handle is dropped.
+#[test]
+fn otlp_export() -> Result<()> {
+    let collector = TcpListener::bind("127.0.0.1:0")?;
+    let endpoint = format!("http://{}", collector.local_addr()?);
+    let telemetry = Telemetry::start(&endpoint, "kvs-test", &kvs::get_default_logger())?;
+    let mut span = telemetry.start_span("get");
+    span.set_attribute("kvs.key", "key1".to_owned());
+    telemetry.finish(span, Some("Key not found".to_owned()));
+    drop(telemetry);
+
+    let (stream, _) = collector.accept()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    assert_eq!(request_line, "POST /v1/traces HTTP/1.1\r\n");
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        if header == "\r\n" {
+            break;
+        }
+        if header.starts_with("Content-Length: ") {
+            content_length = header["Content-Length: ".len()..].trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    (&stream).write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")?;
+
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.starts_with(r#"{"resourceSpans":[{"#));
+    assert!(body.contains(r#""key":"service.name","value":{"stringValue":"kvs-test"}"#));
+    assert!(body.contains(r#""name":"get""#));
+    assert!(body.contains(r#""key":"kvs.key","value":{"stringValue":"key1"}"#));
+    assert!(body.contains(r#""status":{"code":2,"message":"Key not found"}"#));
+    Ok(())
+}
+
+// Log files are rotated between records once they're too big, keeping only the newest few.
+#[test]
+fn rotating_log_file() -> Result<()> {
+    use std::io::Write;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("server.log");
+    let rotation = Rotation {
+        max_size: Some(9),
+        keep: 2,
+        ..Rotation::default()
+    };
+    let mut file = RotatingFile::open(&path, rotation)?;
+    for record in 0..4 {
+        // A record written in pieces stays in one file
+        write!(file, "record ")?;
+        writeln!(file, "{}", record)?;
+        file.flush()?;
+    }
+
+    let read = |name: &str| std::fs::read_to_string(temp_dir.path().join(name)).unwrap();
+    assert_eq!(read("server.log"), "");
+    assert_eq!(read("server.log.1"), "record 3\n");
+    assert_eq!(read("server.log.2"), "record 2\n");
+    assert!(!temp_dir.path().join("server.log.3").exists());
+    Ok(())
+}
+
+// With room for only one open file, reads spread over many pages still find every key.
+#[test]
+fn reads_with_one_open_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.save()?;
+    }
+    drop(store);
+
+    let mut options = Options::default();
+    options.max_open_files = 1;
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    for _ in 0..2 {
+        for i in (0..20).rev() {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    assert!(store.verify()?.is_ok());
+    Ok(())
+}
+
+// Compaction packs pages into segment files, which can be read back after reopening and are
+// deleted by the next compaction.
+#[test]
+fn compaction_packs_segments() -> Result<()> {
+    use kvs::WriteBatch;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let mut options = Options {
+        segment_size: 100_000,
+        ..Options::default()
+    };
+    let files = |extension: &str| -> Vec<std::path::PathBuf> {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == extension))
+            .collect()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+    let mut batch = WriteBatch::new();
+    for i in 0..5000 {
+        batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    store.write_batch(batch)?;
+    store.compact()?;
+    let pages = store.stats()?.pages;
+    let segments = files("segment");
+    assert!(files("log").is_empty());
+    assert!(files("data").is_empty());
+    assert!(segments.len() > 1);
+    assert!((segments.len() as u64) < pages);
+    drop(store);
+
+    options.max_open_files = 1;
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    for i in (0..5000).step_by(7) {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(store.verify()?.is_ok());
+
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.compact()?;
+    assert!(segments.iter().all(|segment| !segment.exists()));
+    assert_eq!(store.get("key0".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(
+        store.get("key4999".to_owned())?,
+        Some("value4999".to_owned())
+    );
+    Ok(())
+}
+
+// Searching overlapping pages on a pool of threads finds the same values, tombstones, and hash
+// fields as searching them in turn.
+#[test]
+fn parallel_lookups() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    for round in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in (0..40).filter(|key_id| key_id % (round + 1) == 0) {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+        if round == 3 {
+            store.remove("key3".to_owned())?;
+        }
+        store.hset(
+            "hash".to_owned(),
+            format!("field{}", round),
+            "value".to_owned(),
+        )?;
+        drop(store);
+    }
+
+    for &lookup_threads in &[1, 4] {
+        let options = Options {
+            lookup_threads,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+        for key_id in 0..40 {
+            let newest = (0..5)
+                .rev()
+                .find(|round| key_id % (round + 1) == 0)
+                .unwrap();
+            let expected = match key_id {
+                3 => None,
+                _ => Some(format!("value{}", newest)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.get("key40".to_owned())?, None);
+        assert_eq!(store.hgetall("hash".to_owned())?.len(), 5);
+    }
+    Ok(())
+}
+
+// Compacting on several threads keeps the newest value of every key, drops removed keys, and
+// folds hash fields together, whichever thread's share of the hashes a key falls in.
+#[test]
+fn parallel_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let options = Options {
+        compaction_threads: 4,
+        ..Options::default()
+    };
+    for round in 0..3 {
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+        for key_id in 0..40 {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+            store.hset(
+                format!("hash{}", key_id % 10),
+                format!("field{}", round),
+                "value".to_owned(),
+            )?;
+        }
+        store.remove(format!("key{}", round))?;
+        drop(store);
+    }
+
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    for key_id in 0..40 {
+        let expected = match key_id {
+            2 => None,
+            _ => Some("value2".to_owned()),
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    for hash_id in 0..10 {
+        assert_eq!(store.hgetall(format!("hash{}", hash_id))?.len(), 3);
+    }
+    assert!(store.verify()?.is_ok());
+    Ok(())
+}
+
+// Both automatic compaction strategies keep the number of pages down as they're saved, without
+// losing any writes, merge operands, or removes.
+#[test]
+fn compaction_strategies() -> Result<()> {
+    let logger = kvs::get_default_logger();
+    for &strategy in [CompactionStrategy::SizeTiered, CompactionStrategy::Leveled].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            compaction: strategy,
+            compaction_fanout: 3,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+        for round in 0..60 {
+            store.set(format!("key{}", round % 20), format!("value{}", round))?;
+            store.hset(
+                format!("hash{}", round % 5),
+                format!("field{}", round),
+                "value".to_owned(),
+            )?;
+            if round % 7 == 0 {
+                store.remove(format!("key{}", round % 20))?;
+            }
+        }
+        let files = std::fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |ext| ext == "log" || ext == "segment")
+            })
+            .count();
+        assert!(files < 20, "{} has {} page files", strategy, files);
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+        for key_id in 0..20 {
+            // The last write of each key was in round 40 + key_id
+            let expected = match (40 + key_id) % 7 {
+                0 => None,
+                _ => Some(format!("value{}", 40 + key_id)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        for hash_id in 0..5 {
+            assert_eq!(store.hgetall(format!("hash{}", hash_id))?.len(), 12);
+        }
+        assert!(store.verify()?.is_ok());
+    }
+    Ok(())
+}
+
+// Pages whose entries have mostly been replaced or removed are rewritten without them, even
+// when the strategy wouldn't merge them yet.
+#[test]
+fn garbage_driven_compaction() -> Result<()> {
+    let logger = kvs::get_default_logger();
+    let mut page_counts = Vec::new();
+    for &ratio in [0.5, 2.0].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            compaction_fanout: 1000,
+            compaction_garbage_ratio: ratio,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+        for round in 0..3 {
+            for key_id in 0..20 {
+                store.set(format!("key{}", key_id), format!("value{}", round))?;
+            }
+            store.remove(format!("key{}", round))?;
+        }
+        page_counts.push(store.stats()?.pages);
+        assert!(store.verify()?.is_ok());
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+        for key_id in 0..20 {
+            let expected = match key_id {
+                2 => None,
+                _ => Some("value2".to_owned()),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+    }
+    assert!(page_counts[0] < page_counts[1], "{:?}", page_counts);
+    Ok(())
+}
+
+// A store keeps hashing keys the way it was created to, and refuses to open with any other
+// hash function or seed.
+#[test]
+fn key_hash_functions() -> Result<()> {
+    let logger = kvs::get_default_logger();
+    for &algorithm in [HashAlgorithm::XxHash, HashAlgorithm::SipHash].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let key_hash = KeyHash {
+            algorithm,
+            seed: 42,
+        };
+        let options = Options {
+            key_hash: Some(key_hash),
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key3".to_owned())?;
+        drop(store);
+
+        for other in &[
+            KeyHash::default(),
+            KeyHash {
+                seed: 43,
+                ..key_hash
+            },
+        ] {
+            let options = Options {
+                key_hash: Some(*other),
+                ..Options::default()
+            };
+            assert!(KvStore::open_with_options(temp_dir.path(), &logger, options).is_err());
+        }
+        drop(KvStore::open_with_options(
+            temp_dir.path(),
+            &logger,
+            options,
+        )?);
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..20 {
+            let expected = match key_id {
+                3 => None,
+                _ => Some(format!("value{}", key_id)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        store.compact()?;
+        assert_eq!(store.get("key7".to_owned())?, Some("value7".to_owned()));
+        assert!(store.verify()?.is_ok());
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    let options = Options {
+        key_hash: Some(KeyHash {
+            algorithm: HashAlgorithm::XxHash,
+            ..KeyHash::default()
+        }),
+        ..Options::default()
+    };
+    assert!(KvStore::open_with_options(temp_dir.path(), &logger, options).is_err());
+    Ok(())
+}
+
+// Pages record each slot's key length and fingerprint, and a page whose slots don't match the
+// keys in its data file is quarantined like any other damaged page.
+#[test]
+fn slot_key_fingerprints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.verify()?.is_ok());
+    drop(store);
+
+    // The first slot's fingerprint follows the page block and every slot's key length
+    let page = log_files(temp_dir.path()).pop().unwrap();
+    let mut bytes = std::fs::read(&page)?;
+    assert_eq!(bytes.len(), 16384 + 1600 * 4);
+    bytes[16384 + 1600 * 2] ^= 0xff;
+    std::fs::write(&page, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(temp_dir
+        .path()
+        .join("quarantine")
+        .join(page.file_name().unwrap())
+        .exists());
+    Ok(())
+}
+
+// The memtable is saved and started over once its keys and values reach the byte budget,
+// counting values that were overwritten, long before it fills a page.
+#[test]
+fn memtable_byte_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let options = Options {
+        durability: Durability::Buffered,
+        memtable_bytes: 10_000,
+        compaction: CompactionStrategy::Manual,
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    let value = "v".repeat(1000);
+    for i in 0..9 {
+        store.set("key".to_owned(), format!("{}{}", value, i))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.pages, 0);
+    assert_eq!(stats.memtable_entries, 1);
+    assert!(stats.memtable_bytes > 9000, "{}", stats.memtable_bytes);
+
+    store.set("key".to_owned(), format!("{}{}", value, 9))?;
+    let stats = store.stats()?;
+    assert_eq!(stats.pages, 1);
+    assert_eq!((stats.memtable_entries, stats.memtable_bytes), (0, 0));
+    assert_eq!(
+        store.get("key".to_owned())?,
+        Some(format!("{}{}", value, 9))
+    );
+
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("key".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Buffered writes are saved once they've waited for the flush interval, by the store's clock.
+#[test]
+fn flush_interval() -> Result<()> {
+    use server::MockClock;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let clock = Arc::new(MockClock::new(SystemTime::now()));
+    let options = Options {
+        durability: Durability::Buffered,
+        flush_interval: Some(Duration::from_secs(5)),
+        clock: clock.clone(),
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    store.flush_if_due()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    clock.advance(Duration::from_secs(3));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush_if_due()?;
+    assert_eq!(store.stats()?.pages, 0);
+
+    // The interval runs from the first unsaved write
+    clock.advance(Duration::from_secs(2));
+    store.flush_if_due()?;
+    assert_eq!(store.stats()?.pages, 1);
+    let reopened = KvStore::open_read_only(temp_dir.path())?.get("key2".to_owned())?;
+    assert_eq!(reopened, Some("value2".to_owned()));
+
+    clock.advance(Duration::from_secs(10));
+    store.flush_if_due()?;
+    assert_eq!(store.stats()?.pages, 1);
+    Ok(())
+}
+
+// Secondary indexes follow every write to the values they index, and one declared on a store
+// that already has values is built from them when it's opened.
+#[test]
+fn secondary_indexes() -> Result<()> {
+    use kvs::WriteBatch;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let mut options = Options {
+        indexes: vec![
+            SecondaryIndex::json_field("city", "city"),
+            SecondaryIndex::function("initial", |value| value.chars().next().map(String::from)),
+        ],
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+    store.set(
+        "ann".to_owned(),
+        r#"{"city": "Oslo", "zip": 150}"#.to_owned(),
+    )?;
+    store.set(
+        "bob".to_owned(),
+        r#"{"city": "Bergen", "zip": 5003}"#.to_owned(),
+    )?;
+    store.set("cat".to_owned(), r#"{"city": "Oslo"}"#.to_owned())?;
+    store.set("dan".to_owned(), "not json".to_owned())?;
+    assert_eq!(store.get_by_index("city", "Oslo")?, vec!["ann", "cat"]);
+    assert_eq!(store.get_by_index("initial", "n")?, vec!["dan"]);
+
+    store.set("ann".to_owned(), r#"{"city": "Bergen"}"#.to_owned())?;
+    store.remove("cat".to_owned())?;
+    let batch: WriteBatch = vec![("eve".to_owned(), r#"{"city": "Oslo"}"#.to_owned())]
+        .into_iter()
+        .collect();
+    store.write_batch(batch)?;
+    assert_eq!(store.get_by_index("city", "Oslo")?, vec!["eve"]);
+    assert_eq!(store.get_by_index("city", "Bergen")?, vec!["ann", "bob"]);
+    assert!(store.get_by_index("zip", "5003").is_err());
+    drop(store);
+
+    options
+        .indexes
+        .push(SecondaryIndex::json_field("zip", "/zip"));
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    assert_eq!(store.get_by_index("zip", "5003")?, vec!["bob"]);
+    assert_eq!(store.get_by_index("zip", "150")?, Vec::<String>::new());
+    assert_eq!(store.get_by_index("city", "Bergen")?, vec!["ann", "bob"]);
+    assert!(store.verify()?.is_ok());
+    Ok(())
+}
+
+// Substring queries find the same values with and without the n-gram index, which is kept up
+// to date by writes, built when it's turned on, and rebuilt by compaction.
+#[test]
+fn substring_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("greeting".to_owned(), "hello world".to_owned())?;
+    store.set("farewell".to_owned(), "goodbye world".to_owned())?;
+    store.set("word".to_owned(), "wordl".to_owned())?;
+    store.hset("hash".to_owned(), "field".to_owned(), "world".to_owned())?;
+    let found = store.find_values_containing("world")?;
+    assert_eq!(
+        found,
+        vec![
+            ("farewell".to_owned(), "goodbye world".to_owned()),
+            ("greeting".to_owned(), "hello world".to_owned()),
+        ]
+    );
+    drop(store);
+
+    let options = Options {
+        substring_index: true,
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options.clone())?;
+    assert_eq!(store.find_values_containing("world")?, found);
+    store.set("greeting".to_owned(), "hello there".to_owned())?;
+    store.remove("farewell".to_owned())?;
+    store.set("planet".to_owned(), "other worlds".to_owned())?;
+    // "wordl" has every n-gram of "orld" but not in order
+    let keys = |found: Vec<(String, String)>| -> Vec<String> {
+        found.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(keys(store.find_values_containing("orld")?), vec!["planet"]);
+    assert_eq!(
+        keys(store.find_values_containing("th")?),
+        vec!["greeting", "planet"]
+    );
+    assert!(store.find_values_containing("nowhere")?.is_empty());
+
+    store.compact()?;
+    assert_eq!(
+        keys(store.find_values_containing("there")?),
+        vec!["greeting"]
+    );
+    assert!(store.verify()?.is_ok());
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    assert_eq!(
+        keys(store.find_values_containing("wor")?),
+        vec!["planet", "word"]
+    );
+    Ok(())
+}
+
+// Scripts see their own writes and apply all of them or none
+#[test]
+fn scripts() -> Result<()> {
+    use kvs::run_script;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+    let transfer = r#"
+        // Move an amount from one balance to another, if there's enough
+        let from = int(get(arg(0)));
+        let amount = int(arg(2));
+        if from < amount {
+            return "insufficient funds";
+        }
+        set(arg(0), from - amount);
+        let to = get(arg(1));
+        if to == nil { to = "0"; }
+        set(arg(1), int(to) + amount);
+        return get(arg(1));
+    "#;
+    store.set("alice".to_owned(), "100".to_owned())?;
+    assert_eq!(
+        run_script(&store, transfer, &args(&["alice", "bob", "30"]))?,
+        Some("30".to_owned())
+    );
+    assert_eq!(
+        run_script(&store, transfer, &args(&["alice", "bob", "80"]))?,
+        Some("insufficient funds".to_owned())
+    );
+    assert_eq!(store.get("alice".to_owned())?, Some("70".to_owned()));
+
+    let script = r#"
+        let i = 0;
+        let joined = "";
+        while i < argc() {
+            set("k" + str(i), arg(i));
+            joined = joined + get("k" + str(i));
+            i = i + 1;
+        }
+        remove("k0");
+        return joined + " " + str(exists("k0")) + " " + str(len(joined) * 2 % 7);
+    "#;
+    assert_eq!(
+        run_script(&store, script, &args(&["a", "b", "c"]))?,
+        Some("abc false 6".to_owned())
+    );
+    assert_eq!(store.get("k0".to_owned())?, None);
+    assert_eq!(store.get("k2".to_owned())?, Some("c".to_owned()));
+    assert_eq!(run_script(&store, "get(\"k1\");", &[])?, None);
+
+    // Nothing is written if the script fails part-way
+    let failing = "set(\"x\", 1); remove(\"k1\"); return 1 / (argc() - 1);";
+    assert!(run_script(&store, failing, &args(&["1"])).is_err());
+    assert_eq!(store.get("x".to_owned())?, None);
+    assert_eq!(store.get("k1".to_owned())?, Some("b".to_owned()));
+
+    assert!(run_script(&store, "while true {}", &[]).is_err());
+    let doubling = "let s = \"x\"; while true { s = s + s; }";
+    match run_script(&store, doubling, &[]) {
+        Err(Error::Message(message)) => assert!(message.contains("bytes"), "{}", message),
+        result => panic!("expected the string to be too long, got {:?}", result),
+    }
+    assert!(run_script(&store, "let x = ;", &[]).is_err());
+    assert!(run_script(&store, "return y;", &[]).is_err());
+    assert!(run_script(&store, "return 1 + \"1\";", &[]).is_err());
+    assert!(run_script(&store, &"(".repeat(10_000), &[]).is_err());
+    match run_script(&store, "return get(\"__kvs_health\");", &[]) {
+        Err(Error::InvalidKey(_)) => {}
+        result => panic!("expected InvalidKey, got {:?}", result),
+    }
+    Ok(())
+}
+
+// A script run through the engine reads and writes under one lock, so concurrent increments
+// aren't lost
+#[test]
+fn concurrent_scripts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("n".to_owned(), "0".to_owned())?;
+
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    store.run_script("set(\"n\", int(get(\"n\")) + 1);", &[])?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(store.get("n".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Sync hooks can veto writes, and async hooks see them once they're applied
+#[test]
+fn mutation_hooks() -> Result<()> {
+    use kvs::WriteBatch;
+    use std::sync::{Arc, Mutex};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.on_set(HookMode::Sync, |key, value| {
+        if key.starts_with("balance/") && value.parse::<u64>().is_err() {
+            return Err(Error::Message(format!("bad balance for {}", key)));
+        }
+        Ok(())
+    });
+    store.on_remove(HookMode::Sync, |key| {
+        if key == "pinned" {
+            return Err(Error::Message("pinned can't be removed".to_owned()));
+        }
+        Ok(())
+    });
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sets = seen.clone();
+    store.on_set(HookMode::Async, move |key, value| {
+        sets.lock().unwrap().push(format!("set {} {}", key, value));
+        Ok(())
+    });
+    let removes = seen.clone();
+    store.on_remove(HookMode::Async, move |key| {
+        removes.lock().unwrap().push(format!("remove {}", key));
+        Err(Error::Message("only logged".to_owned()))
+    });
+
+    store.set("balance/ann".to_owned(), "10".to_owned())?;
+    assert!(store
+        .set("balance/ann".to_owned(), "lots".to_owned())
+        .is_err());
+    store.set("pinned".to_owned(), "x".to_owned())?;
+    assert!(store.remove("pinned".to_owned()).is_err());
+    let mut batch = WriteBatch::new();
+    batch
+        .set("balance/bob".to_owned(), "5".to_owned())
+        .set("balance/cat".to_owned(), "-1".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    let mut batch = WriteBatch::new();
+    batch
+        .set("balance/bob".to_owned(), "5".to_owned())
+        .remove("balance/ann".to_owned());
+    store.write_batch(batch)?;
+    store.hset("hash".to_owned(), "field".to_owned(), "lots".to_owned())?;
+
+    assert_eq!(store.get("balance/ann".to_owned())?, None);
+    assert_eq!(store.get("balance/cat".to_owned())?, None);
+    assert_eq!(store.get("pinned".to_owned())?, Some("x".to_owned()));
+    drop(store);
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            "set balance/ann 10",
+            "set pinned x",
+            "set balance/bob 5",
+            "remove balance/ann",
+        ]
+    );
+    Ok(())
+}
+
+// The key count is estimated from the pages' counts, and samples only pick live keys.
+#[test]
+fn approximate_len_and_sample_keys() -> Result<()> {
+    use kvs::WriteBatch;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.approximate_len(), 0);
+    assert_eq!(store.sample_keys(10)?, Vec::<String>::new());
+
+    let mut batch = WriteBatch::new();
+    for i in 0..3000 {
+        batch.set(format!("key{}", i), "first".to_owned());
+    }
+    store.write_batch(batch)?;
+    let mut batch = WriteBatch::new();
+    for i in 0..500 {
+        batch.set(format!("key{}", i), "second".to_owned());
+        batch.remove(format!("key{}", 2500 + i));
+    }
+    store.write_batch(batch)?;
+    let approximate = store.approximate_len();
+    assert!((2400..=2600).contains(&approximate), "{}", approximate);
+    assert_eq!(store.stats()?.approximate_keys, approximate);
+
+    let sample = store.sample_keys(100)?;
+    assert_eq!(sample.len(), 100);
+    for key in sample.iter() {
+        assert!(store.get(key.clone())?.is_some(), "{} was removed", key);
+    }
+    assert!(sample.iter().any(|key| key.len() < 7));
+    assert!(sample.iter().any(|key| key.len() == 7));
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.approximate_len(), 2500);
+    assert_eq!(store.sample_keys(100)?.len(), 100);
+    Ok(())
+}
+
+// Key and value sizes are counted as they're written, and recounted from the live keys by
+// compaction.
+#[test]
+fn size_histograms() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_sizes.count(), 0);
+    assert_eq!(stats.value_sizes.count(), 0);
+
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.set("big".to_owned(), "x".repeat(10_000))?;
+    store.set("key0".to_owned(), "v".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_sizes.count(), 12);
+    assert_eq!(stats.key_sizes.buckets, vec![0, 0, 1, 11]);
+    assert_eq!(stats.key_sizes.max, 4);
+    assert_eq!(stats.value_sizes.count(), 12);
+    assert_eq!(stats.value_sizes.max, 10_000);
+    assert_eq!(stats.value_sizes.buckets[14], 1);
+    assert_eq!(SizeHistogram::bucket_range(14), (8192, 16383));
+    assert!(stats.to_string().contains("\n  8192-16383 bytes: 1"));
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_sizes.count(), 10);
+    assert_eq!(
+        stats.value_sizes.buckets,
+        vec![0, 1, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]
+    );
+    assert_eq!(stats.value_sizes.max, 10_000);
+    Ok(())
+}
+
+// Scans return the string keys in a range in key order, or descending order with scan_rev,
+// including unsaved writes.
+#[test]
+fn scan_ranges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..300 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    store.set("key100".to_owned(), "changed".to_owned())?;
+    store.remove("key101".to_owned())?;
+    store.remove("key102".to_owned())?;
+    store.hset("key102".to_owned(), "field".to_owned(), "value".to_owned())?;
+
+    let keys = |entries: Vec<(String, String)>| -> Vec<String> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    };
+    let all = store.scan(None, None, None)?;
+    assert_eq!(all.len(), 298);
+    assert_eq!(all[0], ("key000".to_owned(), "value0".to_owned()));
+    assert_eq!(all[100], ("key100".to_owned(), "changed".to_owned()));
+    assert_eq!(all[101].0, "key103");
+
+    let first = store.scan(Some("key099".to_owned()), Some("key104".to_owned()), None)?;
+    assert_eq!(keys(first), vec!["key099", "key100", "key103"]);
+    let last = store.scan_rev(None, None, Some(3))?;
+    assert_eq!(keys(last), vec!["key299", "key298", "key297"]);
+    let last = store.scan_rev(
+        Some("key099".to_owned()),
+        Some("key104".to_owned()),
+        Some(2),
+    )?;
+    assert_eq!(keys(last), vec!["key103", "key100"]);
+    assert_eq!(
+        store.scan(Some("key2".to_owned()), Some("key1".to_owned()), None)?,
+        vec![]
+    );
+
+    let mut reversed = store.scan_rev(None, None, None)?;
+    reversed.reverse();
+    assert_eq!(reversed, store.scan(None, None, None)?);
+    Ok(())
+}
+
+// Following the cursors of a paged scan goes through the whole range, a page at a time.
+#[test]
+fn scan_pages() -> Result<()> {
+    use kvs::{scan_page, WriteBatch, MAX_SCAN_PAGE};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    for i in 0..2500 {
+        batch.set(format!("key{:04}", i), "value".to_owned());
+    }
+    store.write_batch(batch)?;
+    let start = Some("key0010".to_owned());
+    let end = Some("key2490".to_owned());
+    for &reverse in &[false, true] {
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = scan_page(
+                &store,
+                start.clone(),
+                end.clone(),
+                None,
+                reverse,
+                cursor.as_ref().map(String::as_str),
+            )?;
+            pages.push(page.pairs);
+            cursor = match page.cursor {
+                Some(next) => Some(next),
+                None => break,
+            };
+        }
+        let sizes: Vec<usize> = pages.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![MAX_SCAN_PAGE, MAX_SCAN_PAGE, 480]);
+        let scanned: Vec<_> = pages.into_iter().flatten().collect();
+        let expected = if reverse {
+            store.scan_rev(start.clone(), end.clone(), None)?
+        } else {
+            store.scan(start.clone(), end.clone(), None)?
+        };
+        assert_eq!(scanned, expected);
+    }
+
+    let page = scan_page(&store, None, None, Some(2), false, None)?;
+    assert_eq!(page.pairs.len(), 2);
+    let cursor = page.cursor.as_ref().map(String::as_str);
+    let page = scan_page(&store, None, None, Some(1), false, cursor)?;
+    assert_eq!(page.pairs[0].0, "key0002");
+    let page = scan_page(&store, None, None, Some(2500), false, None)?;
+    assert_eq!(page.pairs.len(), MAX_SCAN_PAGE);
+    assert!(page.cursor.is_some());
+    match scan_page(&store, None, None, None, false, Some("zz")) {
+        Err(Error::Message(_)) => {}
+        result => panic!("expected an invalid cursor, got {:?}", result.map(|_| ())),
+    }
+    Ok(())
+}
+
+// A snapshot keeps reading what the store held when it was taken while the store is written
+// to and compacted, and the files it reads are only deleted once it's dropped.
+#[test]
+fn read_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    store.hset("h".to_owned(), "field".to_owned(), "value".to_owned())?;
+    let snapshot = store.read_snapshot()?;
+    assert_eq!(snapshot.sequence(), store.applied_sequence()?);
+
+    store.set("a".to_owned(), "2".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.set("c".to_owned(), "2".to_owned())?;
+    store.compact()?;
+    assert!(!log_files(temp_dir.path()).is_empty());
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+    assert_eq!(snapshot.get("a")?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("b")?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("c")?, None);
+    match snapshot.get("h") {
+        Err(Error::WrongType) => {}
+        result => panic!("expected WrongType, got {:?}", result),
+    }
+    let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+    assert_eq!(
+        snapshot.scan(None, None, None)?,
+        pairs(&[("a", "1"), ("b", "1")])
+    );
+    assert_eq!(
+        snapshot.scan_rev(None, None, Some(1))?,
+        pairs(&[("b", "1")])
+    );
+    assert_eq!(
+        store.scan(None, None, None)?,
+        pairs(&[("a", "2"), ("c", "2")])
+    );
+
+    let reader = std::thread::spawn(move || snapshot.get("a"));
+    assert_eq!(reader.join().unwrap()?, Some("1".to_owned()));
+    store.set("d".to_owned(), "3".to_owned())?;
+    assert_eq!(log_files(temp_dir.path()).len(), 1);
+    Ok(())
+}
+
+// Sets and removes return their sequence numbers, in the order they were applied.
+#[test]
+fn writes_return_sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.set("a".to_owned(), "1".to_owned())?, 1);
+    assert_eq!(store.set("a".to_owned(), "2".to_owned())?, 2);
+    assert_eq!(store.remove("a".to_owned())?, 3);
+    assert_eq!(store.applied_sequence()?, 3);
+    assert!(store.remove("a".to_owned()).is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.set("b".to_owned(), "1".to_owned())?, 4);
+    Ok(())
+}
+
+// Read-only stores open alongside the writer, and see what it has saved once they refresh,
+// including after it compacts away the pages they were reading.
+#[test]
+fn readers_alongside_writer() -> Result<()> {
+    use server::MockClock;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let writer = KvStore::open(temp_dir.path())?;
+    writer.set("a".to_owned(), "1".to_owned())?;
+    writer.flush()?;
+
+    let clock = Arc::new(MockClock::new(SystemTime::now()));
+    let options = Options {
+        read_only: true,
+        refresh_interval: Some(Duration::from_secs(5)),
+        clock: clock.clone(),
+        ..Options::default()
+    };
+    let reader = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    let other = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("a".to_owned())?, Some("1".to_owned()));
+    assert!(reader.set("a".to_owned(), "2".to_owned()).is_err());
+
+    writer.set("b".to_owned(), "2".to_owned())?;
+    assert_eq!(reader.get("b".to_owned())?, None);
+    writer.flush()?;
+    assert_eq!(reader.get("b".to_owned())?, None);
+    clock.advance(Duration::from_secs(3));
+    reader.flush_if_due()?;
+    assert_eq!(reader.get("b".to_owned())?, None);
+    clock.advance(Duration::from_secs(2));
+    reader.flush_if_due()?;
+    assert_eq!(reader.get("b".to_owned())?, Some("2".to_owned()));
+    assert!(!reader.refresh()?);
+
+    // `other` never read the page holding "a", and compaction removes it
+    writer.compact()?;
+    assert_eq!(other.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(other.get("b".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+// A sealed directory serves reads without a lock, and files that don't match their checksums
+// can't be read.
+#[test]
+fn read_only_sealed_store() -> Result<()> {
+    use server::ReadOnlyKvStore;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let sealed_dir = temp_dir.path().join("sealed");
+    std::fs::create_dir(&store_dir)?;
+    let store = KvStore::open(&store_dir)?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.hset("h".to_owned(), "f".to_owned(), "2".to_owned())?;
+    store.snapshot(&sealed_dir)?;
+    drop(store);
+
+    // A store's own directory has no checksums, so it isn't sealed
+    assert!(ReadOnlyKvStore::open(&store_dir).is_err());
+
+    let sealed = ReadOnlyKvStore::open(&sealed_dir)?;
+    let other = ReadOnlyKvStore::open(&sealed_dir)?;
+    assert_eq!(sealed.get("a")?, Some("1".to_owned()));
+    assert_eq!(sealed.hget("h", "f")?, Some("2".to_owned()));
+    assert_eq!(other.scan(None, None, None)?.len(), 1);
+    assert!(sealed.verify()?.is_ok());
+
+    for entry in std::fs::read_dir(&sealed_dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "data")
+        {
+            let mut contents = std::fs::read(&path)?;
+            *contents.last_mut().unwrap() ^= 1;
+            std::fs::write(&path, contents)?;
+        }
+    }
+    let sealed = ReadOnlyKvStore::open(&sealed_dir)?;
+    assert!(sealed.get("a").is_err());
+    assert!(!sealed.verify()?.is_ok());
+    Ok(())
+}
+
+// Scans, compaction, and batches give up once their cancel token is cancelled, without
+// changing anything.
+#[test]
+fn cancel_long_operations() -> Result<()> {
+    use kvs::{CancelToken, WriteBatch};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.flush()?;
+    store.set("a".to_owned(), "2".to_owned())?;
+    store.flush()?;
+    let pages = store.stats()?.pages;
+
+    let token = CancelToken::new();
+    store.set_cancel_token(Some(token.clone()));
+    assert_eq!(store.scan(None, None, None)?.len(), 1);
+    token.cancel();
+    match store.scan(None, None, None) {
+        Err(Error::Cancelled) => {}
+        result => panic!("expected Cancelled, got {:?}", result),
+    }
+    match store.compact() {
+        Err(Error::Cancelled) => {}
+        result => panic!("expected Cancelled, got {:?}", result),
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("b".to_owned(), "3".to_owned());
+    match store.write_batch(batch) {
+        Err(Error::Cancelled) => {}
+        result => panic!("expected Cancelled, got {:?}", result),
+    }
+
+    store.set_cancel_token(None);
+    assert_eq!(store.stats()?.pages, pages);
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    store.compact()?;
+    Ok(())
+}
+
+// Context added to an error keeps its code and its cause.
+#[test]
+fn error_context_and_sources() -> Result<()> {
+    use kvs::ResultExt;
+    use std::error::Error as _;
+    let error = Err::<(), _>(Error::KeyNotFound)
+        .context("Reading key1")
+        .unwrap_err();
+    assert_eq!(error.code(), ErrorCode::KeyNotFound);
+    assert_eq!(error.to_string(), "Reading key1: Key not found");
+    match error.root() {
+        Error::KeyNotFound => {}
+        root => panic!("expected KeyNotFound, got {:?}", root),
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    std::fs::write(temp_dir.path().join("MANIFEST"), "not a manifest")?;
+    let error = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(error
+        .to_string()
+        .starts_with("Could not read the manifest: "));
+    let source = error.source().unwrap();
+    assert!(source.source().is_some());
+    Ok(())
+}
+
+// Compaction, verification, snapshots, and dumps report their progress, ending with
+// everything done.
+#[test]
+fn progress_reports() -> Result<()> {
+    use kvs::Progress;
+    use server::DumpFormat;
+    use std::sync::{Arc, Mutex};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        store.flush()?;
+    }
+    let pages = store.stats()?.pages;
+    assert!(pages > 0);
+    let reports: Arc<Mutex<Vec<Progress>>> = Arc::new(Mutex::new(Vec::new()));
+    let sink = reports.clone();
+    store.set_progress(Some(Arc::new(move |progress: &Progress| {
+        sink.lock().unwrap().push(progress.clone())
+    })));
+    let last = || reports.lock().unwrap().last().cloned().unwrap();
+
+    store.verify()?;
+    let verify = last();
+    assert_eq!(verify.operation, "verify");
+    assert_eq!((verify.done, verify.total), (pages, pages));
+    assert!(verify.bytes > 0);
+    assert_eq!(verify.percent(), Some(100));
+
+    store.compact()?;
+    let compact = last();
+    assert_eq!(compact.operation, "compact");
+    assert_eq!((compact.done, compact.total), (pages, pages));
+
+    store.snapshot(&temp_dir.path().join("snapshot"))?;
+    let snapshot = last();
+    assert_eq!(snapshot.operation, "snapshot");
+    assert!(snapshot.total > 2);
+    assert_eq!(snapshot.done, snapshot.total);
+
+    store.dump(Vec::new(), DumpFormat::Json)?;
+    let dump = last();
+    assert_eq!(
+        (dump.operation.as_str(), dump.done, dump.total),
+        ("dump", 3, 3)
+    );
+    assert_eq!(dump.eta(), Some(Duration::from_secs(0)));
+
+    // Every report for an operation counts up from the one before
+    let count = {
+        let seen = reports.lock().unwrap();
+        for pair in seen.windows(2) {
+            if pair[0].operation == pair[1].operation {
+                assert!(pair[0].done <= pair[1].done);
+            }
+        }
+        seen.len()
+    };
+
+    store.set_progress(None);
+    store.verify()?;
+    assert_eq!(reports.lock().unwrap().len(), count);
+    Ok(())
+}
+
+// Stats count the hits, misses, and evictions of the open file cache and the cache engine.
+#[test]
+fn cache_stats() -> Result<()> {
+    use server::CacheEngine;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logger = kvs::get_default_logger();
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.save()?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.get("key0".to_owned())?;
+    let before = store.stats()?.caches["open_files"].clone();
+    assert!(before.misses > 0);
+    assert_eq!(before.evictions, 0);
+    // Reading the same key again finds its files open
+    store.get("key0".to_owned())?;
+    let after = store.stats()?.caches["open_files"].clone();
+    assert!(after.hits > before.hits);
+    assert_eq!(after.misses, before.misses);
+    assert!(store.stats()?.to_string().contains("open_files cache: "));
+    drop(store);
+
+    // With room for one file, the page and data files of a lookup take turns
+    let options = Options {
+        max_open_files: 1,
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), &logger, options)?;
+    store.get("key0".to_owned())?;
+    let open_files = store.stats()?.caches["open_files"].clone();
+    assert!(open_files.evictions > 0);
+    assert_eq!(open_files.entries, 1);
+
+    let cache = CacheEngine::new(300);
+    for i in 0..3 {
+        cache.set(format!("key{}", i), "x".repeat(100))?;
+    }
+    cache.get("key2".to_owned())?;
+    cache.get("key0".to_owned())?;
+    let values = cache.stats()?.caches["values"].clone();
+    assert_eq!((values.hits, values.misses), (1, 1));
+    assert_eq!(values.evictions, cache.evictions());
+    assert_eq!(values.bytes, cache.used_memory() as u64);
+    assert_eq!(values.hit_rate(), Some(0.5));
+    Ok(())
+}
+
+// A page's checksum covers every byte of it, so damage to a slot nothing reads yet is caught
+// when the page is opened and the page is quarantined.
+#[test]
+fn page_checksums() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let page = log_files(temp_dir.path()).pop().unwrap();
+    let mut bytes = std::fs::read(&page)?;
+    bytes[16383] ^= 0xff;
+    std::fs::write(&page, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(temp_dir
+        .path()
+        .join("quarantine")
+        .join(page.file_name().unwrap())
+        .exists());
+    Ok(())
+}
+
+// Clones of a store share it, and can write to it from several threads at once. A deadline
+// only applies to calls from the thread that set it.
+#[test]
+fn shared_between_threads() -> Result<()> {
+    fn assert_shareable<T: Clone + Send + Sync>() {}
+    assert_shareable::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    store.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..4 {
+        for i in 0..50 {
+            let value = store.get(format!("key{}-{}", t, i))?;
+            assert_eq!(value, Some(format!("value{}", i)));
+        }
+    }
+
+    store.set_deadline(Some(Instant::now()));
+    match store.get("key0-0".to_owned()) {
+        Err(Error::DeadlineExceeded) => {}
+        result => panic!("expected the deadline to pass, got {:?}", result),
+    }
+    let other = store.clone();
+    let value = thread::spawn(move || other.get("key0-0".to_owned()))
+        .join()
+        .unwrap()?;
+    assert_eq!(value, Some("value0".to_owned()));
+    store.set_deadline(None);
+
+    // The store is only closed once the last clone is dropped
+    let other = store.clone();
+    drop(store);
+    assert_eq!(other.get("key3-49".to_owned())?, Some("value49".to_owned()));
+    Ok(())
+}
+
+// Empty, overlong, and control-character keys are refused when they're written.
+#[test]
+fn invalid_keys() -> Result<()> {
+    use server::{CacheEngine, SledEngine};
+    use sled::Db;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let longest = "k".repeat(MAX_KEY_LEN);
+    store.set(longest.clone(), "value".to_owned())?;
+    assert_eq!(store.get(longest)?, Some("value".to_owned()));
+
+    let reserved = format!("{}index/name", kvs::RESERVED_KEY_PREFIX);
+    for key in &["", "key\n", &"k".repeat(MAX_KEY_LEN + 1), &reserved] {
+        match store.set(key.to_string(), "value".to_owned()) {
+            Err(e @ Error::InvalidKey(_)) => assert_eq!(e.code(), ErrorCode::InvalidKey),
+            result => panic!("expected InvalidKey for {:?}, got {:?}", key, result),
+        }
+        assert_eq!(store.get(key.to_string())?, None);
+    }
+
+    // Reserved keys can't be removed or renamed to either, but the server can probe them
+    match store.remove(reserved.clone()) {
+        Err(Error::InvalidKey(_)) => {}
+        result => panic!("expected InvalidKey, got {:?}", result),
+    }
+    store.set("key".to_owned(), "value".to_owned())?;
+    match store.rename("key".to_owned(), reserved.clone()) {
+        Err(Error::InvalidKey(_)) => {}
+        result => panic!("expected InvalidKey, got {:?}", result),
+    }
+    assert_eq!(
+        store.probe(reserved.clone(), "value".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert_eq!(store.get(reserved.clone())?, None);
+
+    // The other engines keep reserved keys to themselves too
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Box<dyn Engine>> = vec![
+        Box::new(SledEngine {
+            db: Db::open(sled_dir.path())?,
+        }),
+        Box::new(CacheEngine::new(1000)),
+    ];
+    for engine in engines {
+        match engine.set(reserved.clone(), "value".to_owned()) {
+            Err(Error::InvalidKey(_)) => {}
+            result => panic!("expected InvalidKey, got {:?}", result),
+        }
+        assert_eq!(
+            engine.probe(reserved.clone(), "value".to_owned())?,
+            Some("value".to_owned())
+        );
+        assert_eq!(engine.get(reserved.clone())?, None);
+    }
+    Ok(())
+}
+
+// Compare-and-swap only sets the key if it has the expected value, or doesn't exist.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.compare_and_swap("key".to_owned(), None, "a".to_owned())?,
+        (true, Some("a".to_owned()))
+    );
+    assert_eq!(
+        store.compare_and_swap("key".to_owned(), None, "b".to_owned())?,
+        (false, Some("a".to_owned()))
+    );
+    assert_eq!(
+        store.compare_and_swap("key".to_owned(), Some("b".to_owned()), "c".to_owned())?,
+        (false, Some("a".to_owned()))
+    );
+    assert_eq!(
+        store.compare_and_swap("key".to_owned(), Some("a".to_owned()), "c".to_owned())?,
+        (true, Some("c".to_owned()))
+    );
+    assert_eq!(
+        store.compare_and_swap("other".to_owned(), Some("a".to_owned()), "c".to_owned())?,
+        (false, None)
+    );
+    assert_eq!(store.get("key".to_owned())?, Some("c".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, None);
+    Ok(())
+}
+
+// Every engine's compare-and-swap is atomic, so concurrent increments that retry on a miss
+// aren't lost, and sled applies the reads and writes of a rename together.
+#[test]
+fn engines_compare_and_swap_concurrently() -> Result<()> {
+    use kvs::WriteBatch;
+    use server::{CacheEngine, SledEngine};
+    use sled::Db;
+    use std::sync::Arc;
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Arc<dyn Engine>> = vec![
+        Arc::new(KvStore::open(kvs_dir.path())?),
+        Arc::new(SledEngine {
+            db: Db::open(sled_dir.path())?,
+        }),
+        Arc::new(CacheEngine::new(1000)),
+    ];
+    for engine in engines {
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = engine.clone();
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..50 {
+                        loop {
+                            let current = engine.get("n".to_owned())?;
+                            let next = current.as_ref().map_or(0, |n| n.parse().unwrap()) + 1;
+                            if engine
+                                .compare_and_swap("n".to_owned(), current, next.to_string())?
+                                .0
+                            {
+                                break;
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        assert_eq!(engine.get("n".to_owned())?, Some("200".to_owned()));
+
+        engine.rename("n".to_owned(), "m".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch
+            .rename("m".to_owned(), "o".to_owned())
+            .rename("missing".to_owned(), "p".to_owned());
+        match engine.write_batch(batch) {
+            Err(Error::KeyNotFound) => {}
+            result => panic!("expected KeyNotFound, got {:?}", result),
+        }
+        assert_eq!(engine.get("n".to_owned())?, None);
+        assert_eq!(engine.get("m".to_owned())?, Some("200".to_owned()));
+        assert_eq!(engine.get("o".to_owned())?, None);
+    }
+    Ok(())
+}
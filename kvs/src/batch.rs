@@ -0,0 +1,116 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::iter::FromIterator;
+
+/// A group of writes that are applied together: either all of them are written or none are.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Unlike `Engine::remove`, removing a key that doesn't exist isn't an error.
+    Remove {
+        key: String,
+    },
+    /// Move the value of `from`, as the writes before it in the batch left it, to `to`, and
+    /// remove `from`. The whole batch fails with `KeyNotFound` if `from` has no value.
+    Rename {
+        from: String,
+        to: String,
+    },
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    pub fn rename(&mut self, from: String, to: String) -> &mut Self {
+        self.ops.push(BatchOp::Rename { from, to });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// The writes in the order they were added. Later writes to a key win.
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+
+    /// The writes with each rename turned into a set of the new key and a remove of the old
+    /// one, for engines that only store strings. `get` reads a key's value from before the
+    /// batch.
+    pub fn expand_renames<F>(self, mut get: F) -> Result<Vec<BatchOp>>
+    where
+        F: FnMut(&str) -> Result<Option<String>>,
+    {
+        // What the writes so far have left each key they wrote holding
+        let mut written: HashMap<String, Option<String>> = HashMap::new();
+        let mut ops = Vec::with_capacity(self.ops.len());
+        for op in self.ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    written.insert(key.clone(), Some(value.clone()));
+                    ops.push(BatchOp::Set { key, value });
+                }
+                BatchOp::Remove { key } => {
+                    written.insert(key.clone(), None);
+                    ops.push(BatchOp::Remove { key });
+                }
+                BatchOp::Rename { from, to } => {
+                    let value = match written.get(&from) {
+                        Some(value) => value.clone(),
+                        None => get(&from)?,
+                    };
+                    let value = value.ok_or(Error::KeyNotFound)?;
+                    written.insert(from.clone(), None);
+                    written.insert(to.clone(), Some(value.clone()));
+                    ops.push(BatchOp::Remove { key: from });
+                    ops.push(BatchOp::Set { key: to, value });
+                }
+            }
+        }
+        Ok(ops)
+    }
+}
+
+/// Sets each key to its value, in order.
+impl Extend<(String, String)> for WriteBatch {
+    fn extend<I: IntoIterator<Item = (String, String)>>(&mut self, iter: I) {
+        self.ops.extend(
+            iter.into_iter()
+                .map(|(key, value)| BatchOp::Set { key, value }),
+        );
+    }
+}
+
+impl FromIterator<(String, String)> for WriteBatch {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        let mut batch = WriteBatch::new();
+        batch.extend(iter);
+        batch
+    }
+}
@@ -1,23 +1,411 @@
+use crate::progress::Progress;
+use crate::scan::ScanPage;
+use crate::stats::{Stats, VerifyReport};
+use crate::StreamId;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum CommandRequest {
-    Get { key: String },
-    Set { key: String, value: Option<String> },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: Option<String>,
+    },
+    /// Set the key to `value` if it currently has the value `expected`, or doesn't exist if
+    /// that's `None`.
+    CompareAndSwap {
+        key: String,
+        expected: Option<String>,
+        value: String,
+    },
+    /// Move the value of `key` to `new_key` and remove `key`, as a single write.
+    Rename {
+        key: String,
+        new_key: String,
+    },
+    Health,
+    /// Check that the server is reachable, without touching the engine.
+    Ping,
+    Compact,
+    Verify,
+    Stats,
+    Snapshot {
+        path: String,
+    },
+    HSet {
+        key: String,
+        field: String,
+        value: String,
+    },
+    HGet {
+        key: String,
+        field: String,
+    },
+    HGetAll {
+        key: String,
+    },
+    HDel {
+        key: String,
+        field: String,
+    },
+    ZAdd {
+        key: String,
+        score: f64,
+        member: String,
+    },
+    ZRangeByScore {
+        key: String,
+        min: f64,
+        max: f64,
+    },
+    ZRank {
+        key: String,
+        member: String,
+    },
+    XAdd {
+        key: String,
+        payload: String,
+    },
+    XRange {
+        key: String,
+        from: StreamId,
+        to: StreamId,
+    },
+    Enqueue {
+        key: String,
+        payload: String,
+    },
+    Dequeue {
+        key: String,
+        visibility_timeout_ms: u64,
+    },
+    Ack {
+        key: String,
+        id: StreamId,
+    },
+    Lock {
+        key: String,
+        ttl_ms: u64,
+    },
+    Unlock {
+        key: String,
+        token: u64,
+    },
+    SetBit {
+        key: String,
+        offset: u64,
+        value: bool,
+    },
+    GetBit {
+        key: String,
+        offset: u64,
+    },
+    BitCount {
+        key: String,
+    },
+    /// Read a page of the keys with string values from `start` up to but not including `end`,
+    /// in key order, or in descending order if `reverse` is set. The response is a
+    /// `CommandResponse::ScanPage` of at most `limit` keys, and no more than `MAX_SCAN_PAGE`.
+    /// `cursor` is the one an earlier page of the same scan came with, to carry on after it.
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<u64>,
+        reverse: bool,
+        cursor: Option<String>,
+    },
+    /// Run a script with `Engine::run_script`, applying all of its writes or none of them. The
+    /// response is a `CommandResponse::Message` with what it returned, empty for `nil`.
+    Eval {
+        script: String,
+        args: Vec<String>,
+    },
+    /// Run `request`, logging it under `request_id` so it can be found in the server's logs. The
+    /// response is a `CommandResponse::Traced`. Wraps any `Deadline`, `Progress`, or `Session`.
+    Traced {
+        request_id: String,
+        request: Box<CommandRequest>,
+    },
+    /// Run `request`, giving up with `CommandResponse::DeadlineExceeded` if it's still running
+    /// `timeout_ms` after the server read it. Wraps any `Progress` or `Session`.
+    Deadline {
+        timeout_ms: u64,
+        request: Box<CommandRequest>,
+    },
+    /// Run `request`, sending a `CommandResponse::Progress` every so often while it runs, before
+    /// the response itself. Only requests that can take a while, like compaction, send any.
+    /// Wraps any `Session`.
+    Progress {
+        request: Box<CommandRequest>,
+    },
+    /// Run `request` as part of a read-your-writes session, once the server has applied every
+    /// write up to `min_sequence`. The response is a `CommandResponse::Session`. Wraps any
+    /// `Idempotent`.
+    Session {
+        min_sequence: u64,
+        request: Box<CommandRequest>,
+    },
+    /// Run `request` unless the server has already applied a request with the same `token`,
+    /// in which case it answers with that request's response again. Makes it safe to retry a
+    /// write that might have been applied. Tokens are kept per client address, and a token
+    /// sent with a different request than the first time is refused.
+    Idempotent {
+        token: String,
+        request: Box<CommandRequest>,
+    },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl CommandRequest {
+    /// The key the request reads or writes, if it's about a single key.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            CommandRequest::Get { key }
+            | CommandRequest::Set { key, .. }
+            | CommandRequest::CompareAndSwap { key, .. }
+            | CommandRequest::Rename { key, .. }
+            | CommandRequest::HSet { key, .. }
+            | CommandRequest::HGet { key, .. }
+            | CommandRequest::HGetAll { key }
+            | CommandRequest::HDel { key, .. }
+            | CommandRequest::ZAdd { key, .. }
+            | CommandRequest::ZRangeByScore { key, .. }
+            | CommandRequest::ZRank { key, .. }
+            | CommandRequest::XAdd { key, .. }
+            | CommandRequest::XRange { key, .. }
+            | CommandRequest::Enqueue { key, .. }
+            | CommandRequest::Dequeue { key, .. }
+            | CommandRequest::Ack { key, .. }
+            | CommandRequest::Lock { key, .. }
+            | CommandRequest::Unlock { key, .. }
+            | CommandRequest::SetBit { key, .. }
+            | CommandRequest::GetBit { key, .. }
+            | CommandRequest::BitCount { key } => Some(key),
+            CommandRequest::Health
+            | CommandRequest::Ping
+            | CommandRequest::Compact
+            | CommandRequest::Verify
+            | CommandRequest::Stats
+            | CommandRequest::Snapshot { .. }
+            | CommandRequest::Scan { .. }
+            | CommandRequest::Eval { .. } => None,
+            CommandRequest::Traced { request, .. }
+            | CommandRequest::Deadline { request, .. }
+            | CommandRequest::Progress { request }
+            | CommandRequest::Session { request, .. }
+            | CommandRequest::Idempotent { request, .. } => request.key(),
+        }
+    }
+
+    /// A short name for the kind of request, for logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandRequest::Get { .. } => "get",
+            CommandRequest::Set { value: Some(_), .. } => "set",
+            CommandRequest::Set { value: None, .. } => "remove",
+            CommandRequest::CompareAndSwap { .. } => "cas",
+            CommandRequest::Rename { .. } => "rename",
+            CommandRequest::Health => "health",
+            CommandRequest::Ping => "ping",
+            CommandRequest::Compact => "compact",
+            CommandRequest::Verify => "verify",
+            CommandRequest::Stats => "stats",
+            CommandRequest::Snapshot { .. } => "snapshot",
+            CommandRequest::HSet { .. } => "hset",
+            CommandRequest::HGet { .. } => "hget",
+            CommandRequest::HGetAll { .. } => "hgetall",
+            CommandRequest::HDel { .. } => "hdel",
+            CommandRequest::ZAdd { .. } => "zadd",
+            CommandRequest::ZRangeByScore { .. } => "zrangebyscore",
+            CommandRequest::ZRank { .. } => "zrank",
+            CommandRequest::XAdd { .. } => "xadd",
+            CommandRequest::XRange { .. } => "xrange",
+            CommandRequest::Enqueue { .. } => "enqueue",
+            CommandRequest::Dequeue { .. } => "dequeue",
+            CommandRequest::Ack { .. } => "ack",
+            CommandRequest::Lock { .. } => "lock",
+            CommandRequest::Unlock { .. } => "unlock",
+            CommandRequest::SetBit { .. } => "setbit",
+            CommandRequest::GetBit { .. } => "getbit",
+            CommandRequest::BitCount { .. } => "bitcount",
+            CommandRequest::Scan { .. } => "scan",
+            CommandRequest::Eval { .. } => "eval",
+            CommandRequest::Traced { request, .. } => request.name(),
+            CommandRequest::Deadline { request, .. } => request.name(),
+            CommandRequest::Progress { request } => request.name(),
+            CommandRequest::Session { request, .. } => request.name(),
+            CommandRequest::Idempotent { request, .. } => request.name(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum CommandResponse {
     Message(String),
+    /// A set or remove was applied, as the write with this sequence number. Engines that don't
+    /// number their writes send 0.
+    Written {
+        sequence: u64,
+    },
+    /// The value read by a get, or `None` if there isn't one.
+    Value(Option<String>),
     KeyNotFound,
+    /// The request failed. The code says how, for clients to act on; the message is for people.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// The request's deadline passed before the server finished it.
+    DeadlineExceeded,
+    Health(HealthStatus),
+    /// Whether a compare-and-swap set the key, and the key's value afterwards.
+    Swap {
+        swapped: bool,
+        current: Option<String>,
+    },
+    /// The answer to a ping, with the server's version.
+    Pong {
+        version: String,
+    },
+    Stats(Stats),
+    Verify(VerifyReport),
+    Hash(BTreeMap<String, String>),
+    /// Members of a sorted set with their scores.
+    Members(Vec<(String, f64)>),
+    /// Stream entries with their ids.
+    Entries(Vec<(StreamId, String)>),
+    ScanPage(ScanPage),
+    /// The response to a request in a session, with the sequence number of the latest write
+    /// the server had applied. The client sends the largest it has seen with its next request.
+    Session {
+        sequence: u64,
+        response: Box<CommandResponse>,
+    },
+    /// The response to a `CommandRequest::Traced`, with its request id.
+    Traced {
+        request_id: String,
+        response: Box<CommandResponse>,
+    },
+    /// How far a request wrapped in `CommandRequest::Progress` has got. Any number of these
+    /// come before its response, and aren't wrapped in anything.
+    Progress(Progress),
+}
+
+/// The kind of failure a `CommandResponse::Error` reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorCode {
+    KeyNotFound,
+    /// The key is malformed or reserved for the server.
+    InvalidKey,
+    /// The engine doesn't support the operation.
+    Unsupported,
+    /// The operation doesn't apply to the kind of value stored under the key.
+    WrongType,
+    /// A lock or the data directory is held by someone else. Trying again later may succeed.
+    Busy,
+    ReadOnly,
+    /// The transaction conflicted with another one, or was aborted to break a deadlock.
+    /// Running it again may succeed.
+    Conflict,
+    /// The server hasn't applied every write the session has seen.
+    BehindSession,
+    DeadlineExceeded,
+    /// Data read from disk couldn't be decoded.
+    Corruption,
+    /// Reading or writing a file failed.
+    Io,
+    /// Anything else, including requests the server doesn't accept.
+    Other,
+    /// The server gave up on the request, e.g. because the client hung up.
+    Cancelled,
+}
+
+/// The result of a health check, suitable for load balancer and liveness probes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HealthStatus {
+    /// Whether the engine could write and read back a sentinel key.
+    pub healthy: bool,
+    /// The reason the check failed, if it did.
+    pub error: Option<String>,
+    pub engine: String,
+    pub uptime_secs: u64,
+    pub requests_served: u64,
 }
 
 impl Display for CommandResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             CommandResponse::Message(s) => write!(f, "{}", s),
+            CommandResponse::Written { sequence } => write!(f, "{}", sequence),
+            CommandResponse::Value(Some(value)) => write!(f, "{}", value),
+            CommandResponse::Value(None) => write!(f, "Key not found"),
             CommandResponse::KeyNotFound => write!(f, "Key not found"),
+            CommandResponse::Error { message, .. } => write!(f, "Error: {}", message),
+            CommandResponse::DeadlineExceeded => write!(f, "Deadline exceeded"),
+            CommandResponse::Health(status) => write!(f, "{}", status),
+            CommandResponse::Swap { swapped: true, .. } => write!(f, "swapped"),
+            CommandResponse::Swap {
+                swapped: false,
+                current: Some(current),
+            } => write!(f, "not swapped, current value: {}", current),
+            CommandResponse::Swap {
+                swapped: false,
+                current: None,
+            } => write!(f, "not swapped, key not found"),
+            CommandResponse::Pong { version } => write!(f, "pong (version {})", version),
+            CommandResponse::Stats(stats) => write!(f, "{}", stats),
+            CommandResponse::Verify(report) => write!(f, "{}", report),
+            CommandResponse::Hash(fields) => {
+                let lines: Vec<String> = fields
+                    .iter()
+                    .map(|(field, value)| format!("{}: {}", field, value))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CommandResponse::Members(members) => {
+                let lines: Vec<String> = members
+                    .iter()
+                    .map(|(member, score)| format!("{}: {}", member, score))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CommandResponse::Entries(entries) => {
+                let lines: Vec<String> = entries
+                    .iter()
+                    .map(|(id, payload)| format!("{}: {}", id, payload))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CommandResponse::ScanPage(page) => {
+                let lines: Vec<String> = page
+                    .pairs
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CommandResponse::Session { response, .. } => write!(f, "{}", response),
+            CommandResponse::Traced { response, .. } => write!(f, "{}", response),
+            CommandResponse::Progress(progress) => write!(f, "{}", progress),
+        }
+    }
+}
+
+impl Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.healthy {
+            writeln!(f, "status: ok")?;
+        } else {
+            writeln!(f, "status: unhealthy")?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "error: {}", error)?;
         }
+        writeln!(f, "engine: {}", self.engine)?;
+        writeln!(f, "uptime: {}s", self.uptime_secs)?;
+        write!(f, "requests served: {}", self.requests_served)
     }
 }
@@ -1,34 +1,150 @@
+use crate::ErrorCode;
 use bincode;
 use logformat;
 use sled;
-use std::fmt::{self, Display};
+use std::fmt::Display;
 use std::io;
+use std::time::SystemTimeError;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("{0}")]
     Message(String),
+    #[error("Key not found")]
     KeyNotFound,
-    IoError(io::Error),
-    LogFormatError(logformat::Error),
-    BincodeError(bincode::Error),
-    SledError(sled::Error),
+    /// The key breaks the rules in `validate_key`, or is reserved for the server. Says why.
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+    #[error("Operation not supported by this engine: {0}")]
+    Unsupported(&'static str),
+    /// The operation doesn't apply to the kind of value stored under the key.
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+    /// The lock is held by someone else, or the token doesn't match the current holder's. Also
+    /// returned when writing a key another transaction has locked.
+    #[error("Lock is held by another client")]
+    LockHeld,
+    /// The store was opened read-only.
+    #[error("Store is opened read-only")]
+    ReadOnly,
+    /// A key the transaction read was written before it committed. Running the transaction
+    /// again may succeed.
+    #[error("Transaction conflicts with a concurrent write")]
+    Conflict,
+    /// The transaction was aborted because it and others were each waiting for a key locked by
+    /// the next.
+    #[error("Transaction was aborted to break a deadlock")]
+    Deadlock,
+    /// The server hasn't applied every write the session has seen, so it can't serve the
+    /// session's reads yet.
+    #[error("Server has applied writes up to {applied}, but the session has seen {required}")]
+    BehindSession { applied: u64, required: u64 },
+    /// The request's deadline passed before the operation finished, so it was abandoned.
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+    /// The operation was cancelled with its `CancelToken` before it finished.
+    #[error("Operation was cancelled")]
+    Cancelled,
+    /// Another process has the data directory open, with its PID if it could be read.
+    #[error("Data directory is locked by {}", locked_by(.0))]
+    AlreadyLocked(Option<u32>),
+    /// What was being done when `source` happened, e.g. which file was being read. The code is
+    /// the source's.
+    #[error("{context}: {source}")]
+    Context {
+        context: String,
+        #[source]
+        source: Box<Error>,
+    },
+    /// Converting something to or from text or bytes outside the store's own formats failed,
+    /// e.g. a dump or the manifest.
+    #[error("{0}")]
+    Serialization(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// The system clock is set before the Unix epoch.
+    #[error("System clock is wrong: {0}")]
+    Clock(#[from] SystemTimeError),
+    #[error("I/O error: {0}")]
+    IoError(#[source] io::Error),
+    #[error("Bad data: {0}")]
+    LogFormatError(#[source] logformat::Error),
+    #[error("Bad data: {0}")]
+    BincodeError(#[source] bincode::Error),
+    #[error("Sled error: {0}")]
+    SledError(#[source] sled::Error),
 }
 
-impl From<sled::Error> for Error {
-    fn from(error: sled::Error) -> Self {
-        Error::SledError(error)
+fn locked_by(pid: &Option<u32>) -> String {
+    match pid {
+        Some(pid) => format!("process {}", pid),
+        None => "another process".to_owned(),
     }
 }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Error {
+    /// The code a server reports this error to clients with. It's the same whatever context
+    /// the error has been given.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::KeyNotFound => ErrorCode::KeyNotFound,
+            Error::InvalidKey(_) => ErrorCode::InvalidKey,
+            Error::Unsupported(_) => ErrorCode::Unsupported,
+            Error::WrongType => ErrorCode::WrongType,
+            Error::LockHeld | Error::AlreadyLocked(_) => ErrorCode::Busy,
+            Error::ReadOnly => ErrorCode::ReadOnly,
+            Error::Conflict | Error::Deadlock => ErrorCode::Conflict,
+            Error::BehindSession { .. } => ErrorCode::BehindSession,
+            Error::DeadlineExceeded => ErrorCode::DeadlineExceeded,
+            Error::Cancelled => ErrorCode::Cancelled,
+            Error::Context { source, .. } => source.code(),
+            Error::LogFormatError(_) | Error::BincodeError(_) => ErrorCode::Corruption,
+            Error::IoError(_) => ErrorCode::Io,
+            Error::Message(_) | Error::Serialization(_) | Error::Clock(_) | Error::SledError(_) => {
+                ErrorCode::Other
+            }
+        }
+    }
+
+    /// The error with any context taken off, to match on what actually went wrong.
+    pub fn root(&self) -> &Error {
         match self {
-            Error::KeyNotFound => write!(f, "Key not found"),
-            _ => write!(f, "{:?}", self),
+            Error::Context { source, .. } => source.root(),
+            error => error,
         }
     }
+
+    /// Wrap a serializer's or parser's error, keeping it as the source.
+    pub fn serialization<E: std::error::Error + Send + Sync + 'static>(error: E) -> Error {
+        Error::Serialization(Box::new(error))
+    }
+}
+
+/// Says what was being done when an error happened, keeping the error as the source.
+pub trait ResultExt<T> {
+    fn context<C: Display>(self, context: C) -> Result<T>;
+
+    /// Like `context`, but only building the context if there's an error.
+    fn with_context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.with_context(|| context)
+    }
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|error| Error::Context {
+            context: context().to_string(),
+            source: Box::new(error.into()),
+        })
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(error: sled::Error) -> Self {
+        Error::SledError(error)
+    }
 }
 
 impl From<io::Error> for Error {
@@ -37,14 +153,22 @@ impl From<io::Error> for Error {
     }
 }
 
+// I/O errors from decoding a file are unwrapped, so callers can tell a failing disk from bad
+// data by looking for `IoError` alone.
 impl From<logformat::Error> for Error {
     fn from(error: logformat::Error) -> Self {
-        Error::LogFormatError(error)
+        match error {
+            logformat::Error::IoError(error) => Error::IoError(error),
+            error => Error::LogFormatError(error),
+        }
     }
 }
 
 impl From<bincode::Error> for Error {
     fn from(error: bincode::Error) -> Self {
-        Error::BincodeError(error)
+        match *error {
+            bincode::ErrorKind::Io(error) => Error::IoError(error),
+            kind => Error::BincodeError(Box::new(kind)),
+        }
     }
 }
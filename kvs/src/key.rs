@@ -0,0 +1,51 @@
+//! What a key may look like. Engines check keys as they're written, which also keeps anyone
+//! using them, not just the server's clients, from writing the keys the server uses itself.
+use crate::{Error, Result};
+
+/// The longest key, in bytes. Keys are stored alongside their values in a page's data file, so
+/// a long key takes room from the value.
+pub const MAX_KEY_LEN: usize = 1024;
+
+/// Keys starting with this are kept for the server's own use, like the health check's sentinel
+/// key. Engines refuse to write them, except through `Engine::probe` and for their own
+/// bookkeeping, and the server doesn't let clients read them either.
+pub const RESERVED_KEY_PREFIX: &str = "__kvs_";
+
+/// Check that a key can be written: it can't be empty or longer than `MAX_KEY_LEN` bytes, it
+/// can't contain control characters, which would garble logs and dumps, and it can't be
+/// reserved.
+pub fn validate_key(key: &str) -> Result<()> {
+    if key.is_empty() {
+        Err(Error::InvalidKey("key is empty".to_owned()))
+    } else if key.len() > MAX_KEY_LEN {
+        Err(Error::InvalidKey(format!(
+            "key is {} bytes, more than the limit of {}",
+            key.len(),
+            MAX_KEY_LEN
+        )))
+    } else if key.chars().any(char::is_control) {
+        Err(Error::InvalidKey(
+            "key contains a control character".to_owned(),
+        ))
+    } else {
+        check_unreserved(key)
+    }
+}
+
+/// Check that a key isn't reserved. That's all a key that's being removed is checked for, so
+/// that keys stored before the other rules were added can still be removed.
+pub fn check_unreserved(key: &str) -> Result<()> {
+    if is_reserved_key(key) {
+        Err(Error::InvalidKey(format!(
+            "keys starting with {} are reserved",
+            RESERVED_KEY_PREFIX
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether the key is kept for the server's own use.
+pub fn is_reserved_key(key: &str) -> bool {
+    key.starts_with(RESERVED_KEY_PREFIX)
+}
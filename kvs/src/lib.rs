@@ -4,13 +4,32 @@ extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
+mod batch;
+mod cancel;
 mod command;
 mod error;
+mod key;
+mod progress;
+mod scan;
+mod script;
+mod stats;
 
 use slog::Drain;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-pub use command::{CommandRequest, CommandResponse};
-pub use error::{Error, Result};
+pub use batch::{BatchOp, WriteBatch};
+pub use cancel::CancelToken;
+pub use command::{CommandRequest, CommandResponse, ErrorCode, HealthStatus};
+pub use error::{Error, Result, ResultExt};
+pub use key::{check_unreserved, is_reserved_key, validate_key, MAX_KEY_LEN, RESERVED_KEY_PREFIX};
+pub use logformat::manifest::SizeHistogram;
+pub use logformat::record::StreamId;
+pub use progress::{Progress, ProgressFn, ProgressTracker, PROGRESS_INTERVAL};
+pub use scan::{scan_page, ScanPage, MAX_SCAN_PAGE};
+pub use script::{run_script, MAX_SCRIPT_STEPS, MAX_SCRIPT_STRING_LEN};
+pub use stats::{CacheStats, Stats, VerifyReport};
 
 pub fn get_default_logger() -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().build();
@@ -20,8 +39,229 @@ pub fn get_default_logger() -> slog::Logger {
     logger
 }
 
-pub trait Engine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
+/// A store the server can serve requests from. It's shared between the threads serving
+/// connections, so every method takes `&self`.
+pub trait Engine: Send + Sync {
+    /// Set the key to the value. Returns the write's sequence number, as `applied_sequence`
+    /// numbers writes, or 0 if the engine doesn't number them.
+    fn set(&self, key: String, value: String) -> Result<u64>;
+    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Remove the key, returning the write's sequence number like `set`.
+    fn remove(&self, key: String) -> Result<u64>;
+
+    /// Set `key`, one of the reserved keys that `set` refuses, to `value`, read it back, and
+    /// remove it again, returning what was read. It's how the server's health check can tell
+    /// the engine works without touching any key a client could.
+    fn probe(&self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(Error::Unsupported("probe"))
+    }
+
+    /// Give up on reads that go through many pages with `DeadlineExceeded` once `deadline` has
+    /// passed, until it's cleared with `None`. It only applies to calls from the thread that set
+    /// it, like the rest of these per-request settings. Engines that can't stop part-way ignore
+    /// it.
+    fn set_deadline(&self, _deadline: Option<Instant>) {}
+
+    /// Give up on the same work as `set_deadline` does, with `Cancelled`, once `token` is
+    /// cancelled, until it's cleared with `None`.
+    fn set_cancel_token(&self, _token: Option<CancelToken>) {}
+
+    /// Report how far compaction, verification, and snapshots have got to `progress`, until
+    /// it's cleared with `None`. Engines that can't tell ignore it.
+    fn set_progress(&self, _progress: Option<ProgressFn>) {}
+
+    /// Set `key` to `value` if its current value is `expected`, where `None` means the key must
+    /// not exist. Returns whether it was set, and the key's value afterwards.
+    ///
+    /// The default reads and then writes, which isn't atomic if another thread writes the key in
+    /// between.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<(bool, Option<String>)> {
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Ok((false, current));
+        }
+        self.set(key, value.clone())?;
+        Ok((true, Some(value)))
+    }
+
+    /// Move the value of `key` to `new_key`, replacing any value it had, and remove `key`, in a
+    /// single write. Fails with `KeyNotFound` if `key` has no value.
+    fn rename(&self, key: String, new_key: String) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.rename(key, new_key);
+        self.write_batch(batch)
+    }
+
+    /// Run a script with `run_script`, applying all of its writes or none of them.
+    ///
+    /// The default reads through `get` and writes through `write_batch`, which isn't atomic if
+    /// another thread writes a key the script read before its writes are applied.
+    fn run_script(&self, script: &str, args: &[String]) -> Result<Option<String>> {
+        script::run_script(self, script, args)
+    }
+
+    /// The keys with string values from `start` up to but not including `end`, with their
+    /// values, in key order, at most `limit` of them. A bound that's `None` leaves that end of
+    /// the range open.
+    fn scan(
+        &self,
+        _start: Option<String>,
+        _end: Option<String>,
+        _limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        Err(Error::Unsupported("scan"))
+    }
+
+    /// Like `scan`, but in descending key order, starting from the end of the range, so that a
+    /// limit keeps the last keys in it.
+    fn scan_rev(
+        &self,
+        _start: Option<String>,
+        _end: Option<String>,
+        _limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        Err(Error::Unsupported("scan"))
+    }
+
+    /// Write out anything the engine is holding in memory, e.g. before shutting down.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write out what the engine is holding in memory if it has held it for too long. The
+    /// server calls this every so often, so that writes to a quiet store aren't left unsaved.
+    fn flush_if_due(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reclaim the space used by overwritten and removed values.
+    fn compact(&self) -> Result<()> {
+        Err(Error::Unsupported("compact"))
+    }
+
+    /// Check the on-disk files for corruption.
+    fn verify(&self) -> Result<VerifyReport> {
+        Err(Error::Unsupported("verify"))
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        Err(Error::Unsupported("stats"))
+    }
+
+    /// Apply every write in the batch, or none of them.
+    fn write_batch(&self, _batch: WriteBatch) -> Result<()> {
+        Err(Error::Unsupported("write_batch"))
+    }
+
+    /// The sequence number of the latest write the engine has applied, for read-your-writes
+    /// sessions.
+    fn applied_sequence(&self) -> Result<u64> {
+        Err(Error::Unsupported("sessions"))
+    }
+
+    /// Write a consistent copy of the store into the (new) directory at `path`.
+    fn snapshot(&self, _path: &Path) -> Result<()> {
+        Err(Error::Unsupported("snapshot"))
+    }
+
+    /// Set a field of the hash stored at `key`, creating the hash if it doesn't exist.
+    fn hset(&self, _key: String, _field: String, _value: String) -> Result<()> {
+        Err(Error::Unsupported("hset"))
+    }
+
+    fn hget(&self, _key: String, _field: String) -> Result<Option<String>> {
+        Err(Error::Unsupported("hget"))
+    }
+
+    /// Get every field of the hash stored at `key`, which is empty if the key doesn't exist.
+    fn hgetall(&self, _key: String) -> Result<BTreeMap<String, String>> {
+        Err(Error::Unsupported("hgetall"))
+    }
+
+    /// Remove a field from the hash stored at `key`.
+    fn hdel(&self, _key: String, _field: String) -> Result<()> {
+        Err(Error::Unsupported("hdel"))
+    }
+
+    /// Add a member to the sorted set stored at `key`, or update its score if it's already
+    /// there.
+    fn zadd(&self, _key: String, _score: f64, _member: String) -> Result<()> {
+        Err(Error::Unsupported("zadd"))
+    }
+
+    /// Get the members with scores between `min` and `max` inclusive, lowest score first.
+    fn zrangebyscore(&self, _key: String, _min: f64, _max: f64) -> Result<Vec<(String, f64)>> {
+        Err(Error::Unsupported("zrangebyscore"))
+    }
+
+    /// Get the member's position in the sorted set, counting from the lowest score at 0.
+    fn zrank(&self, _key: String, _member: String) -> Result<Option<u64>> {
+        Err(Error::Unsupported("zrank"))
+    }
+
+    /// Append an entry to the stream stored at `key`, returning its id. Ids only ever increase.
+    fn xadd(&self, _key: String, _payload: String) -> Result<StreamId> {
+        Err(Error::Unsupported("xadd"))
+    }
+
+    /// Get the stream's entries with ids between `from` and `to` inclusive, oldest first.
+    fn xrange(
+        &self,
+        _key: String,
+        _from: StreamId,
+        _to: StreamId,
+    ) -> Result<Vec<(StreamId, String)>> {
+        Err(Error::Unsupported("xrange"))
+    }
+
+    /// Add an item to the end of the queue stored at `key`, returning its id.
+    fn enqueue(&self, _key: String, _payload: String) -> Result<StreamId> {
+        Err(Error::Unsupported("enqueue"))
+    }
+
+    /// Take the oldest item in the queue that isn't held by another consumer. The item stays in
+    /// the queue, hidden for `visibility_timeout`, and is handed out again unless it's
+    /// acknowledged before then.
+    fn dequeue(
+        &self,
+        _key: String,
+        _visibility_timeout: Duration,
+    ) -> Result<Option<(StreamId, String)>> {
+        Err(Error::Unsupported("dequeue"))
+    }
+
+    /// Remove a dequeued item from the queue for good.
+    fn ack(&self, _key: String, _id: StreamId) -> Result<()> {
+        Err(Error::Unsupported("ack"))
+    }
+
+    /// Take the lock named `key` for `ttl`, unless someone else holds it. Returns a fencing
+    /// token, which is larger than any earlier holder's and is needed to unlock.
+    fn lock(&self, _key: String, _ttl: Duration) -> Result<u64> {
+        Err(Error::Unsupported("lock"))
+    }
+
+    /// Release the lock, if it's still held with `token`.
+    fn unlock(&self, _key: String, _token: u64) -> Result<()> {
+        Err(Error::Unsupported("unlock"))
+    }
+
+    /// Set or clear the bit at `offset` in the bitmap stored at `key`.
+    fn setbit(&self, _key: String, _offset: u64, _value: bool) -> Result<()> {
+        Err(Error::Unsupported("setbit"))
+    }
+
+    fn getbit(&self, _key: String, _offset: u64) -> Result<bool> {
+        Err(Error::Unsupported("getbit"))
+    }
+
+    /// Count the bits that are set in the bitmap stored at `key`.
+    fn bitcount(&self, _key: String) -> Result<u64> {
+        Err(Error::Unsupported("bitcount"))
+    }
 }
@@ -0,0 +1,179 @@
+//! Reporting how far long-running work like compaction has got, e.g. to draw a progress bar.
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often a `ProgressTracker` reports, at most, other than when the work is done.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Called with each report of how far an operation has got.
+pub type ProgressFn = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// How far an operation has got.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Progress {
+    /// What's being done, like "compact" or "verify".
+    pub operation: String,
+    /// What `done` and `total` count, like "pages" or "keys".
+    pub unit: String,
+    pub done: u64,
+    /// How many there are in all, or 0 if that isn't known up front.
+    pub total: u64,
+    /// How many bytes have been read or written so far.
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+}
+
+impl Progress {
+    /// How much of the work is done, from 0 to 100, if the total is known.
+    pub fn percent(&self) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+        Some(self.done.min(self.total) * 100 / self.total)
+    }
+
+    /// How much longer the rest of the work should take, going at the rate it has so far.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.total == 0 || self.done == 0 {
+            return None;
+        }
+        let remaining = self.total.saturating_sub(self.done);
+        Some(Duration::from_millis(
+            self.elapsed_ms * remaining / self.done,
+        ))
+    }
+
+    /// A line of `width` characters or so for a terminal, with a bar if the total is known.
+    pub fn bar(&self, width: usize) -> String {
+        let line = self.to_string();
+        match self.percent() {
+            Some(percent) => {
+                let bar_width = width.saturating_sub(line.len() + 3).max(10);
+                let filled = bar_width * percent as usize / 100;
+                format!(
+                    "[{}{}] {}",
+                    "#".repeat(filled),
+                    " ".repeat(bar_width - filled),
+                    line
+                )
+            }
+            None => line,
+        }
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.operation, self.done)?;
+        if self.total > 0 {
+            write!(f, "/{}", self.total)?;
+        }
+        write!(f, " {}, {}", self.unit, format_bytes(self.bytes))?;
+        if let Some(percent) = self.percent() {
+            write!(f, ", {}%", percent)?;
+        }
+        if let Some(eta) = self.eta() {
+            write!(f, ", {}s left", eta.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// The number of bytes in the largest unit that keeps it at 1 or more, like "3.2 MiB".
+fn format_bytes(bytes: u64) -> String {
+    let units = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Counts the work done on one operation and reports it to a `ProgressFn`, at most every
+/// `PROGRESS_INTERVAL` until it's done. It can be shared between the threads doing the work.
+/// Without a function to report to, it does nothing.
+pub struct ProgressTracker {
+    report: Option<ProgressFn>,
+    operation: &'static str,
+    unit: &'static str,
+    total: u64,
+    started: Instant,
+    state: Mutex<TrackerState>,
+}
+
+struct TrackerState {
+    done: u64,
+    bytes: u64,
+    reported_at: Option<Instant>,
+}
+
+impl ProgressTracker {
+    /// Start tracking, reporting that nothing is done yet.
+    pub fn new(
+        report: Option<ProgressFn>,
+        operation: &'static str,
+        unit: &'static str,
+        total: u64,
+    ) -> ProgressTracker {
+        let tracker = ProgressTracker {
+            report,
+            operation,
+            unit,
+            total,
+            started: Instant::now(),
+            state: Mutex::new(TrackerState {
+                done: 0,
+                bytes: 0,
+                reported_at: None,
+            }),
+        };
+        tracker.advance(0, 0);
+        tracker
+    }
+
+    /// Count `units` more done, and `bytes` more read or written.
+    pub fn advance(&self, units: u64, bytes: u64) {
+        if self.report.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.done += units;
+        state.bytes += bytes;
+        let due = match state.reported_at {
+            Some(reported_at) => reported_at.elapsed() >= PROGRESS_INTERVAL,
+            None => true,
+        };
+        if due || (self.total > 0 && state.done >= self.total) {
+            state.reported_at = Some(Instant::now());
+            self.send(&state);
+        }
+    }
+
+    /// Report where the work ended up, however recently the last report was.
+    pub fn finish(&self) {
+        if self.report.is_some() {
+            self.send(&self.state.lock().unwrap());
+        }
+    }
+
+    fn send(&self, state: &TrackerState) {
+        if let Some(report) = &self.report {
+            report(&Progress {
+                operation: self.operation.to_owned(),
+                unit: self.unit.to_owned(),
+                done: state.done,
+                total: self.total,
+                bytes: state.bytes,
+                elapsed_ms: self.started.elapsed().as_millis() as u64,
+            });
+        }
+    }
+}
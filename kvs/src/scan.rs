@@ -0,0 +1,74 @@
+//! Scans split into pages for the wire, so that no response has to hold every key in a big
+//! range. Each page comes with a cursor, which the client sends back with the same range to get
+//! the page after it.
+use crate::{Engine, Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// The most keys a page of a scan holds, whatever limit the request asks for.
+pub const MAX_SCAN_PAGE: usize = 1000;
+
+/// Keys with their values, in the order they were scanned, and the cursor to send for the next
+/// page if there are more.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ScanPage {
+    pub pairs: Vec<(String, String)>,
+    pub cursor: Option<String>,
+}
+
+/// A page of a scan of the range from `start` up to but not including `end`, like
+/// `Engine::scan`, or `Engine::scan_rev` if `reverse` is set. It holds at most `limit` keys, and
+/// never more than `MAX_SCAN_PAGE`. If `cursor` is set, the page starts after the one that
+/// returned it.
+pub fn scan_page<E: Engine + ?Sized>(
+    engine: &E,
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<usize>,
+    reverse: bool,
+    cursor: Option<&str>,
+) -> Result<ScanPage> {
+    let (mut start, mut end) = (start, end);
+    if let Some(cursor) = cursor {
+        let last = decode_cursor(cursor)?;
+        if reverse {
+            end = Some(last);
+        } else {
+            // The first key after `last` is `last` with a NUL on the end
+            start = Some(last + "\0");
+        }
+    }
+    let page = limit.map_or(MAX_SCAN_PAGE, |limit| limit.min(MAX_SCAN_PAGE));
+    // One key past the page is read, to tell whether there's another page
+    let mut pairs = if reverse {
+        engine.scan_rev(start, end, Some(page + 1))?
+    } else {
+        engine.scan(start, end, Some(page + 1))?
+    };
+    if pairs.len() <= page {
+        return Ok(ScanPage {
+            pairs,
+            cursor: None,
+        });
+    }
+    pairs.truncate(page);
+    let cursor = pairs.last().map(|(key, _)| encode_cursor(key));
+    Ok(ScanPage { pairs, cursor })
+}
+
+/// A cursor is the last key of its page, in hex, so that clients don't go reading meaning into
+/// it.
+fn encode_cursor(key: &str) -> String {
+    key.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Result<String> {
+    let invalid = || Error::Message(format!("Invalid scan cursor: {:?}", cursor));
+    if cursor.len() % 2 != 0 || !cursor.is_ascii() {
+        return Err(invalid());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<u8>>>()?;
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
@@ -0,0 +1,772 @@
+//! Short scripts that run on the server against an engine, for logic over several keys that no
+//! single request covers.
+//!
+//! The language is small. Values are `nil`, bools, 64-bit integers, and strings. A script is a
+//! list of statements: `let x = ...;`, `x = ...;`, `if ... { ... } else { ... }`,
+//! `while ... { ... }`, `return ...;`, and expressions followed by `;`. Expressions have the
+//! usual arithmetic, comparison, and boolean operators, with `+` also joining strings, and
+//! calls to these functions:
+//!
+//! - `get(key)`: the key's value, or `nil` if it doesn't have one.
+//! - `set(key, value)`: set the key to the value, turning numbers and bools into strings.
+//! - `remove(key)`: remove the key. Returns whether it had a value.
+//! - `exists(key)`: whether the key has a value.
+//! - `arg(i)`: the `i`th argument the script was run with, from 0, or `nil`.
+//! - `argc()`: how many arguments the script was run with.
+//! - `int(x)`, `str(x)`: parse a string as an integer, or turn a value into a string.
+//! - `len(s)`: how many characters the string has.
+//!
+//! A script's writes are held until it finishes and then applied as one `WriteBatch`, so
+//! either all of them happen or, if the script fails, none do. Its reads see its own writes.
+//! The server runs scripts with `Engine::run_script`, so whether other requests can change a
+//! key in between is up to the engine.
+use crate::{check_unreserved, validate_key, Engine, Error, Result, WriteBatch};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+
+/// How many statements and expressions a script may evaluate before it's stopped, so that one
+/// that loops forever can't hold up the server.
+pub const MAX_SCRIPT_STEPS: u64 = 100_000;
+
+/// How long a string a script may build, in bytes, so that one that keeps doubling a string
+/// fails long before it runs the server out of memory.
+pub const MAX_SCRIPT_STRING_LEN: usize = 16 * 1024 * 1024;
+
+/// How deeply blocks and expressions may be nested, so that parsing and running a script
+/// can't overflow the stack.
+const MAX_NESTING: usize = 64;
+
+const KEYWORDS: &[&str] = &[
+    "let", "if", "else", "while", "return", "true", "false", "nil",
+];
+
+/// Two-character operators come first, so that `==` isn't read as two `=`s.
+const PUNCTUATION: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "=", "(", ")", "{",
+    "}", ",", ";",
+];
+
+/// The binary operators, from the loosest binding to the tightest.
+const PRECEDENCE: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// Run the script against the engine with the given arguments, returning what it returned, or
+/// `None` if that was `nil` or it didn't return anything.
+pub fn run_script<E: Engine + ?Sized>(
+    engine: &E,
+    script: &str,
+    args: &[String],
+) -> Result<Option<String>> {
+    let body = Parser {
+        tokens: tokenize(script)?,
+        pos: 0,
+        depth: 0,
+    }
+    .script()?;
+    let mut interpreter = Interpreter {
+        engine,
+        args,
+        scopes: vec![HashMap::new()],
+        writes: BTreeMap::new(),
+        steps: 0,
+        line: 1,
+    };
+    let result = match interpreter.statements(&body)? {
+        Flow::Return(value) => value,
+        Flow::Next => Value::Nil,
+    };
+    if !interpreter.writes.is_empty() {
+        let mut batch = WriteBatch::new();
+        for (key, value) in interpreter.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        interpreter.engine.write_batch(batch)?;
+    }
+    Ok(match result {
+        Value::Nil => None,
+        value => Some(value.to_string()),
+    })
+}
+
+fn script_error(line: usize, message: impl Display) -> Error {
+    Error::Message(format!("Script error on line {}: {}", line, message))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "a bool",
+            Value::Int(_) => "an integer",
+            Value::Str(_) => "a string",
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Str(String),
+    Ident(String),
+    Punct(&'static str),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Int(n) => write!(f, "`{}`", n),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Punct(punct) => write!(f, "`{}`", punct),
+        }
+    }
+}
+
+/// Split the script into tokens, each with the line it's on.
+fn tokenize(script: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut rest = script;
+    while let Some(c) = rest.chars().next() {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let len = if c == '\n' {
+            line += 1;
+            1
+        } else if c.is_whitespace() {
+            c.len_utf8()
+        } else if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let n = rest[..len]
+                .parse()
+                .map_err(|_| script_error(line, "integer is too big"))?;
+            tokens.push((Token::Int(n), line));
+            len
+        } else if is_word(c) {
+            let len = rest.find(|c: char| !is_word(c)).unwrap_or(rest.len());
+            tokens.push((Token::Ident(rest[..len].to_owned()), line));
+            len
+        } else if c == '"' {
+            let start_line = line;
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let len = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => value.push(match chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, c)) if c == '"' || c == '\\' => c,
+                        _ => return Err(script_error(line, "unknown escape in string")),
+                    }),
+                    Some((_, c)) => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        value.push(c)
+                    }
+                    None => return Err(script_error(start_line, "unterminated string")),
+                }
+            };
+            tokens.push((Token::Str(value), start_line));
+            len
+        } else if let Some(&punct) = PUNCTUATION.iter().find(|punct| rest.starts_with(**punct)) {
+            tokens.push((Token::Punct(punct), line));
+            punct.len()
+        } else {
+            return Err(script_error(line, format!("unexpected character {:?}", c)));
+        };
+        rest = &rest[len..];
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug)]
+struct Stmt {
+    line: usize,
+    kind: StmtKind,
+}
+
+#[derive(Debug)]
+enum StmtKind {
+    Let(String, Expr),
+    Assign(String, Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Return(Option<Expr>),
+    Expr(Expr),
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    /// How many blocks and expressions the parser is inside of.
+    depth: usize,
+}
+
+impl Parser {
+    fn script(mut self) -> Result<Vec<Stmt>> {
+        let mut body = Vec::new();
+        while self.peek().is_some() {
+            body.push(self.statement()?);
+        }
+        Ok(body)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |(_, line)| *line)
+    }
+
+    fn error(&self, message: impl Display) -> Error {
+        script_error(self.line(), message)
+    }
+
+    /// An error for finding the next token where something else was expected.
+    fn expected(&self, what: &str) -> Error {
+        match self.peek() {
+            Some(token) => self.error(format!("expected {}, found {}", what, token)),
+            None => self.error(format!("expected {}, found the end of the script", what)),
+        }
+    }
+
+    fn eat_punct(&mut self, punct: &'static str) -> bool {
+        let found = self.peek() == Some(&Token::Punct(punct));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_punct(&mut self, punct: &'static str) -> Result<()> {
+        if self.eat_punct(punct) {
+            Ok(())
+        } else {
+            Err(self.expected(&format!("`{}`", punct)))
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(name)) => name == keyword,
+            _ => false,
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn name(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(Token::Ident(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.expected("a name")),
+        }
+    }
+
+    fn nest(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return Err(self.error("blocks or expressions are nested too deeply"));
+        }
+        Ok(())
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>> {
+        self.nest()?;
+        self.expect_punct("{")?;
+        let mut body = Vec::new();
+        while !self.eat_punct("}") {
+            if self.peek().is_none() {
+                return Err(self.expected("`}`"));
+            }
+            body.push(self.statement()?);
+        }
+        self.depth -= 1;
+        Ok(body)
+    }
+
+    fn statement(&mut self) -> Result<Stmt> {
+        let line = self.line();
+        let kind = if self.eat_keyword("let") {
+            let name = self.name()?;
+            self.expect_punct("=")?;
+            let value = self.expr()?;
+            self.expect_punct(";")?;
+            StmtKind::Let(name, value)
+        } else if self.eat_keyword("if") {
+            self.if_statement()?
+        } else if self.eat_keyword("while") {
+            let condition = self.expr()?;
+            StmtKind::While(condition, self.block()?)
+        } else if self.eat_keyword("return") {
+            let value = if self.eat_punct(";") {
+                None
+            } else {
+                let value = self.expr()?;
+                self.expect_punct(";")?;
+                Some(value)
+            };
+            StmtKind::Return(value)
+        } else if self.tokens.get(self.pos + 1).map(|(token, _)| token) == Some(&Token::Punct("="))
+        {
+            let name = self.name()?;
+            self.pos += 1;
+            let value = self.expr()?;
+            self.expect_punct(";")?;
+            StmtKind::Assign(name, value)
+        } else {
+            let expr = self.expr()?;
+            self.expect_punct(";")?;
+            StmtKind::Expr(expr)
+        };
+        Ok(Stmt { line, kind })
+    }
+
+    /// The rest of an `if` statement, after the `if`.
+    fn if_statement(&mut self) -> Result<StmtKind> {
+        let condition = self.expr()?;
+        let then = self.block()?;
+        let otherwise = if !self.eat_keyword("else") {
+            Vec::new()
+        } else if self.is_keyword("if") {
+            let line = self.line();
+            self.pos += 1;
+            let kind = self.if_statement()?;
+            vec![Stmt { line, kind }]
+        } else {
+            self.block()?
+        };
+        Ok(StmtKind::If(condition, then, otherwise))
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        self.nest()?;
+        let expr = self.binary(0)?;
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    /// An expression whose operators bind at least as tightly as `PRECEDENCE[level]`.
+    fn binary(&mut self, level: usize) -> Result<Expr> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Punct(op)) if PRECEDENCE[level].contains(op) => *op,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        for &op in &["!", "-"] {
+            if self.eat_punct(op) {
+                self.nest()?;
+                let operand = self.unary()?;
+                self.depth -= 1;
+                return Ok(Expr::Unary(op, Box::new(operand)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(self.expected("an expression")),
+        };
+        self.pos += 1;
+        match token {
+            Token::Int(n) => Ok(Expr::Literal(Value::Int(n))),
+            Token::Str(s) => Ok(Expr::Literal(Value::Str(s))),
+            Token::Punct("(") => {
+                let expr = self.expr()?;
+                self.expect_punct(")")?;
+                Ok(expr)
+            }
+            Token::Ident(name) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "nil" => Ok(Expr::Literal(Value::Nil)),
+                _ if KEYWORDS.contains(&name.as_str()) => {
+                    self.pos -= 1;
+                    Err(self.expected("an expression"))
+                }
+                _ if self.eat_punct("(") => {
+                    let mut args = Vec::new();
+                    while !self.eat_punct(")") {
+                        if !args.is_empty() {
+                            self.expect_punct(",")?;
+                        }
+                        args.push(self.expr()?);
+                    }
+                    Ok(Expr::Call(name, args))
+                }
+                _ => Ok(Expr::Var(name)),
+            },
+            Token::Punct(_) => {
+                self.pos -= 1;
+                Err(self.expected("an expression"))
+            }
+        }
+    }
+}
+
+/// What running a statement leads to.
+enum Flow {
+    Next,
+    Return(Value),
+}
+
+struct Interpreter<'a, E: Engine + ?Sized> {
+    engine: &'a E,
+    args: &'a [String],
+    /// The variables of each block the script is in, innermost last.
+    scopes: Vec<HashMap<String, Value>>,
+    /// The keys the script has written, and their new values, or `None` for removed.
+    writes: BTreeMap<String, Option<String>>,
+    steps: u64,
+    /// The line of the statement being run, for errors.
+    line: usize,
+}
+
+impl<'a, E: Engine + ?Sized> Interpreter<'a, E> {
+    fn error(&self, message: impl Display) -> Error {
+        script_error(self.line, message)
+    }
+
+    fn step(&mut self) -> Result<()> {
+        self.steps += 1;
+        if self.steps > MAX_SCRIPT_STEPS {
+            return Err(self.error(format!("gave up after {} steps", MAX_SCRIPT_STEPS)));
+        }
+        Ok(())
+    }
+
+    fn statements(&mut self, body: &[Stmt]) -> Result<Flow> {
+        for stmt in body {
+            if let Flow::Return(value) = self.statement(stmt)? {
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn block(&mut self, body: &[Stmt]) -> Result<Flow> {
+        self.scopes.push(HashMap::new());
+        let flow = self.statements(body);
+        self.scopes.pop();
+        flow
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<Flow> {
+        self.line = stmt.line;
+        self.step()?;
+        match &stmt.kind {
+            StmtKind::Let(name, value) => {
+                let value = self.eval(value)?;
+                self.scopes.last_mut().unwrap().insert(name.clone(), value);
+            }
+            StmtKind::Assign(name, value) => {
+                let value = self.eval(value)?;
+                match self
+                    .scopes
+                    .iter_mut()
+                    .rev()
+                    .find_map(|scope| scope.get_mut(name))
+                {
+                    Some(variable) => *variable = value,
+                    None => return Err(self.error(format!("`{}` isn't defined", name))),
+                }
+            }
+            StmtKind::If(condition, then, otherwise) => {
+                let body = if self.condition(condition)? {
+                    then
+                } else {
+                    otherwise
+                };
+                return self.block(body);
+            }
+            StmtKind::While(condition, body) => loop {
+                self.line = stmt.line;
+                if !self.condition(condition)? {
+                    break;
+                }
+                if let Flow::Return(value) = self.block(body)? {
+                    return Ok(Flow::Return(value));
+                }
+            },
+            StmtKind::Return(value) => {
+                let value = match value {
+                    Some(value) => self.eval(value)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+            StmtKind::Expr(expr) => {
+                self.eval(expr)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn condition(&mut self, expr: &Expr) -> Result<bool> {
+        match self.eval(expr)? {
+            Value::Bool(b) => Ok(b),
+            value => Err(self.error(format!("expected a bool, found {}", value.type_name()))),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value> {
+        self.step()?;
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Var(name) => self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(name))
+                .cloned()
+                .ok_or_else(|| self.error(format!("`{}` isn't defined", name))),
+            Expr::Unary(op, operand) => match (*op, self.eval(operand)?) {
+                ("!", Value::Bool(b)) => Ok(Value::Bool(!b)),
+                ("-", Value::Int(n)) => n
+                    .checked_neg()
+                    .map(Value::Int)
+                    .ok_or_else(|| self.error("integer overflow")),
+                (op, value) => {
+                    Err(self.error(format!("can't apply `{}` to {}", op, value.type_name())))
+                }
+            },
+            Expr::Binary("&&", lhs, rhs) => {
+                Ok(Value::Bool(self.condition(lhs)? && self.condition(rhs)?))
+            }
+            Expr::Binary("||", lhs, rhs) => {
+                Ok(Value::Bool(self.condition(lhs)? || self.condition(rhs)?))
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                self.binary(op, lhs, rhs)
+            }
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg))
+                    .collect::<Result<Vec<_>>>()?;
+                self.call(name, args)
+            }
+        }
+    }
+
+    fn binary(&self, op: &str, lhs: Value, rhs: Value) -> Result<Value> {
+        let ordering = match (&lhs, &rhs) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+            _ => None,
+        };
+        let overflow = |result: Option<i64>| {
+            result
+                .map(Value::Int)
+                .ok_or_else(|| self.error("integer overflow"))
+        };
+        match (op, lhs, rhs) {
+            ("==", lhs, rhs) => Ok(Value::Bool(lhs == rhs)),
+            ("!=", lhs, rhs) => Ok(Value::Bool(lhs != rhs)),
+            ("<", _, _) if ordering.is_some() => Ok(Value::Bool(ordering == Some(Ordering::Less))),
+            ("<=", _, _) if ordering.is_some() => {
+                Ok(Value::Bool(ordering != Some(Ordering::Greater)))
+            }
+            (">", _, _) if ordering.is_some() => {
+                Ok(Value::Bool(ordering == Some(Ordering::Greater)))
+            }
+            (">=", _, _) if ordering.is_some() => Ok(Value::Bool(ordering != Some(Ordering::Less))),
+            ("+", Value::Int(a), Value::Int(b)) => overflow(a.checked_add(b)),
+            ("-", Value::Int(a), Value::Int(b)) => overflow(a.checked_sub(b)),
+            ("*", Value::Int(a), Value::Int(b)) => overflow(a.checked_mul(b)),
+            ("/", Value::Int(_), Value::Int(0)) | ("%", Value::Int(_), Value::Int(0)) => {
+                Err(self.error("division by zero"))
+            }
+            ("/", Value::Int(a), Value::Int(b)) => overflow(a.checked_div(b)),
+            ("%", Value::Int(a), Value::Int(b)) => overflow(a.checked_rem(b)),
+            ("+", Value::Str(a), Value::Str(b)) => {
+                if a.len() + b.len() > MAX_SCRIPT_STRING_LEN {
+                    return Err(self.error(format!(
+                        "string longer than {} bytes",
+                        MAX_SCRIPT_STRING_LEN
+                    )));
+                }
+                Ok(Value::Str(a + &b))
+            }
+            (op, lhs, rhs) => Err(self.error(format!(
+                "can't apply `{}` to {} and {}",
+                op,
+                lhs.type_name(),
+                rhs.type_name()
+            ))),
+        }
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value> {
+        let arity = match name {
+            "argc" => 0,
+            "get" | "remove" | "exists" | "arg" | "int" | "str" | "len" => 1,
+            "set" => 2,
+            _ => return Err(self.error(format!("unknown function `{}`", name))),
+        };
+        if args.len() != arity {
+            return Err(self.error(format!(
+                "`{}` takes {} argument{}, not {}",
+                name,
+                arity,
+                if arity == 1 { "" } else { "s" },
+                args.len()
+            )));
+        }
+        let mut args = args.into_iter();
+        let mut arg = || args.next().unwrap();
+        match name {
+            "get" => {
+                let key = self.key(name, arg())?;
+                Ok(self.read(key)?.map_or(Value::Nil, Value::Str))
+            }
+            "set" => {
+                let key = self.key(name, arg())?;
+                validate_key(&key)?;
+                let value = match arg() {
+                    Value::Nil => return Err(self.error("can't set a key to nil, use `remove`")),
+                    value => value.to_string(),
+                };
+                self.writes.insert(key, Some(value));
+                Ok(Value::Nil)
+            }
+            "remove" => {
+                let key = self.key(name, arg())?;
+                let existed = self.read(key.clone())?.is_some();
+                if existed {
+                    self.writes.insert(key, None);
+                }
+                Ok(Value::Bool(existed))
+            }
+            "exists" => {
+                let key = self.key(name, arg())?;
+                Ok(Value::Bool(self.read(key)?.is_some()))
+            }
+            "arg" => match arg() {
+                Value::Int(i) => Ok(if i >= 0 && (i as u64) < self.args.len() as u64 {
+                    Value::Str(self.args[i as usize].clone())
+                } else {
+                    Value::Nil
+                }),
+                value => Err(self.wrong_type(name, "an integer", &value)),
+            },
+            "argc" => Ok(Value::Int(self.args.len() as i64)),
+            "int" => match arg() {
+                Value::Int(n) => Ok(Value::Int(n)),
+                Value::Str(s) => s
+                    .trim()
+                    .parse()
+                    .map(Value::Int)
+                    .map_err(|_| self.error(format!("{:?} isn't an integer", s))),
+                value => Err(self.wrong_type(name, "a string", &value)),
+            },
+            "str" => match arg() {
+                Value::Nil => Err(self.wrong_type(name, "a value", &Value::Nil)),
+                value => Ok(Value::Str(value.to_string())),
+            },
+            "len" => match arg() {
+                Value::Str(s) => Ok(Value::Int(s.chars().count() as i64)),
+                value => Err(self.wrong_type(name, "a string", &value)),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn wrong_type(&self, function: &str, expected: &str, found: &Value) -> Error {
+        self.error(format!(
+            "`{}` expects {}, not {}",
+            function,
+            expected,
+            found.type_name()
+        ))
+    }
+
+    /// The key a function was called with, which has to be a string and not one of the
+    /// server's own.
+    fn key(&self, function: &str, key: Value) -> Result<String> {
+        match key {
+            Value::Str(key) => check_unreserved(&key).map(|_| key),
+            key => Err(self.wrong_type(function, "a string key", &key)),
+        }
+    }
+
+    /// The key's value as the script sees it, with its own writes.
+    fn read(&mut self, key: String) -> Result<Option<String>> {
+        match self.writes.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => self.engine.get(key),
+        }
+    }
+}
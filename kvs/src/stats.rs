@@ -0,0 +1,137 @@
+use logformat::manifest::SizeHistogram;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// A summary of the engine's on-disk and in-memory state.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Stats {
+    pub pages: u64,
+    pub partial_pages: u64,
+    pub memtable_entries: u64,
+    /// What the memtable counts against its byte budget.
+    #[serde(default)]
+    pub memtable_bytes: u64,
+    pub disk_bytes: u64,
+    /// About how many keys the store holds, from what it knows without reading its pages.
+    #[serde(default)]
+    pub approximate_keys: u64,
+    /// The sizes of the keys written, in bytes. Keys that have since been replaced or removed
+    /// are still counted until every page is compacted.
+    #[serde(default)]
+    pub key_sizes: SizeHistogram,
+    /// The sizes of the values written, in bytes, counted the same way as `key_sizes`.
+    /// Structured values are counted at their encoded size.
+    #[serde(default)]
+    pub value_sizes: SizeHistogram,
+    /// How each of the engine's caches is doing, by name.
+    #[serde(default)]
+    pub caches: BTreeMap<String, CacheStats>,
+}
+
+/// How well one of the engine's caches is doing. A cache that misses often and is evicting to
+/// make room would do better bigger; one that rarely evicts wouldn't.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// How many entries have been dropped to make room for others.
+    pub evictions: u64,
+    /// How many entries the cache holds now.
+    pub entries: u64,
+    /// How many bytes the entries take up, if the cache counts them.
+    pub bytes: u64,
+}
+
+impl CacheStats {
+    /// The share of lookups that were hits, from 0 to 1, if there have been any.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return None;
+        }
+        Some(self.hits as f64 / lookups as f64)
+    }
+}
+
+/// The outcome of checking every page and data file referenced by the index.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct VerifyReport {
+    pub pages_checked: u64,
+    /// How many keys were read back through the normal lookup path, if any.
+    pub keys_sampled: u64,
+    pub errors: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pages: {}", self.pages)?;
+        writeln!(f, "partial pages: {}", self.partial_pages)?;
+        writeln!(f, "memtable entries: {}", self.memtable_entries)?;
+        writeln!(f, "memtable bytes: {}", self.memtable_bytes)?;
+        writeln!(f, "disk bytes: {}", self.disk_bytes)?;
+        writeln!(f, "approximate keys: {}", self.approximate_keys)?;
+        for (name, cache) in self.caches.iter() {
+            writeln!(f, "{} cache: {}", name, cache)?;
+        }
+        write_histogram(f, "key sizes", &self.key_sizes)?;
+        writeln!(f)?;
+        write_histogram(f, "value sizes", &self.value_sizes)
+    }
+}
+
+/// A line with the count and biggest size, then one for each bucket that isn't empty.
+fn write_histogram(f: &mut fmt::Formatter<'_>, name: &str, sizes: &SizeHistogram) -> fmt::Result {
+    write!(
+        f,
+        "{}: {} written, largest {} bytes",
+        name,
+        sizes.count(),
+        sizes.max
+    )?;
+    for (bucket, count) in sizes.buckets.iter().enumerate() {
+        if *count > 0 {
+            let (min, max) = SizeHistogram::bucket_range(bucket);
+            write!(f, "\n  {}-{} bytes: {}", min, max, count)?;
+        }
+    }
+    Ok(())
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits, self.misses)?;
+        if let Some(rate) = self.hit_rate() {
+            write!(f, " ({:.1}% hits)", rate * 100.0)?;
+        }
+        write!(
+            f,
+            ", {} evictions, {} entries, {} bytes",
+            self.evictions, self.entries, self.bytes
+        )
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "checked {} pages", self.pages_checked)?;
+        if self.keys_sampled > 0 {
+            write!(f, " and {} keys", self.keys_sampled)?;
+        }
+        if self.is_ok() {
+            write!(f, ", no errors found")
+        } else {
+            write!(f, ", {} errors found", self.errors.len())?;
+            for error in self.errors.iter() {
+                write!(f, "\n{}", error)?;
+            }
+            Ok(())
+        }
+    }
+}
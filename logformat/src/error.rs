@@ -7,30 +7,38 @@ use uuid;
 /// The result type for everything in the logformat crate.
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("{0}")]
     Message(String),
-    IoError(io::Error),
-    UuidError(uuid::Error),
-    SystemTimeError(SystemTimeError),
+    /// Encoding or decoding failed. Says what was being encoded or decoded.
+    #[error("{context}: {source}")]
+    Encoding {
+        context: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Bad UUID: {0}")]
+    UuidError(#[from] uuid::Error),
+    #[error("System clock is wrong: {0}")]
+    SystemTimeError(#[from] SystemTimeError),
+    #[error("Unexpected end of file")]
     UnexpectedEof,
 }
 
-impl From<uuid::Error> for Error {
-    fn from(error: uuid::Error) -> Self {
-        Error::UuidError(error)
-    }
-}
-
-impl From<SystemTimeError> for Error {
-    fn from(error: SystemTimeError) -> Self {
-        Error::SystemTimeError(error)
-    }
-}
-
-impl From<io::Error> for Error {
-    fn from(error: io::Error) -> Self {
-        Error::IoError(error)
+impl Error {
+    /// An `Encoding` error from `source`, e.g. `Error::encoding("Bad index")` as the argument
+    /// to `map_err`.
+    pub fn encoding<E>(context: &'static str) -> impl FnOnce(E) -> Error
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        move |source| Error::Encoding {
+            context,
+            source: Box::new(source),
+        }
     }
 }
 
@@ -45,14 +53,3 @@ impl de::Error for Error {
         Error::Message(msg.to_string())
     }
 }
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Error::Message(msg) => write!(f, "{}", msg),
-            _ => write!(f, "{:?}", self),
-        }
-    }
-}
-
-impl std::error::Error for Error {}
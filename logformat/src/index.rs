@@ -1,10 +1,18 @@
 use crate::page::PageHeader;
+use crate::{fnv1a, Error, Result};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
+/// The index file ends with a checksum of everything before it, so that one cut short or
+/// damaged is noticed rather than read as a shorter index.
+const CHECKSUM_LEN: usize = 8;
+
 // FIXME: make this into a B-tree (or something like it) with pages as leaves
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Index {
+    /// The manifest's `index_generation` when the index was written. An index from an older
+    /// generation is stale, e.g. because the process died between writing the two.
+    pub generation: u64,
     headers: Vec<PageHeader>,
 }
 
@@ -25,7 +33,35 @@ impl Index {
         self.headers.is_empty()
     }
 
+    /// Iterate over the page headers from oldest to newest.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &PageHeader> {
+        self.headers.iter()
+    }
+
     pub fn path() -> PathBuf {
         Path::new("index").to_owned()
     }
+
+    /// The contents of the index file: the serialized index followed by its checksum.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes =
+            bincode::serialize(self).map_err(Error::encoding("Could not encode the index"))?;
+        let checksum = fnv1a(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Read an index file written by `encode`, failing if its checksum doesn't match.
+    pub fn decode(bytes: &[u8]) -> Result<Index> {
+        if bytes.len() < CHECKSUM_LEN {
+            return Err(Error::UnexpectedEof);
+        }
+        let (contents, trailer) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        let mut expected = [0; CHECKSUM_LEN];
+        expected.copy_from_slice(trailer);
+        if fnv1a(contents) != u64::from_le_bytes(expected) {
+            return Err(Error::Message("Index checksum does not match".to_owned()));
+        }
+        bincode::deserialize(contents).map_err(Error::encoding("Bad index"))
+    }
 }
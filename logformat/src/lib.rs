@@ -1,12 +1,39 @@
 //! This crate holds the data types for the log-structured storage in the key-value store.
 //!
 //! Records are split up into pages, each with a corresponding data file holding the byte-string
-//! values. There's also a single index file which is used to quickly sort through the pages on
-//! a `get` command.
+//! values or encoded structured records. There's also a single index file which is used to
+//! quickly sort through the pages on a `get` command, and a manifest listing the pages that are
+//! part of the store.
+//!
+//! Pages can also be packed into larger segment files, each page's two files becoming
+//! consecutive blocks of the segment.
 
 pub mod index;
+pub mod manifest;
 pub mod page;
+pub mod record;
+pub mod segment;
 pub mod slotted;
 
 mod error;
 pub use error::{Error, Result};
+
+/// 64-bit FNV-1a. It only has to catch torn and damaged writes and tell keys apart, not stand
+/// up to deliberate tampering.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// CRC-32 (the IEEE polynomial, as zlib and Ethernet use) of the parts one after another.
+pub(crate) fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & 0u32.wrapping_sub(crc & 1));
+        }
+    }
+    !crc
+}
@@ -0,0 +1,208 @@
+use crate::record::StreamId;
+use crate::segment::SegmentLocation;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// The version of the on-disk format written by this crate. Version 2 stores each value's key
+/// alongside it in the data file. Version 3 can pack
+/// pages into segment files. Version 4 ends the index with a checksum. Version 5 records each
+/// slot's key length and fingerprint in its page. Version 6 records a CRC-32 of each page in
+/// its header, in the page and the index.
+pub const FORMAT_VERSION: u32 = 6;
+
+/// The first format version whose index records the pages' checksums. Older indexes can't be
+/// read, and are rebuilt from the pages.
+pub const PAGE_CHECKSUMS_VERSION: u32 = 6;
+
+/// The manifest is the source of truth for which pages make up the store. The index is only a
+/// cache of their headers and can be rebuilt from the pages the manifest lists.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// Incremented every time a new index is written.
+    pub index_generation: u64,
+    /// The UUIDv1 clock sequence to continue from when the store is reopened.
+    pub clock_sequence: u16,
+    /// Every page in the store, from oldest to newest.
+    pub live_pages: Vec<Uuid>,
+    /// The engine options the store was last opened with.
+    pub options: BTreeMap<String, String>,
+    /// The id of the last stream entry added, so that ids keep increasing across restarts.
+    #[serde(default)]
+    pub last_stream_id: StreamId,
+    /// The number of pages written by `save` over the life of the store (compaction doesn't
+    /// count). Archived pages are named by this number, so they can be replayed in order.
+    #[serde(default)]
+    pub last_page_sequence: u64,
+    /// Committed pages that haven't been copied to the archive yet, by sequence number. They
+    /// can't be deleted by compaction until they have been.
+    #[serde(default)]
+    pub unarchived_pages: Vec<(u64, Uuid)>,
+    /// The sequence number of the last write in a committed page. Sessions use it to tell
+    /// whether a server has applied a client's earlier writes.
+    #[serde(default)]
+    pub last_write_sequence: u64,
+    /// The live pages packed into segment files. Any other page has its own page and data
+    /// files.
+    #[serde(default)]
+    pub page_locations: BTreeMap<Uuid, SegmentLocation>,
+    /// Where pages stand in automatic compaction. Any page not listed is at level 0, in a run
+    /// of its own.
+    #[serde(default)]
+    pub page_levels: BTreeMap<Uuid, PageLevel>,
+    /// Estimates of how many of each page's entries newer pages have replaced or removed,
+    /// updated as pages are saved. Any page not listed has none.
+    #[serde(default)]
+    pub stale_entries: BTreeMap<Uuid, u16>,
+    /// How many of each page's own entries are tombstones. Any page not listed has none, or was
+    /// written before they were counted.
+    #[serde(default)]
+    pub tombstones: BTreeMap<Uuid, u16>,
+    /// How keys are hashed. Stores written before this was recorded all used MetroHash with
+    /// `DEFAULT_HASH_SEED`.
+    #[serde(default)]
+    pub key_hash: KeyHash,
+    /// The names of the secondary indexes whose entries have been built, so that one declared
+    /// later is built from the values already there.
+    #[serde(default)]
+    pub indexes: BTreeSet<String>,
+    /// Whether the substring index has been built and kept up to date since.
+    #[serde(default)]
+    pub substring_index: bool,
+    /// The sizes of the keys written, counted as they're written and recounted from the live
+    /// keys by a compaction of every page.
+    #[serde(default)]
+    pub key_sizes: SizeHistogram,
+    /// The sizes of the values written, counted the same way as `key_sizes`.
+    #[serde(default)]
+    pub value_sizes: SizeHistogram,
+}
+
+/// The seed every store used before the hash function could be chosen.
+pub const DEFAULT_HASH_SEED: u64 = 0x385f_829f_0031_3111;
+
+/// A function that keys can be hashed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    MetroHash,
+    XxHash,
+    SipHash,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashAlgorithm::MetroHash => write!(f, "metrohash"),
+            HashAlgorithm::XxHash => write!(f, "xxhash"),
+            HashAlgorithm::SipHash => write!(f, "siphash"),
+        }
+    }
+}
+
+/// The hash function and seed a store's key hashes are computed with. Pages are sorted and
+/// searched by key hash, so this is fixed when the store is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyHash {
+    pub algorithm: HashAlgorithm,
+    pub seed: u64,
+}
+
+impl Default for KeyHash {
+    fn default() -> Self {
+        KeyHash {
+            algorithm: HashAlgorithm::MetroHash,
+            seed: DEFAULT_HASH_SEED,
+        }
+    }
+}
+
+impl fmt::Display for KeyHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} with seed {:#x}", self.algorithm, self.seed)
+    }
+}
+
+/// A page's place in automatic compaction: its level, and the run it belongs to. A run is the
+/// set of pages written together by one merge, named by the UUID of its first page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageLevel {
+    pub level: u32,
+    pub run: Uuid,
+}
+
+/// How many sizes, in bytes, fell into each power-of-two bucket: the first bucket counts sizes
+/// of 0, and bucket `i` after it counts sizes from `2^(i - 1)` to `2^i - 1`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SizeHistogram {
+    /// The count for each bucket, up to the last that isn't empty.
+    pub buckets: Vec<u64>,
+    /// The biggest size counted.
+    pub max: u64,
+}
+
+impl SizeHistogram {
+    pub fn record(&mut self, size: u64) {
+        let bucket = (64 - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.max = self.max.max(size);
+    }
+
+    /// Add the other histogram's counts to this one's.
+    pub fn merge(&mut self, other: &SizeHistogram) {
+        if self.buckets.len() < other.buckets.len() {
+            self.buckets.resize(other.buckets.len(), 0);
+        }
+        for (count, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *count += other;
+        }
+        self.max = self.max.max(other.max);
+    }
+
+    /// How many sizes have been counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The smallest and biggest size the bucket counts.
+    pub fn bucket_range(bucket: usize) -> (u64, u64) {
+        match bucket {
+            0 => (0, 0),
+            _ => (1 << (bucket - 1), u64::max_value() >> (64 - bucket)),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn new(clock_sequence: u16) -> Self {
+        Manifest {
+            format_version: FORMAT_VERSION,
+            index_generation: 0,
+            clock_sequence,
+            live_pages: Vec::new(),
+            options: BTreeMap::new(),
+            last_stream_id: StreamId::default(),
+            last_page_sequence: 0,
+            unarchived_pages: Vec::new(),
+            last_write_sequence: 0,
+            page_locations: BTreeMap::new(),
+            page_levels: BTreeMap::new(),
+            stale_entries: BTreeMap::new(),
+            tombstones: BTreeMap::new(),
+            key_hash: KeyHash::default(),
+            indexes: BTreeSet::new(),
+            substring_index: false,
+            key_sizes: SizeHistogram::default(),
+            value_sizes: SizeHistogram::default(),
+        }
+    }
+
+    pub fn path() -> PathBuf {
+        Path::new("MANIFEST").to_owned()
+    }
+}
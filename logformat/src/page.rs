@@ -1,8 +1,9 @@
-use crate::{Error, Result};
+use crate::{crc32, fnv1a, Error, Result};
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::v1::{ClockSequence, Timestamp};
 use uuid::Uuid;
 
@@ -16,10 +17,30 @@ impl Page {
     pub fn path(uuid: &Uuid) -> PathBuf {
         Path::new(format!("{}.log", uuid.to_hyphenated_ref()).as_str()).to_owned()
     }
+
+    /// How many bytes the page takes up on disk, not counting its data.
+    pub fn block_len(&self) -> usize {
+        block_len(self.body.keyed)
+    }
 }
 
 pub const MAGIC: u64 = 0x7873_6769;
 
+/// The magic number of pages that record their slots' key lengths and fingerprints, in a
+/// block of `SLOT_KEYS_SIZE` bytes right after the first `BUF_SIZE`.
+pub const KEYED_MAGIC: u64 = 0x7873_676b;
+
+/// The magic numbers of pages like `MAGIC` and `KEYED_MAGIC` ones that also record a checksum
+/// in their header, as every page written now does.
+pub const CHECKED_MAGIC: u64 = 0x7873_6763;
+pub const CHECKED_KEYED_MAGIC: u64 = 0x7873_6764;
+
+/// Where the checksum is in the header, right after the count.
+const CHECKSUM_OFFSET: usize = 50;
+
+/// UUIDv1 timestamps count 100ns intervals from the start of the Gregorian calendar in 1582.
+const UUID_TICKS_AT_UNIX_EPOCH: u64 = 0x01B2_1DD2_1381_4000;
+
 pub const RESERVE_BYTES_FOR_HEADER: usize = 384;
 
 /// Each entry is 10 bytes (a u64 + u16)
@@ -32,6 +53,9 @@ pub struct PageHeader {
     pub min_key_hash: u64,
     pub max_key_hash: u64,
     pub count: u16,
+    /// The CRC-32 of the page block as `PageBuffer::serialize` wrote it, apart from the
+    /// checksum itself. Zero for pages written before pages had checksums.
+    pub checksum: u32,
 }
 
 impl Default for PageHeader {
@@ -42,19 +66,21 @@ impl Default for PageHeader {
             min_key_hash: 0,
             max_key_hash: 0,
             count: 0,
+            checksum: 0,
         }
     }
 }
 
 impl PageHeader {
+    /// A header for a page written at `now`, which is recorded in its UUID.
     pub fn new(
         node_id: &[u8],
         context: &impl ClockSequence,
+        now: SystemTime,
         min_key_hash: u64,
         max_key_hash: u64,
         count: u16,
     ) -> Result<Self> {
-        let now = SystemTime::now();
         let since_epoch = now.duration_since(UNIX_EPOCH)?;
         let timestamp =
             Timestamp::from_unix(context, since_epoch.as_secs(), since_epoch.subsec_nanos());
@@ -65,17 +91,86 @@ impl PageHeader {
             min_key_hash,
             max_key_hash,
             count,
+            checksum: 0,
         })
     }
 
     pub fn is_partial(&self) -> bool {
         self.count != COMMANDS_PER_PAGE as u16
     }
+
+    /// When the page was written, from the timestamp in its UUID.
+    pub fn created_at(&self) -> SystemTime {
+        let since_epoch = self.ticks.saturating_sub(UUID_TICKS_AT_UNIX_EPOCH);
+        UNIX_EPOCH + Duration::from_nanos(since_epoch * 100)
+    }
+}
+
+/// A UUIDv1 clock sequence whose current value can be read back, so that it can be persisted
+/// and picked up again after a restart instead of starting over at the same value.
+#[derive(Debug, Default)]
+pub struct ClockContext {
+    count: AtomicU16,
+}
+
+impl ClockContext {
+    pub fn new(count: u16) -> Self {
+        ClockContext {
+            count: AtomicU16::new(count),
+        }
+    }
+
+    /// The sequence number that will be used for the next UUID.
+    pub fn current(&self) -> u16 {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+impl ClockSequence for ClockContext {
+    fn generate_sequence(&self, _seconds: u64, _nano_seconds: u32) -> u16 {
+        self.count.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// A key's length and a short fingerprint of it, as recorded in a slot holding its entry. Keys
+/// with the same hash almost never have both the same, so a lookup can pass over the other
+/// keys' slots without reading their data.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SlotKey {
+    pub len: u16,
+    pub fingerprint: u16,
+}
+
+impl SlotKey {
+    pub fn new(key: &str) -> SlotKey {
+        let hash = fnv1a(key.as_bytes());
+        SlotKey {
+            len: key.len().min(u16::max_value() as usize) as u16,
+            fingerprint: (hash ^ (hash >> 16) ^ (hash >> 32) ^ (hash >> 48)) as u16,
+        }
+    }
 }
 
 pub struct PageBody {
     pub key_hash: [u64; COMMANDS_PER_PAGE],
     pub value_index: [i16; COMMANDS_PER_PAGE],
+    /// Only meaningful if `keyed` is set. A slot whose key isn't known, like a tombstone
+    /// carried over by compaction, has a length of zero.
+    pub slot_keys: [SlotKey; COMMANDS_PER_PAGE],
+    /// Whether the page records `slot_keys`. Pages written before they existed don't.
+    pub keyed: bool,
+}
+
+impl PageBody {
+    /// The key length and fingerprint recorded for the slot, if there are any.
+    pub fn slot_key(&self, slot: usize) -> Option<SlotKey> {
+        let slot_key = self.slot_keys[slot];
+        if self.keyed && slot_key.len != 0 {
+            Some(slot_key)
+        } else {
+            None
+        }
+    }
 }
 
 impl Default for PageBody {
@@ -83,6 +178,8 @@ impl Default for PageBody {
         PageBody {
             key_hash: [0; COMMANDS_PER_PAGE],
             value_index: [0; COMMANDS_PER_PAGE],
+            slot_keys: [SlotKey::default(); COMMANDS_PER_PAGE],
+            keyed: false,
         }
     }
 }
@@ -90,33 +187,92 @@ impl Default for PageBody {
 /// Each page is 16KiB.
 pub const BUF_SIZE: usize = 16384;
 
+/// Each slot's key length and fingerprint are 4 bytes (two u16s).
+pub const SLOT_KEYS_SIZE: usize = COMMANDS_PER_PAGE * 4;
+
+/// How many bytes a page takes up on disk, depending on whether it records its slots' keys.
+pub fn block_len(keyed: bool) -> usize {
+    if keyed {
+        BUF_SIZE + SLOT_KEYS_SIZE
+    } else {
+        BUF_SIZE
+    }
+}
+
 /// PageBuffer is used to quickly read and write pages to disk in one go.
 pub struct PageBuffer {
     pub buf: [u8; BUF_SIZE],
+    /// The slot keys of a page with `KEYED_MAGIC`.
+    pub slot_keys: [u8; SLOT_KEYS_SIZE],
+}
+
+impl Default for PageBuffer {
+    fn default() -> Self {
+        PageBuffer {
+            buf: [0; BUF_SIZE],
+            slot_keys: [0; SLOT_KEYS_SIZE],
+        }
+    }
 }
 
 impl PageBuffer {
+    /// Fill the buffer from the reader, however few bytes each read returns. The slot keys
+    /// are only read if the page's magic number says it has them. Fails with
+    /// `Error::UnexpectedEof` if the reader ends first.
     pub fn read_from(&mut self, reader: &mut impl Read) -> Result<()> {
-        let mut attempts = 0;
-        let mut remaining = BUF_SIZE;
-        while remaining > 0 {
-            if attempts > 1000 {
-                return Err(Error::Message(
-                    "Failed to load buffer after many attempts".to_owned(),
-                ));
-            }
-
-            let n = reader.read(&mut self.buf[BUF_SIZE - remaining..])?;
-            remaining -= n;
-            attempts += 1;
+        read_exactly(reader, &mut self.buf)?;
+        if self.is_keyed() {
+            read_exactly(reader, &mut self.slot_keys)?;
         }
         Ok(())
     }
 
     pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
         writer.write_all(&self.buf[..])?;
+        if self.is_keyed() {
+            writer.write_all(&self.slot_keys[..])?;
+        }
         Ok(())
     }
+
+    /// How many bytes `write_to` writes.
+    pub fn block_len(&self) -> usize {
+        block_len(self.is_keyed())
+    }
+
+    fn is_keyed(&self) -> bool {
+        let mut magic = [0u8; 8];
+        magic.copy_from_slice(&self.buf[..8]);
+        match u64::from_le_bytes(magic) {
+            KEYED_MAGIC | CHECKED_KEYED_MAGIC => true,
+            _ => false,
+        }
+    }
+
+    /// The CRC-32 of the page block, leaving out where the checksum goes.
+    fn checksum(&self) -> u32 {
+        let checksum_end = CHECKSUM_OFFSET + 4;
+        let head = &self.buf[..CHECKSUM_OFFSET];
+        let rest = &self.buf[checksum_end..];
+        if self.is_keyed() {
+            crc32(&[head, rest, &self.slot_keys[..]])
+        } else {
+            crc32(&[head, rest])
+        }
+    }
+}
+
+fn read_exactly(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Err(Error::UnexpectedEof),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::IoError(e)),
+        }
+    }
+    Ok(())
 }
 
 macro_rules! write_bytes {
@@ -136,16 +292,27 @@ macro_rules! write_int {
 }
 
 impl PageBuffer {
-    pub fn serialize(&mut self, page: &Page) {
-        self.serialize_header(&page.header);
+    /// Write the page into the buffer, and record the checksum of what was written in its
+    /// header.
+    pub fn serialize(&mut self, page: &mut Page) {
+        self.serialize_header(&page.header, page.body.keyed);
         self.serialize_body(&page.body, page.header.count as usize);
+        let checksum = self.checksum();
+        let mut index = CHECKSUM_OFFSET;
+        write_int!(self.buf, index, checksum);
+        page.header.checksum = checksum;
     }
 
-    fn serialize_header(&mut self, header: &PageHeader) {
+    fn serialize_header(&mut self, header: &PageHeader, keyed: bool) {
         let mut index = 0;
 
         // Magic number
-        write_int!(self.buf, index, MAGIC);
+        let magic = if keyed {
+            CHECKED_KEYED_MAGIC
+        } else {
+            CHECKED_MAGIC
+        };
+        write_int!(self.buf, index, magic);
 
         // UUID
         write_bytes!(self.buf, index, header.uuid.as_bytes());
@@ -174,18 +341,31 @@ impl PageBuffer {
         for i in 0..count as usize * 2 {
             self.buf[offset + i] = unsafe { *value_index_bytes.add(i) };
         }
+
+        if body.keyed {
+            let mut index = 0;
+            for slot_key in &body.slot_keys[..count] {
+                write_int!(self.slot_keys, index, slot_key.len);
+            }
+            let mut index = COMMANDS_PER_PAGE * 2;
+            for slot_key in &body.slot_keys[..count] {
+                write_int!(self.slot_keys, index, slot_key.fingerprint);
+            }
+        }
     }
 }
 
 impl PageBuffer {
+    /// Read the page out of the buffer, failing if it has a checksum that doesn't match.
     pub fn deserialize(&self, page: &mut Page) -> Result<()> {
-        self.deserialize_header(&mut page.header)?;
+        page.body.keyed = self.deserialize_header(&mut page.header)?;
         let count = page.header.count;
         self.deserialize_body(&mut page.body, count as usize);
         Ok(())
     }
 
-    fn deserialize_header(&self, header: &mut PageHeader) -> Result<()> {
+    /// Returns whether the page records its slots' keys.
+    fn deserialize_header(&self, header: &mut PageHeader) -> Result<bool> {
         let mut index = 0;
 
         let mut u128_buf = [0u8; 16];
@@ -197,7 +377,13 @@ impl PageBuffer {
             *byte = self.buf[i + index];
         }
         index += 8;
-        assert_eq!(MAGIC, u64::from_le_bytes(u64_buf));
+        let (keyed, checked) = match u64::from_le_bytes(u64_buf) {
+            MAGIC => (false, false),
+            KEYED_MAGIC => (true, false),
+            CHECKED_MAGIC => (false, true),
+            CHECKED_KEYED_MAGIC => (true, true),
+            _ => return Err(Error::Message("Bad magic number in page header".to_owned())),
+        };
 
         // UUID
         for (i, byte) in u128_buf.iter_mut().enumerate() {
@@ -232,7 +418,18 @@ impl PageBuffer {
         u16_buf[1] = self.buf[index + 1];
         header.count = u16::from_le_bytes(u16_buf);
 
-        Ok(())
+        // Checksum
+        header.checksum = 0;
+        if checked {
+            let mut u32_buf = [0u8; 4];
+            u32_buf.copy_from_slice(&self.buf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4]);
+            header.checksum = u32::from_le_bytes(u32_buf);
+            if self.checksum() != header.checksum {
+                return Err(Error::Message("Page checksum does not match".to_owned()));
+            }
+        }
+
+        Ok(keyed)
     }
 
     fn deserialize_body(&self, body: &mut PageBody, count: usize) {
@@ -249,5 +446,15 @@ impl PageBuffer {
                 (&self.buf[offset + i * 2..] as &[u8]).as_ptr() as *const [u8; 2];
             body.value_index[i] = unsafe { i16::from_le_bytes(*value_index_bytes) };
         }
+
+        if body.keyed {
+            let u16_at = |offset: usize| {
+                u16::from_le_bytes([self.slot_keys[offset], self.slot_keys[offset + 1]])
+            };
+            for (i, slot_key) in body.slot_keys[..count].iter_mut().enumerate() {
+                slot_key.len = u16_at(i * 2);
+                slot_key.fingerprint = u16_at(COMMANDS_PER_PAGE * 2 + i * 2);
+            }
+        }
     }
 }
@@ -0,0 +1,483 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+/// The first byte of a data slot holding an encoded `Record` instead of a plain string. It can
+/// never start valid UTF-8, so slots written before there were records still read back as
+/// plain values.
+pub const RECORD_TAG: u8 = 0xff;
+
+/// The first byte of a data slot holding the key the value is stored under, followed by the
+/// value's usual encoding. Pages only hold key hashes, so this is the only place the key itself
+/// is kept. Like `RECORD_TAG`, it can never start valid UTF-8.
+pub const KEYED_TAG: u8 = 0xfe;
+
+/// A value stored under a key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Record {
+    Value(String),
+    Hash(BTreeMap<String, String>),
+    /// Changes to some of the fields of a hash, to be applied on top of the key's older
+    /// entries. A field set to `None` is deleted.
+    HashMerge(BTreeMap<String, Option<String>>),
+    SortedSet(SortedSet),
+    /// Members to add to a sorted set, or whose scores should be updated.
+    SortedSetMerge(BTreeMap<String, f64>),
+    Stream(BTreeMap<StreamId, String>),
+    /// Entries appended to a stream.
+    StreamMerge(BTreeMap<StreamId, String>),
+    /// A stream whose entries are handed out to consumers and removed once acknowledged.
+    Queue(BTreeMap<StreamId, QueueItem>),
+    /// Changes to a queue, in the order they were made.
+    QueueMerge(Vec<(StreamId, QueueOp)>),
+    Lock(Lease),
+    Bitmap(Bitmap),
+    /// Bits set or cleared in a bitmap, in the order they were changed.
+    BitmapMerge(Vec<(u64, bool)>),
+}
+
+/// The state of a lock. The fencing token is kept after the lock is released, so that every
+/// holder gets a larger token than the one before it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    pub fencing_token: u64,
+    /// When the lease runs out, in milliseconds since the Unix epoch. Zero once released.
+    pub expires_at_ms: u64,
+}
+
+/// An item in a queue that hasn't been acknowledged yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueItem {
+    pub payload: String,
+    /// The item can't be dequeued again until this time, in milliseconds since the Unix epoch.
+    pub invisible_until_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum QueueOp {
+    Enqueue(String),
+    /// The item was handed to a consumer, and is hidden from others until the time given.
+    Lease(u64),
+    Ack,
+}
+
+impl Record {
+    /// Whether this is a merge operand, which only makes sense together with older entries.
+    pub fn is_merge(&self) -> bool {
+        match self {
+            Record::HashMerge(_)
+            | Record::SortedSetMerge(_)
+            | Record::StreamMerge(_)
+            | Record::QueueMerge(_)
+            | Record::BitmapMerge(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The name of the kind of value, which is the same for merge operands and the full records
+    /// they apply to.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Record::Value(_) => "string",
+            Record::Hash(_) | Record::HashMerge(_) => "hash",
+            Record::SortedSet(_) | Record::SortedSetMerge(_) => "zset",
+            Record::Stream(_) | Record::StreamMerge(_) => "stream",
+            Record::Queue(_) | Record::QueueMerge(_) => "queue",
+            Record::Lock(_) => "lock",
+            Record::Bitmap(_) | Record::BitmapMerge(_) => "bitmap",
+        }
+    }
+
+    /// Apply `operand` on top of `base`, the key's older entries folded together (or `None` if
+    /// there aren't any).
+    pub fn merge(base: Option<Record>, operand: Record) -> Result<Record> {
+        if !operand.is_merge() {
+            return Ok(operand);
+        }
+        match (base, operand) {
+            (None, Record::HashMerge(changes)) => Ok(Record::Hash(
+                changes
+                    .into_iter()
+                    .filter_map(|(field, value)| value.map(|value| (field, value)))
+                    .collect(),
+            )),
+            (Some(Record::Hash(mut fields)), Record::HashMerge(changes)) => {
+                for (field, value) in changes {
+                    match value {
+                        Some(value) => fields.insert(field, value),
+                        None => fields.remove(&field),
+                    };
+                }
+                Ok(Record::Hash(fields))
+            }
+            (Some(Record::HashMerge(mut older)), Record::HashMerge(changes)) => {
+                older.extend(changes);
+                Ok(Record::HashMerge(older))
+            }
+            (None, Record::SortedSetMerge(changes)) => {
+                Ok(Record::SortedSet(changes.into_iter().collect()))
+            }
+            (Some(Record::SortedSet(mut set)), Record::SortedSetMerge(changes)) => {
+                for (member, score) in changes {
+                    set.insert(member, score);
+                }
+                Ok(Record::SortedSet(set))
+            }
+            (Some(Record::SortedSetMerge(mut older)), Record::SortedSetMerge(changes)) => {
+                older.extend(changes);
+                Ok(Record::SortedSetMerge(older))
+            }
+            (None, Record::StreamMerge(entries)) => Ok(Record::Stream(entries)),
+            (Some(Record::Stream(mut older)), Record::StreamMerge(entries)) => {
+                older.extend(entries);
+                Ok(Record::Stream(older))
+            }
+            (Some(Record::StreamMerge(mut older)), Record::StreamMerge(entries)) => {
+                older.extend(entries);
+                Ok(Record::StreamMerge(older))
+            }
+            (None, Record::QueueMerge(ops)) => {
+                Ok(Record::Queue(apply_queue_ops(BTreeMap::new(), ops)))
+            }
+            (Some(Record::Queue(items)), Record::QueueMerge(ops)) => {
+                Ok(Record::Queue(apply_queue_ops(items, ops)))
+            }
+            (Some(Record::QueueMerge(mut older)), Record::QueueMerge(ops)) => {
+                older.extend(ops);
+                Ok(Record::QueueMerge(older))
+            }
+            (None, Record::BitmapMerge(bits)) => {
+                Ok(Record::Bitmap(apply_bits(Bitmap::default(), bits)))
+            }
+            (Some(Record::Bitmap(bitmap)), Record::BitmapMerge(bits)) => {
+                Ok(Record::Bitmap(apply_bits(bitmap, bits)))
+            }
+            (Some(Record::BitmapMerge(mut older)), Record::BitmapMerge(bits)) => {
+                older.extend(bits);
+                Ok(Record::BitmapMerge(older))
+            }
+            (Some(base), operand) => Err(Error::Message(format!(
+                "Cannot apply a {} operand to a {}",
+                operand.type_name(),
+                base.type_name()
+            ))),
+            (None, operand) => Ok(operand),
+        }
+    }
+
+    /// The bytes to store in a data slot. Plain values are stored as-is.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        match self {
+            Record::Value(value) => Ok(value.as_bytes().to_owned()),
+            record => {
+                let mut bytes = vec![RECORD_TAG];
+                bincode::serialize_into(&mut bytes, record)
+                    .map_err(Error::encoding("Could not encode the record"))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Record> {
+        match bytes.first() {
+            Some(&RECORD_TAG) => {
+                bincode::deserialize(&bytes[1..]).map_err(Error::encoding("Bad record"))
+            }
+            Some(&KEYED_TAG) => decode_entry(bytes).map(|(_, record)| record),
+            _ => Ok(Record::Value(String::from_utf8_lossy(bytes).into_owned())),
+        }
+    }
+}
+
+/// The bytes to store in a data slot for a record along with its key.
+pub fn encode_entry(key: &str, record: &Record) -> Result<Vec<u8>> {
+    let mut bytes = vec![KEYED_TAG];
+    bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(key.as_bytes());
+    bytes.extend(record.encode()?);
+    Ok(bytes)
+}
+
+/// Read a data slot, along with its key if it was written with one.
+pub fn decode_entry(bytes: &[u8]) -> Result<(Option<String>, Record)> {
+    if bytes.first() != Some(&KEYED_TAG) {
+        return Ok((None, Record::decode(bytes)?));
+    }
+    let bad_entry = || Error::Message("Bad entry: truncated key".to_owned());
+    if bytes.len() < 5 {
+        return Err(bad_entry());
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&bytes[1..5]);
+    let key_end = 5 + u32::from_le_bytes(len) as usize;
+    if bytes.len() < key_end {
+        return Err(bad_entry());
+    }
+    let key =
+        String::from_utf8(bytes[5..key_end].to_owned()).map_err(Error::encoding("Bad entry"))?;
+    Ok((Some(key), Record::decode(&bytes[key_end..])?))
+}
+
+fn apply_queue_ops(
+    mut items: BTreeMap<StreamId, QueueItem>,
+    ops: Vec<(StreamId, QueueOp)>,
+) -> BTreeMap<StreamId, QueueItem> {
+    for (id, op) in ops {
+        match op {
+            QueueOp::Enqueue(payload) => {
+                items.insert(
+                    id,
+                    QueueItem {
+                        payload,
+                        invisible_until_ms: 0,
+                    },
+                );
+            }
+            QueueOp::Lease(until_ms) => {
+                if let Some(item) = items.get_mut(&id) {
+                    item.invisible_until_ms = until_ms;
+                }
+            }
+            QueueOp::Ack => {
+                items.remove(&id);
+            }
+        }
+    }
+    items
+}
+
+fn apply_bits(mut bitmap: Bitmap, bits: Vec<(u64, bool)>) -> Bitmap {
+    for (offset, value) in bits {
+        bitmap.set(offset, value);
+    }
+    bitmap
+}
+
+/// A set of members ordered by score, and then by member.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Vec<(String, f64)>", into = "Vec<(String, f64)>")]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    by_score: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    /// Add the member, or move it if it's already in the set.
+    pub fn insert(&mut self, member: String, score: f64) {
+        if let Some(old) = self.scores.insert(member.clone(), score) {
+            self.by_score.remove(&(Score(old), member.clone()));
+        }
+        self.by_score.insert((Score(score), member));
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).cloned()
+    }
+
+    /// The member's position in score order, starting at 0.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(
+            self.by_score
+                .range(..(Score(score), member.to_owned()))
+                .count(),
+        )
+    }
+
+    /// The members with scores between `min` and `max` inclusive, in score order.
+    pub fn range_by_score(&self, min: f64, max: f64) -> Vec<(String, f64)> {
+        self.by_score
+            .range((Score(min), String::new())..)
+            .take_while(|(score, _)| score.0 <= max)
+            .map(|(score, member)| (member.clone(), score.0))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+}
+
+impl std::iter::FromIterator<(String, f64)> for SortedSet {
+    fn from_iter<I: IntoIterator<Item = (String, f64)>>(iter: I) -> Self {
+        let mut set = SortedSet::default();
+        for (member, score) in iter {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+impl From<Vec<(String, f64)>> for SortedSet {
+    fn from(members: Vec<(String, f64)>) -> Self {
+        members.into_iter().collect()
+    }
+}
+
+impl From<SortedSet> for Vec<(String, f64)> {
+    fn from(set: SortedSet) -> Self {
+        set.by_score
+            .into_iter()
+            .map(|(score, member)| (member, score.0))
+            .collect()
+    }
+}
+
+/// The number of bits in each chunk of a bitmap.
+const CHUNK_BITS: u64 = 4096;
+
+/// A bitmap stored sparsely, so that setting a bit at a large offset doesn't take up space for
+/// all of the bits before it. Chunks with no bits set aren't stored at all.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bitmap {
+    chunks: BTreeMap<u64, Vec<u64>>,
+}
+
+impl Bitmap {
+    pub fn get(&self, offset: u64) -> bool {
+        let (chunk, word, bit) = Bitmap::position(offset);
+        match self.chunks.get(&chunk) {
+            Some(words) => words[word] & (1 << bit) != 0,
+            None => false,
+        }
+    }
+
+    pub fn set(&mut self, offset: u64, value: bool) {
+        let (chunk, word, bit) = Bitmap::position(offset);
+        if value {
+            let words = self
+                .chunks
+                .entry(chunk)
+                .or_insert_with(|| vec![0; (CHUNK_BITS / 64) as usize]);
+            words[word] |= 1 << bit;
+        } else if let Some(words) = self.chunks.get_mut(&chunk) {
+            words[word] &= !(1 << bit);
+            if words.iter().all(|word| *word == 0) {
+                self.chunks.remove(&chunk);
+            }
+        }
+    }
+
+    /// The number of bits that are set.
+    pub fn count(&self) -> u64 {
+        self.chunks
+            .values()
+            .flat_map(|words| words.iter())
+            .map(|word| u64::from(word.count_ones()))
+            .sum()
+    }
+
+    /// The offsets of the bits that are set, in increasing order.
+    pub fn offsets(&self) -> Vec<u64> {
+        let mut offsets = Vec::new();
+        for (chunk, words) in self.chunks.iter() {
+            for (i, word) in words.iter().enumerate() {
+                for bit in 0..64 {
+                    if word & (1 << bit) != 0 {
+                        offsets.push(chunk * CHUNK_BITS + i as u64 * 64 + bit);
+                    }
+                }
+            }
+        }
+        offsets
+    }
+
+    fn position(offset: u64) -> (u64, usize, u64) {
+        let within = offset % CHUNK_BITS;
+        (offset / CHUNK_BITS, (within / 64) as usize, within % 64)
+    }
+}
+
+/// A score that can be used as a key. Scores are never NaN, so they have a total order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The id of a stream entry: the time it was added in milliseconds since the Unix epoch, and a
+/// sequence number for entries added in the same millisecond.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: std::u64::MAX,
+        seq: std::u64::MAX,
+    };
+
+    /// The id to give an entry added at `now_ms`, which is always greater than `self` even if
+    /// the clock went backwards.
+    pub fn next(self, now_ms: u64) -> StreamId {
+        if now_ms > self.ms {
+            StreamId { ms: now_ms, seq: 0 }
+        } else {
+            StreamId {
+                ms: self.ms,
+                seq: self.seq + 1,
+            }
+        }
+    }
+
+    /// Parse one end of a range: `-` and `+` are the lowest and highest possible ids, and an id
+    /// without a sequence number covers the whole millisecond.
+    pub fn parse_bound(s: &str, upper: bool) -> Result<StreamId> {
+        match s {
+            "-" => Ok(StreamId::MIN),
+            "+" => Ok(StreamId::MAX),
+            _ if !s.contains('-') => {
+                let ms = s
+                    .parse()
+                    .map_err(|_| Error::Message(format!("Invalid stream id: {}", s)))?;
+                let seq = if upper { std::u64::MAX } else { 0 };
+                Ok(StreamId { ms, seq })
+            }
+            _ => s.parse(),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StreamId> {
+        let invalid = || Error::Message(format!("Invalid stream id: {}", s));
+        let mut parts = s.splitn(2, '-');
+        let ms = parts.next().unwrap().parse().map_err(|_| invalid())?;
+        let seq = match parts.next() {
+            Some(seq) => seq.parse().map_err(|_| invalid())?,
+            None => 0,
+        };
+        Ok(StreamId { ms, seq })
+    }
+}
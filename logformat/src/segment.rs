@@ -0,0 +1,40 @@
+use crate::page::BUF_SIZE;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Where a page packed into a segment file is. A segment holds many pages one after another,
+/// each as its page block followed by its data block, so that a large store isn't tens of
+/// thousands of small files. Segments are named by the UUID of their first page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentLocation {
+    pub segment: Uuid,
+    /// Where the page block starts.
+    pub offset: u64,
+    /// The length of the page block, which depends on whether the page records its slots'
+    /// keys. Segments written before there were such pages only hold the shorter kind.
+    #[serde(default = "page_block_len")]
+    pub page_len: u64,
+    /// The length of the data block, which follows the page block.
+    pub data_len: u64,
+}
+
+impl SegmentLocation {
+    /// Where the data block starts.
+    pub fn data_offset(&self) -> u64 {
+        self.offset + self.page_len
+    }
+
+    /// Where the page's blocks end.
+    pub fn end(&self) -> u64 {
+        self.data_offset() + self.data_len
+    }
+
+    pub fn path(&self) -> PathBuf {
+        Path::new(format!("{}.segment", self.segment.to_hyphenated_ref()).as_str()).to_owned()
+    }
+}
+
+fn page_block_len() -> u64 {
+    BUF_SIZE as u64
+}
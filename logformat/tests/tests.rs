@@ -1,13 +1,17 @@
-use logformat::page::{Page, PageBuffer, PageHeader, BUF_SIZE};
+use logformat::index::Index;
+use logformat::page::{
+    ClockContext, Page, PageBuffer, PageHeader, SlotKey, BUF_SIZE, SLOT_KEYS_SIZE,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::v1::Context;
 
 #[test]
 fn can_read_write_page() {
-    let mut buffer = PageBuffer { buf: [0; BUF_SIZE] };
+    let mut buffer = PageBuffer::default();
 
     let node_id = &[0, 1, 2, 3, 4, 5];
     let context = Context::new(0);
-    let header = PageHeader::new(node_id, &context, 0, 5000, 2).unwrap();
+    let mut header = PageHeader::new(node_id, &context, SystemTime::now(), 0, 5000, 2).unwrap();
 
     {
         let mut page = Page::default();
@@ -16,12 +20,13 @@ fn can_read_write_page() {
         page.body.key_hash[1] = 0xCD;
         page.body.value_index[0] = 100;
         page.body.value_index[1] = 200;
-        buffer.serialize(&page);
+        buffer.serialize(&mut page);
+        assert_ne!(0, page.header.checksum);
+        header.checksum = page.header.checksum;
     }
 
     {
         let mut page = Page::default();
-        page.header = header.clone();
         buffer.deserialize(&mut page).unwrap();
         assert_eq!(0xAB, page.body.key_hash[0]);
         assert_eq!(0xCD, page.body.key_hash[1]);
@@ -30,3 +35,138 @@ fn can_read_write_page() {
         assert_eq!(header, page.header);
     }
 }
+
+#[test]
+fn slot_keys_round_trip() {
+    let header =
+        PageHeader::new(&[0; 6], &ClockContext::new(0), SystemTime::now(), 0, 9, 2).unwrap();
+    let mut page = Page {
+        header,
+        ..Page::default()
+    };
+    page.body.key_hash[0] = 1;
+    page.body.key_hash[1] = 9;
+    page.body.slot_keys[0] = SlotKey::new("first");
+    page.body.keyed = true;
+
+    let mut buffer = PageBuffer::default();
+    buffer.serialize(&mut page);
+    let mut bytes = Vec::new();
+    buffer.write_to(&mut bytes).unwrap();
+    assert_eq!(BUF_SIZE + SLOT_KEYS_SIZE, bytes.len());
+    assert_eq!(bytes.len(), page.block_len());
+
+    let mut buffer = PageBuffer::default();
+    buffer.read_from(&mut &bytes[..]).unwrap();
+    let mut read = Page::default();
+    buffer.deserialize(&mut read).unwrap();
+    assert_eq!(Some(SlotKey::new("first")), read.body.slot_key(0));
+    assert_ne!(SlotKey::new("first"), SlotKey::new("second"));
+    assert_eq!(5, SlotKey::new("first").len);
+    // A slot without a key length is one whose key wasn't known
+    assert_eq!(None, read.body.slot_key(1));
+
+    // Pages without slot keys are the size they always were
+    page.body.keyed = false;
+    let mut buffer = PageBuffer::default();
+    buffer.serialize(&mut page);
+    let mut bytes = Vec::new();
+    buffer.write_to(&mut bytes).unwrap();
+    assert_eq!(BUF_SIZE, bytes.len());
+    let mut buffer = PageBuffer::default();
+    buffer.read_from(&mut &bytes[..]).unwrap();
+    let mut read = Page::default();
+    buffer.deserialize(&mut read).unwrap();
+    assert_eq!(None, read.body.slot_key(0));
+}
+
+#[test]
+fn checksum_detects_damage() {
+    let header =
+        PageHeader::new(&[0; 6], &ClockContext::new(0), SystemTime::now(), 0, 9, 2).unwrap();
+    let mut page = Page {
+        header,
+        ..Page::default()
+    };
+    page.body.key_hash[0] = 1;
+    page.body.value_index[0] = 7;
+    let mut buffer = PageBuffer::default();
+    buffer.serialize(&mut page);
+    let mut read = Page::default();
+    buffer.deserialize(&mut read).unwrap();
+    assert_eq!(page.header, read.header);
+
+    // Flip a bit in the body, then in the header
+    let body_byte = BUF_SIZE - 1;
+    buffer.buf[body_byte] ^= 1;
+    assert!(buffer.deserialize(&mut read).is_err());
+    buffer.buf[body_byte] ^= 1;
+    buffer.buf[8] ^= 1;
+    assert!(buffer.deserialize(&mut read).is_err());
+    buffer.buf[8] ^= 1;
+    buffer.deserialize(&mut read).unwrap();
+}
+
+#[test]
+fn clock_context_advances() {
+    let context = ClockContext::new(u16::max_value());
+    let now = SystemTime::now();
+    let first = PageHeader::new(&[0; 6], &context, now, 0, 0, 0).unwrap();
+    let second = PageHeader::new(&[0; 6], &context, now, 0, 0, 0).unwrap();
+    assert_ne!(first.uuid, second.uuid);
+    // The sequence wraps around instead of overflowing
+    assert_eq!(1, context.current());
+}
+
+#[test]
+fn header_records_creation_time() {
+    let now = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    let header = PageHeader::new(&[0; 6], &ClockContext::new(0), now, 0, 0, 0).unwrap();
+    assert_eq!(now, header.created_at());
+    let later = PageHeader::new(
+        &[0; 6],
+        &ClockContext::new(0),
+        now + Duration::from_secs(1),
+        0,
+        0,
+        0,
+    )
+    .unwrap();
+    assert_eq!(now + Duration::from_secs(1), later.created_at());
+}
+
+#[test]
+fn keyed_entries() {
+    use logformat::record::{decode_entry, encode_entry, Record};
+
+    let record = Record::Value("value".to_owned());
+    let bytes = encode_entry("key", &record).unwrap();
+    assert_eq!(
+        decode_entry(&bytes).unwrap(),
+        (Some("key".to_owned()), record.clone())
+    );
+    // Readers that don't care about the key see just the record
+    assert_eq!(Record::decode(&bytes).unwrap(), record);
+    // Slots written without a key still read back
+    assert_eq!(decode_entry(b"value").unwrap(), (None, record));
+    assert!(decode_entry(&bytes[..6]).is_err());
+}
+
+#[test]
+fn index_detects_damage() {
+    let mut index = Index::default();
+    index.generation = 7;
+    index
+        .push(PageHeader::new(&[0; 6], &ClockContext::new(0), SystemTime::now(), 0, 5, 2).unwrap());
+    let bytes = index.encode().unwrap();
+
+    let decoded = Index::decode(&bytes).unwrap();
+    assert_eq!(7, decoded.generation);
+    assert_eq!(index.get(0), decoded.get(0));
+
+    let mut damaged = bytes.clone();
+    damaged[3] ^= 1;
+    assert!(Index::decode(&damaged).is_err());
+    assert!(Index::decode(&bytes[..bytes.len() - 1]).is_err());
+    assert!(Index::decode(&[]).is_err());
+}
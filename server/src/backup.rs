@@ -0,0 +1,183 @@
+//! Destinations for snapshots and archived pages, and the checksums written alongside every
+//! snapshot so that it can be checked before it's restored.
+use crate::kv::sync_dir;
+use kvs::{Error, Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The file listing every other file in a snapshot with its length and checksum. It's written
+/// last, so a snapshot without one is incomplete.
+pub const CHECKSUMS_FILE: &str = "CHECKSUMS";
+
+/// Somewhere the files of a snapshot or archived pages can be written.
+pub trait BackupSink: Send {
+    /// Write a whole file into the snapshot.
+    fn put(&mut self, name: &str, contents: &[u8]) -> Result<()>;
+}
+
+/// Writes files into a local directory.
+pub struct DirSink {
+    path: PathBuf,
+    /// Whether each file and the directory are fsynced once the file is written.
+    sync: bool,
+}
+
+impl DirSink {
+    /// Create the directory, which must not exist yet.
+    pub fn create(path: &Path) -> Result<DirSink> {
+        fs::create_dir_all(path.parent().unwrap_or(path))?;
+        fs::create_dir(path)?;
+        Ok(DirSink {
+            path: path.to_owned(),
+            sync: false,
+        })
+    }
+
+    /// Open the directory, creating it if it doesn't exist.
+    pub fn open(path: &Path, sync: bool) -> Result<DirSink> {
+        fs::create_dir_all(path)?;
+        Ok(DirSink {
+            path: path.to_owned(),
+            sync,
+        })
+    }
+}
+
+impl BackupSink for DirSink {
+    /// Write the file under a temporary name and rename it into place, so that a file with the
+    /// final name is always complete.
+    fn put(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        let tmp_path = self.path.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        if self.sync {
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, self.path.join(name))?;
+        if self.sync {
+            sync_dir(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Open the sink for a snapshot destination, which is either a local directory or, with the
+/// `object-store` feature, an `s3://bucket/prefix` URL.
+pub fn open_sink(destination: &str, slog: &Logger) -> Result<Box<dyn BackupSink>> {
+    if destination.starts_with("s3://") {
+        open_object_store(destination, slog)
+    } else {
+        Ok(Box::new(DirSink::create(Path::new(destination))?))
+    }
+}
+
+/// Open the sink for a page archive, which is either a local directory (created if it doesn't
+/// exist) or, with the `object-store` feature, an `s3://bucket/prefix` URL.
+pub fn open_archive(destination: &str, sync: bool, slog: &Logger) -> Result<Box<dyn BackupSink>> {
+    if destination.starts_with("s3://") {
+        open_object_store(destination, slog)
+    } else {
+        Ok(Box::new(DirSink::open(Path::new(destination), sync)?))
+    }
+}
+
+#[cfg(feature = "object-store")]
+fn open_object_store(url: &str, slog: &Logger) -> Result<Box<dyn BackupSink>> {
+    Ok(Box::new(crate::s3::S3Sink::from_env(url, slog.clone())?))
+}
+
+#[cfg(not(feature = "object-store"))]
+fn open_object_store(_url: &str, _slog: &Logger) -> Result<Box<dyn BackupSink>> {
+    Err(Error::Unsupported(
+        "snapshots to object storage (built without the object-store feature)",
+    ))
+}
+
+/// The length and SHA-256 of every file in a snapshot.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checksums {
+    pub files: BTreeMap<String, FileChecksum>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileChecksum {
+    pub len: u64,
+    pub sha256: String,
+}
+
+impl FileChecksum {
+    pub fn new(contents: &[u8]) -> FileChecksum {
+        FileChecksum {
+            len: contents.len() as u64,
+            sha256: sha256_hex(contents),
+        }
+    }
+}
+
+impl Checksums {
+    pub fn add(&mut self, name: &str, contents: &[u8]) {
+        self.files
+            .insert(name.to_owned(), FileChecksum::new(contents));
+    }
+
+    pub fn to_ron(&self) -> Result<String> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(Error::serialization)
+    }
+
+    pub fn from_ron(contents: &str) -> Result<Checksums> {
+        ron::de::from_str(contents)
+            .map_err(Error::serialization)
+            .context("Could not read the checksums")
+    }
+
+    /// Compare the files in `dir` against the checksums, returning a description of every file
+    /// that's missing, different, or not listed. Files named in `ignore` aren't expected to be
+    /// listed.
+    pub fn check(&self, dir: &Path, ignore: &[&str]) -> Result<Vec<String>> {
+        let mut errors = Vec::new();
+        for (name, expected) in &self.files {
+            let contents = match fs::read(dir.join(name)) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    errors.push(format!("{}: {}", name, e));
+                    continue;
+                }
+            };
+            if contents.len() as u64 != expected.len {
+                errors.push(format!(
+                    "{}: {} bytes, expected {}",
+                    name,
+                    contents.len(),
+                    expected.len
+                ));
+            } else if sha256_hex(&contents) != expected.sha256 {
+                errors.push(format!("{}: checksum does not match", name));
+            }
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file()
+                && name != CHECKSUMS_FILE
+                && !ignore.contains(&name.as_str())
+                && !self.files.contains_key(&name)
+            {
+                errors.push(format!("{}: not listed in the checksums", name));
+            }
+        }
+        Ok(errors)
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
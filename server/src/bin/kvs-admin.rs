@@ -0,0 +1,377 @@
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
+use kvs::{CommandRequest, CommandResponse, Engine, Error, Progress, Result};
+use server::{KvStore, RecoveryTarget};
+use std::env::current_dir;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn main() -> Result<()> {
+    let dir_arg = Arg::with_name("dir")
+        .long("dir")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Operate on the data directory at PATH (defaults to the current directory)");
+    let addr_arg = Arg::with_name("addr")
+        .long("addr")
+        .takes_value(true)
+        .value_name("IP-ADDR")
+        .conflicts_with("dir")
+        .help("Operate on the running server at IP-ADDR");
+    let progress_arg = Arg::with_name("progress")
+        .long("progress")
+        .help("Draw a progress bar on stderr while it runs");
+    let mut app = App::new("kvs-admin")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Maintenance tasks for a kvs data directory or server")
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("Merge all pages, dropping overwritten and removed values")
+                .arg(&dir_arg)
+                .arg(&addr_arg)
+                .arg(&progress_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check every page and data file for corruption")
+                .arg(&dir_arg)
+                .arg(&addr_arg)
+                .arg(&progress_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print page and disk usage statistics")
+                .arg(&dir_arg)
+                .arg(&addr_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about(
+                    "Copy the live files into a new directory or object store prefix (on the server with --addr)",
+                )
+                .arg(Arg::with_name("dest").required_unless("to"))
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .value_name("DEST")
+                        .conflicts_with("dest")
+                        .help("The directory or s3://bucket/prefix URL to write the snapshot to"),
+                )
+                .arg(&dir_arg)
+                .arg(&addr_arg)
+                .arg(&progress_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Print every live key and its value, one per line in key order")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(&["json", "ron"])
+                        .default_value("json"),
+                )
+                .arg(&dir_arg)
+                .arg(&progress_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("load")
+                .about("Write every key in a dump into the store (- reads from stdin)")
+                .arg(Arg::with_name("file").required(true))
+                .arg(
+                    Arg::with_name("on-conflict")
+                        .long("on-conflict")
+                        .takes_value(true)
+                        .value_name("POLICY")
+                        .possible_values(&["overwrite", "skip", "fail"])
+                        .default_value("fail")
+                        .help("What to do with keys that already exist"),
+                )
+                .arg(&dir_arg)
+                .arg(&progress_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("export-sst")
+                .about("Write every live key to a new RocksDB-compatible SST file")
+                .arg(Arg::with_name("file").required(true))
+                .arg(&dir_arg)
+                .arg(&progress_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("import-sst")
+                .about("Write every key in an SST file (e.g. from RocksDB's SstFileWriter) into the store")
+                .arg(Arg::with_name("file").required(true))
+                .arg(&dir_arg)
+                .arg(&progress_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-backup")
+                .about("Check a snapshot's checksums, manifest, index, and pages without changing it")
+                .arg(Arg::with_name("snapshot").required(true))
+                .arg(
+                    Arg::with_name("sample")
+                        .long("sample")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Also read back N keys spread across the snapshot"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore a snapshot into an empty data directory")
+                .arg(Arg::with_name("snapshot").required(true))
+                .arg(&dir_arg)
+                .arg(
+                    Arg::with_name("archive")
+                        .long("archive")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Replay the pages archived here after the snapshot was taken"),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .requires("archive")
+                        .conflicts_with("until-sequence")
+                        .allow_hyphen_values(true)
+                        .help(
+                            "Stop replaying at TIME, in seconds since the Unix epoch or \
+                             relative to now like -5m",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("until-sequence")
+                        .long("until-sequence")
+                        .takes_value(true)
+                        .value_name("N")
+                        .requires("archive")
+                        .help("Stop replaying after the page with sequence number N"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .setting(AppSettings::Hidden)
+                .about("Print a shell completion script")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&["bash", "zsh", "fish"]),
+                ),
+        );
+    let matches = app.clone().get_matches();
+
+    let (command, maybe_args) = matches.subcommand();
+    let args = maybe_args.unwrap();
+    if command == "completions" {
+        let shell: Shell = args.value_of("shell").unwrap().parse().unwrap();
+        app.gen_completions_to("kvs-admin", shell, &mut io::stdout());
+        return Ok(());
+    }
+    let dir = match args.value_of("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => current_dir()?,
+    };
+    let progress = args.is_present("progress");
+    // Opens the store, drawing a progress bar for its long operations if it was asked for
+    let open = |read_only: bool| -> Result<KvStore> {
+        let store = if read_only {
+            KvStore::open_read_only(&dir)?
+        } else {
+            KvStore::open(&dir)?
+        };
+        if progress {
+            store.set_progress(Some(Arc::new(draw_progress)));
+        }
+        Ok(store)
+    };
+
+    let request = match command {
+        "compact" => CommandRequest::Compact,
+        "verify" => CommandRequest::Verify,
+        "stats" => CommandRequest::Stats,
+        "snapshot" => CommandRequest::Snapshot {
+            path: args
+                .value_of("dest")
+                .or_else(|| args.value_of("to"))
+                .unwrap()
+                .to_owned(),
+        },
+        "dump" => {
+            let format = args.value_of("format").unwrap().parse()?;
+            let stdout = io::stdout();
+            open(true)?.dump(BufWriter::new(stdout.lock()), format)?;
+            end_progress(progress);
+            return Ok(());
+        }
+        "load" => {
+            let policy = args.value_of("on-conflict").unwrap().parse()?;
+            let store = open(false)?;
+            let report = match args.value_of("file").unwrap() {
+                "-" => store.load_dump(io::stdin().lock(), policy)?,
+                file => store.load_dump(BufReader::new(File::open(file)?), policy)?,
+            };
+            end_progress(progress);
+            println!("{}", report);
+            if !report.is_ok() {
+                process::exit(1)
+            }
+            return Ok(());
+        }
+        "export-sst" => {
+            let file = Path::new(args.value_of("file").unwrap());
+            let count = open(false)?.export_sst(file)?;
+            end_progress(progress);
+            println!("Exported {} keys", count);
+            return Ok(());
+        }
+        "import-sst" => {
+            let file = Path::new(args.value_of("file").unwrap());
+            let count = open(false)?.import_sst(file)?;
+            end_progress(progress);
+            println!("Imported {} keys", count);
+            return Ok(());
+        }
+        "verify-backup" => {
+            let snapshot = Path::new(args.value_of("snapshot").unwrap());
+            let samples = match args.value_of("sample") {
+                Some(n) => n
+                    .parse()
+                    .map_err(|_| Error::Message(format!("Invalid sample size: {}", n)))?,
+                None => 0,
+            };
+            let report = KvStore::verify_backup(snapshot, samples)?;
+            println!("{}", report);
+            if !report.is_ok() {
+                process::exit(1)
+            }
+            return Ok(());
+        }
+        "restore" => {
+            let snapshot = Path::new(args.value_of("snapshot").unwrap());
+            let archive = match args.value_of("archive") {
+                Some(archive) => Path::new(archive),
+                None => return KvStore::restore(snapshot, &dir),
+            };
+            let target = if let Some(time) = args.value_of("until") {
+                RecoveryTarget::Time(parse_time(time)?)
+            } else if let Some(sequence) = args.value_of("until-sequence") {
+                RecoveryTarget::Sequence(sequence.parse().map_err(|_| {
+                    Error::Message(format!("Invalid sequence number: {}", sequence))
+                })?)
+            } else {
+                RecoveryTarget::Sequence(std::u64::MAX)
+            };
+            let replayed = KvStore::restore_to(snapshot, archive, &dir, target)?;
+            println!("Replayed {} archived pages", replayed);
+            return Ok(());
+        }
+        _ => unreachable!(),
+    };
+
+    let response = match args.value_of("addr") {
+        Some(addr) => {
+            let request = if progress {
+                CommandRequest::Progress {
+                    request: Box::new(request),
+                }
+            } else {
+                request
+            };
+            let mut stream = TcpStream::connect(addr)?;
+            bincode::serialize_into(&mut stream, &request)?;
+            loop {
+                match bincode::deserialize_from::<&TcpStream, CommandResponse>(&stream)? {
+                    CommandResponse::Progress(report) => draw_progress(&report),
+                    response => break response,
+                }
+            }
+        }
+        None => execute(&mut open(false)?, request)?,
+    };
+    end_progress(progress);
+
+    match response {
+        CommandResponse::Message(message) => {
+            if message != "" {
+                println!("{}", message)
+            }
+        }
+        CommandResponse::Error { .. } => {
+            eprintln!("{}", response);
+            process::exit(1)
+        }
+        CommandResponse::Verify(report) => {
+            println!("{}", report);
+            if !report.is_ok() {
+                process::exit(1)
+            }
+        }
+        response => println!("{}", response),
+    }
+
+    Ok(())
+}
+
+/// Redraw the progress bar on stderr, over the last one.
+fn draw_progress(progress: &Progress) {
+    eprint!("\r{:<80}", progress.bar(80));
+}
+
+/// Move past the progress bar, if one was drawn, so that it's left showing where things ended.
+fn end_progress(drawn: bool) {
+    if drawn {
+        eprintln!();
+    }
+}
+
+/// Run an admin request against a store opened in this process.
+fn execute(store: &mut KvStore, request: CommandRequest) -> Result<CommandResponse> {
+    match request {
+        CommandRequest::Compact => store
+            .compact()
+            .map(|_| CommandResponse::Message("".to_owned())),
+        CommandRequest::Verify => store.verify().map(CommandResponse::Verify),
+        CommandRequest::Stats => store.stats().map(CommandResponse::Stats),
+        CommandRequest::Snapshot { path } => store
+            .snapshot(Path::new(&path))
+            .map(|_| CommandResponse::Message("".to_owned())),
+        _ => Err(Error::Message(format!(
+            "Not an admin request: {:?}",
+            request
+        ))),
+    }
+}
+
+/// Parse a time given in seconds since the Unix epoch, or as a negative offset from now with a
+/// unit, like `-90s`, `-5m`, `-2h`, or `-1d`.
+fn parse_time(s: &str) -> Result<SystemTime> {
+    let invalid = || Error::Message(format!("Invalid time: {}", s));
+    if s.starts_with('-') {
+        let rest = &s[1..];
+        let unit = rest.chars().last().ok_or_else(invalid)?;
+        let number = &rest[..rest.len() - unit.len_utf8()];
+        let multiplier = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = number.parse().map_err(|_| invalid())?;
+        Ok(SystemTime::now() - Duration::from_secs(amount * multiplier))
+    } else {
+        let seconds: u64 = s.parse().map_err(|_| invalid())?;
+        Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+}
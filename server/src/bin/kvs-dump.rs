@@ -0,0 +1,401 @@
+//! Prints the on-disk structures of a data directory as they are, without opening the store:
+//! the manifest, the index's page headers, and optionally every slot of every page. For
+//! debugging corruption and compaction, so a file that can't be read is reported and skipped
+//! rather than ending the dump.
+use clap::{App, Arg};
+use kvs::{Error, Result};
+use logformat::index::Index;
+use logformat::manifest::{Manifest, PageLevel, PAGE_CHECKSUMS_VERSION};
+use logformat::page::{Page, PageBuffer, PageHeader, SlotKey};
+use logformat::record::{decode_entry, Record};
+use logformat::segment::SegmentLocation;
+use logformat::slotted::Slotted;
+use std::collections::BTreeMap;
+use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+fn main() -> Result<()> {
+    let matches = App::new("kvs-dump")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Print the manifest, index, and pages of a kvs data directory")
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("The data directory to inspect (defaults to the current directory)"),
+        )
+        .arg(
+            Arg::with_name("page")
+                .long("page")
+                .takes_value(true)
+                .value_name("UUID")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only print the page with this UUID"),
+        )
+        .arg(
+            Arg::with_name("slots")
+                .long("slots")
+                .help("Print the key hash and value index of every slot in each page"),
+        )
+        .arg(
+            Arg::with_name("values")
+                .long("values")
+                .help("Print each slot's key and the start of its value (implies --slots)"),
+        )
+        .arg(
+            Arg::with_name("preview")
+                .long("preview")
+                .takes_value(true)
+                .value_name("CHARS")
+                .default_value("32")
+                .help("How much of each value to print with --values"),
+        )
+        .get_matches();
+
+    let dir = match matches.value_of("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => current_dir()?,
+    };
+    let only_pages = match matches.values_of("page") {
+        Some(uuids) => Some(
+            uuids
+                .map(|uuid| {
+                    Uuid::parse_str(uuid)
+                        .map_err(|_| Error::Message(format!("Invalid page UUID: {}", uuid)))
+                })
+                .collect::<Result<Vec<Uuid>>>()?,
+        ),
+        None => None,
+    };
+    let preview = matches.value_of("preview").unwrap();
+    let preview = preview
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid preview length: {}", preview)))?;
+    let mut dumper = Dumper {
+        dir,
+        cold_dir: None,
+        page_locations: BTreeMap::new(),
+        page_levels: BTreeMap::new(),
+        stale_entries: BTreeMap::new(),
+        slots: matches.is_present("slots") || matches.is_present("values"),
+        values: matches.is_present("values"),
+        preview,
+        problems: 0,
+    };
+
+    let manifest = dumper.dump_manifest();
+    let index = dumper.dump_index(manifest.as_ref());
+    for (i, header) in index.iter().enumerate() {
+        let wanted = match &only_pages {
+            Some(uuids) => uuids.contains(&header.uuid),
+            None => true,
+        };
+        if wanted {
+            dumper.dump_page(i, header);
+        }
+    }
+
+    if dumper.problems > 0 {
+        eprintln!("Found {} problems", dumper.problems);
+        process::exit(1)
+    }
+    Ok(())
+}
+
+struct Dumper {
+    dir: PathBuf,
+    /// Where compaction moved older pages, as recorded in the manifest.
+    cold_dir: Option<PathBuf>,
+    /// The pages that compaction packed into segments, as recorded in the manifest.
+    page_locations: BTreeMap<Uuid, SegmentLocation>,
+    /// The level and run of each page that automatic compaction wrote, from the manifest.
+    page_levels: BTreeMap<Uuid, PageLevel>,
+    /// The estimated number of replaced entries in each page, from the manifest.
+    stale_entries: BTreeMap<Uuid, u16>,
+    slots: bool,
+    values: bool,
+    preview: usize,
+    /// How many files couldn't be read or didn't agree with each other.
+    problems: usize,
+}
+
+impl Dumper {
+    fn problem(&mut self, message: String) {
+        println!("  PROBLEM: {}", message);
+        self.problems += 1;
+    }
+
+    fn dump_manifest(&mut self) -> Option<Manifest> {
+        let path = self.dir.join(Manifest::path());
+        let manifest: Manifest = match fs::read_to_string(&path) {
+            Ok(contents) => match ron::de::from_str(&contents) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    println!("manifest:");
+                    self.problem(format!("Could not read the manifest: {}", e));
+                    return None;
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                println!("manifest: none");
+                return None;
+            }
+            Err(e) => {
+                println!("manifest:");
+                self.problem(format!("Could not read the manifest: {}", e));
+                return None;
+            }
+        };
+        println!(
+            "manifest: format version {}, index generation {}, {} live pages",
+            manifest.format_version,
+            manifest.index_generation,
+            manifest.live_pages.len()
+        );
+        println!(
+            "  last page sequence {}, last write sequence {}, {} unarchived pages",
+            manifest.last_page_sequence,
+            manifest.last_write_sequence,
+            manifest.unarchived_pages.len()
+        );
+        println!("  keys hashed with {}", manifest.key_hash);
+        for (name, value) in manifest.options.iter() {
+            println!("  option {} = {}", name, value);
+        }
+        self.cold_dir = manifest.options.get("cold_dir").map(PathBuf::from);
+        self.page_locations = manifest.page_locations.clone();
+        self.page_levels = manifest.page_levels.clone();
+        self.stale_entries = manifest.stale_entries.clone();
+        Some(manifest)
+    }
+
+    fn dump_index(&mut self, manifest: Option<&Manifest>) -> Vec<PageHeader> {
+        if let Some(manifest) = manifest {
+            if manifest.format_version < PAGE_CHECKSUMS_VERSION {
+                println!(
+                    "index: format version {} can't be read, and is rebuilt when the store is \
+                     next opened",
+                    manifest.format_version
+                );
+                return Vec::new();
+            }
+        }
+        let path = self.dir.join(Index::path());
+        let index = match fs::read(&path) {
+            Ok(bytes) => match Index::decode(&bytes) {
+                Ok(index) => index,
+                Err(e) => {
+                    println!("index:");
+                    self.problem(format!("Could not read the index: {}", e));
+                    return Vec::new();
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                println!("index: none");
+                return Vec::new();
+            }
+            Err(e) => {
+                println!("index:");
+                self.problem(format!("Could not read the index: {}", e));
+                return Vec::new();
+            }
+        };
+        println!(
+            "index: generation {}, {} pages",
+            index.generation,
+            index.len()
+        );
+        if let Some(manifest) = manifest {
+            if index.generation != manifest.index_generation {
+                self.problem(format!(
+                    "The index is from generation {}, but the manifest is at {}",
+                    index.generation, manifest.index_generation
+                ));
+            }
+            let indexed: Vec<Uuid> = index.iter().map(|header| header.uuid).collect();
+            if indexed != manifest.live_pages {
+                self.problem("The index doesn't list the manifest's live pages".to_owned());
+                for uuid in manifest.live_pages.iter() {
+                    if !indexed.contains(uuid) {
+                        println!("    live page {} is not in the index", uuid);
+                    }
+                }
+                for uuid in indexed.iter() {
+                    if !manifest.live_pages.contains(uuid) {
+                        println!("    indexed page {} is not live", uuid);
+                    }
+                }
+            }
+        }
+        index.iter().cloned().collect()
+    }
+
+    fn dump_page(&mut self, i: usize, header: &PageHeader) {
+        let created = header
+            .created_at()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        println!(
+            "page {}: {} ticks {} created {}.{:03} hashes {:016x}..{:016x} count {}{} crc {:08x}",
+            i,
+            header.uuid,
+            header.ticks,
+            created.as_secs(),
+            created.subsec_millis(),
+            header.min_key_hash,
+            header.max_key_hash,
+            header.count,
+            if header.is_partial() { " partial" } else { "" },
+            header.checksum
+        );
+
+        let location = self.page_locations.get(&header.uuid).cloned();
+        let page_path = match &location {
+            Some(location) => self.page_path(&location.path()),
+            None => self.page_path(&Page::path(&header.uuid)),
+        };
+        if page_path.parent() != Some(self.dir.as_path()) {
+            println!("  in {}", page_path.parent().unwrap().display());
+        }
+        if let Some(location) = &location {
+            println!(
+                "  segment {} offset {} data {} bytes",
+                location.segment, location.offset, location.data_len
+            );
+        }
+        if let Some(page_level) = self.page_levels.get(&header.uuid) {
+            println!("  level {} run {}", page_level.level, page_level.run);
+        }
+        if let Some(stale) = self.stale_entries.get(&header.uuid) {
+            println!("  about {} entries replaced or removed", stale);
+        }
+        let page = match read_page(&page_path, location.as_ref()) {
+            Ok(page) => page,
+            Err(e) => {
+                self.problem(format!("Could not read {}: {}", page_path.display(), e));
+                return;
+            }
+        };
+        if page.header != *header {
+            self.problem(format!(
+                "The page file's header doesn't match the index: {:?}",
+                page.header
+            ));
+        }
+        if !self.slots {
+            return;
+        }
+
+        let mut data = None;
+        if self.values {
+            let data_path = match &location {
+                Some(_) => page_path.clone(),
+                None => self.page_path(&Slotted::path(&header.uuid)),
+            };
+            match read_data(&data_path, location.as_ref()) {
+                Ok(slotted) => data = Some(slotted),
+                Err(e) => self.problem(format!("Could not read {}: {}", data_path.display(), e)),
+            }
+        }
+        for slot in 0..page.header.count as usize {
+            let hash = page.body.key_hash[slot];
+            let slot_key = page.body.slot_key(slot);
+            let hash = match slot_key {
+                Some(slot_key) => format!(
+                    "{:016x} key length {} fingerprint {:04x}",
+                    hash, slot_key.len, slot_key.fingerprint
+                ),
+                None => format!("{:016x}", hash),
+            };
+            let value_index = page.body.value_index[slot];
+            if value_index < 0 {
+                println!("  slot {}: hash {} removed", slot, hash);
+                continue;
+            }
+            let value = match data.as_mut().map(|data| data.get(value_index as usize)) {
+                None => String::new(),
+                Some(None) => " <missing from the data file>".to_owned(),
+                Some(Some(bytes)) => match decode_entry(bytes) {
+                    Ok((key, record)) => {
+                        if let (Some(key), Some(slot_key)) = (&key, slot_key) {
+                            if SlotKey::new(key) != slot_key {
+                                self.problem(format!(
+                                    "Slot {} has the wrong key length or fingerprint for {:?}",
+                                    slot, key
+                                ));
+                            }
+                        }
+                        format!(
+                            " key {} {}",
+                            key.map_or("<unknown>".to_owned(), |key| format!("{:?}", key)),
+                            self.preview(&record)
+                        )
+                    }
+                    Err(e) => format!(" <{}>", e),
+                },
+            };
+            println!(
+                "  slot {}: hash {} value {}{}",
+                slot, hash, value_index, value
+            );
+        }
+    }
+
+    /// A record's type, and for plain values the start of the value.
+    fn preview(&self, record: &Record) -> String {
+        match record {
+            Record::Value(value) => {
+                let mut preview: String = value.chars().take(self.preview).collect();
+                if preview.len() < value.len() {
+                    preview.push_str("...");
+                }
+                format!("{:?}", preview)
+            }
+            record => format!("<{}>", record.type_name()),
+        }
+    }
+
+    /// The path of a page, data, or segment file: in the cold directory if it's only there,
+    /// otherwise in the data directory.
+    fn page_path(&self, name: &Path) -> PathBuf {
+        match &self.cold_dir {
+            Some(cold_dir) if !self.dir.join(name).exists() && cold_dir.join(name).exists() => {
+                cold_dir.join(name)
+            }
+            _ => self.dir.join(name),
+        }
+    }
+}
+
+/// Read a page from its page file, or from its segment if it has a location in one.
+fn read_page(path: &Path, location: Option<&SegmentLocation>) -> Result<Page> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if let Some(location) = location {
+        reader.seek(SeekFrom::Start(location.offset))?;
+    }
+    let mut buffer = PageBuffer::default();
+    buffer.read_from(&mut reader)?;
+    let mut page = Page::default();
+    buffer.deserialize(&mut page)?;
+    Ok(page)
+}
+
+/// Read a page's data from its data file, or from its segment if it has a location in one.
+fn read_data(path: &Path, location: Option<&SegmentLocation>) -> Result<Slotted> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match location {
+        Some(location) => {
+            reader.seek(SeekFrom::Start(location.data_offset()))?;
+            Ok(bincode::deserialize_from(reader.take(location.data_len))?)
+        }
+        None => Ok(bincode::deserialize_from(reader)?),
+    }
+}
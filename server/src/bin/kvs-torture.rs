@@ -0,0 +1,291 @@
+//! Soak test for crash recovery: runs a writer process against a data directory, kills it with
+//! SIGKILL at a random moment, then reopens the store and checks that every write the writer
+//! had acknowledged is there and nothing else is, over and over.
+use clap::{App, AppSettings, Arg, SubCommand};
+use kvs::{Engine, Error, Result};
+use server::{DumpEntry, DumpFormat, DumpValue, Durability, KvStore, Options};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The writer compacts after this many writes, so that crashes during compaction are covered
+/// too.
+const COMPACT_EVERY: u64 = 50;
+
+fn main() -> Result<()> {
+    let dir_arg = Arg::with_name("dir")
+        .long("dir")
+        .takes_value(true)
+        .value_name("PATH")
+        .required(true)
+        .help("The data directory to torture, which must be empty or not exist yet");
+    let durability_arg = Arg::with_name("durability")
+        .long("durability")
+        .takes_value(true)
+        .possible_values(&["flush", "sync"])
+        .default_value("flush")
+        .help("The durability level the writer uses");
+    let matches = App::new("kvs-torture")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Repeatedly kill a writer process and check that the store recovers every acknowledged write")
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(&dir_arg)
+        .arg(&durability_arg)
+        .arg(
+            Arg::with_name("rounds")
+                .long("rounds")
+                .takes_value(true)
+                .value_name("N")
+                .help("Stop after N rounds instead of running until interrupted"),
+        )
+        .arg(
+            Arg::with_name("max-delay")
+                .long("max-delay")
+                .takes_value(true)
+                .value_name("MILLISECONDS")
+                .default_value("1000")
+                .help("The longest the writer runs before it's killed"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .value_name("N")
+                .help("Seed for the kill delays, to repeat an earlier run"),
+        )
+        .subcommand(
+            SubCommand::with_name("writer")
+                .setting(AppSettings::Hidden)
+                .arg(&dir_arg)
+                .arg(&durability_arg)
+                .arg(Arg::with_name("round").required(true)),
+        )
+        .get_matches();
+
+    if let ("writer", Some(args)) = matches.subcommand() {
+        let round = parse_number(args.value_of("round").unwrap())?;
+        return write(
+            Path::new(args.value_of("dir").unwrap()),
+            args.value_of("durability").unwrap().parse()?,
+            round,
+        );
+    }
+
+    let dir = Path::new(matches.value_of("dir").unwrap());
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(Error::Message(format!("{:?} is not empty", dir)));
+    }
+    fs::create_dir_all(dir)?;
+    let durability: Durability = matches.value_of("durability").unwrap().parse()?;
+    let rounds = match matches.value_of("rounds") {
+        Some(rounds) => Some(parse_number(rounds)?),
+        None => None,
+    };
+    let max_delay = parse_number(matches.value_of("max-delay").unwrap())?;
+    let seed = match matches.value_of("seed") {
+        Some(seed) => parse_number(seed)?,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+    };
+    println!("seed: {}", seed);
+
+    let mut rng = XorShift(seed | 1);
+    // The index of the last write that survived in each round
+    let mut durable: BTreeMap<u64, Option<u64>> = BTreeMap::new();
+    let mut round = 0;
+    while rounds.map_or(true, |rounds| round < rounds) {
+        let delay = Duration::from_millis(rng.next() % (max_delay + 1));
+        let acked = run_writer(dir, durability, round, delay)?;
+        durable.insert(round, acked);
+
+        let errors = check(dir, durability, &mut durable)?;
+        if !errors.is_empty() {
+            eprintln!(
+                "round {}: store is inconsistent after the writer was killed",
+                round
+            );
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            process::exit(1);
+        }
+        println!(
+            "round {}: killed after {}ms, {} writes acknowledged, ok",
+            round,
+            delay.as_millis(),
+            acked.map_or(0, |i| i + 1)
+        );
+        round += 1;
+    }
+    Ok(())
+}
+
+/// Write keys for the round until killed, printing the index of each write once it has been
+/// acknowledged.
+fn write(dir: &Path, durability: Durability, round: u64) -> Result<()> {
+    let store = open(dir, durability)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for i in 0.. {
+        store.set(key(round, i), value(round, i))?;
+        writeln!(stdout, "{}", i)?;
+        stdout.flush()?;
+        if (i + 1) % COMPACT_EVERY == 0 {
+            store.compact()?;
+        }
+    }
+    Ok(())
+}
+
+/// Start a writer for the round, kill it after `delay`, and return the index of the last write
+/// it acknowledged.
+fn run_writer(
+    dir: &Path,
+    durability: Durability,
+    round: u64,
+    delay: Duration,
+) -> Result<Option<u64>> {
+    let mut child = Command::new(env::current_exe()?)
+        .arg("writer")
+        .arg("--dir")
+        .arg(dir)
+        .arg("--durability")
+        .arg(durability.to_string())
+        .arg(round.to_string())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut acked = None;
+        for line in BufReader::new(stdout).lines() {
+            match line.ok().and_then(|line| line.parse().ok()) {
+                Some(i) => acked = Some(i),
+                None => break,
+            }
+        }
+        acked
+    });
+
+    thread::sleep(delay);
+    // Sends SIGKILL on Unix, so the writer gets no chance to clean up
+    child.kill()?;
+    let status = child.wait()?;
+    if status.code().is_some() {
+        return Err(Error::Message(format!(
+            "Writer exited by itself: {}",
+            status
+        )));
+    }
+    Ok(reader.join().unwrap())
+}
+
+/// Reopen the store and compare it with the writes that were acknowledged, returning a
+/// description of every difference. The write that was in flight when the writer was killed
+/// may or may not be there; if it is, it has to stay there.
+fn check(
+    dir: &Path,
+    durability: Durability,
+    durable: &mut BTreeMap<u64, Option<u64>>,
+) -> Result<Vec<String>> {
+    let store = open(dir, durability)?;
+    let mut errors = Vec::new();
+    let report = store.verify()?;
+    if !report.is_ok() {
+        errors.push(format!("verify: {}", report));
+    }
+
+    let mut dump = Vec::new();
+    store.dump(&mut dump, DumpFormat::Json)?;
+    let mut present: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+    for line in String::from_utf8_lossy(&dump).lines() {
+        let entry = DumpEntry::parse(line)?;
+        match parse_key(&entry.key) {
+            Some((round, i)) if entry.value == DumpValue::String(value(round, i)) => {
+                present.entry(round).or_default().insert(i);
+            }
+            Some(_) => errors.push(format!("{}: unexpected value {:?}", entry.key, entry.value)),
+            None => errors.push(format!("{}: unexpected key", entry.key)),
+        }
+    }
+
+    let latest = durable.keys().next_back().cloned();
+    for (round, last) in durable.iter_mut() {
+        let found = present.remove(round).unwrap_or_default();
+        // Only the newest round can have an extra write, the one in flight
+        if Some(*round) == latest {
+            let in_flight = last.map_or(0, |i| i + 1);
+            if found.contains(&in_flight) {
+                *last = Some(in_flight);
+            }
+        }
+        let expected = last.map_or(0, |i| i + 1);
+        for i in 0..expected {
+            if !found.contains(&i) {
+                errors.push(format!("{}: acknowledged write is missing", key(*round, i)));
+            }
+        }
+        for i in found.range(expected..) {
+            errors.push(format!("{}: write was never acknowledged", key(*round, *i)));
+        }
+    }
+    for (round, found) in present {
+        for i in found {
+            errors.push(format!(
+                "{}: write from a round that never ran",
+                key(round, i)
+            ));
+        }
+    }
+    Ok(errors)
+}
+
+fn open(dir: &Path, durability: Durability) -> Result<KvStore> {
+    let options = Options {
+        durability,
+        ..Options::default()
+    };
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    KvStore::open_with_options(dir, &logger, options)
+}
+
+fn key(round: u64, i: u64) -> String {
+    format!("torture-{}-{}", round, i)
+}
+
+fn value(round: u64, i: u64) -> String {
+    format!("value-{}-{}", round, i)
+}
+
+fn parse_key(key: &str) -> Option<(u64, u64)> {
+    let mut parts = key.splitn(3, '-');
+    if parts.next() != Some("torture") {
+        return None;
+    }
+    let round = parts.next()?.parse().ok()?;
+    let i = parts.next()?.parse().ok()?;
+    Some((round, i))
+}
+
+fn parse_number(s: &str) -> Result<u64> {
+    s.parse()
+        .map_err(|_| Error::Message(format!("Invalid number: {}", s)))
+}
+
+/// A tiny xorshift generator, since the delays only need to be spread out and repeatable.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -4,23 +4,67 @@ extern crate slog_async;
 extern crate slog_term;
 
 use bincode;
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use ctrlc;
-use kvs::{CommandRequest, CommandResponse, Engine, Error, Result};
-use server::{KvStore, SledEngine};
+use kvs::{
+    is_reserved_key, CancelToken, CommandRequest, CommandResponse, Engine, Error, HealthStatus,
+    Progress, ProgressFn, Result, RESERVED_KEY_PREFIX,
+};
+use server::{
+    parse_hash_algorithm, parse_node_id, systemd_listeners, CacheEngine, Claim, IdempotencyCache,
+    KeyHash, KvStore, Options, RotatingFile, Rotation, SledEngine, Statsd, Telemetry, WorkerPool,
+};
 use sled::Db;
 use slog::Drain;
 use std::boxed::Box;
 use std::env::current_dir;
+use std::fs;
+use std::io;
 use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
-fn main() -> Result<()> {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    let logger = slog::Logger::root(drain, o!("version" => env!("CARGO_PKG_VERSION")));
+/// The prefix of the keys touched by health checks to verify the engine can read and write.
+/// Each check writes its own key, so that concurrent checks don't read each other's writes.
+const HEALTH_SENTINEL_PREFIX: &str = "__kvs_health__/";
+
+/// How often engine metrics are sent to the OTLP collector, if there is one.
+const METRICS_EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often a request that may run for a while checks whether its client has hung up.
+const HANGUP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What wakes up the loop that serves requests.
+enum Event {
+    Connection(io::Result<TcpStream>),
+    /// Time to save writes that have been held in memory for the flush interval.
+    FlushDue,
+    /// SIGTERM or ctrl-c: finish up and exit.
+    Shutdown,
+}
+
+/// What the threads serving connections share.
+struct Server {
+    engine: Box<dyn Engine>,
+    engine_name: String,
+    logger: slog::Logger,
+    telemetry: Option<Mutex<Telemetry>>,
+    last_metrics_export: Mutex<Instant>,
+    statsd: Option<Mutex<Statsd>>,
+    idempotency: Mutex<IdempotencyCache>,
+    started: Instant,
+    requests_served: AtomicU64,
+    /// How long a connection can go without sending its request or reading the response.
+    idle_timeout: Duration,
+}
 
+fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -28,94 +72,1094 @@ fn main() -> Result<()> {
         .arg(
             Arg::with_name("addr")
                 .long("addr")
+                .env("KVS_ADDR")
                 .takes_value(true)
                 .value_name("IP-ADDR")
-                .default_value("127.0.0.1:4000"),
+                .multiple(true)
+                .number_of_values(1)
+                .default_value("127.0.0.1:4000")
+                .help("Listen on IP-ADDR, e.g. [::]:4000; repeat to listen on several addresses"),
+        )
+        .arg(
+            Arg::with_name("systemd")
+                .long("systemd")
+                .help("Require listening sockets from systemd socket activation instead of binding --addr (they're used whenever systemd passes any)"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .env("KVS_DATA_DIR")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Keep the data in PATH, creating it if needed (defaults to the current directory)"),
         )
         .arg(
             Arg::with_name("engine")
                 .long("engine")
+                .env("KVS_ENGINE")
                 .takes_value(true)
                 .value_name("ENGINE-NAME")
-                .possible_values(&["kvs", "sled"])
+                .possible_values(&["kvs", "sled", "cache"])
                 .default_value("kvs"),
         )
+        .arg(
+            Arg::with_name("max-memory")
+                .long("max-memory")
+                .env("KVS_MAX_MEMORY")
+                .takes_value(true)
+                .value_name("BYTES")
+                .default_value("67108864")
+                .help("How much the cache engine holds before evicting the least recently used keys (cache engine only)"),
+        )
+        .arg(
+            Arg::with_name("durability")
+                .long("durability")
+                .env("KVS_DURABILITY")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .possible_values(&["buffered", "flush", "sync"])
+                .default_value("flush")
+                .help("How far writes must reach before they're acknowledged (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("node-id")
+                .long("node-id")
+                .env("KVS_NODE_ID")
+                .takes_value(true)
+                .value_name("HEX")
+                .help("The UUIDv1 node id for new pages (defaults to one derived from the hostname)"),
+        )
+        .arg(
+            Arg::with_name("hash-function")
+                .long("hash-function")
+                .env("KVS_HASH_FUNCTION")
+                .takes_value(true)
+                .value_name("NAME")
+                .possible_values(&["metrohash", "xxhash", "siphash"])
+                .help("The function a new store hashes keys with, which an existing store must match (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("hash-seed")
+                .long("hash-seed")
+                .env("KVS_HASH_SEED")
+                .takes_value(true)
+                .value_name("SEED")
+                .help("The seed for --hash-function, in decimal or 0x-prefixed hex (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("archive-dir")
+                .long("archive-dir")
+                .env("KVS_ARCHIVE_DIR")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Copy every page written to PATH or an s3://bucket/prefix URL, for point-in-time recovery (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("cold-dir")
+                .long("cold-dir")
+                .env("KVS_COLD_DIR")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write compacted pages to PATH, keeping only recently saved pages in the data directory (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("max-open-files")
+                .long("max-open-files")
+                .env("KVS_MAX_OPEN_FILES")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("256")
+                .help("How many page and data files to keep open for reading (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("memtable-bytes")
+                .long("memtable-bytes")
+                .env("KVS_MEMTABLE_BYTES")
+                .takes_value(true)
+                .value_name("BYTES")
+                .default_value("4194304")
+                .help("How many bytes of keys and values are held in memory before they're written to a page (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("flush-interval")
+                .long("flush-interval")
+                .env("KVS_FLUSH_INTERVAL")
+                .takes_value(true)
+                .value_name("SECS")
+                .help("Save writes that have been held in memory this long, even if there are few of them (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("segment-size")
+                .long("segment-size")
+                .env("KVS_SEGMENT_SIZE")
+                .takes_value(true)
+                .value_name("BYTES")
+                .default_value("67108864")
+                .help("How big the segment files that compaction packs pages into get (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("startup-threads")
+                .long("startup-threads")
+                .env("KVS_STARTUP_THREADS")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("4")
+                .help("How many threads check the pages when opening the data directory (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("lookup-threads")
+                .long("lookup-threads")
+                .env("KVS_LOOKUP_THREADS")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("1")
+                .help("How many threads a lookup reads pages on when several might hold the key (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("compaction-threads")
+                .long("compaction-threads")
+                .env("KVS_COMPACTION_THREADS")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("1")
+                .help("How many threads compaction merges pages on (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("compaction")
+                .long("compaction")
+                .env("KVS_COMPACTION")
+                .takes_value(true)
+                .value_name("STRATEGY")
+                .possible_values(&["manual", "size-tiered", "leveled"])
+                .default_value("size-tiered")
+                .help("How pages are merged as they're saved (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("compaction-fanout")
+                .long("compaction-fanout")
+                .env("KVS_COMPACTION_FANOUT")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("4")
+                .help("How many runs size-tiered compaction merges at once, or how much bigger each level of leveled compaction is (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("compaction-garbage-ratio")
+                .long("compaction-garbage-ratio")
+                .env("KVS_COMPACTION_GARBAGE_RATIO")
+                .takes_value(true)
+                .value_name("RATIO")
+                .default_value("0.5")
+                .help("The share of replaced or removed entries at which a run of pages is rewritten on its own (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("statsd")
+                .long("statsd")
+                .env("KVS_STATSD")
+                .takes_value(true)
+                .value_name("HOST:PORT")
+                .help("Push metrics to a statsd or DogStatsD agent, e.g. 127.0.0.1:8125"),
+        )
+        .arg(
+            Arg::with_name("statsd-prefix")
+                .long("statsd-prefix")
+                .env("KVS_STATSD_PREFIX")
+                .takes_value(true)
+                .value_name("PREFIX")
+                .default_value("kvs")
+                .help("What statsd metric names start with"),
+        )
+        .arg(
+            Arg::with_name("statsd-interval")
+                .long("statsd-interval")
+                .env("KVS_STATSD_INTERVAL")
+                .takes_value(true)
+                .value_name("SECS")
+                .default_value("10")
+                .help("How often metrics are pushed to statsd"),
+        )
+        .arg(
+            Arg::with_name("idempotency-tokens")
+                .long("idempotency-tokens")
+                .env("KVS_IDEMPOTENCY_TOKENS")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("10000")
+                .help("How many idempotency tokens to remember the responses for"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .env("KVS_THREADS")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("8")
+                .help("How many connections are served at once; the rest wait to be accepted"),
+        )
+        .arg(
+            Arg::with_name("shutdown-grace")
+                .long("shutdown-grace")
+                .env("KVS_SHUTDOWN_GRACE")
+                .takes_value(true)
+                .value_name("SECS")
+                .default_value("10")
+                .help("On SIGTERM or ctrl-c, how long the requests being served have to finish before the server exits without flushing"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .env("KVS_IDLE_TIMEOUT")
+                .takes_value(true)
+                .value_name("SECS")
+                .default_value("30")
+                .help("Drop a connection that goes SECS seconds without sending its request or reading the response, freeing its thread"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .env("KVS_LOG_FILE")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Log to PATH instead of the terminal"),
+        )
+        .arg(
+            Arg::with_name("log-rotate-size")
+                .long("log-rotate-size")
+                .env("KVS_LOG_ROTATE_SIZE")
+                .takes_value(true)
+                .value_name("BYTES")
+                .requires("log-file")
+                .help("Rotate the log file once it's this big"),
+        )
+        .arg(
+            Arg::with_name("log-rotate-age")
+                .long("log-rotate-age")
+                .env("KVS_LOG_ROTATE_AGE")
+                .takes_value(true)
+                .value_name("SECS")
+                .requires("log-file")
+                .help("Rotate the log file once it's been written to for this long"),
+        )
+        .arg(
+            Arg::with_name("log-keep")
+                .long("log-keep")
+                .env("KVS_LOG_KEEP")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("5")
+                .help("How many rotated log files to keep"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .env("KVS_LOG_LEVEL")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .possible_values(&["critical", "error", "warning", "info", "debug", "trace"])
+                .default_value("info")
+                .help("The least severe messages to log"),
+        )
+        .arg(
+            Arg::with_name("otlp-endpoint")
+                .long("otlp-endpoint")
+                .env("KVS_OTLP_ENDPOINT")
+                .takes_value(true)
+                .value_name("URL")
+                .help("Export traces and metrics to an OTLP/HTTP collector, e.g. http://localhost:4318"),
+        )
         .get_matches();
 
-    let addr = matches.value_of("addr").unwrap();
-    let engine = matches.value_of("engine").unwrap();
+    let drain = match matches.value_of("log-file") {
+        Some(path) => {
+            let rotation = Rotation {
+                max_size: parse_optional(&matches, "log-rotate-size")?,
+                max_age: parse_optional(&matches, "log-rotate-age")?.map(Duration::from_secs),
+                keep: parse_optional(&matches, "log-keep")?.unwrap(),
+            };
+            let file = RotatingFile::open(Path::new(path), rotation)?;
+            let decorator = slog_term::PlainDecorator::new(file);
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain).build().fuse()
+        }
+        None => {
+            let decorator = slog_term::TermDecorator::new().build();
+            let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain).build().fuse()
+        }
+    };
+    let level = matches.value_of("log-level").unwrap();
+    let level = slog::Level::from_str(level)
+        .map_err(|_| Error::Message(format!("Invalid log level: {}", level)))?;
+    let drain = drain.filter_level(level).fuse();
+    let logger = slog::Logger::root(drain, o!("version" => env!("CARGO_PKG_VERSION")));
+
+    let addrs: Vec<&str> = matches.values_of("addr").unwrap().collect();
+    let engine_name = matches.value_of("engine").unwrap();
 
-    info!(logger, "IP-ADDR: {}", addr);
-    info!(logger, "ENGINE-NAME: {}", engine);
+    for addr in addrs.iter() {
+        info!(logger, "IP-ADDR: {}", addr);
+    }
+    info!(logger, "ENGINE-NAME: {}", engine_name);
 
-    let mut engine: Box<dyn kvs::Engine> = if engine == "kvs" {
-        Box::new(KvStore::open(current_dir()?.as_path())?)
-    } else if engine == "sled" {
+    let mut options = Options::default();
+    options.durability = matches.value_of("durability").unwrap().parse()?;
+    if let Some(node_id) = matches.value_of("node-id") {
+        options.node_id = Some(parse_node_id(node_id)?);
+    }
+    if matches.is_present("hash-function") || matches.is_present("hash-seed") {
+        let mut key_hash = KeyHash::default();
+        if let Some(algorithm) = matches.value_of("hash-function") {
+            key_hash.algorithm = parse_hash_algorithm(algorithm)?;
+        }
+        if let Some(seed) = matches.value_of("hash-seed") {
+            let parsed = if seed.starts_with("0x") {
+                u64::from_str_radix(&seed[2..], 16)
+            } else {
+                seed.parse()
+            };
+            key_hash.seed =
+                parsed.map_err(|_| Error::Message(format!("Invalid hash seed: {}", seed)))?;
+        }
+        options.key_hash = Some(key_hash);
+    }
+    options.archive_dir = matches.value_of("archive-dir").map(PathBuf::from);
+    options.cold_dir = matches.value_of("cold-dir").map(PathBuf::from);
+    let max_open_files = matches.value_of("max-open-files").unwrap();
+    options.max_open_files = max_open_files
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid number of open files: {}", max_open_files)))?;
+    let memtable_bytes = matches.value_of("memtable-bytes").unwrap();
+    options.memtable_bytes = memtable_bytes
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid memtable size: {}", memtable_bytes)))?;
+    options.flush_interval = match parse_optional(&matches, "flush-interval")? {
+        Some(0) => return Err(Error::Message("Invalid --flush-interval: 0".to_owned())),
+        secs => secs.map(Duration::from_secs),
+    };
+    let flush_interval = options.flush_interval;
+    let segment_size = matches.value_of("segment-size").unwrap();
+    options.segment_size = segment_size
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid segment size: {}", segment_size)))?;
+    let startup_threads = matches.value_of("startup-threads").unwrap();
+    options.startup_threads = startup_threads
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid number of threads: {}", startup_threads)))?;
+    let lookup_threads = matches.value_of("lookup-threads").unwrap();
+    options.lookup_threads = lookup_threads
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid number of threads: {}", lookup_threads)))?;
+    let compaction_threads = matches.value_of("compaction-threads").unwrap();
+    options.compaction_threads = compaction_threads.parse().map_err(|_| {
+        Error::Message(format!("Invalid number of threads: {}", compaction_threads))
+    })?;
+    options.compaction = matches.value_of("compaction").unwrap().parse()?;
+    let fanout = matches.value_of("compaction-fanout").unwrap();
+    options.compaction_fanout = fanout
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid compaction fanout: {}", fanout)))?;
+    let ratio = matches.value_of("compaction-garbage-ratio").unwrap();
+    options.compaction_garbage_ratio = ratio
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid garbage ratio: {}", ratio)))?;
+
+    let dir = match matches.value_of("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => current_dir()?,
+    };
+    info!(logger, "DATA-DIR: {}", dir.display());
+    if engine_name != "cache" {
+        fs::create_dir_all(&dir)?;
+    }
+
+    let engine: Box<dyn kvs::Engine> = if engine_name == "kvs" {
+        Box::new(KvStore::open_with_options(&dir, &logger, options)?)
+    } else if engine_name == "sled" {
         Box::new(SledEngine {
-            db: Db::open(current_dir()?.as_path())?,
+            db: Db::open(&dir)?,
         })
+    } else if engine_name == "cache" {
+        let max_memory = matches.value_of("max-memory").unwrap();
+        Box::new(CacheEngine::new(max_memory.parse().map_err(|_| {
+            Error::Message(format!("Invalid memory budget: {}", max_memory))
+        })?))
     } else {
-        panic!("Invalid engine: {}", engine);
+        panic!("Invalid engine: {}", engine_name);
+    };
+
+    let telemetry = match matches.value_of("otlp-endpoint") {
+        Some(endpoint) => {
+            info!(logger, "Exporting telemetry to {}", endpoint);
+            Some(Mutex::new(Telemetry::start(
+                endpoint,
+                "kvs-server",
+                &logger,
+            )?))
+        }
+        None => None,
+    };
+
+    let statsd = match matches.value_of("statsd") {
+        Some(addr) => {
+            let interval = matches.value_of("statsd-interval").unwrap();
+            let interval =
+                Duration::from_secs(interval.parse().map_err(|_| {
+                    Error::Message(format!("Invalid statsd interval: {}", interval))
+                })?);
+            let prefix = matches.value_of("statsd-prefix").unwrap();
+            info!(logger, "Pushing metrics to statsd at {}", addr);
+            Some(Mutex::new(Statsd::new(addr, prefix, interval)?))
+        }
+        None => None,
+    };
+
+    let idempotency_tokens = matches.value_of("idempotency-tokens").unwrap();
+    let idempotency = IdempotencyCache::new(idempotency_tokens.parse().map_err(|_| {
+        Error::Message(format!(
+            "Invalid number of idempotency tokens: {}",
+            idempotency_tokens
+        ))
+    })?);
+
+    let idle_timeout = matches.value_of("idle-timeout").unwrap();
+    let idle_timeout = match idle_timeout.parse() {
+        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+        _ => {
+            return Err(Error::Message(format!(
+                "Invalid idle timeout: {}",
+                idle_timeout
+            )))
+        }
     };
 
+    let server = Arc::new(Server {
+        engine,
+        engine_name: engine_name.to_owned(),
+        logger: logger.clone(),
+        telemetry,
+        last_metrics_export: Mutex::new(Instant::now()),
+        statsd,
+        idempotency: Mutex::new(idempotency),
+        started: Instant::now(),
+        requests_served: AtomicU64::new(0),
+        idle_timeout,
+    });
+    let threads = matches.value_of("threads").unwrap();
+    let workers = WorkerPool::new(
+        threads
+            .parse()
+            .map_err(|_| Error::Message(format!("Invalid number of threads: {}", threads)))?,
+    );
+
+    let shutdown_grace = matches.value_of("shutdown-grace").unwrap();
+    let shutdown_grace = Duration::from_secs(shutdown_grace.parse().map_err(|_| {
+        Error::Message(format!("Invalid shutdown grace period: {}", shutdown_grace))
+    })?);
+    let (sender, events) = mpsc::channel();
+    // The first signal stops the server accepting connections once the requests being served
+    // are done. If that takes longer than the grace period, or there's a second signal, it
+    // exits straight away.
+    let shutdown_sender = sender.clone();
+    let mut shutting_down = false;
     ctrlc::set_handler(move || {
-        println!("");
-        println!("Goodbye!");
-        exit(0)
+        if shutting_down || shutdown_sender.send(Event::Shutdown).is_err() {
+            exit(1);
+        }
+        shutting_down = true;
+        thread::spawn(move || {
+            thread::sleep(shutdown_grace);
+            eprintln!("Shutdown grace period is over, exiting");
+            exit(1);
+        });
     })
     .expect("Error setting ctrl-c handler");
 
-    let listener = TcpListener::bind(addr)?;
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                match stream.peer_addr() {
-                    Ok(peer_addr) => info!(logger, "{} connected!", peer_addr),
-                    Err(e) => {
-                        error!(logger, "{}", e);
-                        continue;
+    let mut listeners = systemd_listeners()?;
+    if !listeners.is_empty() {
+        info!(
+            logger,
+            "Listening on {} sockets from systemd",
+            listeners.len()
+        );
+    } else if matches.is_present("systemd") {
+        return Err(Error::Message(
+            "No listening sockets were passed in by systemd".to_owned(),
+        ));
+    } else {
+        // Bind every address before serving any, so a bad one fails startup
+        listeners = addrs
+            .iter()
+            .map(TcpListener::bind)
+            .collect::<io::Result<Vec<_>>>()?;
+    }
+    // Each listener accepts on its own thread, and the connections are served by a pool of
+    // --threads workers
+    for listener in listeners {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if sender.send(Event::Connection(stream)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    // Checking twice per interval means writes wait at most one and a half intervals to be
+    // saved
+    if let Some(interval) = flush_interval {
+        let sender = sender.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval / 2);
+            if sender.send(Event::FlushDue).is_err() {
+                break;
+            }
+        });
+    }
+    drop(sender);
+    for event in events {
+        match event {
+            Event::Shutdown => {
+                info!(logger, "Shutting down");
+                break;
+            }
+            Event::FlushDue => {
+                if let Err(e) = server.engine.flush_if_due() {
+                    warn!(logger, "Could not save held writes: {}", e);
+                }
+            }
+            Event::Connection(Ok(stream)) => {
+                let server = server.clone();
+                workers.execute(move || server.serve(stream));
+            }
+            Event::Connection(Err(e)) => {
+                error!(logger, "Could not connect: {:?}", e);
+                exit(1);
+            }
+        }
+    }
+
+    // Dropping the pool waits for the workers to serve the connections already handed to them
+    drop(workers);
+    server.engine.flush()?;
+    drop(server);
+    println!("Goodbye!");
+    Ok(())
+}
+
+impl Server {
+    /// Read one request from the connection and answer it.
+    fn serve(&self, stream: TcpStream) {
+        let logger = &self.logger;
+        let peer_addr = match stream.peer_addr() {
+            Ok(peer_addr) => peer_addr,
+            Err(e) => {
+                error!(logger, "{}", e);
+                return;
+            }
+        };
+        info!(logger, "{} connected!", peer_addr);
+        // Otherwise a client that stalls would keep this thread from serving anyone else
+        let timeouts = stream
+            .set_read_timeout(Some(self.idle_timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.idle_timeout)));
+        if let Err(e) = timeouts {
+            error!(logger, "{}", e);
+            return;
+        }
+
+        if let Ok(request) = bincode::deserialize_from::<&TcpStream, CommandRequest>(&stream) {
+            // Requests without an id get one, so their log lines can still be found
+            let (request_id, traced, request) = match request {
+                CommandRequest::Traced {
+                    request_id,
+                    request,
+                } => (request_id, true, *request),
+                request => (Uuid::new_v4().to_string(), false, request),
+            };
+            let logger = logger.new(o!("request_id" => request_id.clone()));
+            info!(logger, "REQUEST: {:?}", request);
+            let requests_served = self.requests_served.fetch_add(1, Ordering::SeqCst) + 1;
+            let request_name = request.name();
+            let request_started = Instant::now();
+
+            let mut span = self
+                .telemetry
+                .as_ref()
+                .map(|t| t.lock().unwrap().start_span(request.name()));
+            if let Some(span) = span.as_mut() {
+                span.set_attribute("db.system", "kvs".to_owned());
+                span.set_attribute("db.operation", request.name().to_owned());
+                span.set_attribute("net.peer.name", peer_addr.to_string());
+                span.set_attribute("kvs.request_id", request_id.clone());
+            }
+
+            let (deadline, request) = match request {
+                CommandRequest::Deadline {
+                    timeout_ms,
+                    request,
+                } => (
+                    // A timeout too far off to represent is as good as none
+                    Instant::now().checked_add(Duration::from_millis(timeout_ms)),
+                    *request,
+                ),
+                request => (None, request),
+            };
+            let (progress, request) = match request {
+                CommandRequest::Progress { request } => (true, *request),
+                request => (false, request),
+            };
+            let (min_sequence, request) = match request {
+                CommandRequest::Session {
+                    min_sequence,
+                    request,
+                } => (Some(min_sequence), *request),
+                request => (None, request),
+            };
+            let (token, request) = match request {
+                CommandRequest::Idempotent { token, request } => (Some(token), *request),
+                request => (None, request),
+            };
+            // A retry of a request that was applied gets the same response again, and one that
+            // arrives while the request is still being applied is turned away. Tokens are kept
+            // per client address, since each connection only carries one request.
+            let client = peer_addr.ip().to_string();
+            let mut claim = None;
+            let replayed = match &token {
+                Some(token) => {
+                    match lock_cache(&self.idempotency).claim(&client, token, &request) {
+                        Claim::New => {
+                            claim = Some(TokenClaim {
+                                cache: &self.idempotency,
+                                client,
+                                token: token.clone(),
+                                response: None,
+                            });
+                            Ok(None)
+                        }
+                        Claim::Applied(response) => Ok(Some(response)),
+                        Claim::InFlight => Err(Error::Message(format!(
+                            "A request with idempotency token {} is still being applied",
+                            token
+                        ))),
+                        Claim::Mismatch => Err(Error::Message(format!(
+                            "Idempotency token {} was already sent with a different request",
+                            token
+                        ))),
                     }
                 }
+                None => Ok(None),
+            };
+            // A server that hasn't caught up with the session refuses the request, so
+            // the client can go to one that has
+            let applied = match min_sequence {
+                Some(required) => match self.engine.applied_sequence() {
+                    Ok(applied) if applied < required => {
+                        Err(Error::BehindSession { applied, required })
+                    }
+                    result => result.map(|_| ()),
+                },
+                None => Ok(()),
+            };
+            let applied = match deadline {
+                Some(deadline) if Instant::now() >= deadline => Err(Error::DeadlineExceeded),
+                _ => applied,
+            };
+            // Keys like the health check's are the server's own, and can't be renamed to
+            // either
+            let new_key = match &request {
+                CommandRequest::Rename { new_key, .. } => Some(new_key.as_str()),
+                _ => None,
+            };
+            let reserved = request
+                .key()
+                .into_iter()
+                .chain(new_key)
+                .any(is_reserved_key);
+            let applied = if reserved {
+                Err(Error::InvalidKey(format!(
+                    "keys starting with {} are reserved",
+                    RESERVED_KEY_PREFIX
+                )))
+            } else {
+                applied
+            };
 
-                if let Ok(request) =
-                    bincode::deserialize_from::<&TcpStream, CommandRequest>(&stream)
-                {
-                    info!(logger, "REQUEST: {:?}", request);
-
-                    let response = match request {
-                        CommandRequest::Get { key } => engine.get(key).map(|x| {
-                            CommandResponse::Message(format!(
-                                "{}",
-                                x.unwrap_or("Key not found".to_owned())
-                            ))
-                        }),
-                        CommandRequest::Set { key, value } => if let Some(value) = value {
-                            engine.set(key, value)
-                        } else {
-                            engine.remove(key)
+            let settings = RequestSettings(&*self.engine);
+            self.engine.set_deadline(deadline);
+            if progress {
+                match stream.try_clone() {
+                    Ok(stream) => self
+                        .engine
+                        .set_progress(Some(send_progress(stream, &logger))),
+                    Err(e) => warn!(logger, "Could not send progress: {}", e),
+                }
+            }
+            // Nobody would read the result of a long request whose client has hung up,
+            // so it's given up on
+            let finished = Arc::new(AtomicBool::new(false));
+            if may_run_long(&request) {
+                let token = CancelToken::new();
+                match watch_for_hangup(&stream, &token, &finished, &logger) {
+                    Ok(()) => self.engine.set_cancel_token(Some(token)),
+                    Err(e) => {
+                        warn!(logger, "Could not watch for the client hanging up: {}", e)
+                    }
+                }
+            }
+            let result = applied.and(replayed).and_then(|replayed| match replayed {
+                Some(response) => {
+                    info!(logger, "Already applied, replaying the response");
+                    Ok(response)
+                }
+                None => match request {
+                    CommandRequest::Get { key } => self.engine.get(key).map(CommandResponse::Value),
+                    CommandRequest::Set { key, value } => if let Some(value) = value {
+                        self.engine.set(key, value)
+                    } else {
+                        self.engine.remove(key)
+                    }
+                    .map(|sequence| CommandResponse::Written { sequence }),
+                    CommandRequest::Health => {
+                        let result = health_check(self.engine.as_ref());
+                        if let Err(e) = &result {
+                            warn!(logger, "Health check failed: {}", e);
                         }
+                        Ok(CommandResponse::Health(HealthStatus {
+                            healthy: result.is_ok(),
+                            error: result.err().map(|e| format!("{}", e)),
+                            engine: self.engine_name.clone(),
+                            uptime_secs: self.started.elapsed().as_secs(),
+                            requests_served,
+                        }))
+                    }
+                    CommandRequest::CompareAndSwap {
+                        key,
+                        expected,
+                        value,
+                    } => self
+                        .engine
+                        .compare_and_swap(key, expected, value)
+                        .map(|(swapped, current)| CommandResponse::Swap { swapped, current }),
+                    CommandRequest::Rename { key, new_key } => self
+                        .engine
+                        .rename(key, new_key)
+                        .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::Ping => Ok(CommandResponse::Pong {
+                        version: env!("CARGO_PKG_VERSION").to_owned(),
+                    }),
+                    CommandRequest::Compact => self
+                        .engine
+                        .compact()
+                        .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::Verify => self.engine.verify().map(CommandResponse::Verify),
+                    CommandRequest::Stats => self.engine.stats().map(CommandResponse::Stats),
+                    CommandRequest::Snapshot { path } => self
+                        .engine
+                        .snapshot(Path::new(&path))
+                        .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::HSet { key, field, value } => self
+                        .engine
+                        .hset(key, field, value)
                         .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::HGet { key, field } => {
+                        self.engine.hget(key, field).map(CommandResponse::Value)
                     }
-                    .unwrap_or_else(|e| match e {
-                        Error::KeyNotFound => CommandResponse::KeyNotFound,
-                        _ => CommandResponse::Message(format!("Error: {}", e)),
-                    });
-
-                    info!(logger, "RESPONSE: {:?}", &response);
+                    CommandRequest::HGetAll { key } => {
+                        self.engine.hgetall(key).map(CommandResponse::Hash)
+                    }
+                    CommandRequest::HDel { key, field } => self
+                        .engine
+                        .hdel(key, field)
+                        .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::ZAdd { key, score, member } => self
+                        .engine
+                        .zadd(key, score, member)
+                        .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::ZRangeByScore { key, min, max } => self
+                        .engine
+                        .zrangebyscore(key, min, max)
+                        .map(CommandResponse::Members),
+                    CommandRequest::XAdd { key, payload } => self
+                        .engine
+                        .xadd(key, payload)
+                        .map(|id| CommandResponse::Message(id.to_string())),
+                    CommandRequest::XRange { key, from, to } => self
+                        .engine
+                        .xrange(key, from, to)
+                        .map(CommandResponse::Entries),
+                    CommandRequest::Enqueue { key, payload } => self
+                        .engine
+                        .enqueue(key, payload)
+                        .map(|id| CommandResponse::Message(id.to_string())),
+                    CommandRequest::Dequeue {
+                        key,
+                        visibility_timeout_ms,
+                    } => self
+                        .engine
+                        .dequeue(key, Duration::from_millis(visibility_timeout_ms))
+                        .map(|item| CommandResponse::Entries(item.into_iter().collect())),
+                    CommandRequest::Ack { key, id } => self
+                        .engine
+                        .ack(key, id)
+                        .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::Lock { key, ttl_ms } => self
+                        .engine
+                        .lock(key, Duration::from_millis(ttl_ms))
+                        .map(|token| CommandResponse::Message(token.to_string())),
+                    CommandRequest::Unlock { key, token } => self
+                        .engine
+                        .unlock(key, token)
+                        .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::SetBit { key, offset, value } => self
+                        .engine
+                        .setbit(key, offset, value)
+                        .map(|_| CommandResponse::Message("".to_owned())),
+                    CommandRequest::GetBit { key, offset } => self
+                        .engine
+                        .getbit(key, offset)
+                        .map(|bit| CommandResponse::Message((bit as u8).to_string())),
+                    CommandRequest::BitCount { key } => self
+                        .engine
+                        .bitcount(key)
+                        .map(|count| CommandResponse::Message(count.to_string())),
+                    CommandRequest::Scan {
+                        start,
+                        end,
+                        limit,
+                        reverse,
+                        cursor,
+                    } => {
+                        let limit = limit.map(|limit| limit as usize);
+                        let cursor = cursor.as_ref().map(String::as_str);
+                        kvs::scan_page(self.engine.as_ref(), start, end, limit, reverse, cursor)
+                            .map(CommandResponse::ScanPage)
+                    }
+                    CommandRequest::Eval { script, args } => self
+                        .engine
+                        .run_script(&script, &args)
+                        .map(|result| CommandResponse::Message(result.unwrap_or_default())),
+                    CommandRequest::ZRank { key, member } => {
+                        self.engine.zrank(key, member).map(|x| {
+                            CommandResponse::Message(
+                                x.map(|rank| rank.to_string())
+                                    .unwrap_or("Key not found".to_owned()),
+                            )
+                        })
+                    }
+                    CommandRequest::Session { .. } => {
+                        Err(Error::Message("Sessions can't be nested".to_owned()))
+                    }
+                    CommandRequest::Deadline { .. } => Err(Error::Message(
+                        "A deadline must wrap the whole request".to_owned(),
+                    )),
+                    CommandRequest::Progress { .. } => Err(Error::Message(
+                        "Progress reports must be asked for outside any session".to_owned(),
+                    )),
+                    CommandRequest::Traced { .. } => Err(Error::Message(
+                        "A request id must wrap the whole request".to_owned(),
+                    )),
+                    CommandRequest::Idempotent { .. } => Err(Error::Message(
+                        "An idempotency token must be inside any other wrappers".to_owned(),
+                    )),
+                },
+            });
+            // Only successes are remembered, so a failed request can be retried
+            if let (Some(claim), Ok(response)) = (claim.as_mut(), &result) {
+                claim.response = Some(response.clone());
+            }
+            drop(claim);
+            drop(settings);
+            finished.store(true, Ordering::SeqCst);
 
-                    if let Err(e) = bincode::serialize_into(&stream, &response) {
-                        error!(logger, "{}", e);
+            if let (Some(telemetry), Some(span)) = (self.telemetry.as_ref(), span) {
+                let telemetry = telemetry.lock().unwrap();
+                let error = match &result {
+                    Err(Error::KeyNotFound) | Ok(_) => None,
+                    Err(e) => Some(format!("{}", e)),
+                };
+                telemetry.finish(span, error);
+                let mut last_metrics_export = self.last_metrics_export.lock().unwrap();
+                if last_metrics_export.elapsed() >= METRICS_EXPORT_INTERVAL {
+                    match self.engine.stats() {
+                        Ok(stats) => telemetry.record_metrics(&stats, requests_served),
+                        Err(e) => warn!(logger, "Could not collect engine stats: {}", e),
                     }
-                } else {
-                    warn!(logger, "Bad request");
+                    *last_metrics_export = Instant::now();
                 }
             }
-            Err(e) => {
-                error!(logger, "Could not connect: {:?}", e);
-                exit(1);
+            if let Some(statsd) = &self.statsd {
+                let mut statsd = statsd.lock().unwrap();
+                let failed = match &result {
+                    Err(Error::KeyNotFound) | Ok(_) => false,
+                    Err(_) => true,
+                };
+                statsd.record_request(request_name, request_started.elapsed(), failed);
+                if statsd.is_due() {
+                    match self.engine.stats() {
+                        Ok(stats) => statsd.record_metrics(&stats, requests_served),
+                        Err(e) => warn!(logger, "Could not collect engine stats: {}", e),
+                    }
+                    if let Err(e) = statsd.flush() {
+                        warn!(logger, "Failed to push metrics to statsd: {}", e);
+                    }
+                }
             }
+
+            let mut response = result.unwrap_or_else(|e| match e {
+                Error::KeyNotFound => CommandResponse::KeyNotFound,
+                Error::DeadlineExceeded => CommandResponse::DeadlineExceeded,
+                e => CommandResponse::Error {
+                    code: e.code(),
+                    message: format!("{}", e),
+                },
+            });
+            if min_sequence.is_some() {
+                response = match self.engine.applied_sequence() {
+                    Ok(sequence) => CommandResponse::Session {
+                        sequence,
+                        response: Box::new(response),
+                    },
+                    Err(e) => CommandResponse::Error {
+                        code: e.code(),
+                        message: format!("{}", e),
+                    },
+                };
+            }
+            if traced {
+                response = CommandResponse::Traced {
+                    request_id,
+                    response: Box::new(response),
+                };
+            }
+
+            info!(logger, "RESPONSE: {:?}", &response);
+
+            if let Err(e) = bincode::serialize_into(&stream, &response) {
+                error!(logger, "{}", e);
+            }
+        } else {
+            warn!(logger, "Bad request");
         }
     }
+}
+
+/// Holds a request's idempotency token in the cache until it's dropped, then releases it with the
+/// response to replay, if the request succeeded. A request that fails or panics has its token
+/// forgotten, so it can be retried.
+struct TokenClaim<'a> {
+    cache: &'a Mutex<IdempotencyCache>,
+    client: String,
+    token: String,
+    response: Option<CommandResponse>,
+}
+
+impl Drop for TokenClaim<'_> {
+    fn drop(&mut self) {
+        lock_cache(self.cache).finish(&self.client, &self.token, self.response.take());
+    }
+}
+
+/// Lock the idempotency cache. It's only ever locked to look up or update a token, which leaves
+/// it consistent even if a thread panics, so a poisoned lock is used anyway.
+fn lock_cache(cache: &Mutex<IdempotencyCache>) -> std::sync::MutexGuard<'_, IdempotencyCache> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Clears the deadline, cancel token, and progress callback a request set for the thread serving
+/// it when dropped. That happens even if serving the request panics, since the worker pool goes
+/// on to serve other connections with the same thread.
+struct RequestSettings<'a>(&'a dyn Engine);
+
+impl Drop for RequestSettings<'_> {
+    fn drop(&mut self) {
+        self.0.set_deadline(None);
+        self.0.set_cancel_token(None);
+        self.0.set_progress(None);
+    }
+}
+
+/// Whether the request can take long enough that it's worth stopping if the client hangs up.
+fn may_run_long(request: &CommandRequest) -> bool {
+    match request {
+        CommandRequest::Compact
+        | CommandRequest::Verify
+        | CommandRequest::Scan { .. }
+        | CommandRequest::Eval { .. } => true,
+        _ => false,
+    }
+}
 
+/// Cancel `token` if the client closes the connection before `finished` is set. The client
+/// sends nothing after its request, so the connection only becomes readable when it's closed.
+fn watch_for_hangup(
+    stream: &TcpStream,
+    token: &CancelToken,
+    finished: &Arc<AtomicBool>,
+    logger: &slog::Logger,
+) -> io::Result<()> {
+    let stream = stream.try_clone()?;
+    stream.set_read_timeout(Some(HANGUP_POLL_INTERVAL))?;
+    let (token, finished, logger) = (token.clone(), finished.clone(), logger.clone());
+    thread::spawn(move || {
+        let mut byte = [0; 1];
+        while !finished.load(Ordering::SeqCst) {
+            match stream.peek(&mut byte) {
+                Ok(0) => {
+                    info!(logger, "Client hung up, cancelling the request");
+                    token.cancel();
+                    break;
+                }
+                // The client sent more than its request, so it's still there
+                Ok(_) => break,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(_) => {
+                    token.cancel();
+                    break;
+                }
+            }
+        }
+    });
     Ok(())
 }
+
+/// Send each report of a request's progress to the client that asked for it. The client is
+/// only reading, so reports it doesn't get to in time wait in the socket's buffers.
+fn send_progress(stream: TcpStream, logger: &slog::Logger) -> ProgressFn {
+    let logger = logger.clone();
+    Arc::new(move |progress: &Progress| {
+        let frame = CommandResponse::Progress(progress.clone());
+        if let Err(e) = bincode::serialize_into(&stream, &frame) {
+            debug!(logger, "Could not send progress: {}", e);
+        }
+    })
+}
+
+/// Parse the argument's value, if it has one.
+fn parse_optional<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
+    match matches.value_of(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::Message(format!("Invalid --{}: {}", name, value))),
+        None => Ok(None),
+    }
+}
+
+/// Verify that the engine can both write and read by touching a sentinel key of the check's
+/// own, which is removed again afterwards.
+fn health_check(engine: &dyn Engine) -> Result<()> {
+    let token = Uuid::new_v4().to_string();
+    let key = format!("{}{}", HEALTH_SENTINEL_PREFIX, token);
+    match engine.probe(key, token.clone())? {
+        Some(ref value) if *value == token => Ok(()),
+        Some(value) => Err(Error::Message(format!(
+            "Read back {:?}, expected {:?}",
+            value, token
+        ))),
+        None => Err(Error::Message(
+            "Sentinel key missing after write".to_owned(),
+        )),
+    }
+}
@@ -0,0 +1,209 @@
+//! An engine that keeps everything in memory within a fixed budget, evicting the least recently
+//! used keys to make room, like memcached. Nothing is persisted.
+use kvs::{BatchOp, CacheStats, Engine, Error, Result, Stats, WriteBatch};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Roughly what each entry costs on top of its key and value, for the two maps' nodes and the
+/// strings' headers.
+const ENTRY_OVERHEAD: usize = 96;
+
+struct CacheEntry {
+    value: String,
+    /// When the entry was last read or written, as a position in `recency`.
+    last_used: u64,
+}
+
+/// Keys and values in memory, evicted least recently used first once they'd take up more than
+/// the budget. A value too large to fit in the budget on its own isn't stored at all.
+pub struct CacheEngine {
+    cache: Mutex<Cache>,
+}
+
+/// The entries and their order, behind the engine's lock. Even reads change the order.
+struct Cache {
+    max_memory: usize,
+    used: usize,
+    entries: HashMap<String, CacheEntry>,
+    /// Every key by when it was last used, oldest first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    evictions: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheEngine {
+    /// A cache that holds at most `max_memory` bytes of keys and values, counting a fixed
+    /// overhead for each entry.
+    pub fn new(max_memory: usize) -> CacheEngine {
+        CacheEngine {
+            cache: Mutex::new(Cache {
+                max_memory,
+                used: 0,
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                evictions: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// The bytes the cache is counting against its budget.
+    pub fn used_memory(&self) -> usize {
+        self.cache.lock().unwrap().used
+    }
+
+    /// How many keys have been evicted to make room for others.
+    pub fn evictions(&self) -> u64 {
+        self.cache.lock().unwrap().evictions
+    }
+}
+
+impl Cache {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn insert(&mut self, key: String, value: String) {
+        self.delete(&key);
+        let size = cost(&key, &value);
+        if size > self.max_memory {
+            return;
+        }
+        while self.used + size > self.max_memory {
+            let oldest = self.recency.values().next().cloned().unwrap();
+            self.delete(&oldest);
+            self.evictions += 1;
+        }
+        let last_used = self.next_tick();
+        self.recency.insert(last_used, key.clone());
+        self.used += size;
+        self.entries.insert(key, CacheEntry { value, last_used });
+    }
+
+    fn delete(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.recency.remove(&entry.last_used);
+                self.used -= cost(key, &entry.value);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Engine for CacheEngine {
+    fn set(&self, key: String, value: String) -> Result<u64> {
+        kvs::validate_key(&key)?;
+        self.cache.lock().unwrap().insert(key, value);
+        Ok(0)
+    }
+
+    /// Marks the key as the most recently used.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let mut cache = self.cache.lock().unwrap();
+        let cache = &mut *cache;
+        let tick = cache.next_tick();
+        match cache.entries.get_mut(&key) {
+            Some(entry) => {
+                cache.recency.remove(&entry.last_used);
+                cache.recency.insert(tick, key);
+                entry.last_used = tick;
+                cache.hits += 1;
+                Ok(Some(entry.value.clone()))
+            }
+            None => {
+                cache.misses += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<u64> {
+        kvs::check_unreserved(&key)?;
+        if self.cache.lock().unwrap().delete(&key) {
+            Ok(0)
+        } else {
+            Err(Error::KeyNotFound)
+        }
+    }
+
+    /// Sets the key directly in the cache, since `set` refuses reserved keys.
+    fn probe(&self, key: String, value: String) -> Result<Option<String>> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(key.clone(), value);
+        let read = cache.entries.get(&key).map(|entry| entry.value.clone());
+        cache.delete(&key);
+        Ok(read)
+    }
+
+    /// Compares and sets under the cache's lock, so nothing else can write the key in between.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<(bool, Option<String>)> {
+        let mut cache = self.cache.lock().unwrap();
+        let current = cache.entries.get(&key).map(|entry| entry.value.clone());
+        if current != expected {
+            return Ok((false, current));
+        }
+        kvs::validate_key(&key)?;
+        cache.insert(key, value.clone());
+        Ok((true, Some(value)))
+    }
+
+    /// The cache itself is reported as the `values` cache, as well as the memtable.
+    fn stats(&self) -> Result<Stats> {
+        let cache = self.cache.lock().unwrap();
+        let mut stats = Stats {
+            memtable_entries: cache.entries.len() as u64,
+            memtable_bytes: cache.used as u64,
+            ..Stats::default()
+        };
+        let values = CacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            evictions: cache.evictions,
+            entries: cache.entries.len() as u64,
+            bytes: cache.used as u64,
+        };
+        stats.caches.insert("values".to_owned(), values);
+        Ok(stats)
+    }
+
+    /// Applies every write, though later ones can evict earlier ones.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        let entries = &cache.entries;
+        let ops =
+            batch.expand_renames(|key| Ok(entries.get(key).map(|entry| entry.value.clone())))?;
+        for op in ops.iter() {
+            match op {
+                BatchOp::Set { key, .. } => kvs::validate_key(key)?,
+                BatchOp::Remove { key } => kvs::check_unreserved(key)?,
+                BatchOp::Rename { .. } => unreachable!(),
+            }
+        }
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => cache.insert(key, value),
+                BatchOp::Remove { key } => {
+                    cache.delete(&key);
+                }
+                BatchOp::Rename { .. } => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn cost(key: &str, value: &str) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
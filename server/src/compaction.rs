@@ -0,0 +1,129 @@
+//! Deciding which pages automatic compaction merges next.
+//!
+//! Pages are grouped into levels: saves write pages at level 0, and each merge writes its pages
+//! as one run on a higher level. Merges always take every page of the levels involved, which
+//! sit next to each other in the index with higher levels first, so the newest value of a key
+//! is still found by reading the pages from newest to oldest.
+//!
+//! Before any of that, a run whose pages are mostly entries that newer pages have replaced or
+//! removed is rewritten on its own, since that frees the most space for the least work.
+use crate::options::{CompactionStrategy, Options};
+use std::collections::HashSet;
+use std::ops::Range;
+use uuid::Uuid;
+
+/// What planning needs to know about a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PageInfo {
+    pub level: u32,
+    pub run: Uuid,
+    pub entries: u16,
+    /// An estimate of how many of the entries newer pages have replaced or removed.
+    pub stale: u16,
+}
+
+/// Merge the pages at these positions in the index into a single run on `level`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Merge {
+    pub pages: Range<usize>,
+    pub level: u32,
+}
+
+/// The merge `options.compaction` calls for next, given each page in index order, if any.
+pub(crate) fn plan(options: &Options, pages: &[PageInfo]) -> Option<Merge> {
+    if options.compaction == CompactionStrategy::Manual {
+        return None;
+    }
+    if let Some(merge) = rewrite(options.compaction_garbage_ratio, pages) {
+        return Some(merge);
+    }
+
+    let fanout = options.compaction_fanout.max(2);
+    let top = pages.iter().map(|page| page.level).max()?;
+    for level in 0..=top {
+        let merge = match options.compaction {
+            CompactionStrategy::Manual => None,
+            // The full level's runs become one run on the next level
+            CompactionStrategy::SizeTiered if runs(pages, level) >= fanout => {
+                Some(span(pages, level, level))
+            }
+            CompactionStrategy::SizeTiered => None,
+            // The full level is merged into the next level's run
+            CompactionStrategy::Leveled => {
+                let full = if level == 0 {
+                    runs(pages, 0) >= fanout
+                } else {
+                    let capacity = fanout.saturating_pow(level);
+                    pages.iter().filter(|page| page.level == level).count() > capacity
+                };
+                if full {
+                    Some(span(pages, level + 1, level))
+                } else {
+                    None
+                }
+            }
+        };
+        if let Some(pages) = merge {
+            return Some(Merge {
+                pages,
+                level: level + 1,
+            });
+        }
+    }
+    None
+}
+
+/// The run with the biggest estimated share of replaced and removed entries, to be rewritten
+/// without them on the level it's on, if that share is at least `ratio`.
+fn rewrite(ratio: f64, pages: &[PageInfo]) -> Option<Merge> {
+    let mut best: Option<(f64, Merge)> = None;
+    let mut start = 0;
+    while start < pages.len() {
+        // A run's pages were all written by one merge, so they're next to each other
+        let run = pages[start].run;
+        let end = start
+            + pages[start..]
+                .iter()
+                .take_while(|page| page.run == run)
+                .count();
+        let entries: u64 = pages[start..end]
+            .iter()
+            .map(|page| page.entries as u64)
+            .sum();
+        let stale: u64 = pages[start..end].iter().map(|page| page.stale as u64).sum();
+        let share = stale as f64 / entries.max(1) as f64;
+        if stale > 0 && share >= ratio && best.as_ref().map_or(true, |(best, _)| share > *best) {
+            let merge = Merge {
+                pages: start..end,
+                level: pages[start].level,
+            };
+            best = Some((share, merge));
+        }
+        start = end;
+    }
+    best.map(|(_, merge)| merge)
+}
+
+/// How many runs have pages on the level.
+fn runs(pages: &[PageInfo], level: u32) -> usize {
+    pages
+        .iter()
+        .filter(|page| page.level == level)
+        .map(|page| page.run)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// The positions from the first page on level `oldest` or `newest` to the last one on level
+/// `newest`. Since higher levels come first, these are all the pages on the levels between.
+fn span(pages: &[PageInfo], oldest: u32, newest: u32) -> Range<usize> {
+    let first = pages
+        .iter()
+        .position(|page| page.level == oldest || page.level == newest)
+        .unwrap_or(0);
+    let last = pages
+        .iter()
+        .rposition(|page| page.level == newest)
+        .map_or(pages.len(), |i| i + 1);
+    first..last
+}
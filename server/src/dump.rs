@@ -0,0 +1,213 @@
+//! The line-per-key text format written by `KvStore::dump` and read back by
+//! `KvStore::load_dump`.
+use kvs::{Error, Result, StreamId};
+use logformat::record::{Bitmap, Lease, QueueItem, Record, SortedSet};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// How each line of a dump is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object per line.
+    Json,
+    /// One RON struct per line.
+    Ron,
+}
+
+impl Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpFormat::Json => write!(f, "json"),
+            DumpFormat::Ron => write!(f, "ron"),
+        }
+    }
+}
+
+impl FromStr for DumpFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "ron" => Ok(DumpFormat::Ron),
+            _ => Err(Error::Message(format!("Unknown dump format: {}", s))),
+        }
+    }
+}
+
+/// A key and its value, as one line of a dump.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpEntry {
+    pub key: String,
+    pub value: DumpValue,
+}
+
+/// A value in a form that reads well as text. Unlike `Record`, everything is keyed by strings,
+/// so it can be written as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DumpValue {
+    String(String),
+    Hash(BTreeMap<String, String>),
+    /// Members with their scores, from lowest score to highest.
+    Zset(Vec<(String, f64)>),
+    /// Entries by id, oldest first.
+    Stream(Vec<(String, String)>),
+    /// Unacknowledged items by id, oldest first.
+    Queue(Vec<(String, QueueItem)>),
+    Lock(Lease),
+    /// The offsets of the bits that are set.
+    Bitmap(Vec<u64>),
+}
+
+impl DumpEntry {
+    /// Parse a line of a dump in either format.
+    pub fn parse(line: &str) -> Result<DumpEntry> {
+        if line.starts_with('(') {
+            ron::de::from_str(line).map_err(Error::serialization)
+        } else {
+            serde_json::from_str(line).map_err(Error::serialization)
+        }
+    }
+}
+
+impl DumpValue {
+    /// Convert a full record. Merge operands have to be folded into their key's value first.
+    pub fn from_record(record: Record) -> Result<DumpValue> {
+        Ok(match record {
+            Record::Value(value) => DumpValue::String(value),
+            Record::Hash(fields) => DumpValue::Hash(fields),
+            Record::SortedSet(members) => DumpValue::Zset(members.into()),
+            Record::Stream(entries) => DumpValue::Stream(
+                entries
+                    .into_iter()
+                    .map(|(id, payload)| (id.to_string(), payload))
+                    .collect(),
+            ),
+            Record::Queue(items) => DumpValue::Queue(
+                items
+                    .into_iter()
+                    .map(|(id, item)| (id.to_string(), item))
+                    .collect(),
+            ),
+            Record::Lock(lease) => DumpValue::Lock(lease),
+            Record::Bitmap(bitmap) => DumpValue::Bitmap(bitmap.offsets()),
+            record => {
+                return Err(Error::Message(format!(
+                    "Cannot dump a {} merge operand",
+                    record.type_name()
+                )))
+            }
+        })
+    }
+
+    /// Convert back to a record, checking that it's one the store could have written.
+    pub fn into_record(self) -> Result<Record> {
+        let invalid = |message: &str| Err(Error::Message(message.to_owned()));
+        Ok(match self {
+            DumpValue::String(value) => Record::Value(value),
+            DumpValue::Hash(ref fields) if fields.is_empty() => return invalid("empty hash"),
+            DumpValue::Hash(fields) => Record::Hash(fields),
+            DumpValue::Zset(members) => {
+                if members.iter().any(|(_, score)| score.is_nan()) {
+                    return invalid("sorted set score is NaN");
+                }
+                Record::SortedSet(members.into_iter().collect::<SortedSet>())
+            }
+            DumpValue::Stream(entries) => Record::Stream(
+                entries
+                    .into_iter()
+                    .map(|(id, payload)| Ok((id.parse()?, payload)))
+                    .collect::<Result<_>>()?,
+            ),
+            DumpValue::Queue(items) => Record::Queue(
+                items
+                    .into_iter()
+                    .map(|(id, item)| Ok((id.parse()?, item)))
+                    .collect::<Result<_>>()?,
+            ),
+            DumpValue::Lock(lease) => Record::Lock(lease),
+            DumpValue::Bitmap(offsets) => {
+                let mut bitmap = Bitmap::default();
+                for offset in offsets {
+                    bitmap.set(offset, true);
+                }
+                Record::Bitmap(bitmap)
+            }
+        })
+    }
+}
+
+/// What `KvStore::load_dump` does with a key that already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the existing value.
+    Overwrite,
+    /// Keep the existing value.
+    Skip,
+    /// Stop loading without writing any of the dump.
+    Fail,
+}
+
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::Overwrite => write!(f, "overwrite"),
+            ConflictPolicy::Skip => write!(f, "skip"),
+            ConflictPolicy::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "fail" => Ok(ConflictPolicy::Fail),
+            _ => Err(Error::Message(format!("Unknown conflict policy: {}", s))),
+        }
+    }
+}
+
+/// The outcome of loading a dump.
+#[derive(Debug, Default, Clone)]
+pub struct LoadReport {
+    pub loaded: u64,
+    /// Keys that already existed and were left alone.
+    pub skipped: u64,
+    /// A description of every line that couldn't be loaded, with its line number.
+    pub errors: Vec<String>,
+}
+
+impl LoadReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "loaded {} keys", self.loaded)?;
+        if self.skipped > 0 {
+            write!(f, ", skipped {} existing keys", self.skipped)?;
+        }
+        for error in self.errors.iter() {
+            write!(f, "\n{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// The largest stream or queue id in the record, if it has any.
+pub fn max_stream_id(record: &Record) -> Option<StreamId> {
+    match record {
+        Record::Stream(entries) => entries.keys().next_back().cloned(),
+        Record::Queue(items) => items.keys().next_back().cloned(),
+        _ => None,
+    }
+}
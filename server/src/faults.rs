@@ -0,0 +1,110 @@
+//! I/O fault injection for tests, enabled with the `failpoints` feature. Unlike failpoints,
+//! which fail one named step, these slow down or break every read and write of page, data, and
+//! index files, to exercise the paths that have to cope with a flaky disk.
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The raw OS error for an I/O error on Linux and macOS.
+const EIO: i32 = 5;
+
+/// Faults injected into the store's file I/O, set with `Options::io_faults`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoFaults {
+    /// How long every read, write, and sync waits before it starts.
+    pub latency: Duration,
+    /// The most bytes a single read returns, so that readers have to handle short reads. At
+    /// least one byte is always returned, unless the file has ended.
+    pub max_read: Option<usize>,
+    /// Fail every nth read, write, or sync with `EIO`, counting across all files. The failure
+    /// is transient: trying again succeeds.
+    pub error_every: Option<u64>,
+}
+
+/// The faults and a count of the operations they've been applied to, shared by every file the
+/// store opens.
+#[derive(Debug)]
+pub(crate) struct FaultInjector {
+    faults: IoFaults,
+    operations: AtomicU64,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(faults: IoFaults) -> Arc<FaultInjector> {
+        Arc::new(FaultInjector {
+            faults,
+            operations: AtomicU64::new(0),
+        })
+    }
+
+    /// Wait out the latency, then fail if this is one of the operations that should.
+    fn before_operation(&self) -> io::Result<()> {
+        if self.faults.latency > Duration::from_secs(0) {
+            thread::sleep(self.faults.latency);
+        }
+        let operation = self.operations.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.faults.error_every {
+            Some(every) if every > 0 && operation % every == 0 => {
+                Err(io::Error::from_raw_os_error(EIO))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A page, data, or index file, with faults injected if the store has any configured.
+#[derive(Debug)]
+pub(crate) struct FaultyFile {
+    file: File,
+    injector: Option<Arc<FaultInjector>>,
+}
+
+impl FaultyFile {
+    pub(crate) fn new(file: File, injector: Option<Arc<FaultInjector>>) -> FaultyFile {
+        FaultyFile { file, injector }
+    }
+
+    fn before_operation(&self) -> io::Result<()> {
+        match &self.injector {
+            Some(injector) => injector.before_operation(),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        self.before_operation()?;
+        self.file.sync_all()
+    }
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.before_operation()?;
+        let len = match self.injector.as_ref().and_then(|i| i.faults.max_read) {
+            Some(max_read) => cmp::min(buf.len(), cmp::max(max_read, 1)),
+            None => buf.len(),
+        };
+        self.file.read(&mut buf[..len])
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.before_operation()?;
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
@@ -0,0 +1,131 @@
+//! Functions the application registers with `KvStore::on_set` and `KvStore::on_remove`, to
+//! enforce invariants on what's written or to pass changes on to something else.
+use crate::parallel::WorkerPool;
+use kvs::Result;
+use logformat::record::Record;
+use slog::Logger;
+use std::sync::Arc;
+
+pub(crate) type SetHookFn = dyn Fn(&str, &str) -> Result<()> + Send + Sync;
+pub(crate) type RemoveHookFn = dyn Fn(&str) -> Result<()> + Send + Sync;
+
+/// When a hook is called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookMode {
+    /// On the writing thread, before anything is written, while the store is locked. A hook
+    /// that returns an error vetoes the write, which fails with that error, along with the rest
+    /// of its batch or transaction.
+    Sync,
+    /// On a background thread, once the write has been applied, in the order writes were
+    /// applied. An error it returns is only logged.
+    Async,
+}
+
+/// A write as the hooks see it: a key and its new value, or `None` if it was removed.
+pub(crate) type Change = (String, Option<String>);
+
+/// The change hooks are called with for a write, if it's one they're called for: a string
+/// value being set, or a key being removed. Writes to the server's own keys, like index
+/// entries, don't call them.
+pub(crate) fn change(key: &str, record: Option<&Record>) -> Option<Change> {
+    if kvs::is_reserved_key(key) {
+        return None;
+    }
+    match record {
+        Some(Record::Value(value)) => Some((key.to_owned(), Some(value.clone()))),
+        Some(_) => None,
+        None => Some((key.to_owned(), None)),
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Hooks {
+    on_set: Vec<(HookMode, Arc<SetHookFn>)>,
+    on_remove: Vec<(HookMode, Arc<RemoveHookFn>)>,
+    /// Calls the async hooks, one change at a time. Started when the first is registered, and
+    /// waited for when the store is dropped.
+    worker: Option<WorkerPool>,
+}
+
+impl Hooks {
+    pub fn on_set(&mut self, mode: HookMode, f: Arc<SetHookFn>) {
+        self.start_worker(mode);
+        self.on_set.push((mode, f));
+    }
+
+    pub fn on_remove(&mut self, mode: HookMode, f: Arc<RemoveHookFn>) {
+        self.start_worker(mode);
+        self.on_remove.push((mode, f));
+    }
+
+    fn start_worker(&mut self, mode: HookMode) {
+        if mode == HookMode::Async && self.worker.is_none() {
+            self.worker = Some(WorkerPool::new(1));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.on_set.is_empty() && self.on_remove.is_empty()
+    }
+
+    /// Call the sync hooks for each change, stopping at the first that vetoes one.
+    pub fn before(&self, changes: &[Change]) -> Result<()> {
+        for (key, value) in changes {
+            match value {
+                Some(value) => {
+                    for (_, f) in self
+                        .on_set
+                        .iter()
+                        .filter(|(mode, _)| *mode == HookMode::Sync)
+                    {
+                        f(key, value)?;
+                    }
+                }
+                None => {
+                    for (_, f) in self
+                        .on_remove
+                        .iter()
+                        .filter(|(mode, _)| *mode == HookMode::Sync)
+                    {
+                        f(key)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Have the async hooks called for each change.
+    pub fn after(&self, changes: Vec<Change>, logger: &Logger) {
+        let worker = match &self.worker {
+            Some(worker) if !changes.is_empty() => worker,
+            _ => return,
+        };
+        let on_set: Vec<Arc<SetHookFn>> = self
+            .on_set
+            .iter()
+            .filter(|(mode, _)| *mode == HookMode::Async)
+            .map(|(_, f)| f.clone())
+            .collect();
+        let on_remove: Vec<Arc<RemoveHookFn>> = self
+            .on_remove
+            .iter()
+            .filter(|(mode, _)| *mode == HookMode::Async)
+            .map(|(_, f)| f.clone())
+            .collect();
+        let logger = logger.clone();
+        worker.execute(move || {
+            for (key, value) in changes {
+                let results: Vec<Result<()>> = match &value {
+                    Some(value) => on_set.iter().map(|f| f(&key, value)).collect(),
+                    None => on_remove.iter().map(|f| f(&key)).collect(),
+                };
+                for result in results {
+                    if let Err(e) = result {
+                        warn!(logger, "A hook failed for {:?}: {}", key, e);
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,116 @@
+//! Remembers the responses to requests sent with an idempotency token, so that a client retrying
+//! a write it never heard back about gets the original answer instead of applying it twice.
+use kvs::{CommandRequest, CommandResponse};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+/// What the cache knows about a request's token, as returned by `IdempotencyCache::claim`.
+#[derive(Debug)]
+pub enum Claim {
+    /// The token hasn't been seen, and is now held for the request until `finish` is called.
+    New,
+    /// A request with the token was applied, and this was its response.
+    Applied(CommandResponse),
+    /// A request with the token is still being applied.
+    InFlight,
+    /// The token was sent with a different request.
+    Mismatch,
+}
+
+/// A token as sent by one client.
+type ClientToken = (String, String);
+
+/// What's known about one client's token.
+struct Entry {
+    /// Tells the request the token was first sent with from any other.
+    fingerprint: u64,
+    /// `None` while the request is being applied.
+    response: Option<CommandResponse>,
+}
+
+/// The responses to the most recent requests with idempotency tokens. A token only stands for
+/// the request it was first sent with, and only for the client that sent it, so clients that
+/// happen to pick the same token don't see each other's responses. Once it's full, the oldest
+/// token is forgotten for each new one.
+pub struct IdempotencyCache {
+    capacity: usize,
+    entries: HashMap<ClientToken, Entry>,
+    /// Tokens from oldest to newest.
+    order: VecDeque<ClientToken>,
+}
+
+impl IdempotencyCache {
+    /// Remember up to `capacity` tokens.
+    pub fn new(capacity: usize) -> IdempotencyCache {
+        IdempotencyCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Look up the token `client` sent with `request`. If it's new, it's held as in flight, so
+    /// that a retry sent while the request is still being applied isn't applied as well.
+    pub fn claim(&mut self, client: &str, token: &str, request: &CommandRequest) -> Claim {
+        let fingerprint = fingerprint(request);
+        let key = (client.to_owned(), token.to_owned());
+        match self.entries.get(&key) {
+            Some(entry) if entry.fingerprint != fingerprint => return Claim::Mismatch,
+            Some(Entry {
+                response: Some(response),
+                ..
+            }) => return Claim::Applied(response.clone()),
+            Some(Entry { response: None, .. }) => return Claim::InFlight,
+            None => {}
+        }
+        if self.capacity > 0 {
+            self.entries.insert(
+                key.clone(),
+                Entry {
+                    fingerprint,
+                    response: None,
+                },
+            );
+            self.order.push_back(key);
+            while self.order.len() > self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        Claim::New
+    }
+
+    /// Release the token `claim` held, remembering the response to replay for retries. Without
+    /// one, because the request failed, the token is forgotten so the request can be retried.
+    pub fn finish(&mut self, client: &str, token: &str, response: Option<CommandResponse>) {
+        let key = (client.to_owned(), token.to_owned());
+        match (self.entries.get_mut(&key), response) {
+            (Some(entry), Some(response)) => entry.response = Some(response),
+            (Some(_), None) => {
+                self.entries.remove(&key);
+                self.order.retain(|queued| *queued != key);
+            }
+            // Pushed out by newer tokens while the request was being applied
+            (None, _) => {}
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// A hash of the request, to tell whether a token is being sent with the same one again.
+fn fingerprint(request: &CommandRequest) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bincode::serialize(request)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish()
+}
@@ -1,18 +1,65 @@
+use crate::backup::{self, BackupSink, Checksums, FileChecksum, CHECKSUMS_FILE};
+use crate::compaction::{self, PageInfo};
+use crate::dump::{self, ConflictPolicy, DumpEntry, DumpFormat, DumpValue, LoadReport};
+#[cfg(feature = "failpoints")]
+use crate::faults::{FaultInjector, FaultyFile};
+use crate::hooks::{self, Change, HookMode, Hooks};
+use crate::locks::LockTable;
+use crate::memtable::Memtable;
+use crate::options::{Durability, Options};
+use crate::parallel::{self, WorkerPool};
+use crate::readers::ReaderCache;
+use crate::sealed::Sealed;
+use crate::secondary_index::{self, SecondaryIndex, NGRAM_KEY_PREFIX, NGRAM_LEN};
+use crate::sst::{self, SstWriter};
+use crate::txn::{self, KeyVersions, ScopedTransaction, Transaction};
 use bincode;
-use kvs::{self, Error, Result};
+use fs2::FileExt;
+use kvs::{
+    self, BatchOp, CancelToken, Error, ProgressFn, ProgressTracker, Result, ResultExt, Stats,
+    StreamId, VerifyReport, WriteBatch,
+};
 use logformat::index::Index;
-use logformat::page::{Page, PageBody, PageBuffer, PageHeader, BUF_SIZE, COMMANDS_PER_PAGE};
+use logformat::manifest::{
+    HashAlgorithm, KeyHash, Manifest, PageLevel, SizeHistogram, DEFAULT_HASH_SEED, FORMAT_VERSION,
+    PAGE_CHECKSUMS_VERSION,
+};
+use logformat::page::{
+    ClockContext, Page, PageBody, PageBuffer, PageHeader, SlotKey, COMMANDS_PER_PAGE,
+};
+use logformat::record::{
+    decode_entry, encode_entry, Bitmap, Lease, QueueOp, Record, SortedSet, RECORD_TAG,
+};
+use logformat::segment::SegmentLocation;
 use logformat::slotted::Slotted;
 use metrohash::MetroHash64;
+use ron::ser::PrettyConfig;
+use siphasher::sip::SipHasher24;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use sled::Db;
 use slog::Logger;
 use std::cmp::{self, Ordering};
-use std::collections::{BTreeMap, HashMap};
-use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
 use std::hash::{Hash, Hasher};
-use std::io::{BufReader, Seek, SeekFrom};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Bound, Range, RangeInclusive};
 use std::path::{Path, PathBuf};
-use uuid::{v1, Uuid};
+use std::process;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec;
+use twox_hash::XxHash64;
+use uuid::Uuid;
+
+/// Page, data, and index files are read and written through this, so that tests can inject
+/// faults into them.
+#[cfg(feature = "failpoints")]
+type StoreFile = FaultyFile;
+#[cfg(not(feature = "failpoints"))]
+type StoreFile = File;
 
 pub struct SledEngine {
     pub db: Db,
@@ -23,13 +70,14 @@ impl Drop for SledEngine {
 }
 
 impl kvs::Engine for SledEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&self, key: String, value: String) -> Result<u64> {
+        kvs::validate_key(&key)?;
         self.db.insert(key, value.as_bytes())?;
         self.db.flush()?;
-        Ok(())
+        Ok(0)
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+    fn get(&self, key: String) -> Result<Option<String>> {
         let result = self
             .db
             .get(key)
@@ -38,27 +86,352 @@ impl kvs::Engine for SledEngine {
         Ok(result)
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&self, key: String) -> Result<u64> {
+        kvs::check_unreserved(&key)?;
         let result = if let None = self.db.remove(key)? {
             Err(Error::KeyNotFound)
         } else {
-            Ok(())
+            Ok(0)
         };
         self.db.flush()?;
         result
     }
+
+    /// Uses sled's own compare-and-swap, so nothing else can write the key in between.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> Result<(bool, Option<String>)> {
+        kvs::validate_key(&key)?;
+        let expected = expected.as_ref().map(|expected| expected.as_bytes());
+        let result = self
+            .db
+            .compare_and_swap(key.as_bytes(), expected, Some(value.as_bytes()))?;
+        self.db.flush()?;
+        Ok(match result {
+            Ok(()) => (true, Some(value)),
+            Err(e) => (
+                false,
+                e.current
+                    .map(|current| String::from_utf8_lossy(&current).into_owned()),
+            ),
+        })
+    }
+
+    /// Applied in a sled transaction, so the values that renames move are read in the same
+    /// transaction that writes them.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let sources: Vec<String> = batch
+            .clone()
+            .into_ops()
+            .into_iter()
+            .filter_map(|op| match op {
+                BatchOp::Rename { from, .. } => Some(from),
+                _ => None,
+            })
+            .collect();
+        let result = self.db.transaction(|tree| {
+            let mut values = HashMap::new();
+            for key in sources.iter() {
+                let value = tree
+                    .get(key.as_bytes())?
+                    .map(|value| String::from_utf8_lossy(&value).into_owned());
+                values.insert(key.as_str(), value);
+            }
+            let ops = batch
+                .clone()
+                .expand_renames(|key| Ok(values[key].clone()))
+                .map_err(ConflictableTransactionError::Abort)?;
+            for op in ops {
+                match op {
+                    BatchOp::Set { key, value } => {
+                        kvs::validate_key(&key).map_err(ConflictableTransactionError::Abort)?;
+                        tree.insert(key.as_bytes(), value.as_bytes())?;
+                    }
+                    BatchOp::Remove { key } => {
+                        kvs::check_unreserved(&key).map_err(ConflictableTransactionError::Abort)?;
+                        tree.remove(key.as_bytes())?;
+                    }
+                    BatchOp::Rename { .. } => unreachable!(),
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {}
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Writes straight to sled, since `set` refuses reserved keys.
+    fn probe(&self, key: String, value: String) -> Result<Option<String>> {
+        self.db.insert(key.as_bytes(), value.as_bytes())?;
+        let read = self
+            .db
+            .get(key.as_bytes())?
+            .map(|value| String::from_utf8_lossy(&value).into_owned());
+        self.db.remove(key.as_bytes())?;
+        self.db.flush()?;
+        Ok(read)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let limit = limit.unwrap_or(usize::max_value());
+        self.sled_range(start, end).take(limit).collect()
+    }
+
+    fn scan_rev(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let limit = limit.unwrap_or(usize::max_value());
+        self.sled_range(start, end).rev().take(limit).collect()
+    }
+}
+
+impl SledEngine {
+    /// The keys from `start` up to but not including `end` with their values, skipping the
+    /// server's own keys.
+    fn sled_range(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+    ) -> impl DoubleEndedIterator<Item = Result<(String, String)>> {
+        let start = start.map_or(Bound::Unbounded, |key| Bound::Included(key.into_bytes()));
+        let end = end.map_or(Bound::Unbounded, |key| Bound::Excluded(key.into_bytes()));
+        self.db
+            .range((start, end))
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((
+                    String::from_utf8_lossy(&key).into_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                ))
+            })
+            .filter(|entry| match entry {
+                Ok((key, _)) => !kvs::is_reserved_key(key),
+                Err(_) => true,
+            })
+    }
+}
+
+/// Where a page's blocks are on disk.
+#[derive(Clone)]
+enum PageFiles {
+    /// A page file and a data file of its own, as `save` writes them.
+    Separate([PathBuf; 2]),
+    /// Packed into a segment file by compaction.
+    Segment(PathBuf, SegmentLocation),
+}
+
+impl PageFiles {
+    /// The file the page block is in, and where it starts.
+    fn page(&self) -> (&Path, u64) {
+        match self {
+            PageFiles::Separate([path, _]) => (path, 0),
+            PageFiles::Segment(path, location) => (path, location.offset),
+        }
+    }
+
+    /// The file the data block is in, where it starts, and how long it is.
+    fn data(&self) -> (&Path, u64, u64) {
+        match self {
+            PageFiles::Separate([_, path]) => (path, 0, u64::max_value()),
+            PageFiles::Segment(path, location) => (path, location.data_offset(), location.data_len),
+        }
+    }
+
+    /// How many bytes of its files the page takes up, or 0 if they can't be found.
+    fn disk_len(&self) -> u64 {
+        match self {
+            PageFiles::Separate(paths) => paths
+                .iter()
+                .filter_map(|path| fs::metadata(path).ok())
+                .map(|metadata| metadata.len())
+                .sum(),
+            PageFiles::Segment(_, location) => location.end() - location.offset,
+        }
+    }
+}
+
+/// A segment file being written by compaction.
+struct SegmentWriter {
+    id: Uuid,
+    writer: BufWriter<StoreFile>,
+    len: u64,
+}
+
+/// Opens page, data, segment, and index files, injecting any faults the options ask for, and
+/// checking them against their checksums if the directory is sealed. It's cheap to clone, so
+/// that pages can be read on other threads.
+#[derive(Clone)]
+struct FileOpener {
+    #[cfg(feature = "failpoints")]
+    fault_injector: Option<Arc<FaultInjector>>,
+    sealed: Option<Arc<Sealed>>,
+}
+
+/// A key hash with the key, if it was written with one, and its full value, or none if the key
+/// was removed.
+type MergedEntry = (u64, Option<String>, Option<Record>);
+
+/// The store's pages at one moment, newest first, which can be read from any thread.
+/// Compaction's merge workers each read the pages through a clone of one.
+#[derive(Clone)]
+struct PageSet {
+    files: FileOpener,
+    pages: Arc<Vec<(PageFiles, PageHeader)>>,
+    deadline: Option<Instant>,
+    cancel: Option<CancelToken>,
+    /// Counts the pages read, if they're being read for something that reports its progress.
+    progress: Option<Arc<ProgressTracker>>,
+    /// Searches the pages for a key all at once, for a lookup finished without the lock.
+    lookup_pool: Option<Arc<WorkerPool>>,
+    /// The store's open readers, for a lookup finished without the lock to reuse.
+    readers: Option<SharedReaders>,
+}
+
+/// Open page, data, and segment files, shared by reads made with and without the store's lock.
+type SharedReaders = Arc<Mutex<ReaderCache<PathBuf, BufReader<StoreFile>>>>;
+
+/// How far `StoreState::start_resolve` got with a key.
+enum Resolving {
+    /// The memtable had the key's whole value, or its removal.
+    Done(Option<Record>),
+    /// The rest is to be read from the pages, without the lock.
+    Pages(PendingRead),
+}
+
+/// A read of a key that's left to finish from the pages once the handle's lock is released.
+struct PendingRead {
+    key: InMemoryKey,
+    /// The key's merge operands in the memtable, newest first, to fold onto what the pages hold.
+    operands: Vec<Record>,
+    /// The pages whose ranges cover the key's hash, as they were when the read started.
+    pages: PageSet,
+    /// Keeps compaction from deleting the files of the pages until the read is done.
+    _pin: Arc<()>,
+}
+
+/// A read-only view of a `KvStore` as of one write, taken with `KvStore::read_snapshot`. Later
+/// writes and compactions don't change what it reads. It only reads pages, which are never
+/// changed once written, so it doesn't borrow the store and can be read from another thread.
+pub struct Snapshot {
+    pages: PageSet,
+    key_hash: KeyHash,
+    sequence: u64,
+    /// Keeps compaction from deleting the files of the pages while the snapshot is open.
+    _pin: Arc<()>,
+}
+
+/// The entries for a key hash in the pages that might hold it, newest first.
+struct Lookup {
+    key_hash: u64,
+    /// The key's length and fingerprint, to pass over slots of other keys with the same hash,
+    /// if the key itself is known.
+    key: Option<SlotKey>,
+    /// The pages left to search, newest first.
+    pages: vec::IntoIter<Uuid>,
+    /// What searching each page found, if the lookup pool has already searched all of them at
+    /// once.
+    searched: Option<vec::IntoIter<(Uuid, Result<Option<i16>>)>>,
+}
+
+impl FileOpener {
+    fn open(&self, options: &OpenOptions, path: &Path) -> io::Result<StoreFile> {
+        if let Some(sealed) = &self.sealed {
+            sealed.check(path)?;
+        }
+        let file = options.open(path)?;
+        #[cfg(feature = "failpoints")]
+        let file = FaultyFile::new(file, self.fault_injector.clone());
+        Ok(file)
+    }
 }
 
+/// A handle on an open store. Clones share the store, so it can be used from several threads
+/// at once; their calls take turns. The store is closed once every handle is dropped.
+#[derive(Clone)]
 pub struct KvStore {
+    state: Arc<Mutex<StoreState>>,
+    /// The deadline, cancel token, and progress callback each thread has set for its requests.
+    requests: Arc<Mutex<HashMap<ThreadId, RequestScope>>>,
+}
+
+/// What a thread has set with `Engine::set_deadline` and the like, for the request it's serving.
+#[derive(Clone, Default)]
+struct RequestScope {
+    deadline: Option<Instant>,
+    cancel: Option<CancelToken>,
+    progress: Option<ProgressFn>,
+}
+
+/// Everything about an open store, behind its handles' lock.
+pub(crate) struct StoreState {
     log_path: PathBuf,
     index: Index,
-    page_readers: HashMap<Uuid, BufReader<File>>,
-    data_readers: HashMap<Uuid, BufReader<File>>,
-    in_memory: BTreeMap<InMemoryKey, Option<String>>,
+    /// Open page, data, and segment files, up to `options.max_open_files` of them.
+    readers: SharedReaders,
+    in_memory: Memtable,
     page_buffer: PageBuffer,
     node_id: [u8; 6],
-    context: v1::Context,
+    context: ClockContext,
+    manifest: Manifest,
     slog: Logger,
+    /// Holds an exclusive lock on the directory for as long as the store is open, unless it's
+    /// open read-only.
+    _lock_file: Option<File>,
+    options: Options,
+    /// Where committed pages are copied to, if `options.archive_dir` is set.
+    archive: Option<Box<dyn BackupSink>>,
+    /// When the memtable first had changes that haven't been written to a page yet, if it has
+    /// any.
+    dirty_since: Option<SystemTime>,
+    /// When a read-only store last re-read the manifest and index.
+    refreshed_at: SystemTime,
+    /// The checksums of page and data files by file name. They never change once written, so
+    /// each is only read once however many snapshots it ends up in.
+    page_checksums: HashMap<String, FileChecksum>,
+    /// The latest write to each recently written key, for validating transactions.
+    versions: KeyVersions,
+    /// The keys locked by open transactions.
+    locks: LockTable,
+    /// The id of the most recently started transaction.
+    last_transaction_id: u64,
+    /// When reads that go through many pages give up, if the current request has a deadline.
+    deadline: Option<Instant>,
+    /// Makes the same reads give up once it's cancelled, if the current request has one.
+    cancel: Option<CancelToken>,
+    /// Where compaction, verification, snapshots, dumps, and imports report how far they've got.
+    progress: Option<ProgressFn>,
+    /// Opens page, data, and index files, injecting `options.io_faults` into them.
+    files: FileOpener,
+    /// Searches the pages for a key all at once, if `options.lookup_threads` is more than one.
+    lookup_pool: Option<Arc<WorkerPool>>,
+    /// Called on writes, as registered with `on_set` and `on_remove`.
+    hooks: Hooks,
+    /// Cloned into every `Snapshot`, so that compaction can tell whether any are open.
+    snapshots: Arc<()>,
+    /// Files that compaction replaced while a snapshot was open, which it might still read.
+    /// They're deleted once no snapshot is.
+    retained_files: Vec<PathBuf>,
 }
 
 /// Holds the key with its hash, ordered by the hash.
@@ -69,10 +442,8 @@ pub struct InMemoryKey {
 }
 
 impl InMemoryKey {
-    pub fn new(key: String) -> Self {
-        let mut hasher = MetroHash64::with_seed(METROHASH_SEED);
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
+    pub fn new(key: String, key_hash: &KeyHash) -> Self {
+        let hash = hash_key(key_hash, &key);
         InMemoryKey { key, hash }
     }
 }
@@ -89,94 +460,278 @@ impl PartialOrd for InMemoryKey {
     }
 }
 
-const METROHASH_SEED: u64 = 0x385f_829f_0031_3111;
+/// How far to replay archived pages when restoring to a point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryTarget {
+    /// Replay every page written at or before this time.
+    Time(SystemTime),
+    /// Replay every page up to and including this page sequence number.
+    Sequence(u64),
+}
+
+/// The name of the file locked by the process that has the directory open.
+const LOCK_FILE: &str = "LOCK";
+
+/// How many times `KvStore::transaction` runs its closure before giving up on a transaction
+/// that keeps conflicting.
+pub const MAX_TRANSACTION_ATTEMPTS: usize = 10;
+
+/// How often a call with a deadline or cancel token checks it while waiting for the lock.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The subdirectory that unreadable pages are moved into when the store is opened.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// The subdirectory that files not referenced by the index are moved into.
+const LOST_AND_FOUND_DIR: &str = "lost+found";
 
 impl kvs::Engine for KvStore {
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> kvs::Result<()> {
-        if let Err(e) = self.push(key, Some(value)) {
-            Err(kvs::Error::Message(format!("{}", e)))
-        } else {
-            Ok(())
-        }
+    fn set(&self, key: String, value: String) -> kvs::Result<u64> {
+        self.state()?.set(key, value)
     }
 
     /// Gets the string value of a given string key.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> kvs::Result<Option<String>> {
-        trace!(self.slog, "Getting {}", &key);
-        let key_with_hash = InMemoryKey::new(key);
-        if let Some(maybe_value) = self.in_memory.get(&key_with_hash) {
-            if let Some(value) = maybe_value {
-                trace!(self.slog, "Found {} in memory", value);
-                return Ok(Some(value.to_string()));
-            } else {
-                trace!(self.slog, "Found None in memory");
-                return Ok(None);
-            }
-        }
+    /// Returns `None` if the given key does not exist. Only the memtable is read with the store
+    /// locked; the pages are read once it's unlocked, so other calls aren't held up behind them.
+    fn get(&self, key: String) -> kvs::Result<Option<String>> {
+        string_value(self.resolve(key)?)
+    }
 
-        let key_hash = key_with_hash.hash;
-        let len = self.index.len();
-        for i in 0..len {
-            let header = self.index.get(len - i - 1).unwrap();
-            let uuid = header.uuid;
-            if header.min_key_hash <= key_hash && key_hash <= header.max_key_hash {
-                let page = self.read_page(&uuid);
-                if let Err(e) = page {
-                    return Err(kvs::Error::Message(format!("{}", e)));
-                }
-                let page = page.unwrap();
+    /// Remove a given key.
+    ///
+    /// Unless `Options::check_exists_on_remove` is turned off, the key must exist. Checking only
+    /// reads the memtable and page files, never the data files.
+    fn remove(&self, key: String) -> kvs::Result<u64> {
+        self.state()?.remove(key)
+    }
 
-                trace!(self.slog, "Reading page {:?}", &page.header);
-                for (index, hash) in page.body.key_hash[..].iter().enumerate() {
-                    // FIXME: use binary search
-                    if hash != &key_hash {
-                        continue;
-                    }
+    /// Reads and writes under one lock, bypassing the checks that keep reserved keys from
+    /// being written.
+    fn probe(&self, key: String, value: String) -> kvs::Result<Option<String>> {
+        self.state()?.probe(key, value)
+    }
 
-                    let value_index = page.body.value_index[index];
-                    if value_index < 0 {
-                        return Ok(None);
-                    }
+    /// Pages are ordered by key hash rather than by key, so every page is read, and the keys in
+    /// the range are sorted. Any unsaved writes are saved first, and the pages are then read
+    /// without the store locked.
+    fn scan(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> kvs::Result<Vec<(String, String)>> {
+        let view = self.state()?.current_view()?;
+        view.scan(start, end, limit)
+    }
 
-                    let data = self.read_data(&uuid);
-                    if let Err(e) = data {
-                        return Err(kvs::Error::Message(format!("{}", e)));
-                    }
-                    let mut data = data.unwrap();
-                    let bytes = data.get(value_index as usize).expect("bad index");
-                    let value = String::from_utf8_lossy(bytes).into_owned();
-                    trace!(self.slog, "Found {} on disk", value);
-                    return Ok(Some(value));
-                }
-            }
+    fn scan_rev(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> kvs::Result<Vec<(String, String)>> {
+        let view = self.state()?.current_view()?;
+        view.scan_rev(start, end, limit)
+    }
+
+    /// Apply the batch in a single commit. The writes all go into the memtable together, so
+    /// even under `Durability::Buffered` it's saved either whole or not at all.
+    fn write_batch(&self, batch: WriteBatch) -> kvs::Result<()> {
+        self.state()?.write_batch(batch)
+    }
+
+    /// Reads and writes under one lock, so nothing else can write the key in between.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        value: String,
+    ) -> kvs::Result<(bool, Option<String>)> {
+        let mut state = self.state()?;
+        let current = state.get(key.clone())?;
+        if current != expected {
+            return Ok((false, current));
         }
+        state.set(key, value.clone())?;
+        Ok((true, Some(value)))
+    }
 
-        trace!(self.slog, "Key not found");
-        Ok(None)
+    /// Runs the whole script under one lock, so nothing else can write a key between the script
+    /// reading it and its writes being applied.
+    fn run_script(&self, script: &str, args: &[String]) -> kvs::Result<Option<String>> {
+        let mut state = self.state()?;
+        kvs::run_script(&LockedStore(Mutex::new(&mut *state)), script, args)
     }
 
-    /// Remove a given key.
-    fn remove(&mut self, key: String) -> kvs::Result<()> {
-        if let Ok(Some(_)) = self.get(key.clone()) {
-            if let Err(e) = self.push(key, None) {
-                Err(kvs::Error::Message(format!("{}", e)))
-            } else {
-                Ok(())
-            }
-        } else {
-            Err(kvs::Error::KeyNotFound)
+    /// Checked before reading each page in lookups, scans, compaction, and verification. Keys
+    /// in the memtable can always be read.
+    fn set_deadline(&self, deadline: Option<Instant>) {
+        self.set_request(|request| request.deadline = deadline);
+    }
+
+    /// Checked wherever the deadline is. A write batch is applied all at once, so it can only
+    /// be cancelled before any of it is.
+    fn set_cancel_token(&self, token: Option<CancelToken>) {
+        self.set_request(|request| request.cancel = token);
+    }
+
+    /// Compaction counts the pages it reads, verification the pages it checks, and snapshots
+    /// the files they copy. Dumps and imports, which aren't part of `Engine`, count keys.
+    fn set_progress(&self, progress: Option<ProgressFn>) {
+        self.set_request(|request| request.progress = progress);
+    }
+
+    /// Saves the memtable, like dropping the store does, but reporting errors.
+    fn flush(&self) -> kvs::Result<()> {
+        self.state()?.flush()
+    }
+
+    /// Saves the memtable if it has had unsaved writes for `Options::flush_interval`. A
+    /// read-only store refreshes instead, once `Options::refresh_interval` has passed.
+    fn flush_if_due(&self) -> kvs::Result<()> {
+        self.state()?.flush_if_due()
+    }
+
+    /// Every write is numbered, including ones still in the memtable. The numbering continues
+    /// across restarts from the last committed write.
+    fn applied_sequence(&self) -> kvs::Result<u64> {
+        self.state()?.applied_sequence()
+    }
+
+    /// Merge every page into a fresh set of full pages, keeping only the newest value for each
+    /// key and dropping removed keys entirely. The new pages become a single run on the highest
+    /// level, whatever `options.compaction` is.
+    fn compact(&self) -> kvs::Result<()> {
+        self.state()?.compact()
+    }
+
+    /// Check that every page in the index can be read back and agrees with its data file.
+    fn verify(&self) -> kvs::Result<VerifyReport> {
+        self.state()?.verify()
+    }
+
+    fn stats(&self) -> kvs::Result<Stats> {
+        self.state()?.stats()
+    }
+
+    /// Copy the index and every live page into `path`, which must not exist yet.
+    ///
+    /// Page, data, and segment files are never changed once written, so if `path` is on the same
+    /// filesystem they're hard-linked into it instead of copied, and only the index and
+    /// manifest are copied. `path` can also be an `s3://bucket/prefix` URL if the server was
+    /// built with the `object-store` feature. Either way, a `CHECKSUMS` file is written once
+    /// everything else has been.
+    fn snapshot(&self, path: &Path) -> kvs::Result<()> {
+        self.state()?.snapshot(path)
+    }
+
+    fn hset(&self, key: String, field: String, value: String) -> kvs::Result<()> {
+        self.state()?.hset(key, field, value)
+    }
+
+    fn hget(&self, key: String, field: String) -> kvs::Result<Option<String>> {
+        Ok(hash_value(self.resolve(key)?)?.remove(&field))
+    }
+
+    fn hgetall(&self, key: String) -> kvs::Result<BTreeMap<String, String>> {
+        hash_value(self.resolve(key)?)
+    }
+
+    /// Remove a field from a hash. Like `remove`, the field must exist unless
+    /// `Options::check_exists_on_remove` is turned off.
+    fn hdel(&self, key: String, field: String) -> kvs::Result<()> {
+        self.state()?.hdel(key, field)
+    }
+
+    fn zadd(&self, key: String, score: f64, member: String) -> kvs::Result<()> {
+        self.state()?.zadd(key, score, member)
+    }
+
+    fn zrangebyscore(&self, key: String, min: f64, max: f64) -> kvs::Result<Vec<(String, f64)>> {
+        Ok(sorted_set_value(self.resolve(key)?)?.range_by_score(min, max))
+    }
+
+    fn zrank(&self, key: String, member: String) -> kvs::Result<Option<u64>> {
+        let set = sorted_set_value(self.resolve(key)?)?;
+        Ok(set.rank(&member).map(|rank| rank as u64))
+    }
+
+    fn xadd(&self, key: String, payload: String) -> kvs::Result<StreamId> {
+        self.state()?.xadd(key, payload)
+    }
+
+    fn xrange(
+        &self,
+        key: String,
+        from: StreamId,
+        to: StreamId,
+    ) -> kvs::Result<Vec<(StreamId, String)>> {
+        if from > to {
+            return Ok(Vec::new());
         }
+        stream_range(self.resolve(key)?, from, to)
+    }
+
+    fn enqueue(&self, key: String, payload: String) -> kvs::Result<StreamId> {
+        self.state()?.enqueue(key, payload)
+    }
+
+    fn dequeue(
+        &self,
+        key: String,
+        visibility_timeout: Duration,
+    ) -> kvs::Result<Option<(StreamId, String)>> {
+        self.state()?.dequeue(key, visibility_timeout)
+    }
+
+    fn ack(&self, key: String, id: StreamId) -> kvs::Result<()> {
+        self.state()?.ack(key, id)
+    }
+
+    fn lock(&self, key: String, ttl: Duration) -> kvs::Result<u64> {
+        self.state()?.lock(key, ttl)
+    }
+
+    fn unlock(&self, key: String, token: u64) -> kvs::Result<()> {
+        self.state()?.unlock(key, token)
+    }
+
+    fn setbit(&self, key: String, offset: u64, value: bool) -> kvs::Result<()> {
+        self.state()?.setbit(key, offset, value)
+    }
+
+    fn getbit(&self, key: String, offset: u64) -> kvs::Result<bool> {
+        Ok(bitmap_value(self.resolve(key)?)?.get(offset))
+    }
+
+    fn bitcount(&self, key: String) -> kvs::Result<u64> {
+        Ok(bitmap_value(self.resolve(key)?)?.count())
     }
 }
 
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        self.save().unwrap();
+/// A store's state while its handle's lock is held, as an engine, so that a script can run
+/// against it without taking the lock for each of its reads.
+struct LockedStore<'a>(Mutex<&'a mut StoreState>);
+
+impl<'a> kvs::Engine for LockedStore<'a> {
+    fn set(&self, key: String, value: String) -> kvs::Result<u64> {
+        self.0.lock().unwrap().set(key, value)
+    }
+
+    fn get(&self, key: String) -> kvs::Result<Option<String>> {
+        self.0.lock().unwrap().get(key)
+    }
+
+    fn remove(&self, key: String) -> kvs::Result<u64> {
+        self.0.lock().unwrap().remove(key)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> kvs::Result<()> {
+        self.0.lock().unwrap().write_batch(batch)
     }
 }
 
@@ -186,170 +741,3563 @@ impl KvStore {
         KvStore::open_with_logger(path, &logger)
     }
 
-    /// Creates a `KvStore` by opening all of the log files in the given path.
     pub fn open_with_logger(path: &Path, logger: &Logger) -> Result<KvStore> {
-        let log_path = path.to_owned();
-
-        let slog = logger.new(o!("path" => format!("{:?}", &log_path)));
+        KvStore::open_with_options(path, logger, Options::default())
+    }
 
-        if !log_path.is_dir() {
-            return Err(Error::Message("Path is not a directory".to_owned()));
-        }
+    /// Creates a `KvStore` by opening all of the log files in the given path.
+    pub fn open_with_options(path: &Path, logger: &Logger, options: Options) -> Result<KvStore> {
+        KvStore::open_with_files(path, logger, options, None)
+    }
 
-        let mut kvs = KvStore {
-            slog,
-            log_path,
-            page_readers: HashMap::new(),
-            data_readers: HashMap::new(),
-            index: Index::default(),
-            in_memory: BTreeMap::default(),
-            page_buffer: PageBuffer { buf: [0; BUF_SIZE] },
-            node_id: [b'g', b'o', b'o', b'd', b'!', b'!'],
-            context: v1::Context::new(0),
+    /// Open the sealed directory at `path` read-only, checking every file it reads against
+    /// `sealed`.
+    pub(crate) fn open_sealed(path: &Path, logger: &Logger, sealed: Sealed) -> Result<KvStore> {
+        let options = Options {
+            read_only: true,
+            ..Options::default()
         };
+        KvStore::open_with_files(path, logger, options, Some(Arc::new(sealed)))
+    }
 
-        kvs.read_index()?;
-
-        Ok(kvs)
+    fn open_with_files(
+        path: &Path,
+        logger: &Logger,
+        options: Options,
+        sealed: Option<Arc<Sealed>>,
+    ) -> Result<KvStore> {
+        let state = StoreState::open(path, logger, options, sealed)?;
+        Ok(KvStore {
+            state: Arc::new(Mutex::new(state)),
+            requests: Arc::new(Mutex::new(HashMap::new())),
+        })
     }
 
-    pub fn save(&mut self) -> Result<()> {
-        if !self.in_memory.is_empty() {
-            self.write_page()?;
-            self.write_index()?;
+    /// Restore the snapshot at `snapshot` into the empty (or missing) directory `path`.
+    ///
+    /// The snapshot is verified before anything is copied.
+    pub fn restore(snapshot: &Path, path: &Path) -> Result<()> {
+        if path.is_dir() && fs::read_dir(path)?.next().is_some() {
+            return Err(Error::Message(format!("{:?} is not empty", path)));
+        }
+
+        let report = kvs::Engine::verify(&KvStore::open_read_only(snapshot)?)?;
+        if !report.is_ok() {
+            return Err(Error::Message(format!("Snapshot is corrupt: {}", report)));
         }
-        Ok(())
-    }
 
-    /// Write the index to the index file, truncating the previous one.
-    // FIXME: this could cause us to lose all of the data
-    fn write_index(&self) -> Result<()> {
-        let path = self.log_path.join(Index::path());
-        let file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(path)?;
-        trace!(self.slog, "Writing {:?}", &self.index);
-        bincode::serialize_into(file, &self.index)?;
+        fs::create_dir_all(path)?;
+        for entry in fs::read_dir(snapshot)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if entry.file_type()?.is_file() && name != LOCK_FILE && name != CHECKSUMS_FILE {
+                fs::copy(entry.path(), path.join(entry.file_name()))?;
+            }
+        }
         Ok(())
     }
 
-    /// Read the index from the index file.
-    fn read_index(&mut self) -> Result<()> {
-        let path = self.log_path.join(Index::path());
-        trace!(self.slog, "Reading index at {:?}", &path);
-        match OpenOptions::new().read(true).open(path) {
-            Ok(file) => {
-                trace!(self.slog, "Deserializing index");
-                self.index = bincode::deserialize_from(file)?;
-                trace!(self.slog, "Index has {:?} entries", self.index.len());
-                Ok(())
+    /// Check a snapshot without changing it: its files against its checksums, its manifest and
+    /// index against its pages, and every page against its data file. If `samples` is
+    /// non-zero, up to that many keys spread across the pages are also read back.
+    pub fn verify_backup(snapshot: &Path, samples: usize) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        match fs::read_to_string(snapshot.join(CHECKSUMS_FILE)) {
+            Ok(contents) => {
+                let checksums = Checksums::from_ron(&contents)?;
+                report.errors = checksums.check(snapshot, &[LOCK_FILE])?;
             }
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::NotFound => {
-                    trace!(self.slog, "Index not found");
-                    self.index = Index::default();
-                    Ok(())
-                }
-                _ => Err(Error::IoError(e)),
-            },
+            Err(e) => report
+                .errors
+                .push(format!("{}: {} (incomplete snapshot?)", CHECKSUMS_FILE, e)),
         }
+
+        let store = match KvStore::open_read_only(snapshot) {
+            Ok(store) => store,
+            Err(e) => {
+                report.errors.push(format!("could not open: {}", e));
+                return Ok(report);
+            }
+        };
+        let pages = kvs::Engine::verify(&store)?;
+        report.pages_checked = pages.pages_checked;
+        report.errors.extend(pages.errors);
+        if samples > 0 && report.is_ok() {
+            store.state()?.sample_lookups(samples, &mut report)?;
+        }
+        Ok(report)
     }
 
-    /// Take the in-memory store, and write it out as a page in order of key-hash, along with
-    /// the data file.
-    fn write_page(&mut self) -> Result<()> {
-        let mut min = std::u64::MAX;
-        let mut max = std::u64::MIN;
-        let mut body = PageBody::default();
-        let mut data = Slotted::new();
+    /// Open the store at `path` with `Options::read_only`.
+    pub fn open_read_only(path: &Path) -> Result<KvStore> {
+        let mut options = Options::default();
+        options.read_only = true;
+        KvStore::open_with_options(path, &kvs::get_default_logger(), options)
+    }
 
-        let mut i = 0;
-        for (key, value) in self.in_memory.iter() {
-            if i >= COMMANDS_PER_PAGE {
-                panic!("Writing page with more than COMMANDS_PER_PAGE commands");
-            }
+    /// Re-read the manifest and index of a read-only store, to see the pages the writer has
+    /// saved since they were last read. Writes the writer is still holding in memory aren't
+    /// visible. Returns whether anything changed.
+    ///
+    /// If the writer is part-way through replacing the two files, the view stays as it was
+    /// and the next refresh tries again.
+    pub fn refresh(&self) -> Result<bool> {
+        self.state()?.refresh()
+    }
 
-            min = cmp::min(min, key.hash);
-            max = cmp::max(max, key.hash);
-            let value_index = value.as_ref().map(|s| data.push(s.as_bytes()) as i16);
-            body.key_hash[i] = key.hash;
-            body.value_index[i] = value_index.unwrap_or(-1);
+    /// Call `f` with the key and value of every string value set from now on, as `mode` says.
+    /// Writes of other kinds of value, like hashes, don't call it. Dropping the store waits
+    /// for any async hooks that are still running.
+    ///
+    /// A `HookMode::Sync` hook runs while the store is locked, so it mustn't call this store
+    /// or any clone of it, which would deadlock.
+    pub fn on_set<F>(&self, mode: HookMode, f: F)
+    where
+        F: Fn(&str, &str) -> Result<()> + Send + Sync + 'static,
+    {
+        self.lock_state().on_set(mode, f)
+    }
 
-            i += 1;
-        }
+    /// Call `f` with every key removed from now on, as `mode` says. Removes in a batch call it
+    /// whether or not the key existed. Like `on_set`'s, a sync hook mustn't call the store.
+    pub fn on_remove<F>(&self, mode: HookMode, f: F)
+    where
+        F: Fn(&str) -> Result<()> + Send + Sync + 'static,
+    {
+        self.lock_state().on_remove(mode, f)
+    }
 
-        let header = PageHeader::new(&self.node_id, &self.context, min, max, i as u16)?;
-        self.index.push(header.clone());
-        let page = Page { body, header };
-        trace!(self.slog, "{}", &page.body.key_hash[0]);
+    /// About how many keys the store holds, without reading any pages: each page's entries
+    /// less its tombstones and the entries newer pages have replaced or removed, plus the
+    /// memtable's values if it has unsaved changes. Those estimates are only updated as pages
+    /// are saved, so keys written again since then are counted twice, as are keys with merge
+    /// operands on disk that haven't been compacted. Includes the server's own keys, like
+    /// index entries.
+    pub fn approximate_len(&self) -> u64 {
+        self.lock_state().approximate_len()
+    }
 
-        let page_path = self.log_path.join(Page::path(&page.header.uuid));
-        let mut page_file = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(page_path)?;
-        self.page_buffer.serialize(&page);
-        self.page_buffer.write_to(&mut page_file)?;
+    /// Up to `n` distinct live keys picked at random, in order. Entries are drawn from the
+    /// memtable and pages in proportion to how many each holds, and an entry only counts if
+    /// it's its key's newest, so every key is about as likely to be picked however often it
+    /// has been written. Only the drawn pages are read. The server's own keys, and values
+    /// written before keys were stored with them, are never picked.
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<String>> {
+        self.state()?.sample_keys(n)
+    }
 
-        let data_path = self.log_path.join(Slotted::path(&page.header.uuid));
-        let data_file = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(data_path)?;
-        bincode::serialize_into(data_file, &data)?;
+    /// Every key whose string value contains `pattern`, in order, with the value. With
+    /// `Options::substring_index`, only the values that have all of the pattern's n-grams are
+    /// read, unless the pattern is too short to have any. Otherwise every value is.
+    pub fn find_values_containing(&self, pattern: &str) -> Result<Vec<(String, String)>> {
+        self.state()?.find_values_containing(pattern)
+    }
 
-        info!(self.slog, "Wrote {} commands to disk", i);
+    /// The keys, in order, whose values the index in `Options::indexes` named `index` has under
+    /// `value`.
+    pub fn get_by_index(&self, index: &str, value: &str) -> Result<Vec<String>> {
+        self.state()?.get_by_index(index, value)
+    }
 
-        Ok(())
+    /// A read-only view of the store as it is now, which keeps seeing the same values however
+    /// the store is written to or compacted afterwards. Any unsaved writes are saved first.
+    ///
+    /// The files of the pages it reads are kept until it's dropped, so a snapshot that's held
+    /// onto while the store is compacted over and over holds on to disk space.
+    pub fn read_snapshot(&self) -> Result<Snapshot> {
+        self.state()?.read_snapshot()
     }
 
-    /// Read the page with the UUID from disk.
-    fn read_page(&mut self, uuid: &Uuid) -> Result<Page> {
-        if !self.page_readers.contains_key(&uuid) {
-            let path = self.log_path.join(Page::path(uuid));
-            let file = OpenOptions::new().read(true).open(path)?;
-            self.page_readers.insert(*uuid, BufReader::new(file));
-        }
-
-        if let Some(reader) = self.page_readers.get_mut(uuid) {
-            reader.seek(SeekFrom::Start(0))?;
-            let mut page = Page::default();
-            self.page_buffer.read_from(reader)?;
-            self.page_buffer.deserialize(&mut page)?;
-            Ok(page)
-        } else {
-            panic!("Error retrieving cached reader")
-        }
+    /// Write every live key to `writer`, one per line in key order, so that dumps of stores with
+    /// the same contents are identical. Returns the number of keys written.
+    pub fn dump<W: Write>(&self, writer: W, format: DumpFormat) -> Result<u64> {
+        self.state()?.dump(writer, format)
     }
 
-    /// Read the data file with the UUID from disk.
-    fn read_data(&mut self, uuid: &Uuid) -> Result<Slotted> {
-        if !self.data_readers.contains_key(&uuid) {
-            let path = self.log_path.join(Slotted::path(uuid));
-            let file = OpenOptions::new().read(true).open(path)?;
-            self.data_readers.insert(*uuid, BufReader::new(file));
-        }
+    /// Read a dump written by `dump` (in either format) back into the store, a page's worth of
+    /// keys at a time through the batch write path. Lines that can't be parsed or hold invalid
+    /// values are reported and skipped. Under `ConflictPolicy::Fail` nothing is written until
+    /// the whole dump has been read, so the dump is held in memory.
+    pub fn load_dump<R: BufRead>(&self, reader: R, policy: ConflictPolicy) -> Result<LoadReport> {
+        self.state()?.load_dump(reader, policy)
+    }
 
-        if let Some(reader) = self.data_readers.get_mut(uuid) {
-            reader.seek(SeekFrom::Start(0))?;
-            let data = bincode::deserialize_from(reader)?;
-            Ok(data)
-        } else {
-            panic!("Error retrieving cached reader")
-        }
+    /// Write every live key to a new SST file at `path`, in RocksDB's format. Returns the number
+    /// of keys written.
+    ///
+    /// String values are written as-is; other types are written in their kvs encoding, which
+    /// only kvs can read back.
+    pub fn export_sst(&self, path: &Path) -> Result<u64> {
+        self.state()?.export_sst(path)
     }
 
-    /// Append a log entry to the end of the log.
-    fn push(&mut self, key: String, value: Option<String>) -> Result<()> {
-        trace!(self.slog, "Pushing ({:?}, {:?})", &key, &value);
-        self.in_memory.insert(InMemoryKey::new(key), value);
-        if self.in_memory.len() >= COMMANDS_PER_PAGE {
-            self.write_page()?;
-            self.in_memory = BTreeMap::new();
-        }
-        self.save().unwrap();
-        Ok(())
+    /// Write every key in the SST file at `path` into the store, removing the keys the file
+    /// deletes. Returns the number of keys imported.
+    pub fn import_sst(&self, path: &Path) -> Result<u64> {
+        self.state()?.import_sst(path)
+    }
+
+    /// Start an optimistic transaction. Reads through it note the version of each key they
+    /// see, and `commit_transaction` fails with `Error::Conflict` if any of those keys has
+    /// been written since. It can also lock keys so that nothing else can write them until
+    /// it's committed or aborted.
+    pub fn begin(&self) -> Transaction {
+        self.lock_state().begin()
     }
+
+    /// Apply the transaction's writes in a single batch, unless a key it read has been written
+    /// since it read it or another transaction has locked a key it writes. Either way, its
+    /// locks are released.
+    ///
+    /// A transaction that was aborted to break a deadlock fails with `Error::Deadlock`.
+    pub fn commit_transaction(&self, txn: Transaction) -> Result<()> {
+        self.state()?.commit_transaction(txn)
+    }
+
+    /// Drop the transaction's writes and release its locks.
+    pub fn abort_transaction(&self, txn: Transaction) {
+        self.lock_state().abort_transaction(txn)
+    }
+
+    /// Run `f` in a transaction from `begin` and commit it if `f` succeeds. The store isn't
+    /// locked while `f` runs, so other writes can go ahead, and `f` can use the store itself.
+    /// If the commit conflicts with one of them, or the transaction is aborted to break a
+    /// deadlock, `f` is run again in a new transaction, up to `MAX_TRANSACTION_ATTEMPTS` times
+    /// in all.
+    pub fn transaction<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut ScopedTransaction) -> Result<T>,
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let mut scoped = ScopedTransaction {
+                store: self,
+                txn: self.begin(),
+            };
+            let result = f(&mut scoped);
+            let txn = scoped.txn;
+            let result = match result {
+                Ok(value) => self.commit_transaction(txn).map(|_| value),
+                Err(e) => {
+                    self.abort_transaction(txn);
+                    Err(e)
+                }
+            };
+            match result {
+                Err(Error::Conflict) | Err(Error::Deadlock)
+                    if attempts < MAX_TRANSACTION_ATTEMPTS => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Write any unsaved changes in memory out to a page and update the index.
+    pub fn save(&self) -> Result<()> {
+        self.state()?.save()
+    }
+
+    /// Restore the snapshot at `snapshot` into `path` like `restore`, then replay the pages
+    /// archived after the snapshot was taken, up to and including `target`. Returns the number
+    /// of pages replayed.
+    pub fn restore_to(
+        snapshot: &Path,
+        archive: &Path,
+        path: &Path,
+        target: RecoveryTarget,
+    ) -> Result<u64> {
+        KvStore::restore(snapshot, path)?;
+        KvStore::open(path)?
+            .state()?
+            .replay_archive(archive, target)
+    }
+
+    /// Lock the store for a call from this thread, with the deadline, cancel token, and
+    /// progress callback the thread has set. Waiting for the lock gives up like reading a page
+    /// does, once the deadline passes or the token is cancelled.
+    pub(crate) fn state(&self) -> Result<MutexGuard<'_, StoreState>> {
+        let request = self.request();
+        let state = if request.deadline.is_none() && request.cancel.is_none() {
+            self.state.lock().map_err(poisoned)?
+        } else {
+            loop {
+                match self.state.try_lock() {
+                    Ok(state) => break state,
+                    Err(TryLockError::WouldBlock) => {
+                        check_interrupted(request.deadline, &request.cancel)?;
+                        thread::sleep(LOCK_POLL_INTERVAL);
+                    }
+                    Err(TryLockError::Poisoned(e)) => return Err(poisoned(e)),
+                }
+            }
+        };
+        Ok(with_request(state, request))
+    }
+
+    /// Lock the store like `state`, for calls that can't fail, waiting however long it takes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a call panicked while it held the lock, since the store may have been left
+    /// half-changed.
+    fn lock_state(&self) -> MutexGuard<'_, StoreState> {
+        let state = self
+            .state
+            .lock()
+            .expect("the store was poisoned by an earlier panic");
+        with_request(state, self.request())
+    }
+
+    /// The key's current value. Only the memtable is read with the store locked; any pages
+    /// that might hold the key are read once it's unlocked, as they were when the read
+    /// started.
+    fn resolve(&self, key: String) -> Result<Option<Record>> {
+        let resolving = self.state()?.start_resolve(key)?;
+        let read = match resolving {
+            Resolving::Done(record) => return Ok(record),
+            Resolving::Pages(read) => read,
+        };
+        let slot_key = Some(SlotKey::new(&read.key.key));
+        let result =
+            read.pages
+                .resolve(read.key.hash, slot_key, read.operands, &mut HashMap::new());
+        match result {
+            // The writer compacted away a page this read-only view still had; reading with the
+            // store locked refreshes the view and tries again
+            Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                self.state()?.resolve(&read.key)
+            }
+            result => result,
+        }
+    }
+
+    /// What this thread has set for its requests.
+    fn request(&self) -> RequestScope {
+        self.requests
+            .lock()
+            .unwrap()
+            .get(&thread::current().id())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Change this thread's request settings, forgetting them once they're all cleared.
+    fn set_request<F: FnOnce(&mut RequestScope)>(&self, f: F) {
+        let mut requests = self.requests.lock().unwrap();
+        let id = thread::current().id();
+        let mut request = requests.remove(&id).unwrap_or_default();
+        f(&mut request);
+        if request.deadline.is_some() || request.cancel.is_some() || request.progress.is_some() {
+            requests.insert(id, request);
+        }
+    }
+}
+
+impl StoreState {
+    fn set(&mut self, key: String, value: String) -> kvs::Result<u64> {
+        self.push(key, Some(Record::Value(value)))?;
+        Ok(self.versions.sequence())
+    }
+
+    pub(crate) fn get(&mut self, key: String) -> kvs::Result<Option<String>> {
+        trace!(self.slog, "Getting {}", &key);
+        match self.resolve(&self.key(key))? {
+            Some(Record::Value(value)) => {
+                trace!(self.slog, "Found {}", value);
+                Ok(Some(value))
+            }
+            Some(_) => Err(Error::WrongType),
+            None => {
+                trace!(self.slog, "Key not found");
+                Ok(None)
+            }
+        }
+    }
+
+    fn remove(&mut self, key: String) -> kvs::Result<u64> {
+        kvs::check_unreserved(&key)?;
+        let key_with_hash = self.key(key);
+        if self.options.check_exists_on_remove && !self.contains_key(&key_with_hash)? {
+            return Err(kvs::Error::KeyNotFound);
+        }
+        self.push(key_with_hash.key, None)?;
+        Ok(self.versions.sequence())
+    }
+
+    fn probe(&mut self, key: String, value: String) -> kvs::Result<Option<String>> {
+        self.push_internal(key.clone(), Some(Record::Value(value)))?;
+        let read = self.get(key.clone());
+        self.push_internal(key, None)?;
+        read
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> kvs::Result<()> {
+        self.write_batch_as(batch, None)
+    }
+
+    fn flush(&mut self) -> kvs::Result<()> {
+        self.save()
+    }
+
+    fn flush_if_due(&mut self) -> kvs::Result<()> {
+        if self.options.read_only {
+            let due = self.options.refresh_interval.map_or(false, |interval| {
+                let now = self.options.clock.now();
+                now.duration_since(self.refreshed_at)
+                    .ok()
+                    .map_or(false, |elapsed| elapsed >= interval)
+            });
+            if due {
+                self.refresh()?;
+            }
+            return Ok(());
+        }
+        let due = match (self.options.flush_interval, self.dirty_since) {
+            (Some(interval), Some(since)) => {
+                let now = self.options.clock.now();
+                now.duration_since(since)
+                    .ok()
+                    .map_or(false, |dirty| dirty >= interval)
+            }
+            _ => false,
+        };
+        if due {
+            info!(
+                self.slog,
+                "Saving writes held longer than the flush interval"
+            );
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn applied_sequence(&mut self) -> kvs::Result<u64> {
+        Ok(self.versions.sequence())
+    }
+
+    fn compact(&mut self) -> kvs::Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.options.substring_index {
+            self.rebuild_substring_index()?;
+        }
+        self.save()?;
+        let top = self
+            .manifest
+            .page_levels
+            .values()
+            .map(|page| page.level)
+            .max()
+            .unwrap_or(0);
+        let len = self.index.len();
+        self.merge_pages(0..len, cmp::max(top, 1))
+    }
+
+    fn verify(&mut self) -> kvs::Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let len = self.index.len();
+        let progress = ProgressTracker::new(self.progress.clone(), "verify", "pages", len as u64);
+        for i in 0..len {
+            self.check_deadline()?;
+            let header = self.index.get(i).unwrap().clone();
+            report.pages_checked += 1;
+            if let Err(e) = self.verify_page(&header) {
+                report.errors.push(format!("page {}: {}", header.uuid, e));
+            }
+            progress.advance(1, self.page_files(&header.uuid).disk_len());
+        }
+        progress.finish();
+        Ok(report)
+    }
+
+    fn stats(&mut self) -> kvs::Result<Stats> {
+        let mut stats = Stats::default();
+        stats.memtable_entries = self.in_memory.len() as u64;
+        stats.memtable_bytes = self.in_memory.bytes() as u64;
+        for header in self.index.iter() {
+            stats.pages += 1;
+            if header.is_partial() {
+                stats.partial_pages += 1;
+            }
+        }
+        for path in self.live_files() {
+            if let Ok(metadata) = fs::metadata(path) {
+                stats.disk_bytes += metadata.len();
+            }
+        }
+        if let Ok(metadata) = fs::metadata(self.log_path.join(Index::path())) {
+            stats.disk_bytes += metadata.len();
+        }
+        stats.approximate_keys = self.approximate_len();
+        stats.key_sizes = self.manifest.key_sizes.clone();
+        stats.value_sizes = self.manifest.value_sizes.clone();
+        stats
+            .caches
+            .insert("open_files".to_owned(), self.readers().stats());
+        Ok(stats)
+    }
+
+    fn snapshot(&mut self, path: &Path) -> kvs::Result<()> {
+        self.save()?;
+        let destination = path.to_string_lossy();
+        let mut sink = backup::open_sink(&destination, &self.slog)?;
+        let mut link = !destination.starts_with("s3://");
+        let mut checksums = Checksums::default();
+        let mut linked = 0;
+        let live_files = self.live_files();
+        // The index and manifest are copied as well
+        let total = live_files.len() as u64 + 2;
+        let progress = ProgressTracker::new(self.progress.clone(), "snapshot", "files", total);
+        for source in live_files {
+            let name = source.file_name().unwrap().to_string_lossy().into_owned();
+            if link {
+                match fs::hard_link(&source, path.join(&name)) {
+                    Ok(()) => {
+                        let checksum = self.page_checksum(&source, &name)?;
+                        progress.advance(1, checksum.len);
+                        checksums.files.insert(name, checksum);
+                        linked += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        info!(self.slog, "Copying pages into the snapshot: {}", e);
+                        link = false;
+                    }
+                }
+            }
+            let contents = fs::read(&source)?;
+            sink.put(&name, &contents)?;
+            checksums.add(&name, &contents);
+            self.page_checksums
+                .insert(name, FileChecksum::new(&contents));
+            progress.advance(1, contents.len() as u64);
+        }
+        self.page_checksums
+            .retain(|name, _| checksums.files.contains_key(name));
+
+        for source in &[Index::path(), Manifest::path()] {
+            let name = source.file_name().unwrap().to_string_lossy().into_owned();
+            let contents = fs::read(self.log_path.join(source))?;
+            sink.put(&name, &contents)?;
+            checksums.add(&name, &contents);
+            progress.advance(1, contents.len() as u64);
+        }
+        sink.put(CHECKSUMS_FILE, checksums.to_ron()?.as_bytes())?;
+        progress.finish();
+        info!(
+            self.slog,
+            "Wrote snapshot to {:?}, linking {} files", path, linked
+        );
+        Ok(())
+    }
+
+    fn hset(&mut self, key: String, field: String, value: String) -> kvs::Result<()> {
+        let key_with_hash = self.key(key);
+        self.check_type(&key_with_hash, "hash")?;
+        let mut changes = BTreeMap::new();
+        changes.insert(field, Some(value));
+        self.push(key_with_hash.key, Some(Record::HashMerge(changes)))
+    }
+
+    fn hdel(&mut self, key: String, field: String) -> kvs::Result<()> {
+        let key_with_hash = self.key(key);
+        if self.options.check_exists_on_remove {
+            match self.resolve(&key_with_hash)? {
+                Some(Record::Hash(ref fields)) if fields.contains_key(&field) => {}
+                None | Some(Record::Hash(_)) => return Err(Error::KeyNotFound),
+                Some(_) => return Err(Error::WrongType),
+            }
+        } else {
+            self.check_type(&key_with_hash, "hash")?;
+        }
+        let mut changes = BTreeMap::new();
+        changes.insert(field, None);
+        self.push(key_with_hash.key, Some(Record::HashMerge(changes)))
+    }
+
+    fn zadd(&mut self, key: String, score: f64, member: String) -> kvs::Result<()> {
+        if score.is_nan() {
+            return Err(Error::Message("Score is not a number".to_owned()));
+        }
+        let key_with_hash = self.key(key);
+        self.check_type(&key_with_hash, "zset")?;
+        let mut changes = BTreeMap::new();
+        changes.insert(member, score);
+        self.push(key_with_hash.key, Some(Record::SortedSetMerge(changes)))
+    }
+
+    fn xadd(&mut self, key: String, payload: String) -> kvs::Result<StreamId> {
+        let key_with_hash = self.key(key);
+        self.check_type(&key_with_hash, "stream")?;
+        let id = self.next_stream_id();
+        let mut entries = BTreeMap::new();
+        entries.insert(id, payload);
+        self.push(key_with_hash.key, Some(Record::StreamMerge(entries)))?;
+        Ok(id)
+    }
+
+    fn enqueue(&mut self, key: String, payload: String) -> kvs::Result<StreamId> {
+        let key_with_hash = self.key(key);
+        self.check_type(&key_with_hash, "queue")?;
+        let id = self.next_stream_id();
+        let op = Record::QueueMerge(vec![(id, QueueOp::Enqueue(payload))]);
+        self.push(key_with_hash.key, Some(op))?;
+        Ok(id)
+    }
+
+    fn dequeue(
+        &mut self,
+        key: String,
+        visibility_timeout: Duration,
+    ) -> kvs::Result<Option<(StreamId, String)>> {
+        let key_with_hash = self.key(key);
+        let items = match self.resolve(&key_with_hash)? {
+            Some(Record::Queue(items)) => items,
+            Some(_) => return Err(Error::WrongType),
+            None => return Ok(None),
+        };
+        let now = self.options.clock.now_ms();
+        let visible = items
+            .into_iter()
+            .find(|(_, item)| item.invisible_until_ms <= now);
+        if let Some((id, item)) = visible {
+            let until = ms_after(now, visibility_timeout);
+            let op = Record::QueueMerge(vec![(id, QueueOp::Lease(until))]);
+            self.push(key_with_hash.key, Some(op))?;
+            Ok(Some((id, item.payload)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn ack(&mut self, key: String, id: StreamId) -> kvs::Result<()> {
+        let key_with_hash = self.key(key);
+        match self.resolve(&key_with_hash)? {
+            Some(Record::Queue(ref items)) if items.contains_key(&id) => {}
+            None | Some(Record::Queue(_)) => return Err(Error::KeyNotFound),
+            Some(_) => return Err(Error::WrongType),
+        }
+        let op = Record::QueueMerge(vec![(id, QueueOp::Ack)]);
+        self.push(key_with_hash.key, Some(op))
+    }
+
+    fn lock(&mut self, key: String, ttl: Duration) -> kvs::Result<u64> {
+        let key_with_hash = self.key(key);
+        let now = self.options.clock.now_ms();
+        let previous_token = match self.resolve(&key_with_hash)? {
+            Some(Record::Lock(lease)) if lease.expires_at_ms > now => return Err(Error::LockHeld),
+            Some(Record::Lock(lease)) => lease.fencing_token,
+            Some(_) => return Err(Error::WrongType),
+            None => 0,
+        };
+        let lease = Lease {
+            fencing_token: previous_token + 1,
+            expires_at_ms: ms_after(now, ttl),
+        };
+        self.push(key_with_hash.key, Some(Record::Lock(lease)))?;
+        Ok(lease.fencing_token)
+    }
+
+    fn unlock(&mut self, key: String, token: u64) -> kvs::Result<()> {
+        let key_with_hash = self.key(key);
+        match self.resolve(&key_with_hash)? {
+            Some(Record::Lock(lease))
+                if lease.fencing_token == token
+                    && lease.expires_at_ms > self.options.clock.now_ms() => {}
+            Some(Record::Lock(_)) | None => return Err(Error::LockHeld),
+            Some(_) => return Err(Error::WrongType),
+        }
+        let released = Lease {
+            fencing_token: token,
+            expires_at_ms: 0,
+        };
+        self.push(key_with_hash.key, Some(Record::Lock(released)))
+    }
+
+    fn setbit(&mut self, key: String, offset: u64, value: bool) -> kvs::Result<()> {
+        let key_with_hash = self.key(key);
+        self.check_type(&key_with_hash, "bitmap")?;
+        let op = Record::BitmapMerge(vec![(offset, value)]);
+        self.push(key_with_hash.key, Some(op))
+    }
+}
+
+impl Drop for StoreState {
+    fn drop(&mut self) {
+        self.save().unwrap();
+    }
+}
+
+/// Sets every key in a single batch, so either all of them are written or none are.
+///
+/// # Panics
+///
+/// Panics if the batch can't be written, e.g. because a key is locked by a transaction. Use
+/// `write_batch` with a collected `WriteBatch` to handle the error instead.
+impl Extend<(String, String)> for KvStore {
+    fn extend<I: IntoIterator<Item = (String, String)>>(&mut self, iter: I) {
+        let batch: WriteBatch = iter.into_iter().collect();
+        if !batch.is_empty() {
+            self.lock_state().write_batch_as(batch, None).unwrap();
+        }
+    }
+}
+
+/// Every live key and its value in key order, as `dump` would write them. If the entries can't
+/// be read, the only item is the error. The handle is dropped once they've been read, closing
+/// the store if it was the last one; its files are left as they are.
+impl IntoIterator for KvStore {
+    type Item = Result<(String, DumpValue)>;
+    type IntoIter = vec::IntoIter<Result<(String, DumpValue)>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let entries = self.state().and_then(|mut state| state.keyed_entries());
+        let entries = entries.and_then(|entries| {
+            entries
+                .into_iter()
+                .map(|(key, record)| Ok((key, DumpValue::from_record(record)?)))
+                .collect::<Result<Vec<_>>>()
+        });
+        match entries {
+            Ok(entries) => entries.into_iter().map(Ok).collect::<Vec<_>>().into_iter(),
+            Err(e) => vec![Err(e)].into_iter(),
+        }
+    }
+}
+
+impl StoreState {
+    fn open(
+        path: &Path,
+        logger: &Logger,
+        options: Options,
+        sealed: Option<Arc<Sealed>>,
+    ) -> Result<StoreState> {
+        let log_path = path.to_owned();
+
+        let slog = logger.new(o!("path" => format!("{:?}", &log_path)));
+
+        if !log_path.is_dir() {
+            return Err(Error::Message("Path is not a directory".to_owned()));
+        }
+
+        if let (Some(cold_dir), false) = (&options.cold_dir, options.read_only) {
+            fs::create_dir_all(cold_dir)?;
+        }
+
+        let lock_file = if options.read_only {
+            None
+        } else {
+            Some(StoreState::lock_dir(&log_path)?)
+        };
+        let archive = match &options.archive_dir {
+            Some(archive_dir) if !options.read_only => Some(backup::open_archive(
+                &archive_dir.to_string_lossy(),
+                options.durability == Durability::Sync,
+                &slog,
+            )?),
+            _ => None,
+        };
+
+        let mut kvs = StoreState {
+            slog,
+            log_path,
+            readers: Arc::new(Mutex::new(ReaderCache::new(options.max_open_files))),
+            index: Index::default(),
+            in_memory: Memtable::default(),
+            page_buffer: PageBuffer::default(),
+            node_id: options.node_id.unwrap_or_else(machine_node_id),
+            context: ClockContext::default(),
+            manifest: Manifest::new(0),
+            _lock_file: lock_file,
+            deadline: None,
+            cancel: None,
+            progress: None,
+            files: FileOpener {
+                #[cfg(feature = "failpoints")]
+                fault_injector: options.io_faults.clone().map(FaultInjector::new),
+                sealed,
+            },
+            lookup_pool: if options.lookup_threads > 1 {
+                Some(Arc::new(WorkerPool::new(options.lookup_threads)))
+            } else {
+                None
+            },
+            options,
+            archive,
+            dirty_since: None,
+            refreshed_at: SystemTime::now(),
+            page_checksums: HashMap::new(),
+            versions: KeyVersions::default(),
+            locks: LockTable::default(),
+            last_transaction_id: 0,
+            hooks: Hooks::default(),
+            snapshots: Arc::new(()),
+            retained_files: Vec::new(),
+        };
+
+        kvs.load()?;
+        kvs.refreshed_at = kvs.options.clock.now();
+        if !kvs.options.read_only {
+            kvs.recover()?;
+            kvs.clean_up_orphans()?;
+            kvs.retry_archiving();
+            kvs.build_indexes()?;
+        }
+
+        Ok(kvs)
+    }
+
+    /// Read the manifest and the index, rebuilding the index from the pages the manifest lists
+    /// if the two disagree (e.g. because we crashed between writing them), or the index is
+    /// damaged.
+    fn load(&mut self) -> Result<()> {
+        let index_result = self.read_index();
+        let previous = self.read_manifest()?;
+
+        match &previous {
+            Some(manifest) => {
+                if manifest.format_version > FORMAT_VERSION {
+                    return Err(Error::Message(format!(
+                        "Data directory has format version {}, but only {} is supported",
+                        manifest.format_version, FORMAT_VERSION
+                    )));
+                }
+                self.manifest = manifest.clone();
+                self.check_key_hash()?;
+                // Older indexes are laid out differently, so whatever was read from one is
+                // nonsense
+                let current = manifest.format_version >= PAGE_CHECKSUMS_VERSION;
+                let index_pages: Vec<Uuid> = self.index.iter().map(|header| header.uuid).collect();
+                let matches = current
+                    && index_pages == self.manifest.live_pages
+                    && self.index.generation == self.manifest.index_generation;
+                if self.options.read_only && !current {
+                    return Err(Error::Message(format!(
+                        "Data directory has format version {}, and has to be opened for writing \
+                         once to rebuild its index",
+                        manifest.format_version
+                    )));
+                }
+                if self.options.read_only {
+                    // Nothing can be repaired without writing
+                    index_result?;
+                    if !matches {
+                        return Err(Error::Message(
+                            "Index does not match the manifest".to_owned(),
+                        ));
+                    }
+                    self.context = ClockContext::new(self.manifest.clock_sequence);
+                    self.versions = KeyVersions::starting_at(self.manifest.last_write_sequence);
+                    return Ok(());
+                }
+                if !current {
+                    info!(
+                        self.slog,
+                        "Rebuilding the index from format version {}", manifest.format_version
+                    );
+                    self.rebuild_index()?;
+                } else if let Err(e) = index_result {
+                    warn!(self.slog, "Could not read the index: {}", e);
+                    self.rebuild_index()?;
+                } else if !matches {
+                    warn!(self.slog, "Index does not match the manifest");
+                    self.rebuild_index()?;
+                }
+            }
+            None => {
+                // A new store, or one written before there was a manifest: the index (if any)
+                // is all we have to go on.
+                index_result?;
+                self.manifest = Manifest::new(random_clock_sequence());
+                self.manifest.live_pages = self.index.iter().map(|header| header.uuid).collect();
+                match self.options.key_hash {
+                    Some(key_hash) if self.index.is_empty() => self.manifest.key_hash = key_hash,
+                    _ => self.check_key_hash()?,
+                }
+            }
+        }
+        self.context = ClockContext::new(self.manifest.clock_sequence);
+        self.versions = KeyVersions::starting_at(self.manifest.last_write_sequence);
+        // Older formats can still be read, but everything written from now on is in this one
+        self.manifest.format_version = FORMAT_VERSION;
+
+        let mut options = BTreeMap::new();
+        options.insert("durability".to_owned(), self.options.durability.to_string());
+        let node_id: Vec<String> = self.node_id.iter().map(|b| format!("{:02x}", b)).collect();
+        options.insert("node_id".to_owned(), node_id.join(":"));
+        options.insert("compaction".to_owned(), self.options.compaction.to_string());
+        if let Some(cold_dir) = &self.options.cold_dir {
+            options.insert(
+                "cold_dir".to_owned(),
+                cold_dir.to_string_lossy().into_owned(),
+            );
+        }
+        self.manifest.options = options;
+
+        if previous.as_ref() != Some(&self.manifest) && !self.options.read_only {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Fail if the options ask for keys to be hashed differently than the store's are.
+    fn check_key_hash(&self) -> Result<()> {
+        match self.options.key_hash {
+            Some(key_hash) if key_hash != self.manifest.key_hash => Err(Error::Message(format!(
+                "Data directory hashes keys with {}, not {}",
+                self.manifest.key_hash, key_hash
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rebuild the index by reading the header of every page listed in the manifest, on
+    /// `options.startup_threads` threads.
+    fn rebuild_index(&mut self) -> Result<()> {
+        info!(
+            self.slog,
+            "Rebuilding the index from {} pages",
+            self.manifest.live_pages.len()
+        );
+        let uuids = self.manifest.live_pages.clone();
+        let locations: Vec<PageFiles> = uuids.iter().map(|uuid| self.page_files(uuid)).collect();
+        let files = self.files.clone();
+        let pages = parallel::map(locations, self.options.startup_threads, move |location| {
+            read_page_files(&files, &location)
+        });
+
+        let mut index = Index::default();
+        for (uuid, page) in uuids.into_iter().zip(pages) {
+            match page {
+                Ok(page) => index.push(page.header),
+                Err(e) if is_disk_failure(&e) => return Err(e),
+                Err(e) => {
+                    warn!(self.slog, "Quarantining unreadable page {}: {}", uuid, e);
+                    self.quarantine_page(&uuid)?;
+                }
+            }
+        }
+        self.index = index;
+        // Make sure the rebuilt index gets written out
+        self.manifest.live_pages.clear();
+        Ok(())
+    }
+
+    /// Move pages that can't be read back (e.g. because the process died while writing them)
+    /// into the quarantine directory and drop them from the index, so the store still opens.
+    /// If reading a page fails because of the disk, opening fails instead.
+    ///
+    /// Pages are checked on `options.startup_threads` threads, since every one of them is read
+    /// in full.
+    fn recover(&mut self) -> Result<()> {
+        let pages: Vec<(PageFiles, PageHeader)> = self
+            .index
+            .iter()
+            .map(|header| (self.page_files(&header.uuid), header.clone()))
+            .collect();
+        let files = self.files.clone();
+        let checked = parallel::map(
+            pages,
+            self.options.startup_threads,
+            move |(location, header)| {
+                let result = verify_page_files(&files, &location, &header);
+                (header, result)
+            },
+        );
+
+        let mut index = Index::default();
+        let mut quarantined = 0;
+        for (header, result) in checked {
+            match result {
+                Ok(()) => index.push(header),
+                Err(e) if is_disk_failure(&e) => return Err(e),
+                Err(e) => {
+                    warn!(
+                        self.slog,
+                        "Quarantining unreadable page {}: {}", header.uuid, e
+                    );
+                    self.quarantine_page(&header.uuid)?;
+                    quarantined += 1;
+                }
+            }
+        }
+
+        if quarantined > 0 {
+            self.index = index;
+            self.commit()?;
+            warn!(
+                self.slog,
+                "Dropped {} unreadable pages from the index", quarantined
+            );
+        }
+        Ok(())
+    }
+
+    /// Move page, data, and segment files that aren't referenced by the manifest (left behind
+    /// by crashes or failed compactions) into `lost+found/`, and delete the ones that have been
+    /// there longer than the grace period. The cold directory has its own `lost+found/`.
+    fn clean_up_orphans(&mut self) -> Result<()> {
+        let mut dirs = vec![self.log_path.clone()];
+        dirs.extend(self.options.cold_dir.clone());
+        for dir in dirs.iter() {
+            self.clean_up_orphans_in(dir)?;
+        }
+        Ok(())
+    }
+
+    fn clean_up_orphans_in(&mut self, dir: &Path) -> Result<()> {
+        let live: HashSet<Uuid> = self.manifest.live_pages.iter().cloned().collect();
+        let segments: HashSet<Uuid> = self
+            .manifest
+            .page_locations
+            .values()
+            .map(|location| location.segment)
+            .collect();
+        let lost_and_found = dir.join(LOST_AND_FOUND_DIR);
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|ext| ext.to_str());
+            if extension == Some("tmp") {
+                trace!(self.slog, "Removing leftover temporary file {:?}", &path);
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let referenced = match extension {
+                Some("log") | Some("data") => &live,
+                Some("segment") => &segments,
+                _ => continue,
+            };
+            let uuid = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| Uuid::parse_str(stem).ok());
+            if let Some(uuid) = uuid {
+                if !referenced.contains(&uuid) {
+                    warn!(self.slog, "Moving orphaned file {:?} to lost+found", &path);
+                    fs::create_dir_all(&lost_and_found)?;
+                    fs::rename(&path, lost_and_found.join(path.file_name().unwrap()))?;
+                }
+            }
+        }
+
+        if lost_and_found.is_dir() {
+            let now = SystemTime::now();
+            for entry in fs::read_dir(&lost_and_found)? {
+                let entry = entry?;
+                let age = now
+                    .duration_since(entry.metadata()?.modified()?)
+                    .unwrap_or_default();
+                if age >= self.options.orphan_grace_period {
+                    info!(self.slog, "Deleting orphaned file {:?}", entry.path());
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the page and data files for the page with the UUID into the quarantine directory.
+    /// A page in a segment shares the file with other pages, so whatever is left of its blocks
+    /// is copied out into a page and data file instead, and the segment is left alone.
+    fn quarantine_page(&mut self, uuid: &Uuid) -> Result<()> {
+        self.manifest
+            .unarchived_pages
+            .retain(|(_, unarchived)| unarchived != uuid);
+        match self.page_files(uuid) {
+            PageFiles::Separate(paths) => {
+                for path in paths.iter() {
+                    self.readers().remove(path);
+                    if path.exists() {
+                        // Next to the file, since the cold directory may be on another
+                        // filesystem
+                        let quarantine = path.parent().unwrap().join(QUARANTINE_DIR);
+                        fs::create_dir_all(&quarantine)?;
+                        fs::rename(path, quarantine.join(path.file_name().unwrap()))?;
+                    }
+                }
+            }
+            PageFiles::Segment(path, location) => {
+                if path.exists() {
+                    let quarantine = path.parent().unwrap().join(QUARANTINE_DIR);
+                    fs::create_dir_all(&quarantine)?;
+                    let [page, data] = read_segment_blocks(&path, &location)?;
+                    fs::write(quarantine.join(Page::path(uuid)), page)?;
+                    fs::write(quarantine.join(Slotted::path(uuid)), data)?;
+                }
+                self.manifest.page_locations.remove(uuid);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take an exclusive advisory lock on the directory so that no other process can open it,
+    /// and record our PID in the lock file.
+    fn lock_dir(path: &Path) -> Result<File> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path.join(LOCK_FILE))?;
+        if file.try_lock_exclusive().is_err() {
+            let mut contents = String::new();
+            let pid = file
+                .read_to_string(&mut contents)
+                .ok()
+                .and_then(|_| contents.trim().parse().ok());
+            return Err(Error::AlreadyLocked(pid));
+        }
+        file.set_len(0)?;
+        write!(file, "{}", process::id())?;
+        Ok(file)
+    }
+
+    fn refresh(&mut self) -> Result<bool> {
+        if !self.options.read_only {
+            return Ok(false);
+        }
+        self.refreshed_at = self.options.clock.now();
+        let manifest = match self.read_manifest()? {
+            Some(manifest) if manifest.index_generation != self.manifest.index_generation => {
+                manifest
+            }
+            _ => return Ok(false),
+        };
+        let index = self.read_index_file()?;
+        let index_pages: Vec<Uuid> = index.iter().map(|header| header.uuid).collect();
+        if index_pages != manifest.live_pages || index.generation != manifest.index_generation {
+            debug!(
+                self.slog,
+                "The writer is replacing the index, not refreshing yet"
+            );
+            return Ok(false);
+        }
+        debug!(
+            self.slog,
+            "Refreshed to index generation {}", manifest.index_generation
+        );
+        self.manifest = manifest;
+        self.index = index;
+        self.context = ClockContext::new(self.manifest.clock_sequence);
+        self.versions = KeyVersions::starting_at(self.manifest.last_write_sequence);
+        Ok(true)
+    }
+
+    fn on_set<F>(&mut self, mode: HookMode, f: F)
+    where
+        F: Fn(&str, &str) -> Result<()> + Send + Sync + 'static,
+    {
+        self.hooks.on_set(mode, Arc::new(f));
+    }
+
+    fn on_remove<F>(&mut self, mode: HookMode, f: F)
+    where
+        F: Fn(&str) -> Result<()> + Send + Sync + 'static,
+    {
+        self.hooks.on_remove(mode, Arc::new(f));
+    }
+
+    fn approximate_len(&self) -> u64 {
+        let on_disk: u64 = self
+            .index
+            .iter()
+            .map(|header| {
+                let tombstones = self.manifest.tombstones.get(&header.uuid).cloned();
+                let dead = self
+                    .stale_entries(&header.uuid)
+                    .saturating_add(tombstones.unwrap_or(0));
+                u64::from(header.count.saturating_sub(dead))
+            })
+            .sum();
+        // Once saved, the memtable's entries are already counted in its pages
+        let in_memory = match self.dirty_since {
+            Some(_) => self
+                .in_memory
+                .iter()
+                .filter(|(_, value)| value.is_some())
+                .count(),
+            None => 0,
+        };
+        on_disk + in_memory as u64
+    }
+
+    fn sample_keys(&mut self, n: usize) -> Result<Vec<String>> {
+        let mut sources: Vec<(Option<Uuid>, u64)> = vec![(None, self.in_memory.len() as u64)];
+        sources.extend(
+            self.index
+                .iter()
+                .map(|header| (Some(header.uuid), u64::from(header.count))),
+        );
+        let total: u64 = sources.iter().map(|(_, count)| count).sum();
+        let mut found = BTreeSet::new();
+        if total == 0 {
+            return Ok(Vec::new());
+        }
+        // A tiny xorshift generator is plenty for picking entries
+        let mut state = Uuid::new_v4().as_u128() as u64 | 1;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        // Tombstones and replaced entries are drawn but passed over, so give up after enough
+        // draws that a store of mostly those doesn't take long
+        for _ in 0..n.saturating_mul(8) {
+            if found.len() >= n {
+                break;
+            }
+            let mut slot = random() % total;
+            let mut source = None;
+            for (uuid, count) in sources.iter() {
+                if slot < *count {
+                    source = Some(*uuid);
+                    break;
+                }
+                slot -= count;
+            }
+            let key = match source.unwrap() {
+                None => match self.in_memory.iter().nth(slot as usize) {
+                    Some((key, Some(_))) => key.key.clone(),
+                    _ => continue,
+                },
+                Some(uuid) => {
+                    self.check_deadline()?;
+                    let page = self.read_page(&uuid)?;
+                    let value_index = page.body.value_index[slot as usize];
+                    if value_index < 0 {
+                        continue;
+                    }
+                    let mut data = self.read_data(&uuid)?;
+                    let bytes = data.get(value_index as usize).expect("bad index");
+                    let key = match decode_entry(bytes)? {
+                        (Some(key), _) => self.key(key),
+                        (None, _) => continue,
+                    };
+                    if self.in_memory.has_value(&key).is_some()
+                        || self.locate(&key)? != Some((uuid, value_index))
+                    {
+                        continue;
+                    }
+                    key.key
+                }
+            };
+            if !kvs::is_reserved_key(&key) {
+                found.insert(key);
+            }
+        }
+        Ok(found.into_iter().collect())
+    }
+
+    fn find_values_containing(&mut self, pattern: &str) -> Result<Vec<(String, String)>> {
+        let ngrams = secondary_index::ngrams(pattern);
+        if !self.options.substring_index || pattern.chars().count() < NGRAM_LEN {
+            return Ok(self
+                .keyed_entries()?
+                .into_iter()
+                .filter_map(|(key, record)| match record {
+                    Record::Value(value) if !kvs::is_reserved_key(&key) => Some((key, value)),
+                    _ => None,
+                })
+                .filter(|(_, value)| value.contains(pattern))
+                .collect());
+        }
+
+        let mut candidates: Option<BTreeSet<String>> = None;
+        for ngram in ngrams {
+            let keys: BTreeSet<String> =
+                match self.resolve(&self.key(secondary_index::ngram_key(&ngram)))? {
+                    Some(Record::Hash(fields)) => fields.into_iter().map(|(key, _)| key).collect(),
+                    Some(_) => return Err(Error::WrongType),
+                    None => BTreeSet::new(),
+                };
+            let keys = match candidates {
+                Some(candidates) => candidates.intersection(&keys).cloned().collect(),
+                None => keys,
+            };
+            if keys.is_empty() {
+                return Ok(Vec::new());
+            }
+            candidates = Some(keys);
+        }
+        // Having every n-gram doesn't mean having them in the right order
+        let mut found = Vec::new();
+        for key in candidates.unwrap_or_default() {
+            if let Some(Record::Value(value)) = self.resolve(&self.key(key.clone()))? {
+                if value.contains(pattern) {
+                    found.push((key, value));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    fn get_by_index(&mut self, index: &str, value: &str) -> Result<Vec<String>> {
+        let index = match self.options.indexes.iter().find(|i| i.name == index) {
+            Some(index) => index.clone(),
+            None => return Err(Error::Message(format!("Unknown index: {}", index))),
+        };
+        let keys = match self.resolve(&self.key(index.entry_key(value)))? {
+            Some(Record::Hash(fields)) => fields.into_iter().map(|(key, _)| key).collect(),
+            Some(_) => return Err(Error::WrongType),
+            None => Vec::new(),
+        };
+        // An entry can be out of date if the index was dropped and declared again since
+        let mut found = Vec::new();
+        for key in keys {
+            if let Some(Record::Value(current)) = self.resolve(&self.key(key.clone()))? {
+                if index.extract(&current).as_ref().map(String::as_str) == Some(value) {
+                    found.push(key);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Look up evenly spaced keys from across every page, as a reader would.
+    fn sample_lookups(&mut self, samples: usize, report: &mut VerifyReport) -> Result<()> {
+        let mut hashes = Vec::new();
+        for i in 0..self.index.len() {
+            self.check_deadline()?;
+            let uuid = self.index.get(i).unwrap().uuid;
+            let page = self.read_page(&uuid)?;
+            hashes.extend_from_slice(&page.body.key_hash[..page.header.count as usize]);
+        }
+        let step = cmp::max(hashes.len() / samples, 1);
+        for hash in hashes.into_iter().step_by(step).take(samples) {
+            report.keys_sampled += 1;
+            match self.resolve_on_disk(hash, None, Vec::new()) {
+                Err(Error::DeadlineExceeded) => return Err(Error::DeadlineExceeded),
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Err(e) => report
+                    .errors
+                    .push(format!("key with hash {:016x}: {}", hash, e)),
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Every key with a value on disk, in order of key hash, with its key (if it was written with
+    /// one) and its full value, with any merge operands folded in.
+    fn live_entries(&mut self) -> Result<Vec<(u64, Option<String>, Record)>> {
+        let len = self.index.len();
+        self.page_set(0..len).live_entries(0..=u64::max_value())
+    }
+
+    /// The pages at these positions in the index as they are now, for reading on other threads.
+    fn page_set(&self, positions: Range<usize>) -> PageSet {
+        let mut pages: Vec<_> = self
+            .index
+            .iter()
+            .skip(positions.start)
+            .take(positions.len())
+            .map(|header| (self.page_files(&header.uuid), header.clone()))
+            .collect();
+        pages.reverse();
+        PageSet {
+            files: self.files.clone(),
+            pages: Arc::new(pages),
+            deadline: self.deadline,
+            cancel: self.cancel.clone(),
+            progress: None,
+            lookup_pool: None,
+            readers: None,
+        }
+    }
+
+    /// Every live key with its full value, in key order, including any unsaved changes.
+    fn keyed_entries(&mut self) -> Result<Vec<(String, Record)>> {
+        self.save()?;
+        sort_by_key(self.live_entries()?)
+    }
+
+    fn read_snapshot(&mut self) -> Result<Snapshot> {
+        let mut snapshot = self.current_view()?;
+        snapshot.pages.deadline = None;
+        snapshot.pages.cancel = None;
+        Ok(snapshot)
+    }
+
+    /// The store as it is now, like `read_snapshot`, but giving up at the current request's
+    /// deadline, for one read that's finished without the lock.
+    fn current_view(&mut self) -> Result<Snapshot> {
+        self.save()?;
+        Ok(Snapshot {
+            pages: self.page_set(0..self.index.len()),
+            key_hash: self.manifest.key_hash,
+            sequence: self.versions.sequence(),
+            _pin: self.snapshots.clone(),
+        })
+    }
+
+    /// Start reading the key's current value like `resolve`, from the memtable. Unless that's
+    /// all there is to it, the pages that might hold the key are left to be read without the
+    /// lock.
+    fn start_resolve(&mut self, key: String) -> Result<Resolving> {
+        let key = self.key(key);
+        let operands = match self.in_memory.get(&key)? {
+            Some(Some(operand)) if operand.is_merge() => vec![operand],
+            Some(record) => return Ok(Resolving::Done(drop_empty_hash(record))),
+            None => Vec::new(),
+        };
+        let pages: Vec<_> = self
+            .index
+            .iter()
+            .rev()
+            .filter(|header| header.min_key_hash <= key.hash && key.hash <= header.max_key_hash)
+            .map(|header| (self.page_files(&header.uuid), header.clone()))
+            .collect();
+        Ok(Resolving::Pages(PendingRead {
+            key,
+            operands,
+            pages: PageSet {
+                files: self.files.clone(),
+                pages: Arc::new(pages),
+                deadline: self.deadline,
+                cancel: self.cancel.clone(),
+                progress: None,
+                lookup_pool: self.lookup_pool.clone(),
+                readers: Some(self.readers.clone()),
+            },
+            _pin: self.snapshots.clone(),
+        }))
+    }
+
+    /// Delete the files compaction left for snapshots, once none are open. They're no longer
+    /// part of the store, so failing to delete one is only logged, and it's tried again later.
+    fn remove_retained_files(&mut self) {
+        if self.retained_files.is_empty() || Arc::strong_count(&self.snapshots) > 1 {
+            return;
+        }
+        for path in mem::replace(&mut self.retained_files, Vec::new()) {
+            if let Err(e) = self.remove_file(&path) {
+                warn!(self.slog, "Could not delete {:?}: {}", path, e);
+                self.retained_files.push(path);
+            }
+        }
+    }
+
+    fn dump<W: Write>(&mut self, mut writer: W, format: DumpFormat) -> Result<u64> {
+        let mut count = 0;
+        let entries = self.keyed_entries()?;
+        let total = entries.len() as u64;
+        let progress = ProgressTracker::new(self.progress.clone(), "dump", "keys", total);
+        for (key, record) in entries {
+            progress.advance(1, key.len() as u64 + value_size(&record)?);
+            let entry = DumpEntry {
+                key,
+                value: DumpValue::from_record(record)?,
+            };
+            match format {
+                DumpFormat::Json => {
+                    serde_json::to_writer(&mut writer, &entry).map_err(Error::serialization)?
+                }
+                DumpFormat::Ron => write!(
+                    writer,
+                    "{}",
+                    ron::ser::to_string(&entry).map_err(Error::serialization)?
+                )?,
+            }
+            writeln!(writer)?;
+            count += 1;
+        }
+        writer.flush()?;
+        progress.finish();
+        Ok(count)
+    }
+
+    fn load_dump<R: BufRead>(&mut self, reader: R, policy: ConflictPolicy) -> Result<LoadReport> {
+        let mut report = LoadReport::default();
+        let mut batch = Vec::new();
+        // Keys that are loaded but not yet written, so that a second line for one is a conflict
+        let mut batch_keys = HashSet::new();
+        // Under `Fail`, full batches wait here until the whole dump is known to have no conflicts
+        let mut staged = Vec::new();
+        let mut last_stream_id = self.manifest.last_stream_id;
+        // A dump can be read from a pipe, so there's no telling how long it is
+        let progress = ProgressTracker::new(self.progress.clone(), "load", "lines", 0);
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            progress.advance(1, line.len() as u64 + 1);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, record) = match DumpEntry::parse(&line)
+                .and_then(|entry| Ok((entry.key, entry.value.into_record()?)))
+            {
+                Ok(entry) => entry,
+                Err(e) => {
+                    report.errors.push(format!("line {}: {}", i + 1, e));
+                    continue;
+                }
+            };
+
+            if policy != ConflictPolicy::Overwrite {
+                let exists =
+                    batch_keys.contains(&key) || self.resolve(&self.key(key.clone()))?.is_some();
+                if exists && policy == ConflictPolicy::Fail {
+                    report
+                        .errors
+                        .push(format!("line {}: {:?} already exists", i + 1, key));
+                    return Ok(report);
+                }
+                if exists {
+                    report.skipped += 1;
+                    continue;
+                }
+                batch_keys.insert(key.clone());
+            }
+
+            // Ids given out from now on have to come after the loaded ones
+            if let Some(id) = dump::max_stream_id(&record) {
+                last_stream_id = cmp::max(last_stream_id, id);
+            }
+            batch.push((key, Some(record)));
+            if batch.len() >= COMMANDS_PER_PAGE {
+                let full = mem::replace(&mut batch, Vec::new());
+                if policy == ConflictPolicy::Fail {
+                    staged.push(full);
+                } else {
+                    report.loaded += full.len() as u64;
+                    self.manifest.last_stream_id = last_stream_id;
+                    self.apply_batch(full)?;
+                    batch_keys.clear();
+                }
+            }
+        }
+        staged.push(batch);
+        self.manifest.last_stream_id = last_stream_id;
+        for batch in staged {
+            report.loaded += batch.len() as u64;
+            self.apply_batch(batch)?;
+        }
+        self.save()?;
+        progress.finish();
+        info!(self.slog, "Loaded {} keys", report.loaded);
+        Ok(report)
+    }
+
+    fn export_sst(&mut self, path: &Path) -> Result<u64> {
+        let entries = self.keyed_entries()?;
+        let total = entries.len() as u64;
+        let progress = ProgressTracker::new(self.progress.clone(), "export", "keys", total);
+        let mut writer = SstWriter::create(path)?;
+        for (key, record) in entries.iter() {
+            let value = record.encode()?;
+            writer.add(key.as_bytes(), &value)?;
+            progress.advance(1, (key.len() + value.len()) as u64);
+        }
+        let count = writer.finish()?;
+        progress.finish();
+        info!(self.slog, "Exported {} keys to {:?}", count, path);
+        Ok(count)
+    }
+
+    fn import_sst(&mut self, path: &Path) -> Result<u64> {
+        let entries = sst::read_sst(path)?;
+        let count = entries.len() as u64;
+        let progress = ProgressTracker::new(self.progress.clone(), "import", "keys", count);
+        for (key, value) in entries {
+            let len = key.len() + value.as_ref().map_or(0, Vec::len);
+            progress.advance(1, len as u64);
+            let key = String::from_utf8(key)
+                .map_err(|_| Error::Message("SST file has a key that isn't UTF-8".to_owned()))?;
+            let record = match value {
+                Some(ref bytes) if bytes.first() == Some(&RECORD_TAG) => {
+                    Some(Record::decode(bytes)?)
+                }
+                Some(bytes) => {
+                    Some(Record::Value(String::from_utf8(bytes).map_err(|_| {
+                        Error::Message(format!("Value of {:?} isn't UTF-8", key))
+                    })?))
+                }
+                None => None,
+            };
+            self.push(key, record)?;
+        }
+        self.save()?;
+        progress.finish();
+        info!(self.slog, "Imported {} keys from {:?}", count, path);
+        Ok(count)
+    }
+
+    fn begin(&mut self) -> Transaction {
+        self.last_transaction_id += 1;
+        Transaction::new(self.last_transaction_id, self.versions.sequence())
+    }
+
+    fn commit_transaction(&mut self, mut txn: Transaction) -> Result<()> {
+        let result = txn::validate(&txn, self).and_then(|_| {
+            let batch = txn.take_writes();
+            if batch.is_empty() {
+                Ok(())
+            } else {
+                self.write_batch_as(batch, Some(txn.id))
+            }
+        });
+        self.release_locks(&mut txn);
+        result
+    }
+
+    fn abort_transaction(&mut self, mut txn: Transaction) {
+        self.release_locks(&mut txn);
+    }
+
+    /// Release every lock the transaction holds, and stop tracking what it's waiting for.
+    pub(crate) fn release_locks(&mut self, txn: &mut Transaction) {
+        for key in txn.locks.drain(..) {
+            self.locks.release(&key, txn.id);
+        }
+        self.locks.forget(txn.id);
+    }
+
+    /// Lock the key for the transaction `owner`. Returns whether it wasn't already holding it.
+    pub(crate) fn lock_key(&mut self, key: &str, owner: u64) -> Result<bool> {
+        self.locks.acquire(key, owner)
+    }
+
+    /// Apply a batch on behalf of the transaction `owner`, or of no transaction, failing if
+    /// another transaction has locked any of its keys.
+    fn write_batch_as(&mut self, batch: WriteBatch, owner: Option<u64>) -> Result<()> {
+        let mut records: Vec<(String, Option<Record>)> = Vec::new();
+        // Where each key the batch has written so far is in `records`, for renames to read
+        let mut written: HashMap<String, usize> = HashMap::new();
+        for op in batch.into_ops() {
+            let writes = match op {
+                BatchOp::Set { key, value } => vec![(key, Some(Record::Value(value)))],
+                BatchOp::Remove { key } => vec![(key, None)],
+                // Any kind of value can be renamed, not just strings
+                BatchOp::Rename { from, to } => {
+                    let record = match written.get(&from) {
+                        Some(i) => records[*i].1.clone(),
+                        None => self.resolve(&self.key(from.clone()))?,
+                    };
+                    let record = record.ok_or(Error::KeyNotFound)?;
+                    vec![(from, None), (to, Some(record))]
+                }
+            };
+            for (key, record) in writes {
+                written.insert(key.clone(), records.len());
+                records.push((key, record));
+            }
+        }
+        self.check_deadline()?;
+        for (key, _) in records.iter() {
+            self.locks.check(key, owner)?;
+        }
+        self.apply_batch(records)
+    }
+
+    /// The sequence number of the latest write to the key, if it's recent enough to be
+    /// remembered.
+    pub(crate) fn key_version(&self, key: &str) -> Option<u64> {
+        self.versions.get(self.key(key.to_owned()).hash)
+    }
+
+    /// Every write up to this sequence number has been forgotten by `key_version`.
+    pub(crate) fn forgotten_versions(&self) -> u64 {
+        self.versions.forgotten()
+    }
+
+    fn save(&mut self) -> Result<()> {
+        if self.dirty_since.is_some() && !self.in_memory.is_empty() {
+            let written = self.write_pages()?;
+            // The counts are only estimates, so a page that can't be read is just left out
+            if let Err(e) = self.count_stale_entries(written) {
+                warn!(self.slog, "Could not count replaced entries: {}", e);
+            }
+            for i in self.index.len() - written..self.index.len() {
+                self.manifest.last_page_sequence += 1;
+                if self.archive.is_some() {
+                    let uuid = self.index.get(i).unwrap().uuid;
+                    let sequence = self.manifest.last_page_sequence;
+                    self.manifest.unarchived_pages.push((sequence, uuid));
+                }
+            }
+            // All of the pages become live at once, so a batch bigger than a page is still
+            // written atomically
+            self.commit()?;
+            // Only committed pages are archived, so the archive never holds a page that isn't
+            // part of the store's history. The write has already succeeded, so a failure here
+            // only leaves the page to be archived later.
+            self.retry_archiving();
+            self.dirty_since = None;
+            // Likewise, the pages are already saved if merging them fails, and they'll be
+            // merged after a later save instead.
+            if let Err(e) = self.compact_automatically() {
+                warn!(self.slog, "Could not compact: {}", e);
+            }
+        }
+        self.dirty_since = None;
+        self.remove_retained_files();
+        Ok(())
+    }
+
+    /// Merge the pages at these positions in the index into a single run of full pages on
+    /// `level`, keeping only the newest entry for each key, and leaving out keys that newer
+    /// pages replace or remove. The key hashes are split into
+    /// `options.compaction_threads` ranges that are merged at the same time. The new pages are
+    /// packed into segment files of about `options.segment_size` bytes. If there's a cold
+    /// directory, the segments are written there, and only pages saved since stay in the data
+    /// directory. Merging every page recounts the size histograms from the keys that are left.
+    fn merge_pages(&mut self, pages: Range<usize>, level: u32) -> Result<()> {
+        // The old pages are deleted below, so they have to be in the archive first
+        self.archive_pending()?;
+
+        let old_files = self.live_files();
+        let merged_pages = pages.len();
+        // Pages older than the merged ones are still needed, for merge operands to be folded
+        // onto. Only if there are none can removed keys be dropped instead of kept as
+        // tombstones.
+        let mut page_set = self.page_set(0..pages.end);
+        let tombstones = pages.start > 0;
+        let every_page = pages.start == 0 && pages.end == self.index.len();
+        let newer = self.page_set(pages.end..self.index.len());
+        let threads = self.options.compaction_threads.max(1);
+        let ranges = hash_ranges(threads);
+        // Each range's merge reads the pages it overlaps, so a page can be read more than once
+        let reads = ranges
+            .iter()
+            .map(|hashes| page_set.overlapping(hashes, merged_pages))
+            .sum::<usize>();
+        let progress = Arc::new(ProgressTracker::new(
+            self.progress.clone(),
+            "compact",
+            "pages",
+            reads as u64,
+        ));
+        page_set.progress = Some(progress.clone());
+        let merged = parallel::map(ranges, threads, move |hashes| -> Result<_> {
+            let replaced = newer.replaced(hashes.clone())?;
+            let mut entries = Vec::new();
+            let mut key_sizes = SizeHistogram::default();
+            let mut value_sizes = SizeHistogram::default();
+            for (hash, key, record) in page_set.merged_entries(hashes, merged_pages, tombstones)? {
+                if replaced.contains(&hash) {
+                    continue;
+                }
+                if let (Some(key), Some(record)) = (&key, &record) {
+                    if !kvs::is_reserved_key(key) {
+                        key_sizes.record(key.len() as u64);
+                        value_sizes.record(value_size(record)?);
+                    }
+                }
+                let slot_key = key
+                    .as_ref()
+                    .map_or_else(SlotKey::default, |key| SlotKey::new(key));
+                let bytes = match (key, record) {
+                    (_, None) => None,
+                    (Some(key), Some(record)) => Some(encode_entry(&key, &record)?),
+                    (None, Some(record)) => Some(record.encode()?),
+                };
+                entries.push((hash, slot_key, bytes));
+            }
+            Ok((entries, key_sizes, value_sizes))
+        });
+        let mut entries = Vec::new();
+        let mut key_sizes = SizeHistogram::default();
+        let mut value_sizes = SizeHistogram::default();
+        for range in merged {
+            let (range, keys, values) = range?;
+            entries.extend(range);
+            key_sizes.merge(&keys);
+            value_sizes.merge(&values);
+        }
+
+        let dir = match &self.options.cold_dir {
+            Some(cold_dir) => cold_dir.clone(),
+            None => self.log_path.clone(),
+        };
+        let mut headers = Vec::new();
+        let mut page_locations = BTreeMap::new();
+        let mut segment = None;
+        for chunk in entries.chunks(COMMANDS_PER_PAGE) {
+            let mut body = PageBody {
+                keyed: true,
+                ..PageBody::default()
+            };
+            let mut data = Slotted::new();
+            for (i, (hash, slot_key, bytes)) in chunk.iter().enumerate() {
+                body.key_hash[i] = *hash;
+                body.slot_keys[i] = *slot_key;
+                body.value_index[i] = match bytes {
+                    Some(bytes) => data.push(bytes) as i16,
+                    None => -1,
+                };
+            }
+            let min = chunk.first().unwrap().0;
+            let max = chunk.last().unwrap().0;
+            let header = PageHeader::new(
+                &self.node_id,
+                &self.context,
+                self.options.clock.now(),
+                min,
+                max,
+                chunk.len() as u16,
+            )?;
+            let uuid = header.uuid;
+            let tombstones = count_tombstones(&body, header.count);
+            if tombstones > 0 {
+                self.manifest.tombstones.insert(uuid, tombstones);
+            }
+            let mut page = Page { header, body };
+            let location = self.write_segment_page(&mut segment, &dir, &mut page, &data)?;
+            headers.push(page.header);
+            page_locations.insert(uuid, location);
+        }
+        if let Some(segment) = segment {
+            self.finish_segment(segment)?;
+        }
+        if let (Some(cold_dir), Durability::Sync) =
+            (&self.options.cold_dir, self.options.durability)
+        {
+            sync_dir(cold_dir)?;
+        }
+
+        let mut index = Index::default();
+        for header in self.index.iter().take(pages.start) {
+            index.push(header.clone());
+        }
+        if let Some(run) = headers.first().map(|header| header.uuid) {
+            for header in headers.iter() {
+                let page_level = PageLevel { level, run };
+                self.manifest.page_levels.insert(header.uuid, page_level);
+            }
+        }
+        for header in headers.iter() {
+            index.push(header.clone());
+        }
+        for header in self.index.iter().skip(pages.end) {
+            index.push(header.clone());
+        }
+
+        // Writing the new manifest is the commit point; only then is it safe to drop the old
+        // pages.
+        self.index = index;
+        self.manifest.page_locations.extend(page_locations);
+        if every_page {
+            self.manifest.key_sizes = key_sizes;
+            self.manifest.value_sizes = value_sizes;
+        }
+        self.commit()?;
+        let live: HashSet<PathBuf> = self.live_files().into_iter().collect();
+        for path in old_files.iter().filter(|path| !live.contains(*path)) {
+            if Arc::strong_count(&self.snapshots) > 1 {
+                self.retained_files.push(path.clone());
+            } else {
+                self.remove_file(path)?;
+            }
+        }
+
+        progress.finish();
+        info!(
+            self.slog,
+            "Merged {} pages into {} on level {}",
+            merged_pages,
+            headers.len(),
+            level
+        );
+        Ok(())
+    }
+
+    /// Merge pages as `options.compaction` calls for, until it's satisfied.
+    fn compact_automatically(&mut self) -> Result<()> {
+        loop {
+            let pages: Vec<PageInfo> = self
+                .index
+                .iter()
+                .map(|header| {
+                    let page_level = self.page_level(&header.uuid);
+                    PageInfo {
+                        level: page_level.level,
+                        run: page_level.run,
+                        entries: header.count,
+                        stale: self.stale_entries(&header.uuid),
+                    }
+                })
+                .collect();
+            match compaction::plan(&self.options, &pages) {
+                Some(merge) => self.merge_pages(merge.pages, merge.level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Count the entries of the `written` newest pages against the older pages they replace
+    /// or remove entries of. Each key only counts against the page that held its newest entry
+    /// before, found the way a lookup would be, and merge operands don't replace anything.
+    fn count_stale_entries(&mut self, written: usize) -> Result<()> {
+        // The newest pages were written from the whole memtable
+        let mut hashes = BTreeSet::new();
+        for (key, bytes) in self.in_memory.iter() {
+            match bytes {
+                Some(bytes) if Record::decode(bytes)?.is_merge() => {}
+                _ => {
+                    hashes.insert(key.hash);
+                }
+            }
+        }
+        let older: Vec<PageHeader> = self
+            .index
+            .iter()
+            .take(self.index.len() - written)
+            .cloned()
+            .collect();
+        for header in older.iter().rev() {
+            if hashes.is_empty() {
+                break;
+            }
+            let range = header.min_key_hash..=header.max_key_hash;
+            if hashes.range(range).next().is_none() {
+                continue;
+            }
+            let page = self.read_page(&header.uuid)?;
+            let replaced = page.body.key_hash[..page.header.count as usize]
+                .iter()
+                .filter(|hash| hashes.remove(hash))
+                .count() as u16;
+            if replaced > 0 {
+                let stale = self.manifest.stale_entries.entry(header.uuid).or_insert(0);
+                *stale = cmp::min(stale.saturating_add(replaced), header.count);
+            }
+        }
+        Ok(())
+    }
+
+    /// The estimate of how many of the page's entries newer pages have replaced or removed.
+    fn stale_entries(&self, uuid: &Uuid) -> u16 {
+        self.manifest.stale_entries.get(uuid).cloned().unwrap_or(0)
+    }
+
+    /// Where the page stands in automatic compaction. Pages that no merge has written are on
+    /// level 0, each in a run of its own.
+    fn page_level(&self, uuid: &Uuid) -> PageLevel {
+        match self.manifest.page_levels.get(uuid) {
+            Some(page_level) => *page_level,
+            None => PageLevel {
+                level: 0,
+                run: *uuid,
+            },
+        }
+    }
+
+    /// Try to archive the pages that haven't been yet, logging rather than returning any error.
+    fn retry_archiving(&mut self) {
+        if self.archive.is_none() && !self.manifest.unarchived_pages.is_empty() {
+            warn!(
+                self.slog,
+                "Archiving is off, so {} pages will not be archived",
+                self.manifest.unarchived_pages.len()
+            );
+            self.manifest.unarchived_pages.clear();
+        }
+        if let Err(e) = self.archive_pending() {
+            warn!(
+                self.slog,
+                "Could not archive {} pages, will retry: {}",
+                self.manifest.unarchived_pages.len(),
+                e
+            );
+        }
+    }
+
+    /// Copy every unarchived page to the archive, oldest first.
+    ///
+    /// Pages are only dropped from the list in memory; the next commit records that. Until
+    /// then, a restart just archives them again.
+    fn archive_pending(&mut self) -> Result<()> {
+        while let Some(&(sequence, uuid)) = self.manifest.unarchived_pages.first() {
+            self.archive_page(sequence, &uuid)?;
+            self.manifest.unarchived_pages.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Copy a page into the archive, named by its sequence number.
+    fn archive_page(&mut self, sequence: u64, uuid: &Uuid) -> Result<()> {
+        if self.archive.is_none() {
+            return Ok(());
+        }
+        let [page, data] = match self.page_files(uuid) {
+            PageFiles::Separate([page_path, data_path]) => {
+                [fs::read(page_path)?, fs::read(data_path)?]
+            }
+            PageFiles::Segment(path, location) => read_segment_blocks(&path, &location)?,
+        };
+        if let Some(archive) = &mut self.archive {
+            // The page file goes last, since replaying only looks for pages with a page file.
+            archive.put(&archive_name(sequence, &Slotted::path(uuid)), &data)?;
+            archive.put(&archive_name(sequence, &Page::path(uuid)), &page)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the archived pages that come after the store's newest page, in order.
+    fn replay_archive(&mut self, archive: &Path, target: RecoveryTarget) -> Result<u64> {
+        self.save()?;
+        let mut pages = BTreeMap::new();
+        for entry in fs::read_dir(archive)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".log") {
+                continue;
+            }
+            let mut parts = name.trim_end_matches(".log").splitn(2, '-');
+            let sequence = parts.next().and_then(|s| s.parse::<u64>().ok());
+            let uuid = parts.next().and_then(|s| Uuid::parse_str(s).ok());
+            if let (Some(sequence), Some(uuid)) = (sequence, uuid) {
+                pages.insert(sequence, uuid);
+            }
+        }
+
+        let mut replayed = 0;
+        let start = self.manifest.last_page_sequence + 1;
+        for (&sequence, uuid) in pages.range(start..) {
+            if sequence != self.manifest.last_page_sequence + 1 {
+                return Err(Error::Message(format!(
+                    "Archive is missing page {}",
+                    self.manifest.last_page_sequence + 1
+                )));
+            }
+            if let RecoveryTarget::Sequence(last) = target {
+                if sequence > last {
+                    break;
+                }
+            }
+
+            for path in self.page_file_paths(uuid).iter() {
+                fs::copy(archive.join(archive_name(sequence, path)), path)?;
+            }
+            let header = self.read_page(uuid)?.header;
+            if let RecoveryTarget::Time(time) = target {
+                if header.created_at() > time {
+                    self.remove_page_files(uuid)?;
+                    break;
+                }
+            }
+            self.verify_page(&header)?;
+            self.index.push(header);
+            self.manifest.last_page_sequence = sequence;
+            replayed += 1;
+        }
+
+        if replayed > 0 {
+            self.commit()?;
+        }
+        info!(self.slog, "Replayed {} archived pages", replayed);
+        Ok(replayed)
+    }
+
+    /// Make the current set of pages the live one by writing a new manifest, then write the
+    /// matching index.
+    fn commit(&mut self) -> Result<()> {
+        self.manifest.index_generation += 1;
+        self.index.generation = self.manifest.index_generation;
+        self.manifest.live_pages = self.index.iter().map(|header| header.uuid).collect();
+        let live: HashSet<Uuid> = self.manifest.live_pages.iter().cloned().collect();
+        let locations = mem::replace(&mut self.manifest.page_locations, BTreeMap::new());
+        self.manifest.page_locations = locations
+            .into_iter()
+            .filter(|(uuid, _)| live.contains(uuid))
+            .collect();
+        let levels = mem::replace(&mut self.manifest.page_levels, BTreeMap::new());
+        self.manifest.page_levels = levels
+            .into_iter()
+            .filter(|(uuid, _)| live.contains(uuid))
+            .collect();
+        let stale = mem::replace(&mut self.manifest.stale_entries, BTreeMap::new());
+        self.manifest.stale_entries = stale
+            .into_iter()
+            .filter(|(uuid, _)| live.contains(uuid))
+            .collect();
+        let tombstones = mem::replace(&mut self.manifest.tombstones, BTreeMap::new());
+        self.manifest.tombstones = tombstones
+            .into_iter()
+            .filter(|(uuid, _)| live.contains(uuid))
+            .collect();
+        self.manifest.clock_sequence = self.context.current();
+        self.manifest.last_write_sequence = self.versions.sequence();
+        self.write_manifest()?;
+        self.write_index()
+    }
+
+    /// Write the manifest to a temporary file and rename it over the previous one, so that a
+    /// crash leaves either the old or the new manifest in place.
+    fn write_manifest(&self) -> Result<()> {
+        let path = self.log_path.join(Manifest::path());
+        let tmp_path = path.with_extension("tmp");
+        let contents = ron::ser::to_string_pretty(&self.manifest, PrettyConfig::default())
+            .map_err(Error::serialization)?;
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        if self.options.durability == Durability::Sync {
+            // The pages the manifest refers to must be durable before the manifest is.
+            sync_dir(&self.log_path)?;
+            file.sync_all()?;
+        }
+        fail_point!("write-manifest", |_| Err(injected_failure(
+            "write-manifest"
+        )));
+        fs::rename(&tmp_path, &path)?;
+        if self.options.durability == Durability::Sync {
+            sync_dir(&self.log_path)?;
+        }
+        Ok(())
+    }
+
+    /// Read the manifest, if there is one.
+    fn read_manifest(&self) -> Result<Option<Manifest>> {
+        match fs::read_to_string(self.log_path.join(Manifest::path())) {
+            Ok(contents) => ron::de::from_str(&contents)
+                .map(Some)
+                .map_err(Error::serialization)
+                .context("Could not read the manifest"),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::IoError(e)),
+        }
+    }
+
+    /// Write the index to a temporary file and rename it over the previous one, so that a crash
+    /// leaves either the old or the new index in place.
+    fn write_index(&self) -> Result<()> {
+        let path = self.log_path.join(Index::path());
+        let tmp_path = path.with_extension("tmp");
+        let file = self.open_file(
+            OpenOptions::new().create(true).truncate(true).write(true),
+            &tmp_path,
+        )?;
+        trace!(self.slog, "Writing {:?}", &self.index);
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&self.index.encode()?)?;
+        let file = writer.into_inner().map_err(io::Error::from)?;
+        if self.options.durability == Durability::Sync {
+            file.sync_all()?;
+        }
+        fail_point!("write-index", |_| Err(injected_failure("write-index")));
+        fs::rename(&tmp_path, &path)?;
+        if self.options.durability == Durability::Sync {
+            sync_dir(&self.log_path)?;
+        }
+        Ok(())
+    }
+
+    /// Read the index from the index file.
+    fn read_index(&mut self) -> Result<()> {
+        self.index = self.read_index_file()?;
+        Ok(())
+    }
+
+    /// Read the index file, which is empty if there isn't one.
+    fn read_index_file(&self) -> Result<Index> {
+        let path = self.log_path.join(Index::path());
+        trace!(self.slog, "Reading index at {:?}", &path);
+        match self.open_file(OpenOptions::new().read(true), &path) {
+            Ok(file) => {
+                trace!(self.slog, "Deserializing index");
+                let mut bytes = Vec::new();
+                BufReader::new(file).read_to_end(&mut bytes)?;
+                let index = Index::decode(&bytes)?;
+                trace!(self.slog, "Index has {:?} entries", index.len());
+                Ok(index)
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => {
+                    trace!(self.slog, "Index not found");
+                    Ok(Index::default())
+                }
+                _ => Err(Error::IoError(e)),
+            },
+        }
+    }
+
+    /// Take the in-memory store, and write it out as pages in order of key-hash, along with
+    /// their data files. Returns the number of pages written.
+    fn write_pages(&mut self) -> Result<usize> {
+        let mut pages = Vec::new();
+        let entries: Vec<(&InMemoryKey, Option<&[u8]>)> = self.in_memory.iter().collect();
+        for chunk in entries.chunks(COMMANDS_PER_PAGE) {
+            let mut body = PageBody {
+                keyed: true,
+                ..PageBody::default()
+            };
+            let mut data = Slotted::new();
+            for (i, (key, value)) in chunk.iter().enumerate() {
+                body.key_hash[i] = key.hash;
+                body.slot_keys[i] = SlotKey::new(&key.key);
+                body.value_index[i] = match value {
+                    Some(bytes) => data.push(bytes) as i16,
+                    None => -1,
+                };
+            }
+            let min = chunk.first().unwrap().0.hash;
+            let max = chunk.last().unwrap().0.hash;
+            let header = PageHeader::new(
+                &self.node_id,
+                &self.context,
+                self.options.clock.now(),
+                min,
+                max,
+                chunk.len() as u16,
+            )?;
+            let tombstones = count_tombstones(&body, header.count);
+            if tombstones > 0 {
+                self.manifest.tombstones.insert(header.uuid, tombstones);
+            }
+            pages.push((Page { body, header }, data));
+        }
+
+        // A page only goes into the index once its files are written, so that a failed write
+        // can't leave the next commit referring to a page that doesn't exist
+        for (page, data) in pages.iter_mut() {
+            self.write_page_files(page, data)?;
+            self.index.push(page.header.clone());
+            info!(self.slog, "Wrote {} commands to disk", page.header.count);
+        }
+        Ok(pages.len())
+    }
+
+    /// Write a page and its data file into the data directory, recording the page's checksum in
+    /// its header. Both files must not exist yet.
+    fn write_page_files(&mut self, page: &mut Page, data: &Slotted) -> Result<()> {
+        fail_point!("write-page", |_| Err(injected_failure("write-page")));
+        let page_path = self.log_path.join(Page::path(&page.header.uuid));
+        let mut page_file =
+            self.open_file(OpenOptions::new().create_new(true).write(true), &page_path)?;
+        self.page_buffer.serialize(page);
+        self.page_buffer.write_to(&mut page_file)?;
+
+        fail_point!("write-data", |_| Err(injected_failure("write-data")));
+        let data_path = self.log_path.join(Slotted::path(&page.header.uuid));
+        let data_file =
+            self.open_file(OpenOptions::new().create_new(true).write(true), &data_path)?;
+        let mut writer = BufWriter::new(data_file);
+        bincode::serialize_into(&mut writer, data)?;
+        let data_file = writer.into_inner().map_err(io::Error::from)?;
+
+        if self.options.durability == Durability::Sync {
+            page_file.sync_all()?;
+            data_file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Append a page and its data to the segment being written, first starting a new one in
+    /// `dir` if there isn't one yet or it has reached `options.segment_size`. The page's
+    /// checksum is recorded in its header.
+    fn write_segment_page(
+        &mut self,
+        segment: &mut Option<SegmentWriter>,
+        dir: &Path,
+        page: &mut Page,
+        data: &Slotted,
+    ) -> Result<SegmentLocation> {
+        fail_point!("write-segment", |_| Err(injected_failure("write-segment")));
+        let full = match segment {
+            Some(segment) => segment.len >= self.options.segment_size,
+            None => true,
+        };
+        if full {
+            if let Some(previous) = segment.take() {
+                self.finish_segment(previous)?;
+            }
+            let location = SegmentLocation {
+                segment: page.header.uuid,
+                offset: 0,
+                page_len: 0,
+                data_len: 0,
+            };
+            let file = self.open_file(
+                OpenOptions::new().create_new(true).write(true),
+                &dir.join(location.path()),
+            )?;
+            *segment = Some(SegmentWriter {
+                id: location.segment,
+                writer: BufWriter::new(file),
+                len: 0,
+            });
+        }
+
+        let segment = segment.as_mut().unwrap();
+        self.page_buffer.serialize(page);
+        self.page_buffer.write_to(&mut segment.writer)?;
+        bincode::serialize_into(&mut segment.writer, data)?;
+        let location = SegmentLocation {
+            segment: segment.id,
+            offset: segment.len,
+            page_len: self.page_buffer.block_len() as u64,
+            data_len: bincode::serialized_size(data)?,
+        };
+        segment.len = location.end();
+        Ok(location)
+    }
+
+    /// Flush a segment that compaction has finished writing.
+    fn finish_segment(&mut self, segment: SegmentWriter) -> Result<()> {
+        let file = segment.writer.into_inner().map_err(io::Error::from)?;
+        if self.options.durability == Durability::Sync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Where the page with the UUID is: in a segment if compaction packed it into one,
+    /// otherwise in a page file and data file of its own.
+    fn page_files(&self, uuid: &Uuid) -> PageFiles {
+        match self.manifest.page_locations.get(uuid) {
+            Some(location) => PageFiles::Segment(self.find_file(&location.path()), *location),
+            None => PageFiles::Separate(self.page_file_paths(uuid)),
+        }
+    }
+
+    /// The paths of the page file and data file for the page with the UUID, when it isn't in
+    /// a segment.
+    fn page_file_paths(&self, uuid: &Uuid) -> [PathBuf; 2] {
+        let page_path = self.find_file(&Page::path(uuid));
+        let data_path = page_path.with_file_name(Slotted::path(uuid));
+        [page_path, data_path]
+    }
+
+    /// The path of a file written by the store: in the cold directory if compaction wrote it
+    /// there, otherwise in the data directory.
+    fn find_file(&self, name: &Path) -> PathBuf {
+        match &self.options.cold_dir {
+            Some(cold_dir)
+                if !self.log_path.join(name).exists() && cold_dir.join(name).exists() =>
+            {
+                cold_dir.join(name)
+            }
+            _ => self.log_path.join(name),
+        }
+    }
+
+    /// Every page, data, and segment file that live pages are in, each once.
+    fn live_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut segments = HashSet::new();
+        for header in self.index.iter() {
+            match self.page_files(&header.uuid) {
+                PageFiles::Separate(paths) => files.extend(paths.iter().cloned()),
+                PageFiles::Segment(path, location) => {
+                    if segments.insert(location.segment) {
+                        files.push(path);
+                    }
+                }
+            }
+        }
+        files
+    }
+
+    /// The checksum of a page, data, or segment file, read from the file the first time it's
+    /// needed.
+    fn page_checksum(&mut self, path: &Path, name: &str) -> Result<FileChecksum> {
+        if let Some(checksum) = self.page_checksums.get(name) {
+            return Ok(checksum.clone());
+        }
+        let checksum = FileChecksum::new(&fs::read(path)?);
+        self.page_checksums
+            .insert(name.to_owned(), checksum.clone());
+        Ok(checksum)
+    }
+
+    /// Delete the page and data files for the page with the UUID, closing any cached readers.
+    /// The page must not be in a segment.
+    fn remove_page_files(&mut self, uuid: &Uuid) -> Result<()> {
+        for path in self.page_file_paths(uuid).iter() {
+            self.remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Delete a page, data, or segment file if it exists, closing its cached reader.
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.readers().remove(&path.to_owned());
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(Error::IoError(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the page with the header can be read back and that its data file holds every
+    /// value the page refers to. Always reads the files, rather than through the reader cache.
+    fn verify_page(&mut self, header: &PageHeader) -> Result<()> {
+        verify_page_files(&self.files, &self.page_files(&header.uuid), header)
+    }
+
+    /// Whether the key currently has a value, without reading any data files.
+    fn contains_key(&mut self, key_with_hash: &InMemoryKey) -> Result<bool> {
+        if let Some(has_value) = self.in_memory.has_value(key_with_hash) {
+            return Ok(has_value);
+        }
+        Ok(match self.locate(key_with_hash)? {
+            Some((_, value_index)) => value_index >= 0,
+            None => false,
+        })
+    }
+
+    /// The id for a new stream or queue entry.
+    fn next_stream_id(&mut self) -> StreamId {
+        let id = self
+            .manifest
+            .last_stream_id
+            .next(self.options.clock.now_ms());
+        self.manifest.last_stream_id = id;
+        id
+    }
+
+    /// Fold the key's entries, from the memtable down to the oldest page, into its current
+    /// value. Stops at the newest entry that isn't a merge operand.
+    fn resolve(&mut self, key: &InMemoryKey) -> Result<Option<Record>> {
+        let result = match self.in_memory.get(key)? {
+            Some(Some(operand)) if operand.is_merge() => {
+                self.resolve_on_disk(key.hash, Some(SlotKey::new(&key.key)), vec![operand])
+            }
+            Some(record) => Ok(drop_empty_hash(record)),
+            None => self.resolve_on_disk(key.hash, Some(SlotKey::new(&key.key)), Vec::new()),
+        };
+        match &result {
+            // The writer compacted away a page this read-only view still had, so the view is
+            // out of date
+            Err(Error::IoError(e))
+                if e.kind() == io::ErrorKind::NotFound
+                    && self.options.read_only
+                    && self.refresh()? =>
+            {
+                self.resolve(key)
+            }
+            _ => result,
+        }
+    }
+
+    /// Fold the merge operands (newest first) onto the key hash's entries in the pages. With
+    /// `key`, only the entries of the key it was made from are folded in, as far as the pages
+    /// record their slots' keys.
+    fn resolve_on_disk(
+        &mut self,
+        key_hash: u64,
+        key: Option<SlotKey>,
+        mut operands: Vec<Record>,
+    ) -> Result<Option<Record>> {
+        let mut base = None;
+        let mut lookup = self.lookup(key_hash, key)?;
+        while let Some((uuid, value_index)) = self.next_entry(&mut lookup)? {
+            if value_index < 0 {
+                break;
+            }
+            let record = self.read_record(&uuid, value_index as usize)?;
+            if record.is_merge() {
+                operands.push(record);
+            } else {
+                base = Some(record);
+                break;
+            }
+        }
+
+        for operand in operands.into_iter().rev() {
+            base = Some(Record::merge(base, operand)?);
+        }
+        Ok(drop_empty_hash(base))
+    }
+
+    /// Fail with `WrongType` if the key holds a different kind of value, judging only by its
+    /// newest entry.
+    fn check_type(&mut self, key: &InMemoryKey, type_name: &str) -> Result<()> {
+        let newest = match self.in_memory.get(key)? {
+            Some(record) => record,
+            None => match self.locate(key)? {
+                Some((uuid, value_index)) if value_index >= 0 => {
+                    Some(self.read_record(&uuid, value_index as usize)?)
+                }
+                _ => None,
+            },
+        };
+        match newest {
+            Some(ref record) if record.type_name() != type_name => Err(Error::WrongType),
+            _ => Ok(()),
+        }
+    }
+
+    /// Find the newest page entry for the key, returning the page's UUID and the entry's index
+    /// into the data file (negative for a tombstone).
+    fn locate(&mut self, key: &InMemoryKey) -> Result<Option<(Uuid, i16)>> {
+        let mut lookup = self.lookup(key.hash, Some(SlotKey::new(&key.key)))?;
+        self.next_entry(&mut lookup)
+    }
+
+    /// Start looking for the key hash's entries. If more than one page's range covers the hash
+    /// and there's a lookup pool, all of those pages are read and searched at once, so the
+    /// lookup takes about as long as reading one page rather than all of them in turn.
+    fn lookup(&mut self, key_hash: u64, key: Option<SlotKey>) -> Result<Lookup> {
+        let pages: Vec<Uuid> = self
+            .index
+            .iter()
+            .rev()
+            .filter(|header| header.min_key_hash <= key_hash && key_hash <= header.max_key_hash)
+            .map(|header| header.uuid)
+            .collect();
+        let mut searched = None;
+        if let (Some(pool), true) = (&self.lookup_pool, pages.len() > 1) {
+            self.check_deadline()?;
+            let locations: Vec<PageFiles> =
+                pages.iter().map(|uuid| self.page_files(uuid)).collect();
+            let files = self.files.clone();
+            let readers = self.readers.clone();
+            let results = pool.map(locations, move |location| {
+                search_page_files(&files, Some(&readers), &location, key_hash, key)
+            });
+            let results: Vec<_> = pages.iter().cloned().zip(results).collect();
+            searched = Some(results.into_iter());
+        }
+        Ok(Lookup {
+            key_hash,
+            key,
+            pages: pages.into_iter(),
+            searched,
+        })
+    }
+
+    /// The next newest entry for the lookup's key hash, as its page's UUID and its index into
+    /// the data file (negative for a tombstone). Pages are read as they're needed, unless the
+    /// lookup pool has already searched them. Either way, a page that can't be read only fails
+    /// the lookup once it gets that far.
+    fn next_entry(&mut self, lookup: &mut Lookup) -> Result<Option<(Uuid, i16)>> {
+        if let Some(searched) = &mut lookup.searched {
+            for (uuid, result) in searched {
+                if let Some(value_index) = result? {
+                    return Ok(Some((uuid, value_index)));
+                }
+            }
+            return Ok(None);
+        }
+        for uuid in &mut lookup.pages {
+            self.check_deadline()?;
+            let page = self.read_page(&uuid)?;
+            trace!(self.slog, "Reading page {:?}", &page.header);
+            if let Some(value_index) = search_page(&page, lookup.key_hash, lookup.key) {
+                return Ok(Some((uuid, value_index)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fail with `DeadlineExceeded` if the current request's deadline has passed, or
+    /// `Cancelled` if it has been cancelled.
+    fn check_deadline(&self) -> Result<()> {
+        check_interrupted(self.deadline, &self.cancel)
+    }
+
+    /// The key with its hash, as the store hashes keys.
+    fn key(&self, key: String) -> InMemoryKey {
+        InMemoryKey::new(key, &self.manifest.key_hash)
+    }
+
+    /// Read the page with the UUID from disk.
+    fn read_page(&mut self, uuid: &Uuid) -> Result<Page> {
+        let location = self.page_files(uuid);
+        let (path, offset) = location.page();
+        let buffer = &mut self.page_buffer;
+        with_reader(&self.files, Some(&self.readers), path, |reader| {
+            read_page_block(reader, offset, buffer)
+        })
+    }
+
+    /// Read the data file with the UUID from disk.
+    fn read_data(&mut self, uuid: &Uuid) -> Result<Slotted> {
+        let location = self.page_files(uuid);
+        let (path, offset, len) = location.data();
+        with_reader(&self.files, Some(&self.readers), path, |reader| {
+            read_data_block(reader, offset, len)
+        })
+    }
+
+    /// The cache of open readers.
+    fn readers(&self) -> MutexGuard<'_, ReaderCache<PathBuf, BufReader<StoreFile>>> {
+        self.readers.lock().unwrap()
+    }
+
+    /// Open a page, data, segment, or index file, injecting any faults the options ask for.
+    fn open_file(&self, options: &OpenOptions, path: &Path) -> io::Result<StoreFile> {
+        self.files.open(options, path)
+    }
+
+    /// Read a single value out of the data file with the UUID.
+    fn read_record(&mut self, uuid: &Uuid, value_index: usize) -> Result<Record> {
+        let mut data = self.read_data(uuid)?;
+        let bytes = data.get(value_index).expect("bad index");
+        Ok(Record::decode(bytes)?)
+    }
+
+    /// Append a log entry to the end of the log. Merge operands are folded into the key's
+    /// entry in memory, if it has one. Keys are only fully validated when they're written, so
+    /// keys stored before a rule was added can still be removed, but reserved keys can't be
+    /// written or removed at all.
+    fn push(&mut self, key: String, record: Option<Record>) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        validate_write(&key, record.as_ref())?;
+        self.push_internal(key, record)
+    }
+
+    /// Like `push`, but for the store's and server's own keys, which aren't validated.
+    fn push_internal(&mut self, key: String, record: Option<Record>) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        trace!(self.slog, "Pushing ({:?}, {:?})", &key, &record);
+        let key = self.key(key);
+        self.locks.check(&key.key, None)?;
+        let changes = self.hook_changes(vec![(key.key.as_str(), record.as_ref())]);
+        self.hooks.before(&changes)?;
+        self.count_sizes(&key.key, record.as_ref())?;
+        let record = match (record, self.in_memory.get(&key)?) {
+            (Some(operand), Some(previous)) if operand.is_merge() => {
+                Some(Record::merge(previous, operand)?)
+            }
+            (record, _) => record,
+        };
+        self.insert_write(key, record)?;
+        self.mark_dirty();
+        self.flush_memtable()?;
+        self.hooks.after(changes, &self.slog);
+        Ok(())
+    }
+
+    /// Write full records (not merge operands) for several keys in a single commit.
+    fn apply_batch(&mut self, records: Vec<(String, Option<Record>)>) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        for (key, record) in records.iter() {
+            validate_write(key, record.as_ref())?;
+        }
+        let changes = self.hook_changes(
+            records
+                .iter()
+                .map(|(key, record)| (key.as_str(), record.as_ref()))
+                .collect(),
+        );
+        self.hooks.before(&changes)?;
+        for (key, record) in records {
+            self.count_sizes(&key, record.as_ref())?;
+            let key = self.key(key);
+            self.insert_write(key, record)?;
+        }
+        self.mark_dirty();
+        self.flush_memtable()?;
+        self.hooks.after(changes, &self.slog);
+        Ok(())
+    }
+
+    /// Count a write's key and value in the manifest's size histograms. Removals and writes to
+    /// the server's own keys aren't counted.
+    fn count_sizes(&mut self, key: &str, record: Option<&Record>) -> Result<()> {
+        if let Some(record) = record {
+            if !kvs::is_reserved_key(key) {
+                self.manifest.key_sizes.record(key.len() as u64);
+                self.manifest.value_sizes.record(value_size(record)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// What the hooks are called with for the writes, skipping the work when there are none.
+    fn hook_changes(&self, writes: Vec<(&str, Option<&Record>)>) -> Vec<Change> {
+        if self.hooks.is_empty() {
+            return Vec::new();
+        }
+        writes
+            .into_iter()
+            .filter_map(|(key, record)| hooks::change(key, record))
+            .collect()
+    }
+
+    /// Put a write into the memtable, along with the changes it makes to the entries of
+    /// `options.indexes`.
+    fn insert_write(&mut self, key: InMemoryKey, record: Option<Record>) -> Result<()> {
+        let is_merge = record.as_ref().map_or(false, Record::is_merge);
+        let indexed = !self.options.indexes.is_empty() || self.options.substring_index;
+        if indexed && !is_merge && !kvs::is_reserved_key(&key.key) {
+            let old = match self.resolve(&key)? {
+                Some(Record::Value(value)) => Some(value),
+                _ => None,
+            };
+            let new = match &record {
+                Some(Record::Value(value)) => Some(value.as_str()),
+                _ => None,
+            };
+            let mut changes = Vec::new();
+            for index in self.options.indexes.iter() {
+                let before = old.as_ref().and_then(|value| index.extract(value));
+                let after = new.and_then(|value| index.extract(value));
+                if before == after {
+                    continue;
+                }
+                if let Some(before) = before {
+                    changes.push((index.entry_key(&before), None));
+                }
+                if let Some(after) = after {
+                    changes.push((index.entry_key(&after), Some(String::new())));
+                }
+            }
+            if self.options.substring_index {
+                let before = old.as_ref().map(|value| secondary_index::ngrams(value));
+                let before = before.unwrap_or_default();
+                let after = new.map(secondary_index::ngrams).unwrap_or_default();
+                for ngram in before.difference(&after) {
+                    changes.push((secondary_index::ngram_key(ngram), None));
+                }
+                for ngram in after.difference(&before) {
+                    changes.push((secondary_index::ngram_key(ngram), Some(String::new())));
+                }
+            }
+            for (entry_key, change) in changes {
+                self.add_to_index_entry(entry_key, &key.key, change)?;
+            }
+        }
+        self.versions.record(key.hash);
+        self.in_memory.insert(key, record.as_ref())
+    }
+
+    /// Add the key to an index entry (with `Some`) or take it out (with `None`), as a merge
+    /// operand folded onto the entry's memtable entry if it has one.
+    fn add_to_index_entry(
+        &mut self,
+        entry_key: String,
+        key: &str,
+        change: Option<String>,
+    ) -> Result<()> {
+        let entry_key = self.key(entry_key);
+        let mut changes = BTreeMap::new();
+        changes.insert(key.to_owned(), change);
+        let operand = Record::HashMerge(changes);
+        let record = match self.in_memory.get(&entry_key)? {
+            Some(previous) => Record::merge(previous, operand)?,
+            None => operand,
+        };
+        self.in_memory.insert(entry_key, Some(&record))
+    }
+
+    /// Build the entries of the indexes in `options.indexes` that the store hasn't built yet
+    /// from every value in it, and forget the ones that are no longer declared.
+    fn build_indexes(&mut self) -> Result<()> {
+        let declared: BTreeSet<String> = self
+            .options
+            .indexes
+            .iter()
+            .map(|index| index.name.clone())
+            .collect();
+        let substring_index = self.options.substring_index;
+        if declared == self.manifest.indexes && substring_index == self.manifest.substring_index {
+            return Ok(());
+        }
+        let new: Vec<SecondaryIndex> = self
+            .options
+            .indexes
+            .iter()
+            .filter(|index| !self.manifest.indexes.contains(&index.name))
+            .cloned()
+            .collect();
+        if !new.is_empty() {
+            info!(self.slog, "Building {} secondary indexes", new.len());
+            // Values written before keys were stored with them can't be indexed
+            for (_, key, record) in self.live_entries()? {
+                let (key, value) = match (key, record) {
+                    (Some(key), Record::Value(value)) if !kvs::is_reserved_key(&key) => {
+                        (key, value)
+                    }
+                    _ => continue,
+                };
+                for index in new.iter() {
+                    if let Some(indexed) = index.extract(&value) {
+                        self.add_to_index_entry(
+                            index.entry_key(&indexed),
+                            &key,
+                            Some(String::new()),
+                        )?;
+                    }
+                }
+                self.mark_dirty();
+                if self.in_memory.len() >= COMMANDS_PER_PAGE {
+                    self.save()?;
+                    self.in_memory.clear();
+                }
+            }
+        }
+        // The substring index is rebuilt from scratch, since it could have been turned off and
+        // gone out of date since it was last built
+        if substring_index && !self.manifest.substring_index {
+            self.rebuild_substring_index()?;
+        }
+        // Entries of indexes that are dropped are left behind, and `get_by_index` passes over
+        // any that went out of date if the index is declared again
+        self.manifest.indexes = declared;
+        self.manifest.substring_index = substring_index;
+        if self.dirty_since.is_some() {
+            self.save()
+        } else {
+            self.commit()
+        }
+    }
+
+    /// Write the substring index's entries afresh from every value in the store, and remove
+    /// the ones no value has the n-gram of anymore. Every entry is written whole, so a crash
+    /// part-way through leaves each one either as it was or rebuilt.
+    fn rebuild_substring_index(&mut self) -> Result<()> {
+        self.save()?;
+        let mut entries: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+        let mut old_entries = Vec::new();
+        for (_, key, record) in self.live_entries()? {
+            let key = match key {
+                Some(key) => key,
+                None => continue,
+            };
+            if key.starts_with(NGRAM_KEY_PREFIX) {
+                old_entries.push(key);
+            } else if let (Record::Value(value), false) = (record, kvs::is_reserved_key(&key)) {
+                for ngram in secondary_index::ngrams(&value) {
+                    entries
+                        .entry(secondary_index::ngram_key(&ngram))
+                        .or_default()
+                        .insert(key.clone(), String::new());
+                }
+            }
+        }
+        info!(
+            self.slog,
+            "Rebuilding {} substring index entries",
+            entries.len()
+        );
+        let removed: Vec<String> = old_entries
+            .into_iter()
+            .filter(|key| !entries.contains_key(key))
+            .collect();
+        let writes = entries
+            .into_iter()
+            .map(|(key, fields)| (key, Some(Record::Hash(fields))))
+            .chain(removed.into_iter().map(|key| (key, None)));
+        for (key, record) in writes {
+            let key = self.key(key);
+            self.versions.record(key.hash);
+            self.in_memory.insert(key, record.as_ref())?;
+            self.mark_dirty();
+            if self.in_memory.len() >= COMMANDS_PER_PAGE {
+                self.save()?;
+                self.in_memory.clear();
+            }
+        }
+        self.save()
+    }
+
+    /// Note that the memtable has unsaved changes, starting the clock on `options.flush_interval`
+    /// if they're the first.
+    fn mark_dirty(&mut self) {
+        if self.dirty_since.is_none() {
+            self.dirty_since = Some(self.options.clock.now());
+        }
+    }
+
+    /// Save the memtable if the durability level calls for it, starting a new one once it's
+    /// filled a page or reached `options.memtable_bytes`.
+    fn flush_memtable(&mut self) -> Result<()> {
+        if self.in_memory.len() >= COMMANDS_PER_PAGE
+            || self.in_memory.bytes() >= self.options.memtable_bytes
+        {
+            self.save()?;
+            self.in_memory.clear();
+        } else if self.options.durability != Durability::Buffered {
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// The error returned by a failpoint configured to return.
+#[cfg(feature = "failpoints")]
+fn injected_failure(name: &str) -> Error {
+    Error::Message(format!("Injected failure at {}", name))
+}
+
+/// Apply a thread's request settings to the store it has locked.
+fn with_request(
+    mut state: MutexGuard<'_, StoreState>,
+    request: RequestScope,
+) -> MutexGuard<'_, StoreState> {
+    state.deadline = request.deadline;
+    state.cancel = request.cancel;
+    state.progress = request.progress;
+    state
+}
+
+/// The error for a store whose lock was poisoned by a panic, which may have left it
+/// half-changed, so it isn't used again.
+fn poisoned<T>(_: PoisonError<T>) -> Error {
+    Error::Message("Store is unusable after a panic while it was locked".to_owned())
+}
+
+/// Fail with `DeadlineExceeded` if `deadline` has passed, or `Cancelled` if `cancel` has been
+/// cancelled.
+fn check_interrupted(deadline: Option<Instant>, cancel: &Option<CancelToken>) -> Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(Error::DeadlineExceeded),
+        _ if cancel.as_ref().map_or(false, CancelToken::is_cancelled) => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Whether reading a file failed because of the disk rather than what's in it. Pages that
+/// fail this way aren't quarantined, since reading them again may well work.
+fn is_disk_failure(error: &Error) -> bool {
+    match error {
+        Error::IoError(e) => {
+            e.kind() != io::ErrorKind::NotFound && e.kind() != io::ErrorKind::UnexpectedEof
+        }
+        _ => false,
+    }
+}
+
+/// Check a page like `KvStore::verify_page` does, opening its files with `files`.
+fn verify_page_files(files: &FileOpener, location: &PageFiles, header: &PageHeader) -> Result<()> {
+    if let PageFiles::Segment(path, segment) = location {
+        let len = fs::metadata(path)?.len();
+        if len < segment.end() {
+            return Err(Error::Message(format!(
+                "segment file is {} bytes, but the page ends at {}",
+                len,
+                segment.end()
+            )));
+        }
+    }
+
+    let page = read_page_files(files, location)?;
+    let (len, what) = match location {
+        PageFiles::Separate([page_path, _]) => (fs::metadata(page_path)?.len(), "page file"),
+        PageFiles::Segment(_, segment) => (segment.page_len, "page block"),
+    };
+    if len != page.block_len() as u64 {
+        return Err(Error::Message(format!(
+            "{} is {} bytes, expected {}",
+            what,
+            len,
+            page.block_len()
+        )));
+    }
+    if page.header != *header {
+        return Err(Error::Message(
+            "page header does not match the index".to_owned(),
+        ));
+    }
+
+    let (path, offset, len) = location.data();
+    let mut reader = BufReader::new(files.open(OpenOptions::new().read(true), path)?);
+    let mut data = read_data_block(&mut reader, offset, len)?;
+    for i in 0..page.header.count as usize {
+        let hash = page.body.key_hash[i];
+        if hash < header.min_key_hash || hash > header.max_key_hash {
+            return Err(Error::Message(format!(
+                "slot {} has a hash outside the page's range",
+                i
+            )));
+        }
+        let value_index = page.body.value_index[i];
+        if value_index < 0 {
+            continue;
+        }
+        match data.get(value_index as usize) {
+            Some(bytes) => match decode_entry(bytes) {
+                Err(e) => return Err(Error::Message(format!("slot {}: {}", i, e))),
+                Ok((Some(key), _)) => {
+                    if let Some(slot_key) = page.body.slot_key(i) {
+                        if slot_key != SlotKey::new(&key) {
+                            return Err(Error::Message(format!(
+                                "slot {} has the wrong key length or fingerprint",
+                                i
+                            )));
+                        }
+                    }
+                }
+                Ok((None, _)) => {}
+            },
+            None => {
+                return Err(Error::Message(format!(
+                    "slot {} refers to missing value {}",
+                    i, value_index
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Snapshot {
+    /// The sequence number of the last write the snapshot sees, as
+    /// `Engine::applied_sequence` numbers them.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The key's string value as of the snapshot, like `Engine::get`.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let hash = hash_key(&self.key_hash, key);
+        let slot_key = Some(SlotKey::new(key));
+        match self
+            .pages
+            .resolve(hash, slot_key, Vec::new(), &mut HashMap::new())?
+        {
+            Some(Record::Value(value)) => Ok(Some(value)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// The keys with string values in the range as of the snapshot, like `Engine::scan`.
+    pub fn scan(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let limit = limit.unwrap_or(usize::max_value());
+        let entries = sort_by_key(self.pages.live_entries(0..=u64::max_value())?)?;
+        Ok(string_range(entries, start, end).take(limit).collect())
+    }
+
+    /// Like `scan`, but in descending key order, like `Engine::scan_rev`.
+    pub fn scan_rev(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let limit = limit.unwrap_or(usize::max_value());
+        let entries = sort_by_key(self.pages.live_entries(0..=u64::max_value())?)?;
+        Ok(string_range(entries, start, end)
+            .rev()
+            .take(limit)
+            .collect())
+    }
+}
+
+impl PageSet {
+    /// Fail like `KvStore::check_deadline`, for the request that made the set.
+    fn check_deadline(&self) -> Result<()> {
+        check_interrupted(self.deadline, &self.cancel)
+    }
+
+    /// How many of the newest `newest` pages have key hash ranges that overlap `hashes`.
+    fn overlapping(&self, hashes: &RangeInclusive<u64>, newest: usize) -> usize {
+        self.pages
+            .iter()
+            .take(newest)
+            .filter(|(_, header)| {
+                header.min_key_hash <= *hashes.end() && *hashes.start() <= header.max_key_hash
+            })
+            .count()
+    }
+
+    /// Every key with a value whose hash is in `hashes`, like `KvStore::live_entries`. Only the
+    /// pages whose ranges overlap `hashes` are read.
+    fn live_entries(
+        &self,
+        hashes: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Option<String>, Record)>> {
+        let entries = self.merged_entries(hashes, self.pages.len(), false)?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|(hash, key, record)| record.map(|record| (hash, key, record)))
+            .collect())
+    }
+
+    /// Every key whose hash is in `hashes` and which has an entry in the newest `newest` pages,
+    /// with its key (if it was written with one) and its full value, with any merge operands
+    /// from older pages folded in. A removed key comes with no value if `tombstones` is set,
+    /// and is left out otherwise.
+    fn merged_entries(
+        &self,
+        hashes: RangeInclusive<u64>,
+        newest: usize,
+        tombstones: bool,
+    ) -> Result<Vec<MergedEntry>> {
+        // Walk the pages from newest to oldest so that the first entry we see for a hash wins.
+        let mut live: BTreeMap<u64, Option<(usize, usize)>> = BTreeMap::new();
+        for (i, (location, header)) in self.pages.iter().enumerate().take(newest) {
+            if header.max_key_hash < *hashes.start() || *hashes.end() < header.min_key_hash {
+                continue;
+            }
+            self.check_deadline()?;
+            let page = read_page_files(&self.files, location)?;
+            if let Some(progress) = &self.progress {
+                progress.advance(1, location.disk_len());
+            }
+            for slot in 0..page.header.count as usize {
+                let hash = page.body.key_hash[slot];
+                if !hashes.contains(&hash) {
+                    continue;
+                }
+                let value_index = page.body.value_index[slot];
+                let location = if value_index < 0 {
+                    None
+                } else {
+                    Some((i, value_index as usize))
+                };
+                live.entry(hash).or_insert(location);
+            }
+        }
+
+        let mut data_files = HashMap::new();
+        let mut entries = Vec::new();
+        for (hash, location) in live {
+            let (key, record) = match location {
+                Some((page, value_index)) => {
+                    let data = self.data(&mut data_files, page)?;
+                    let (key, record) = decode_entry(data.get(value_index).expect("bad index"))?;
+                    // Merge operands are folded into a single full record for the key
+                    let record = if record.is_merge() {
+                        self.resolve(hash, None, Vec::new(), &mut data_files)?
+                    } else {
+                        Some(record)
+                    };
+                    (key, record)
+                }
+                None => (None, None),
+            };
+            if record.is_some() || tombstones {
+                entries.push((hash, key, record));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The hashes in `hashes` that some page in the set has a full value or a tombstone for,
+    /// so that older entries for them are never read.
+    fn replaced(&self, hashes: RangeInclusive<u64>) -> Result<HashSet<u64>> {
+        let mut replaced = HashSet::new();
+        let mut data_files = HashMap::new();
+        for (i, (location, header)) in self.pages.iter().enumerate() {
+            if header.max_key_hash < *hashes.start() || *hashes.end() < header.min_key_hash {
+                continue;
+            }
+            self.check_deadline()?;
+            let page = read_page_files(&self.files, location)?;
+            for slot in 0..page.header.count as usize {
+                let hash = page.body.key_hash[slot];
+                if !hashes.contains(&hash) || replaced.contains(&hash) {
+                    continue;
+                }
+                let value_index = page.body.value_index[slot];
+                if value_index >= 0 {
+                    let data = self.data(&mut data_files, i)?;
+                    let (_, record) =
+                        decode_entry(data.get(value_index as usize).expect("bad index"))?;
+                    if record.is_merge() {
+                        continue;
+                    }
+                }
+                replaced.insert(hash);
+            }
+        }
+        Ok(replaced)
+    }
+
+    /// Fold the merge operands for the key hash (newest first, starting with `operands`) onto
+    /// its base value, like `KvStore::resolve_on_disk`.
+    fn resolve(
+        &self,
+        key_hash: u64,
+        key: Option<SlotKey>,
+        mut operands: Vec<Record>,
+        data_files: &mut HashMap<usize, Slotted>,
+    ) -> Result<Option<Record>> {
+        let candidates: Vec<usize> = (0..self.pages.len())
+            .filter(|&i| {
+                let header = &self.pages[i].1;
+                header.min_key_hash <= key_hash && key_hash <= header.max_key_hash
+            })
+            .collect();
+        // With a lookup pool, the pages are all searched at once, like `KvStore::lookup` does
+        let mut searched = None;
+        if let (Some(pool), true) = (&self.lookup_pool, candidates.len() > 1) {
+            self.check_deadline()?;
+            let locations: Vec<PageFiles> = candidates
+                .iter()
+                .map(|&i| self.pages[i].0.clone())
+                .collect();
+            let files = self.files.clone();
+            let readers = self.readers.clone();
+            let results = pool.map(locations, move |location| {
+                search_page_files(&files, readers.as_ref(), &location, key_hash, key)
+            });
+            searched = Some(results.into_iter());
+        }
+
+        let mut base = None;
+        for i in candidates {
+            let found = match &mut searched {
+                Some(searched) => searched.next().unwrap()?,
+                None => {
+                    self.check_deadline()?;
+                    let location = &self.pages[i].0;
+                    search_page_files(&self.files, self.readers.as_ref(), location, key_hash, key)?
+                }
+            };
+            if let Some(value_index) = found {
+                if value_index < 0 {
+                    break;
+                }
+                let data = self.data(data_files, i)?;
+                let record = Record::decode(data.get(value_index as usize).expect("bad index"))?;
+                if record.is_merge() {
+                    operands.push(record);
+                } else {
+                    base = Some(record);
+                    break;
+                }
+            }
+        }
+
+        for operand in operands.into_iter().rev() {
+            base = Some(Record::merge(base, operand)?);
+        }
+        Ok(drop_empty_hash(base))
+    }
+
+    /// The data of the `page`th page, read the first time it's needed and kept in `data_files`.
+    fn data<'a>(
+        &self,
+        data_files: &'a mut HashMap<usize, Slotted>,
+        page: usize,
+    ) -> Result<&'a mut Slotted> {
+        if !data_files.contains_key(&page) {
+            let (path, offset, len) = self.pages[page].0.data();
+            let data = with_reader(&self.files, self.readers.as_ref(), path, |reader| {
+                read_data_block(reader, offset, len)
+            })?;
+            data_files.insert(page, data);
+        }
+        Ok(data_files.get_mut(&page).unwrap())
+    }
+}
+
+/// Check that a key can be written with the record, or removed without one, as `push` does.
+fn validate_write(key: &str, record: Option<&Record>) -> Result<()> {
+    match record {
+        Some(_) => kvs::validate_key(key),
+        None => kvs::check_unreserved(key),
+    }
+}
+
+/// The time `duration` after `now_ms`, in milliseconds since the epoch. A duration too long to
+/// count in milliseconds ends at the end of time rather than wrapping around to the past.
+pub(crate) fn ms_after(now_ms: u64, duration: Duration) -> u64 {
+    let ms = duration.as_millis();
+    if ms > u128::from(u64::max_value()) {
+        u64::max_value()
+    } else {
+        now_ms.saturating_add(ms as u64)
+    }
+}
+
+/// Split the key hashes into `parts` ranges of about the same size, in order.
+fn hash_ranges(parts: usize) -> Vec<RangeInclusive<u64>> {
+    let parts = parts as u64;
+    let step = u64::max_value() / parts;
+    (0..parts)
+        .map(|i| {
+            let start = i * step;
+            if i + 1 == parts {
+                start..=u64::max_value()
+            } else {
+                start..=start + step - 1
+            }
+        })
+        .collect()
+}
+
+/// The index into the data file of the page's entry for the key hash, if it has one. Given the
+/// key's length and fingerprint, slots that record a different one are skipped, since they
+/// belong to some other key with the same hash.
+fn search_page(page: &Page, key_hash: u64, key: Option<SlotKey>) -> Option<i16> {
+    // FIXME: use binary search
+    (0..page.header.count as usize)
+        .find(|&slot| {
+            page.body.key_hash[slot] == key_hash
+                && match (key, page.body.slot_key(slot)) {
+                    (Some(key), Some(slot_key)) => key == slot_key,
+                    _ => true,
+                }
+        })
+        .map(|slot| page.body.value_index[slot])
+}
+
+/// Read a page from its files, through `readers` if given, and search it for the key hash, like
+/// `search_page`.
+fn search_page_files(
+    files: &FileOpener,
+    readers: Option<&SharedReaders>,
+    location: &PageFiles,
+    key_hash: u64,
+    key: Option<SlotKey>,
+) -> Result<Option<i16>> {
+    let (path, offset) = location.page();
+    let mut buffer = PageBuffer::default();
+    let page = with_reader(files, readers, path, |reader| {
+        read_page_block(reader, offset, &mut buffer)
+    })?;
+    Ok(search_page(&page, key_hash, key))
+}
+
+/// Read from the file at `path` with its open reader from `readers`, or a newly opened one,
+/// which is given back to `readers` afterwards. The reader is taken out of the cache while it's
+/// used, so a read on another thread opens its own rather than waiting for it.
+fn with_reader<T>(
+    files: &FileOpener,
+    readers: Option<&SharedReaders>,
+    path: &Path,
+    read: impl FnOnce(&mut BufReader<StoreFile>) -> Result<T>,
+) -> Result<T> {
+    let path = path.to_owned();
+    let mut reader = match readers.and_then(|readers| readers.lock().unwrap().take(&path)) {
+        Some(reader) => reader,
+        None => BufReader::new(files.open(OpenOptions::new().read(true), &path)?),
+    };
+    let result = read(&mut reader)?;
+    if let Some(readers) = readers {
+        readers.lock().unwrap().insert(path, reader);
+    }
+    Ok(result)
+}
+
+/// Read a page from its page file or segment, without going through a reader cache.
+fn read_page_files(files: &FileOpener, location: &PageFiles) -> Result<Page> {
+    let (path, offset) = location.page();
+    let mut reader = BufReader::new(files.open(OpenOptions::new().read(true), path)?);
+    let mut buffer = PageBuffer::default();
+    read_page_block(&mut reader, offset, &mut buffer)
+}
+
+/// Read a page block starting at `offset`.
+fn read_page_block<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    buffer: &mut PageBuffer,
+) -> Result<Page> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut page = Page::default();
+    buffer.read_from(reader)?;
+    buffer.deserialize(&mut page)?;
+    Ok(page)
+}
+
+/// Read a data block of at most `len` bytes starting at `offset`.
+fn read_data_block<R: Read + Seek>(reader: &mut R, offset: u64, len: u64) -> Result<Slotted> {
+    reader.seek(SeekFrom::Start(offset))?;
+    Ok(bincode::deserialize_from(reader.take(len))?)
+}
+
+/// Read a page's page block and data block out of its segment. Blocks cut short by the end of
+/// the file come back short rather than failing, so what's left of a damaged page can still be
+/// kept.
+fn read_segment_blocks(path: &Path, location: &SegmentLocation) -> Result<[Vec<u8>; 2]> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(location.offset))?;
+    let mut page = Vec::new();
+    (&mut file).take(location.page_len).read_to_end(&mut page)?;
+    let mut data = Vec::new();
+    file.take(location.data_len).read_to_end(&mut data)?;
+    Ok([page, data])
+}
+
+/// The name of a page or data file in the archive directory.
+fn archive_name(sequence: u64, path: &Path) -> String {
+    format!(
+        "{:020}-{}",
+        sequence,
+        path.file_name().unwrap().to_string_lossy()
+    )
+}
+
+/// A key's string value, from what resolving it found.
+fn string_value(record: Option<Record>) -> Result<Option<String>> {
+    match record {
+        Some(Record::Value(value)) => Ok(Some(value)),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(None),
+    }
+}
+
+/// A key's hash, which has no fields if the key doesn't exist.
+fn hash_value(record: Option<Record>) -> Result<BTreeMap<String, String>> {
+    match record {
+        Some(Record::Hash(fields)) => Ok(fields),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// A key's sorted set, which is empty if the key doesn't exist.
+fn sorted_set_value(record: Option<Record>) -> Result<SortedSet> {
+    match record {
+        Some(Record::SortedSet(set)) => Ok(set),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(SortedSet::default()),
+    }
+}
+
+/// A key's bitmap, which has no bits set if the key doesn't exist.
+fn bitmap_value(record: Option<Record>) -> Result<Bitmap> {
+    match record {
+        Some(Record::Bitmap(bitmap)) => Ok(bitmap),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(Bitmap::default()),
+    }
+}
+
+/// The entries of a key's stream with ids from `from` to `to`, none if the key doesn't exist.
+fn stream_range(
+    record: Option<Record>,
+    from: StreamId,
+    to: StreamId,
+) -> Result<Vec<(StreamId, String)>> {
+    match record {
+        Some(Record::Stream(entries)) => Ok(entries
+            .range(from..=to)
+            .map(|(id, payload)| (*id, payload.clone()))
+            .collect()),
+        Some(_) => Err(Error::WrongType),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// A hash with no fields left is the same as no value at all.
+fn drop_empty_hash(record: Option<Record>) -> Option<Record> {
+    match record {
+        Some(Record::Hash(ref fields)) if fields.is_empty() => None,
+        record => record,
+    }
+}
+
+/// Hash a key with the function and seed the store was created with.
+fn hash_key(key_hash: &KeyHash, key: &str) -> u64 {
+    fn finish(mut hasher: impl Hasher, key: &str) -> u64 {
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+    let seed = key_hash.seed;
+    match key_hash.algorithm {
+        HashAlgorithm::MetroHash => finish(MetroHash64::with_seed(seed), key),
+        HashAlgorithm::XxHash => finish(XxHash64::with_seed(seed), key),
+        // SipHash takes a 128-bit key, of which the seed is the first half
+        HashAlgorithm::SipHash => finish(SipHasher24::new_with_keys(seed, 0), key),
+    }
+}
+
+/// Entries with their keys, sorted by key. Fails if any were written before keys were stored
+/// with their values.
+fn sort_by_key(entries: Vec<(u64, Option<String>, Record)>) -> Result<Vec<(String, Record)>> {
+    let mut keyed = Vec::new();
+    let mut keyless = 0;
+    for (_, key, record) in entries {
+        match key {
+            Some(key) => keyed.push((key, record)),
+            None => keyless += 1,
+        }
+    }
+    if keyless > 0 {
+        return Err(Error::Message(format!(
+            "{} values were written before keys were stored with them, so they can't be \
+             exported",
+            keyless
+        )));
+    }
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(keyed)
+}
+
+/// The entries with string values from `start` up to but not including `end`, leaving out the
+/// server's own keys. The entries must be sorted by key.
+fn string_range(
+    entries: Vec<(String, Record)>,
+    start: Option<String>,
+    end: Option<String>,
+) -> vec::IntoIter<(String, String)> {
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|(key, _)| {
+            start.as_ref().map_or(true, |start| key >= start)
+                && end.as_ref().map_or(true, |end| key < end)
+                && !kvs::is_reserved_key(key)
+        })
+        .filter_map(|(key, record)| match record {
+            Record::Value(value) => Some((key, value)),
+            _ => None,
+        })
+        .collect();
+    entries.into_iter()
+}
+
+/// The size of a value as the size histograms count it: a string's length, or the encoded
+/// length of any other record.
+fn value_size(record: &Record) -> Result<u64> {
+    let size = match record {
+        Record::Value(value) => value.len(),
+        record => record.encode()?.len(),
+    };
+    Ok(size as u64)
+}
+
+/// How many of a new page's entries are tombstones, for `KvStore::approximate_len`.
+fn count_tombstones(body: &PageBody, count: u16) -> u16 {
+    body.value_index[..count as usize]
+        .iter()
+        .filter(|value_index| **value_index < 0)
+        .count() as u16
+}
+
+/// A random-ish clock sequence for a new store, as RFC 4122 recommends.
+fn random_clock_sequence() -> u16 {
+    let mut hasher = MetroHash64::with_seed(DEFAULT_HASH_SEED);
+    process::id().hash(&mut hasher);
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+        .hash(&mut hasher);
+    hasher.finish() as u16
+}
+
+/// Derive a UUIDv1 node id from the machine's hostname, so that different machines mint
+/// different page UUIDs without any configuration.
+///
+/// As RFC 4122 requires for node ids that aren't MAC addresses, the multicast bit is set so it
+/// can never clash with a real network card.
+fn machine_node_id() -> [u8; 6] {
+    let hostname = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .unwrap_or_default();
+    let mut hasher = MetroHash64::with_seed(DEFAULT_HASH_SEED);
+    hostname.trim().hash(&mut hasher);
+    let bytes = hasher.finish().to_le_bytes();
+    let mut node_id = [0; 6];
+    node_id.copy_from_slice(&bytes[..6]);
+    node_id[0] |= 0x01;
+    node_id
+}
+
+/// Fsync a directory so that the files created in or renamed into it are durable.
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
 }
@@ -1,9 +1,55 @@
 #[macro_use]
+extern crate fail;
+#[macro_use]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
+mod backup;
+mod cache;
+mod clock;
+mod compaction;
+mod dump;
+#[cfg(feature = "failpoints")]
+mod faults;
+mod hooks;
+mod idempotency;
 mod kv;
+mod locks;
+mod log_file;
+mod memtable;
+mod options;
+mod parallel;
+mod readers;
+#[cfg(feature = "object-store")]
+mod s3;
+mod sealed;
+mod secondary_index;
+mod session_store;
+mod sst;
+mod statsd;
+mod systemd;
+mod telemetry;
+mod txn;
 
-pub use kv::KvStore;
+pub use backup::{BackupSink, Checksums, DirSink, FileChecksum, CHECKSUMS_FILE};
+pub use cache::CacheEngine;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use dump::{ConflictPolicy, DumpEntry, DumpFormat, DumpValue, LoadReport};
+#[cfg(feature = "failpoints")]
+pub use faults::IoFaults;
+pub use hooks::HookMode;
+pub use idempotency::{Claim, IdempotencyCache};
 pub use kv::SledEngine;
+pub use kv::{KvStore, RecoveryTarget, Snapshot, MAX_TRANSACTION_ATTEMPTS};
+pub use log_file::{RotatingFile, Rotation};
+pub use logformat::manifest::{HashAlgorithm, KeyHash};
+pub use options::{parse_hash_algorithm, parse_node_id, CompactionStrategy, Durability, Options};
+pub use parallel::WorkerPool;
+pub use sealed::ReadOnlyKvStore;
+pub use secondary_index::{ExtractFn, Extractor, SecondaryIndex};
+pub use session_store::SessionStore;
+pub use statsd::Statsd;
+pub use systemd::systemd_listeners;
+pub use telemetry::{Span, Telemetry};
+pub use txn::{ScopedTransaction, Transaction};
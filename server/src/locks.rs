@@ -0,0 +1,71 @@
+//! Exclusive per-key locks held by transactions. While a transaction holds a key's lock, no
+//! other transaction can lock it and nothing else can write it.
+//!
+//! Taking a lock never blocks: a transaction that finds the key locked gets `Error::LockHeld`
+//! and is recorded as waiting for the holder until it takes a lock or ends. If that would make
+//! a cycle of transactions each waiting for the next, it gets `Error::Deadlock` instead, so
+//! that retrying can't go on forever.
+use kvs::{Error, Result};
+use std::collections::{HashMap, HashSet};
+
+/// The owner of every locked key. It's only used under the store's lock, so it needs none of
+/// its own.
+#[derive(Default)]
+pub(crate) struct LockTable {
+    holders: HashMap<String, u64>,
+    /// The transaction each waiting transaction is waiting for.
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockTable {
+    /// Lock the key for the transaction `owner`. Returns whether it wasn't already holding it,
+    /// `Error::LockHeld` if another transaction is, or `Error::Deadlock` if that transaction is
+    /// waiting for `owner`, directly or through others.
+    pub(crate) fn acquire(&mut self, key: &str, owner: u64) -> Result<bool> {
+        let holder = match self.holders.get(key) {
+            Some(&holder) if holder != owner => holder,
+            Some(_) => return Ok(false),
+            None => {
+                self.holders.insert(key.to_owned(), owner);
+                self.waits_for.remove(&owner);
+                return Ok(true);
+            }
+        };
+
+        let mut visited = HashSet::new();
+        let mut next = Some(holder);
+        while let Some(waiter) = next {
+            if waiter == owner {
+                self.waits_for.remove(&owner);
+                return Err(Error::Deadlock);
+            }
+            if !visited.insert(waiter) {
+                break;
+            }
+            next = self.waits_for.get(&waiter).cloned();
+        }
+        self.waits_for.insert(owner, holder);
+        Err(Error::LockHeld)
+    }
+
+    /// Fail with `Error::LockHeld` if a transaction other than `owner` holds the key's lock.
+    pub(crate) fn check(&self, key: &str, owner: Option<u64>) -> Result<()> {
+        match self.holders.get(key) {
+            Some(&holder) if Some(holder) != owner => Err(Error::LockHeld),
+            _ => Ok(()),
+        }
+    }
+
+    /// Release the key's lock, if `owner` holds it.
+    pub(crate) fn release(&mut self, key: &str, owner: u64) {
+        if self.holders.get(key) == Some(&owner) {
+            self.holders.remove(key);
+        }
+    }
+
+    /// Forget that `owner` is waiting for, or being waited for by, anyone, once it's ended.
+    pub(crate) fn forget(&mut self, owner: u64) {
+        self.waits_for
+            .retain(|&waiter, &mut holder| waiter != owner && holder != owner);
+    }
+}
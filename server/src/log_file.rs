@@ -0,0 +1,110 @@
+//! A log file that's rotated once it gets too big or too old, for servers that run long enough
+//! that one file would grow without bound.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// When to rotate the log file, and how many rotated files to keep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rotation {
+    /// Rotate once the file is at least this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate once the file has been written to for this long.
+    pub max_age: Option<Duration>,
+    /// How many rotated files to keep, as `PATH.1` (the newest) to `PATH.N`. Older ones are
+    /// deleted.
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Rotation {
+        Rotation {
+            max_size: None,
+            max_age: None,
+            keep: 5,
+        }
+    }
+}
+
+/// Appends to the file at a path, moving it aside and starting a new one when the rotation
+/// policy says to. Files are only rotated on `flush`, which slog's decorators call after every
+/// record, so a record is never split across files.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    /// Open the log file at `path`, appending to it if it exists.
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_owned(),
+            rotation,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn is_due(&self) -> bool {
+        let too_big = match self.rotation.max_size {
+            Some(max_size) => self.size >= max_size,
+            None => false,
+        };
+        let too_old = match self.rotation.max_age {
+            Some(max_age) => self.size > 0 && self.opened.elapsed() >= max_age,
+            None => false,
+        };
+        too_big || too_old
+    }
+
+    /// Shift `PATH.N-1` to `PATH.N` and so on, move the current file to `PATH.1`, and start a
+    /// new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = self.rotated_path(self.rotation.keep);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for n in (1..self.rotation.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *self = RotatingFile::open(&self.path, self.rotation.clone())?;
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(format!(".{}", n));
+        self.path.with_file_name(name)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.is_due() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
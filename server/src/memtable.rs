@@ -0,0 +1,133 @@
+//! Writes that haven't been saved to a page yet.
+//!
+//! Each entry is encoded the way it will be stored in a data file and copied into an arena of
+//! large chunks, instead of being kept as its own `Record` with allocations of its own. Saving
+//! the memtable writes the encoded entries as they are, and then frees the whole arena at
+//! once. Entries that are replaced stay in the arena until then, so the memtable's size is
+//! every write since it was last started over, which is what its byte budget is counted in.
+use crate::kv::InMemoryKey;
+use kvs::Result;
+use logformat::record::{encode_entry, Record};
+use std::collections::BTreeMap;
+
+/// Entries are copied into chunks of this many bytes, or one of their own if they're bigger.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where an encoded entry is in the arena.
+#[derive(Debug, Clone, Copy)]
+struct Slice {
+    chunk: usize,
+    start: usize,
+    len: usize,
+}
+
+/// Chunks that are only ever appended to, so an entry's bytes never move once copied in.
+#[derive(Default)]
+struct Arena {
+    chunks: Vec<Vec<u8>>,
+    /// The bytes copied in since the arena was last cleared.
+    used: usize,
+}
+
+impl Arena {
+    fn alloc(&mut self, bytes: &[u8]) -> Slice {
+        let fits = match self.chunks.last() {
+            Some(chunk) => chunk.capacity() - chunk.len() >= bytes.len(),
+            None => false,
+        };
+        if !fits {
+            self.chunks
+                .push(Vec::with_capacity(CHUNK_SIZE.max(bytes.len())));
+        }
+        let chunk = self.chunks.len() - 1;
+        let buf = &mut self.chunks[chunk];
+        let start = buf.len();
+        buf.extend_from_slice(bytes);
+        self.used += bytes.len();
+        Slice {
+            chunk,
+            start,
+            len: bytes.len(),
+        }
+    }
+
+    fn get(&self, slice: Slice) -> &[u8] {
+        &self.chunks[slice.chunk][slice.start..slice.start + slice.len]
+    }
+
+    /// Free every chunk but the first, which is kept to start filling again.
+    fn clear(&mut self) {
+        self.chunks.truncate(1);
+        if let Some(chunk) = self.chunks.first_mut() {
+            chunk.clear();
+        }
+        self.used = 0;
+    }
+}
+
+/// The newest entry for each key written since the memtable was last started over, ordered by
+/// key hash. An entry without a value is a tombstone.
+#[derive(Default)]
+pub(crate) struct Memtable {
+    entries: BTreeMap<InMemoryKey, Option<Slice>>,
+    arena: Arena,
+    /// The length of every key in `entries`.
+    key_bytes: usize,
+}
+
+impl Memtable {
+    /// The key's entry, if the memtable has one: `Some(None)` for a tombstone.
+    pub fn get(&self, key: &InMemoryKey) -> Result<Option<Option<Record>>> {
+        match self.entries.get(key) {
+            Some(Some(slice)) => Ok(Some(Some(Record::decode(self.arena.get(*slice))?))),
+            Some(None) => Ok(Some(None)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the key's entry has a value, if the memtable has one, without decoding it.
+    pub fn has_value(&self, key: &InMemoryKey) -> Option<bool> {
+        self.entries.get(key).map(Option::is_some)
+    }
+
+    pub fn insert(&mut self, key: InMemoryKey, record: Option<&Record>) -> Result<()> {
+        let slice = match record {
+            Some(record) => Some(self.arena.alloc(&encode_entry(&key.key, record)?)),
+            None => None,
+        };
+        if !self.entries.contains_key(&key) {
+            self.key_bytes += key.key.len();
+        }
+        self.entries.insert(key, slice);
+        Ok(())
+    }
+
+    /// Each entry in order of key hash, as the bytes to store in a data file.
+    pub fn iter(&self) -> impl Iterator<Item = (&InMemoryKey, Option<&[u8]>)> {
+        let arena = &self.arena;
+        self.entries
+            .iter()
+            .map(move |(key, slice)| (key, slice.map(|slice| arena.get(slice))))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The bytes counted against `Options::memtable_bytes`: the keys, and every entry written
+    /// into the arena, including ones that have since been replaced.
+    pub fn bytes(&self) -> usize {
+        self.key_bytes + self.arena.used
+    }
+
+    /// Drop every entry and free the arena.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.arena.clear();
+        self.key_bytes = 0;
+    }
+}
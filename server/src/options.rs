@@ -0,0 +1,243 @@
+use crate::clock::{Clock, SystemClock};
+#[cfg(feature = "failpoints")]
+use crate::faults::IoFaults;
+use crate::secondary_index::SecondaryIndex;
+use kvs::Error;
+use logformat::manifest::{HashAlgorithm, KeyHash};
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How hard the store tries to get a write onto stable storage before acknowledging it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are kept in memory until a full page is ready, `save` is called, or the store is
+    /// dropped. A crash of the process loses every write since the last page was written.
+    Buffered,
+    /// Every write is written out to a page and the index before it is acknowledged, but
+    /// nothing is fsynced. Survives the process crashing, but the OS crashing or losing power
+    /// can still lose recently acknowledged writes.
+    Flush,
+    /// Like `Flush`, but the page, data, and index files and the directory itself are fsynced
+    /// before a write is acknowledged. Survives the OS crashing or losing power.
+    Sync,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Flush
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Buffered => write!(f, "buffered"),
+            Durability::Flush => write!(f, "flush"),
+            Durability::Sync => write!(f, "sync"),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buffered" => Ok(Durability::Buffered),
+            "flush" => Ok(Durability::Flush),
+            "sync" => Ok(Durability::Sync),
+            _ => Err(Error::Message(format!("Unknown durability level: {}", s))),
+        }
+    }
+}
+
+/// How the store merges its pages on its own as they're saved. `KvStore::compact` always
+/// merges every page, whichever is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    /// Pages are only merged by `compact`.
+    Manual,
+    /// Once a level has `Options::compaction_fanout` runs, they're merged into one run on the
+    /// next level. Each write is rewritten about once per level, but a read may have to look
+    /// through nearly a fanout's worth of runs on every level.
+    SizeTiered,
+    /// Every level past the first is a single run, each holding `Options::compaction_fanout`
+    /// times as many pages as the one before. A level that outgrows that is merged into the
+    /// next, so reads look at one run per level, but each merge rewrites the whole next level.
+    Leveled,
+}
+
+impl Default for CompactionStrategy {
+    fn default() -> Self {
+        CompactionStrategy::SizeTiered
+    }
+}
+
+impl Display for CompactionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactionStrategy::Manual => write!(f, "manual"),
+            CompactionStrategy::SizeTiered => write!(f, "size-tiered"),
+            CompactionStrategy::Leveled => write!(f, "leveled"),
+        }
+    }
+}
+
+impl FromStr for CompactionStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(CompactionStrategy::Manual),
+            "size-tiered" => Ok(CompactionStrategy::SizeTiered),
+            "leveled" => Ok(CompactionStrategy::Leveled),
+            _ => Err(Error::Message(format!(
+                "Unknown compaction strategy: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Settings used when opening a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub durability: Durability,
+    /// The UUIDv1 node id for new pages. Derived from the machine's hostname if not set.
+    pub node_id: Option<[u8; 6]>,
+    /// How long page files that aren't referenced by the index are kept in `lost+found/`
+    /// before they're deleted.
+    pub orphan_grace_period: Duration,
+    /// Whether `remove` checks that the key exists, returning `KeyNotFound` if it doesn't.
+    /// Turning this off makes removes blind tombstone writes that never touch the disk.
+    pub check_exists_on_remove: bool,
+    /// If set, every page is copied here once it's committed, for point-in-time recovery with
+    /// `KvStore::restore_to`. With the `object-store` feature this can also be an
+    /// `s3://bucket/prefix` URL. Compaction won't delete a page until it has been archived.
+    pub archive_dir: Option<PathBuf>,
+    /// If set, compaction writes its pages here instead of into the data directory, e.g. on a
+    /// bigger, slower disk. Pages saved since the last compaction stay in the data directory,
+    /// and reads look in both.
+    pub cold_dir: Option<PathBuf>,
+    /// Open the store without taking the directory lock or changing any file in it, e.g. to
+    /// check a backup. Writes fail with `Error::ReadOnly`.
+    ///
+    /// Any number of read-only processes can open a directory while one writer has it open.
+    /// They see the pages the writer had saved when they opened it, until `KvStore::refresh`.
+    pub read_only: bool,
+    /// If set, `Engine::flush_if_due` refreshes a read-only store once this long has passed
+    /// since it last did, so that it keeps up with the writer.
+    pub refresh_interval: Option<Duration>,
+    /// How many page and data files are kept open for reading. Once there are this many, the
+    /// least recently read one is closed before another is opened.
+    pub max_open_files: usize,
+    /// How many bytes of keys and encoded values the memtable holds before it's saved and
+    /// started over, counting values that later writes replaced. It's also started over once
+    /// it fills a page, whichever comes first.
+    pub memtable_bytes: usize,
+    /// If set, `Engine::flush_if_due` saves the memtable once it has had unsaved writes for
+    /// this long, however few there are. With `Durability::Buffered`, this bounds how much a
+    /// crash can lose on a store that's rarely written to.
+    pub flush_interval: Option<Duration>,
+    /// Compaction packs its pages into segment files of about this many bytes, starting a new
+    /// segment once one reaches it. Pages saved between compactions have files of their own.
+    pub segment_size: u64,
+    /// How many threads check the pages when the store is opened. Every page is read in full,
+    /// so this is most of the time it takes to open a large store.
+    pub startup_threads: usize,
+    /// How many threads a lookup reads pages on when the key's hash is in more than one page's
+    /// range. With one, the pages are read in turn, newest first, stopping at the first that
+    /// has the key.
+    pub lookup_threads: usize,
+    /// How many threads compaction merges pages on, each taking a share of the key hashes.
+    /// Each thread reads every page whose range overlaps its share, so pages saved since the
+    /// last compaction, which cover most hashes, are read by all of them.
+    pub compaction_threads: usize,
+    /// How pages are merged as they're saved.
+    pub compaction: CompactionStrategy,
+    /// How many runs a level of size-tiered compaction collects before they're merged, and how
+    /// much bigger each level of leveled compaction is than the one before. At least 2.
+    pub compaction_fanout: usize,
+    /// A run of pages is rewritten on its own once about this share of its entries have been
+    /// replaced or removed by newer pages, before anything the strategy would merge. Above 1,
+    /// runs are never rewritten just for that.
+    pub compaction_garbage_ratio: f64,
+    /// The hash function and seed for the keys of a new store, MetroHash with the default seed
+    /// if not set. If it's set when opening an existing store, it has to be what the store
+    /// was created with, or opening fails.
+    pub key_hash: Option<KeyHash>,
+    /// Secondary indexes over the store's string values, looked up with
+    /// `KvStore::get_by_index`. An index that's new to the store is built from its values when
+    /// it's opened.
+    pub indexes: Vec<SecondaryIndex>,
+    /// Keep an index of the n-grams in every string value, so that
+    /// `KvStore::find_values_containing` only has to read the values that might match. It's
+    /// built when it's first turned on and rebuilt by `compact`, and makes every write of a
+    /// value also write an entry for each n-gram that's new to it or gone from it.
+    pub substring_index: bool,
+    /// Where TTLs, lease expiry, stream ids, and page timestamps get the time from.
+    pub clock: Arc<dyn Clock>,
+    /// Faults to inject into every read and write of page, data, and index files, for tests.
+    #[cfg(feature = "failpoints")]
+    pub io_faults: Option<IoFaults>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            durability: Durability::default(),
+            node_id: None,
+            orphan_grace_period: Duration::from_secs(7 * 24 * 60 * 60),
+            check_exists_on_remove: true,
+            archive_dir: None,
+            cold_dir: None,
+            read_only: false,
+            refresh_interval: None,
+            max_open_files: 256,
+            memtable_bytes: 4 * 1024 * 1024,
+            flush_interval: None,
+            segment_size: 64 * 1024 * 1024,
+            startup_threads: 4,
+            lookup_threads: 1,
+            compaction_threads: 1,
+            compaction: CompactionStrategy::default(),
+            compaction_fanout: 4,
+            compaction_garbage_ratio: 0.5,
+            key_hash: None,
+            indexes: Vec::new(),
+            substring_index: false,
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "failpoints")]
+            io_faults: None,
+        }
+    }
+}
+
+/// Parse a node id written as 12 hex digits, optionally separated by colons like a MAC address.
+pub fn parse_node_id(s: &str) -> Result<[u8; 6], Error> {
+    let digits: String = s.chars().filter(|c| *c != ':').collect();
+    if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::Message(format!(
+            "Invalid node id {:?}, expected 12 hex digits",
+            s
+        )));
+    }
+    let mut node_id = [0; 6];
+    for (i, byte) in node_id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    Ok(node_id)
+}
+
+/// Parse the name of a hash function, as `HashAlgorithm` displays it.
+pub fn parse_hash_algorithm(s: &str) -> Result<HashAlgorithm, Error> {
+    match s {
+        "metrohash" => Ok(HashAlgorithm::MetroHash),
+        "xxhash" => Ok(HashAlgorithm::XxHash),
+        "siphash" => Ok(HashAlgorithm::SipHash),
+        _ => Err(Error::Message(format!("Unknown hash function: {}", s))),
+    }
+}
@@ -0,0 +1,107 @@
+//! A small pool of threads for reading many pages at once, like when a store is opened or a
+//! lookup has several pages to search, and for serving connections.
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that run jobs from a shared queue until the pool is dropped.
+pub struct WorkerPool {
+    /// Only `None` while the pool is being dropped, so that the workers see the queue close.
+    jobs: Option<Mutex<Sender<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// A pool of `threads` threads, or one if `threads` is zero.
+    pub fn new(threads: usize) -> WorkerPool {
+        let (sender, receiver) = mpsc::channel();
+        let receiver: Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    // The lock is released before the job runs, so the workers only contend on
+                    // taking the next one
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        // A job that panics loses its result, which `map` notices; the worker
+                        // carries on with the next one
+                        Ok(job) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        WorkerPool {
+            jobs: Some(Mutex::new(sender)),
+            workers,
+        }
+    }
+
+    /// Run the job on one of the pool's threads without waiting for it. A pool of one thread
+    /// runs its jobs in the order they were given.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        let jobs = self.jobs.as_ref().unwrap().lock().unwrap();
+        jobs.send(Box::new(job))
+            .expect("the worker pool has stopped");
+    }
+
+    /// Apply `f` to every item on the pool's threads, returning the results in the order of
+    /// the items.
+    pub fn map<T, R, F>(&self, items: Vec<T>, f: F) -> Vec<R>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+    {
+        let len = items.len();
+        let f = Arc::new(f);
+        let (sender, receiver) = mpsc::channel();
+        {
+            let jobs = self.jobs.as_ref().unwrap().lock().unwrap();
+            for (i, item) in items.into_iter().enumerate() {
+                let f = f.clone();
+                let sender = sender.clone();
+                let job: Job = Box::new(move || {
+                    let _ = sender.send((i, f(item)));
+                });
+                jobs.send(job).expect("the worker pool has stopped");
+            }
+        }
+        drop(sender);
+
+        let mut results: Vec<(usize, R)> = receiver.iter().collect();
+        assert_eq!(results.len(), len, "a job in the worker pool panicked");
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Apply `f` to every item on up to `threads` threads, returning the results in the order of
+/// the items. With one thread, or one item, everything runs on the calling thread.
+pub(crate) fn map<T, R, F>(items: Vec<T>, threads: usize, f: F) -> Vec<R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    let threads = threads.min(items.len());
+    if threads <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+    WorkerPool::new(threads).map(items, f)
+}
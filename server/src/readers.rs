@@ -0,0 +1,86 @@
+//! Open readers for page and data files, bounded so that a store with many pages doesn't run
+//! out of file descriptors.
+use kvs::CacheStats;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Readers by key. Inserting one when the cache is full closes the least recently used.
+pub(crate) struct ReaderCache<K, R> {
+    capacity: usize,
+    /// Each reader with the tick it was last used at.
+    readers: HashMap<K, (u64, R)>,
+    /// Keys by the tick they were last used at, oldest first.
+    recency: BTreeMap<u64, K>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl<K: Hash + Eq + Clone, R> ReaderCache<K, R> {
+    /// A cache holding up to `capacity` readers, or one if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        ReaderCache {
+            capacity: capacity.max(1),
+            readers: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Take the reader for the key out of the cache while it's used, so that the cache needn't
+    /// be held meanwhile. Giving it back with `insert` marks it as the most recently used.
+    pub fn take(&mut self, key: &K) -> Option<R> {
+        match self.readers.remove(key) {
+            Some((last_used, reader)) => {
+                self.recency.remove(&last_used);
+                self.hits += 1;
+                Some(reader)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Add a reader, closing the least recently used one if the cache is full.
+    pub fn insert(&mut self, key: K, reader: R) {
+        self.remove(&key);
+        while self.readers.len() >= self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&oldest) {
+                self.readers.remove(&key);
+                self.evictions += 1;
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.readers.insert(key, (self.tick, reader));
+    }
+
+    /// How often readers were already open when they were needed, and how many are open now.
+    /// The readers' buffers aren't counted in bytes.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            entries: self.readers.len() as u64,
+            bytes: 0,
+        }
+    }
+
+    /// Close the reader for the key, if it's open.
+    pub fn remove(&mut self, key: &K) {
+        if let Some((last_used, _)) = self.readers.remove(key) {
+            self.recency.remove(&last_used);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Uploads snapshot files to an S3-compatible object store, signing requests with AWS
+//! Signature Version 4.
+//!
+//! Like the OTLP exporter this speaks plain HTTP to keep the dependencies small, so the
+//! endpoint has to be reachable over `http://` (e.g. MinIO, or a local TLS-terminating proxy in
+//! front of S3 or GCS's XML API).
+use crate::backup::{sha256_hex, BackupSink};
+use hmac::{Hmac, Mac};
+use kvs::{Error, Result};
+use sha2::Sha256;
+use slog::{warn, Logger};
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Each upload is tried this many times before the snapshot fails.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The wait before the first retry, doubled for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Writes snapshot files as objects under `s3://bucket/prefix/`.
+///
+/// The endpoint and credentials come from the `KVS_S3_ENDPOINT`, `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY`, and (optionally) `AWS_REGION` environment variables.
+pub struct S3Sink {
+    host: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    slog: Logger,
+}
+
+impl S3Sink {
+    pub fn from_env(url: &str, slog: Logger) -> Result<S3Sink> {
+        let rest = &url["s3://".len()..];
+        let (bucket, prefix) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i + 1..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if bucket.is_empty() {
+            return Err(Error::Message(format!("No bucket in {:?}", url)));
+        }
+
+        let endpoint = required_env("KVS_S3_ENDPOINT")?;
+        if !endpoint.starts_with("http://") {
+            return Err(Error::Message(format!(
+                "Unsupported object store endpoint {:?}, expected http://HOST:PORT",
+                endpoint
+            )));
+        }
+        Ok(S3Sink {
+            host: endpoint["http://".len()..].trim_end_matches('/').to_owned(),
+            bucket: bucket.to_owned(),
+            prefix: prefix.to_owned(),
+            region: env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+            access_key: required_env("AWS_ACCESS_KEY_ID")?,
+            secret_key: required_env("AWS_SECRET_ACCESS_KEY")?,
+            slog,
+        })
+    }
+
+    fn try_put(&self, path: &str, contents: &[u8], payload_hash: &str) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let (date, amz_date) = utc_timestamp(now.as_secs());
+
+        let canonical_request = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n\
+             host;x-amz-content-sha256;x-amz-date\n{}",
+            path, self.host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in &[self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature: String = hmac(&key, &string_to_sign)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let mut stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        write!(
+            stream,
+            "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nx-amz-content-sha256: {}\r\n\
+             x-amz-date: {}\r\nAuthorization: AWS4-HMAC-SHA256 Credential={}/{}, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}\r\n\
+             Connection: close\r\n\r\n",
+            path,
+            self.host,
+            contents.len(),
+            payload_hash,
+            amz_date,
+            self.access_key,
+            scope,
+            signature
+        )?;
+        stream.write_all(contents)?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(Error::Message(format!(
+                "Object store responded with {:?}",
+                status_line.trim()
+            ))),
+        }
+    }
+}
+
+impl BackupSink for S3Sink {
+    /// Upload the file, retrying with exponential backoff. The store checks the body against
+    /// the SHA-256 sent with it, so a corrupted upload is rejected rather than stored.
+    fn put(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        let key = if self.prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        };
+        let path = format!("/{}/{}", uri_encode(&self.bucket), uri_encode(&key));
+        let payload_hash = sha256_hex(contents);
+
+        let mut attempt = 0;
+        loop {
+            match self.try_put(&path, contents, &payload_hash) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt + 1 < MAX_ATTEMPTS => {
+                    warn!(self.slog, "Uploading {} failed, retrying: {}", key, e);
+                    thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn required_env(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| Error::Message(format!("{} is not set", name)))
+}
+
+fn hmac(key: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.input(message.as_bytes());
+    mac.result().code().to_vec()
+}
+
+/// Percent-encode everything but unreserved characters and slashes, as SigV4 requires.
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Format a Unix time as a `YYYYMMDD` date and a `YYYYMMDDTHHMMSSZ` timestamp in UTC.
+fn utc_timestamp(secs: u64) -> (String, String) {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    );
+    (date, timestamp)
+}
@@ -0,0 +1,148 @@
+//! Frozen datasets: a directory that's never written to again, like a snapshot, served without
+//! any way to change it.
+use crate::backup::{sha256_hex, Checksums, CHECKSUMS_FILE};
+use crate::kv::KvStore;
+use kvs::{Engine, Error, Result, Stats, StreamId, VerifyReport};
+use logformat::manifest::Manifest;
+use slog::Logger;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The checksums of a sealed directory, and which of its files have been checked against them
+/// so far.
+pub(crate) struct Sealed {
+    checksums: Checksums,
+    checked: Mutex<HashSet<PathBuf>>,
+}
+
+impl Sealed {
+    /// Check the file against its checksum, unless it already has been. A file the checksums
+    /// don't list fails, since nothing is added to a sealed directory.
+    pub(crate) fn check(&self, path: &Path) -> io::Result<()> {
+        if self.checked.lock().unwrap().contains(path) {
+            return Ok(());
+        }
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let expected = match self.checksums.files.get(&name) {
+            Some(expected) => expected,
+            None => return Err(invalid(format!("{}: not listed in the checksums", name))),
+        };
+        let contents = fs::read(path)?;
+        if contents.len() as u64 != expected.len || sha256_hex(&contents) != expected.sha256 {
+            return Err(invalid(format!("{}: checksum does not match", name)));
+        }
+        self.checked.lock().unwrap().insert(path.to_owned());
+        Ok(())
+    }
+}
+
+/// A store opened from a sealed directory: one with a `CHECKSUMS` file listing every file in
+/// it, as snapshots have. It takes no lock and never writes, so any number of processes can
+/// serve the same directory, and it has no methods that change anything. Like `KvStore`, it can
+/// be cloned to read from several threads.
+///
+/// Every file is checked against its checksum the first time it's read, and reads from a file
+/// that doesn't match fail.
+#[derive(Clone)]
+pub struct ReadOnlyKvStore {
+    store: KvStore,
+}
+
+impl ReadOnlyKvStore {
+    pub fn open(path: &Path) -> Result<ReadOnlyKvStore> {
+        ReadOnlyKvStore::open_with_logger(path, &kvs::get_default_logger())
+    }
+
+    pub fn open_with_logger(path: &Path, logger: &Logger) -> Result<ReadOnlyKvStore> {
+        let checksums = match fs::read_to_string(path.join(CHECKSUMS_FILE)) {
+            Ok(contents) => Checksums::from_ron(&contents)?,
+            Err(e) => {
+                return Err(Error::Message(format!(
+                    "{:?} is not sealed: {}: {}",
+                    path, CHECKSUMS_FILE, e
+                )))
+            }
+        };
+        let sealed = Sealed {
+            checksums,
+            checked: Mutex::new(HashSet::new()),
+        };
+        // The store reads the manifest without going through its files, so it's checked here
+        sealed.check(&path.join(Manifest::path()))?;
+        let store = KvStore::open_sealed(path, logger, sealed)?;
+        Ok(ReadOnlyKvStore { store })
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.get(key.to_owned())
+    }
+
+    /// Like `Engine::scan`.
+    pub fn scan(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        self.store.scan(start, end, limit)
+    }
+
+    /// Like `Engine::scan_rev`.
+    pub fn scan_rev(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        self.store.scan_rev(start, end, limit)
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        self.store.hget(key.to_owned(), field.to_owned())
+    }
+
+    pub fn hgetall(&self, key: &str) -> Result<BTreeMap<String, String>> {
+        self.store.hgetall(key.to_owned())
+    }
+
+    pub fn zrangebyscore(&self, key: &str, min: f64, max: f64) -> Result<Vec<(String, f64)>> {
+        self.store.zrangebyscore(key.to_owned(), min, max)
+    }
+
+    pub fn zrank(&self, key: &str, member: &str) -> Result<Option<u64>> {
+        self.store.zrank(key.to_owned(), member.to_owned())
+    }
+
+    pub fn xrange(
+        &self,
+        key: &str,
+        from: StreamId,
+        to: StreamId,
+    ) -> Result<Vec<(StreamId, String)>> {
+        self.store.xrange(key.to_owned(), from, to)
+    }
+
+    pub fn getbit(&self, key: &str, offset: u64) -> Result<bool> {
+        self.store.getbit(key.to_owned(), offset)
+    }
+
+    pub fn bitcount(&self, key: &str) -> Result<u64> {
+        self.store.bitcount(key.to_owned())
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        self.store.stats()
+    }
+
+    /// Check every page, reading each file (and so checking its checksum) along the way.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.store.verify()
+    }
+}
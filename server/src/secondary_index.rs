@@ -0,0 +1,114 @@
+//! Secondary indexes: lookups of string values by something extracted from them.
+//!
+//! An index is declared in `Options::indexes` with a name and an extractor. Each of its entries
+//! is stored in the store itself, as a hash under a reserved key made from the index's name
+//! and the extracted value, whose fields are the keys with that value. Entries are changed in
+//! the same commit as the write that changes them, as hash merge operands, so they're never
+//! ahead of or behind the values they point to.
+//!
+//! The substring index, turned on with `Options::substring_index`, is kept the same way, with
+//! an entry for every run of `NGRAM_LEN` characters in any value.
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+
+/// What the index entries' keys start with, followed by the index's name, a slash, and the
+/// extracted value.
+pub(crate) const INDEX_KEY_PREFIX: &str = "__kvs_index/";
+
+/// What the substring index's entries' keys start with, followed by the n-gram.
+pub(crate) const NGRAM_KEY_PREFIX: &str = "__kvs_ngram/";
+
+/// How many characters the substring index's n-grams are. Shorter patterns can't be looked up
+/// in it.
+pub(crate) const NGRAM_LEN: usize = 3;
+
+/// A function from a value to what it's indexed under, if anything.
+pub type ExtractFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// How an index gets what to index a value under.
+#[derive(Clone)]
+pub enum Extractor {
+    /// Parse the value as JSON and take a field of it: a top-level field by name, or any
+    /// field by a JSON pointer like `/address/city`. Strings are indexed as they are, and
+    /// numbers and booleans as JSON. Values that aren't JSON objects, or don't have the field,
+    /// aren't indexed.
+    JsonField(String),
+    /// Call a function on the value.
+    Function(Arc<ExtractFn>),
+}
+
+impl fmt::Debug for Extractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Extractor::JsonField(field) => f.debug_tuple("JsonField").field(field).finish(),
+            Extractor::Function(_) => f.write_str("Function(..)"),
+        }
+    }
+}
+
+/// A named index over the store's string values.
+#[derive(Debug, Clone)]
+pub struct SecondaryIndex {
+    pub name: String,
+    pub extractor: Extractor,
+}
+
+impl SecondaryIndex {
+    pub fn json_field(name: &str, field: &str) -> SecondaryIndex {
+        SecondaryIndex {
+            name: name.to_owned(),
+            extractor: Extractor::JsonField(field.to_owned()),
+        }
+    }
+
+    pub fn function<F>(name: &str, f: F) -> SecondaryIndex
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        SecondaryIndex {
+            name: name.to_owned(),
+            extractor: Extractor::Function(Arc::new(f)),
+        }
+    }
+
+    /// What the value is indexed under, if anything.
+    pub fn extract(&self, value: &str) -> Option<String> {
+        match &self.extractor {
+            Extractor::JsonField(field) => {
+                let json: Value = serde_json::from_str(value).ok()?;
+                let found = if field.starts_with('/') {
+                    json.pointer(field)?
+                } else {
+                    json.as_object()?.get(field)?
+                };
+                match found {
+                    Value::String(s) => Some(s.clone()),
+                    Value::Number(_) | Value::Bool(_) => Some(found.to_string()),
+                    _ => None,
+                }
+            }
+            Extractor::Function(f) => f(value),
+        }
+    }
+
+    /// The key of the entry for values indexed under `indexed`.
+    pub(crate) fn entry_key(&self, indexed: &str) -> String {
+        format!("{}{}/{}", INDEX_KEY_PREFIX, self.name, indexed)
+    }
+}
+
+/// Every distinct run of `NGRAM_LEN` characters in the value.
+pub(crate) fn ngrams(value: &str) -> BTreeSet<String> {
+    let chars: Vec<char> = value.chars().collect();
+    chars
+        .windows(NGRAM_LEN)
+        .map(|ngram| ngram.iter().collect())
+        .collect()
+}
+
+/// The key of the substring index's entry for the n-gram.
+pub(crate) fn ngram_key(ngram: &str) -> String {
+    format!("{}{}", NGRAM_KEY_PREFIX, ngram)
+}
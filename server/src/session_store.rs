@@ -0,0 +1,89 @@
+//! Web session storage on top of any engine: session data is kept under its id with an expiry
+//! time, the shape that web frameworks' session-store traits ask for.
+use crate::clock::{Clock, SystemClock};
+use crate::kv::ms_after;
+use kvs::{Engine, Error, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The prefix sessions are stored under, so they don't collide with other keys.
+const DEFAULT_PREFIX: &str = "session:";
+
+/// Loads, stores, and destroys sessions by id. Each session is a string value holding its
+/// expiry time and data.
+///
+/// Expired sessions stay in the engine until they're stored again or destroyed. Loading one
+/// doesn't remove it, since by then it may already have been stored again.
+pub struct SessionStore<E: Engine> {
+    engine: E,
+    prefix: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl<E: Engine> SessionStore<E> {
+    pub fn new(engine: E) -> Self {
+        SessionStore::with_clock(engine, Arc::new(SystemClock))
+    }
+
+    /// Like `new`, but telling whether sessions have expired by `clock`.
+    pub fn with_clock(engine: E, clock: Arc<dyn Clock>) -> Self {
+        SessionStore {
+            engine,
+            prefix: DEFAULT_PREFIX.to_owned(),
+            clock,
+        }
+    }
+
+    /// Store sessions under `prefix` instead of `session:`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_owned();
+        self
+    }
+
+    /// The session's data, or `None` if there's no such session or it has expired.
+    pub fn load(&self, id: &str) -> Result<Option<String>> {
+        let key = self.key(id);
+        let value = match self.engine.get(key.clone())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let (expires_at_ms, data) = match value.find(':') {
+            Some(i) => (value[..i].parse::<u64>().ok(), &value[i + 1..]),
+            None => (None, ""),
+        };
+        match expires_at_ms {
+            Some(expires_at_ms) if expires_at_ms > self.clock.now_ms() => Ok(Some(data.to_owned())),
+            Some(_) => Ok(None),
+            None => Err(Error::Message(format!("{:?} is not a session", key))),
+        }
+    }
+
+    /// Create or replace the session, expiring `ttl` from now.
+    pub fn store(&self, id: &str, data: &str, ttl: Duration) -> Result<()> {
+        let expires_at_ms = ms_after(self.clock.now_ms(), ttl);
+        let key = self.key(id);
+        self.engine
+            .set(key, format!("{}:{}", expires_at_ms, data))?;
+        Ok(())
+    }
+
+    /// Remove the session. It's not an error if there's no such session.
+    pub fn destroy(&self, id: &str) -> Result<()> {
+        match self.engine.remove(self.key(id)) {
+            Ok(_) | Err(Error::KeyNotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn engine_mut(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    fn key(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+}
@@ -0,0 +1,609 @@
+//! Reads and writes SST files in RocksDB's block-based table format, so data can move between
+//! kvs and RocksDB (or anything else that reads its SSTs) without going through a text dump.
+//!
+//! Files are written the way RocksDB's `SstFileWriter` writes them, uncompressed and with every
+//! key at sequence number 0, so they can be bulk-loaded with `IngestExternalFile`. Files
+//! produced elsewhere can be read if they're uncompressed or Snappy-compressed and use the
+//! default binary search index.
+use kvs::{Error, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::mem;
+use std::path::Path;
+
+const MAGIC: u64 = 0x88e2_41b7_85f4_cff7;
+/// The magic number of format version 0 files, which have a shorter footer.
+const LEGACY_MAGIC: u64 = 0xdb47_7524_8b80_fb57;
+const FORMAT_VERSION: u32 = 2;
+const FOOTER_LEN: usize = 53;
+const LEGACY_FOOTER_LEN: usize = 48;
+/// Block handles are padded out to this length in the footer.
+const HANDLES_LEN: usize = 40;
+const BLOCK_TRAILER_LEN: usize = 5;
+const BLOCK_SIZE: usize = 4096;
+const RESTART_INTERVAL: usize = 16;
+
+const NO_COMPRESSION: u8 = 0;
+const SNAPPY_COMPRESSION: u8 = 1;
+const CRC32C_CHECKSUM: u8 = 1;
+
+const TYPE_DELETION: u8 = 0x0;
+const TYPE_VALUE: u8 = 0x1;
+const TYPE_SINGLE_DELETION: u8 = 0x7;
+
+const PROPERTIES_BLOCK: &[u8] = b"rocksdb.properties";
+
+/// Writes an SST file. Keys must be added in increasing order.
+pub struct SstWriter {
+    file: BufWriter<File>,
+    offset: u64,
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    last_key: Option<Vec<u8>>,
+    entries: u64,
+    data_blocks: u64,
+    raw_key_size: u64,
+    raw_value_size: u64,
+}
+
+impl SstWriter {
+    /// Create the file at `path`, which must not exist yet.
+    pub fn create(path: &Path) -> Result<SstWriter> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        Ok(SstWriter {
+            file: BufWriter::new(file),
+            offset: 0,
+            data_block: BlockBuilder::new(RESTART_INTERVAL),
+            index_block: BlockBuilder::new(1),
+            last_key: None,
+            entries: 0,
+            data_blocks: 0,
+            raw_key_size: 0,
+            raw_value_size: 0,
+        })
+    }
+
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if let Some(last_key) = &self.last_key {
+            if key <= last_key.as_slice() {
+                return Err(Error::Message(
+                    "Keys must be added to an SST in increasing order".to_owned(),
+                ));
+            }
+        }
+        let internal_key = internal_key(key, TYPE_VALUE);
+        self.data_block.add(&internal_key, value);
+        self.last_key = Some(key.to_owned());
+        self.entries += 1;
+        self.raw_key_size += internal_key.len() as u64;
+        self.raw_value_size += value.len() as u64;
+        if self.data_block.size_estimate() >= BLOCK_SIZE {
+            self.flush_data_block()?;
+        }
+        Ok(())
+    }
+
+    /// Write out the rest of the file. Returns the number of keys in it.
+    pub fn finish(mut self) -> Result<u64> {
+        if self.entries == 0 {
+            return Err(Error::Message(
+                "Cannot write an SST with no keys".to_owned(),
+            ));
+        }
+        self.flush_data_block()?;
+        let data_size = self.offset;
+
+        let index = mem::replace(&mut self.index_block, BlockBuilder::new(1)).finish();
+        let index_handle = self.write_block(&index)?;
+
+        let mut properties: BTreeMap<&str, Vec<u8>> = BTreeMap::new();
+        let mut number = |name, value| {
+            let mut bytes = Vec::new();
+            put_varint(&mut bytes, value);
+            properties.insert(name, bytes);
+        };
+        number("rocksdb.data.size", data_size);
+        number(
+            "rocksdb.index.size",
+            index.len() as u64 + BLOCK_TRAILER_LEN as u64,
+        );
+        number("rocksdb.filter.size", 0);
+        number("rocksdb.raw.key.size", self.raw_key_size);
+        number("rocksdb.raw.value.size", self.raw_value_size);
+        number("rocksdb.num.data.blocks", self.data_blocks);
+        number("rocksdb.num.entries", self.entries);
+        number("rocksdb.deleted.keys", 0);
+        number("rocksdb.merge.operands", 0);
+        number("rocksdb.num.range-deletions", 0);
+        number("rocksdb.format.version", 0);
+        number("rocksdb.fixed.key.length", 0);
+        let strings = [
+            ("rocksdb.comparator", "leveldb.BytewiseComparator"),
+            ("rocksdb.merge.operator", "nullptr"),
+            ("rocksdb.prefix.extractor.name", "nullptr"),
+            ("rocksdb.property.collectors", "[]"),
+            ("rocksdb.compression", "NoCompression"),
+        ];
+        for (name, value) in strings.iter() {
+            properties.insert(name, value.as_bytes().to_owned());
+        }
+        // What `SstFileWriter` adds, without which RocksDB won't ingest the file
+        properties.insert(
+            "rocksdb.external_sst_file.version",
+            2u32.to_le_bytes().to_vec(),
+        );
+        properties.insert(
+            "rocksdb.external_sst_file.global_seqno",
+            0u64.to_le_bytes().to_vec(),
+        );
+        properties.insert(
+            "rocksdb.block.based.table.index.type",
+            0u32.to_le_bytes().to_vec(),
+        );
+        let mut properties_block = BlockBuilder::new(1);
+        for (name, value) in properties.iter() {
+            properties_block.add(name.as_bytes(), value);
+        }
+        let properties_handle = self.write_block(&properties_block.finish())?;
+
+        let mut metaindex = BlockBuilder::new(1);
+        metaindex.add(PROPERTIES_BLOCK, &properties_handle.encode());
+        let metaindex_handle = self.write_block(&metaindex.finish())?;
+
+        let mut footer = vec![CRC32C_CHECKSUM];
+        footer.extend(metaindex_handle.encode());
+        footer.extend(index_handle.encode());
+        footer.resize(1 + HANDLES_LEN, 0);
+        footer.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        footer.extend_from_slice(&MAGIC.to_le_bytes());
+        self.file.write_all(&footer)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        Ok(self.entries)
+    }
+
+    fn flush_data_block(&mut self) -> Result<()> {
+        if self.data_block.is_empty() {
+            return Ok(());
+        }
+        let block = mem::replace(&mut self.data_block, BlockBuilder::new(RESTART_INTERVAL));
+        let handle = self.write_block(&block.finish())?;
+        // The block's last key is a valid separator between it and the next block
+        let last_key = internal_key(self.last_key.as_ref().unwrap(), TYPE_VALUE);
+        self.index_block.add(&last_key, &handle.encode());
+        self.data_blocks += 1;
+        Ok(())
+    }
+
+    fn write_block(&mut self, contents: &[u8]) -> Result<BlockHandle> {
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: contents.len() as u64,
+        };
+        let checksum = mask_crc(crc32c(&[contents, &[NO_COMPRESSION]]));
+        self.file.write_all(contents)?;
+        self.file.write_all(&[NO_COMPRESSION])?;
+        self.file.write_all(&checksum.to_le_bytes())?;
+        self.offset += (contents.len() + BLOCK_TRAILER_LEN) as u64;
+        Ok(handle)
+    }
+}
+
+/// Read every key in the SST file at `path`, in order. A key maps to `None` if the file deletes
+/// it.
+pub fn read_sst(path: &Path) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>> {
+    let file = fs::read(path)?;
+    let bad = |what: &str| Error::Message(format!("Bad SST file: {}", what));
+    if file.len() < LEGACY_FOOTER_LEN {
+        return Err(bad("too short"));
+    }
+    let magic = read_u64(&file[file.len() - 8..]);
+    let (checksum_type, handles) = if magic == MAGIC && file.len() >= FOOTER_LEN {
+        let footer = &file[file.len() - FOOTER_LEN..];
+        let version = read_u32(&footer[1 + HANDLES_LEN..]);
+        if version > 5 {
+            return Err(Error::Message(format!(
+                "SST format version {} is not supported",
+                version
+            )));
+        }
+        (footer[0], &footer[1..=HANDLES_LEN])
+    } else if magic == LEGACY_MAGIC {
+        let footer = &file[file.len() - LEGACY_FOOTER_LEN..];
+        (CRC32C_CHECKSUM, &footer[..HANDLES_LEN])
+    } else {
+        return Err(bad("not a block-based table"));
+    };
+    let mut handles = handles;
+    let metaindex_handle = BlockHandle::decode(&mut handles).ok_or_else(|| bad("footer"))?;
+    let index_handle = BlockHandle::decode(&mut handles).ok_or_else(|| bad("footer"))?;
+
+    let mut index_is_delta_encoded = false;
+    let metaindex = read_block(&file, &metaindex_handle, checksum_type)?;
+    for (name, value) in block_entries(&metaindex)? {
+        if name != PROPERTIES_BLOCK {
+            continue;
+        }
+        let handle = BlockHandle::decode(&mut value.as_slice()).ok_or_else(|| bad("metaindex"))?;
+        for (name, value) in block_entries(&read_block(&file, &handle, checksum_type)?)? {
+            match name.as_slice() {
+                b"rocksdb.block.based.table.index.type" => {
+                    if value.len() != 4 || read_u32(&value) != 0 {
+                        return Err(Error::Message(
+                            "Only SSTs with a binary search index are supported".to_owned(),
+                        ));
+                    }
+                }
+                b"rocksdb.index.value.is.delta.encoded" => {
+                    index_is_delta_encoded = get_varint(&mut value.as_slice()) == Some(1);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let index = read_block(&file, &index_handle, checksum_type)?;
+    let data_handles = if index_is_delta_encoded {
+        delta_encoded_handles(&index)?
+    } else {
+        let mut handles = Vec::new();
+        for (_, value) in block_entries(&index)? {
+            handles.push(BlockHandle::decode(&mut value.as_slice()).ok_or_else(|| bad("index"))?);
+        }
+        handles
+    };
+
+    let mut entries: Vec<(Vec<u8>, Option<Vec<u8>>)> = Vec::new();
+    for handle in data_handles.iter() {
+        for (internal_key, value) in block_entries(&read_block(&file, handle, checksum_type)?)? {
+            if internal_key.len() < 8 {
+                return Err(bad("key too short"));
+            }
+            let (key, trailer) = internal_key.split_at(internal_key.len() - 8);
+            // Newer versions of a key come first; older ones are shadowed
+            if entries
+                .last()
+                .map_or(false, |(last, _)| last.as_slice() == key)
+            {
+                continue;
+            }
+            let value = match trailer[0] {
+                TYPE_VALUE => Some(value),
+                TYPE_DELETION | TYPE_SINGLE_DELETION => None,
+                kind => {
+                    return Err(Error::Message(format!(
+                        "SST entries of type {} are not supported",
+                        kind
+                    )))
+                }
+            };
+            entries.push((key.to_owned(), value));
+        }
+    }
+    Ok(entries)
+}
+
+/// Read a block's contents, checking its checksum and decompressing it.
+fn read_block(file: &[u8], handle: &BlockHandle, checksum_type: u8) -> Result<Vec<u8>> {
+    let start = handle.offset as usize;
+    let end = start + handle.size as usize;
+    if end + BLOCK_TRAILER_LEN > file.len() {
+        return Err(Error::Message(
+            "Bad SST file: block past the end".to_owned(),
+        ));
+    }
+    let contents = &file[start..end];
+    let compression = file[end];
+    if checksum_type == CRC32C_CHECKSUM {
+        let expected = read_u32(&file[end + 1..]);
+        if mask_crc(crc32c(&[contents, &[compression]])) != expected {
+            return Err(Error::Message(format!(
+                "Bad SST file: checksum mismatch in block at {}",
+                start
+            )));
+        }
+    }
+    match compression {
+        NO_COMPRESSION => Ok(contents.to_owned()),
+        SNAPPY_COMPRESSION => snappy_decompress(contents),
+        compression => Err(Error::Message(format!(
+            "SST compression type {} is not supported",
+            compression
+        ))),
+    }
+}
+
+/// The key-value pairs in a block, in order.
+fn block_entries(block: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let bad = || Error::Message("Bad SST file: corrupt block".to_owned());
+    let restarts_end = block.len().checked_sub(4).ok_or_else(bad)?;
+    let packed = read_u32(&block[restarts_end..]);
+    if packed & (1 << 31) != 0 {
+        return Err(Error::Message(
+            "SSTs with data block hash indexes are not supported".to_owned(),
+        ));
+    }
+    let entries_end = restarts_end
+        .checked_sub(4 * packed as usize)
+        .ok_or_else(bad)?;
+
+    let mut entries = Vec::new();
+    let mut rest = &block[..entries_end];
+    let mut key: Vec<u8> = Vec::new();
+    while !rest.is_empty() {
+        let shared = get_varint(&mut rest).ok_or_else(bad)? as usize;
+        let non_shared = get_varint(&mut rest).ok_or_else(bad)? as usize;
+        let value_len = get_varint(&mut rest).ok_or_else(bad)? as usize;
+        if shared > key.len() || non_shared + value_len > rest.len() {
+            return Err(bad());
+        }
+        key.truncate(shared);
+        key.extend_from_slice(&rest[..non_shared]);
+        entries.push((
+            key.clone(),
+            rest[non_shared..non_shared + value_len].to_owned(),
+        ));
+        rest = &rest[non_shared + value_len..];
+    }
+    Ok(entries)
+}
+
+/// The block handles in an index block written with `format_version` 4 or later, where only
+/// the first entry after each restart point has a full handle and the rest store the difference
+/// in size from the previous block.
+fn delta_encoded_handles(block: &[u8]) -> Result<Vec<BlockHandle>> {
+    let bad = || Error::Message("Bad SST file: corrupt index".to_owned());
+    let restarts_end = block.len().checked_sub(4).ok_or_else(bad)?;
+    let restarts = read_u32(&block[restarts_end..]) as usize;
+    let entries_end = restarts_end.checked_sub(4 * restarts).ok_or_else(bad)?;
+    let restart_offsets: Vec<usize> = (0..restarts)
+        .map(|i| read_u32(&block[entries_end + 4 * i..]) as usize)
+        .collect();
+
+    let mut handles: Vec<BlockHandle> = Vec::new();
+    let mut rest = &block[..entries_end];
+    while !rest.is_empty() {
+        let offset = entries_end - rest.len();
+        let _shared = get_varint(&mut rest).ok_or_else(bad)?;
+        let non_shared = get_varint(&mut rest).ok_or_else(bad)? as usize;
+        if non_shared > rest.len() {
+            return Err(bad());
+        }
+        rest = &rest[non_shared..];
+        let handle = match handles.last() {
+            Some(previous) if !restart_offsets.contains(&offset) => {
+                let delta = get_varint(&mut rest).ok_or_else(bad)?;
+                let delta = (delta >> 1) as i64 ^ -((delta & 1) as i64);
+                BlockHandle {
+                    offset: previous.offset + previous.size + BLOCK_TRAILER_LEN as u64,
+                    size: (previous.size as i64 + delta) as u64,
+                }
+            }
+            _ => BlockHandle::decode(&mut rest).ok_or_else(bad)?,
+        };
+        handles.push(handle);
+    }
+    Ok(handles)
+}
+
+/// Builds a block of prefix-compressed entries, with a restart point (a key stored in full)
+/// every `restart_interval` entries.
+struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    restart_interval: usize,
+    since_restart: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    fn new(restart_interval: usize) -> BlockBuilder {
+        BlockBuilder {
+            buf: Vec::new(),
+            restarts: vec![0],
+            restart_interval,
+            since_restart: 0,
+            last_key: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.since_restart < self.restart_interval {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            self.restarts.push(self.buf.len() as u32);
+            self.since_restart = 0;
+            0
+        };
+        put_varint(&mut self.buf, shared as u64);
+        put_varint(&mut self.buf, (key.len() - shared) as u64);
+        put_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+        self.last_key = key.to_owned();
+        self.since_restart += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn size_estimate(&self) -> usize {
+        self.buf.len() + 4 * self.restarts.len() + 4
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for restart in self.restarts.iter() {
+            self.buf.extend_from_slice(&restart.to_le_bytes());
+        }
+        self.buf
+            .extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        self.buf
+    }
+}
+
+/// The location of a block in the file, not counting its trailer.
+#[derive(Debug, Clone, Copy)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+impl BlockHandle {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_varint(&mut bytes, self.offset);
+        put_varint(&mut bytes, self.size);
+        bytes
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Option<BlockHandle> {
+        Some(BlockHandle {
+            offset: get_varint(bytes)?,
+            size: get_varint(bytes)?,
+        })
+    }
+}
+
+/// A user key followed by the sequence number (always 0 here) and type of the entry.
+fn internal_key(key: &[u8], kind: u8) -> Vec<u8> {
+    let mut internal_key = key.to_owned();
+    internal_key.extend_from_slice(&u64::from(kind).to_le_bytes());
+    internal_key
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Read a varint from the front of `bytes`, advancing past it.
+fn get_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+/// CRC-32C (Castagnoli) of the parts, one after the other.
+fn crc32c(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for part in parts {
+        for &byte in part.iter() {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0x82f6_3b78
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+    }
+    !crc
+}
+
+/// RocksDB stores checksums masked, so that checksums of data containing checksums are sound.
+fn mask_crc(crc: u32) -> u32 {
+    ((crc >> 15) | (crc << 17)).wrapping_add(0xa282_ead8)
+}
+
+/// Decompress a raw (unframed) Snappy block.
+fn snappy_decompress(input: &[u8]) -> Result<Vec<u8>> {
+    let bad = || Error::Message("Bad SST file: corrupt Snappy block".to_owned());
+    let mut rest = input;
+    let len = get_varint(&mut rest).ok_or_else(bad)? as usize;
+    let mut output = Vec::with_capacity(len);
+    while !rest.is_empty() {
+        let tag = rest[0];
+        rest = &rest[1..];
+        let (copy_len, offset) = match tag & 0b11 {
+            0 => {
+                let mut literal_len = (tag >> 2) as usize;
+                if literal_len >= 60 {
+                    let bytes = literal_len - 59;
+                    if rest.len() < bytes {
+                        return Err(bad());
+                    }
+                    literal_len = rest[..bytes]
+                        .iter()
+                        .rev()
+                        .fold(0, |len, &byte| (len << 8) | byte as usize);
+                    rest = &rest[bytes..];
+                }
+                let literal_len = literal_len + 1;
+                if rest.len() < literal_len {
+                    return Err(bad());
+                }
+                output.extend_from_slice(&rest[..literal_len]);
+                rest = &rest[literal_len..];
+                continue;
+            }
+            1 => {
+                let low = *rest.first().ok_or_else(bad)? as usize;
+                rest = &rest[1..];
+                (
+                    ((tag >> 2) & 0b111) as usize + 4,
+                    ((tag as usize >> 5) << 8) | low,
+                )
+            }
+            2 => {
+                if rest.len() < 2 {
+                    return Err(bad());
+                }
+                let offset = rest[0] as usize | (rest[1] as usize) << 8;
+                rest = &rest[2..];
+                ((tag >> 2) as usize + 1, offset)
+            }
+            _ => {
+                if rest.len() < 4 {
+                    return Err(bad());
+                }
+                let offset = read_u32(rest) as usize;
+                rest = &rest[4..];
+                ((tag >> 2) as usize + 1, offset)
+            }
+        };
+        if offset == 0 || offset > output.len() {
+            return Err(bad());
+        }
+        // Copies can overlap the bytes they produce, so go one byte at a time
+        let start = output.len() - offset;
+        for i in 0..copy_len {
+            let byte = output[start + i];
+            output.push(byte);
+        }
+    }
+    if output.len() != len {
+        return Err(bad());
+    }
+    Ok(output)
+}
@@ -0,0 +1,121 @@
+//! Pushes engine metrics and request timings to a statsd agent (or DogStatsD) over UDP, for
+//! environments whose metrics pipeline is built around statsd rather than OpenTelemetry.
+use kvs::{Error, Result, Stats};
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Datagrams are kept under this size so they aren't fragmented on a typical network.
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Buffers metrics and sends them to the agent in batches, at most once per flush interval.
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    interval: Duration,
+    last_flush: Instant,
+    /// Metric lines waiting to be sent.
+    lines: Vec<String>,
+    /// The request count at the last flush, since statsd counters are sent as increments.
+    requests_reported: u64,
+}
+
+impl Statsd {
+    /// Send to the agent at `addr`, e.g. `127.0.0.1:8125`, naming every metric `prefix.*`.
+    pub fn new(addr: &str, prefix: &str, interval: Duration) -> Result<Statsd> {
+        let addr: SocketAddr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::Message(format!("Invalid statsd address: {}", addr)))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Statsd {
+            socket,
+            prefix: prefix.to_owned(),
+            interval,
+            last_flush: Instant::now(),
+            lines: Vec::new(),
+            requests_reported: 0,
+        })
+    }
+
+    /// Record how long a request took, and count it as an error if it failed.
+    pub fn record_request(&mut self, name: &str, elapsed: Duration, failed: bool) {
+        let millis = elapsed.as_micros() as f64 / 1000.0;
+        let line = format!("{}.request.{}:{}|ms", self.prefix, name, millis);
+        self.lines.push(line);
+        if failed {
+            let line = format!("{}.request.{}.errors:1|c", self.prefix, name);
+            self.lines.push(line);
+        }
+    }
+
+    /// Whether the flush interval has passed, so it's time to `record_metrics` and `flush`.
+    pub fn is_due(&self) -> bool {
+        self.last_flush.elapsed() >= self.interval
+    }
+
+    /// Record the engine's statistics as gauges, and the requests served since the last flush
+    /// as a counter, under the same names as the OTLP metrics.
+    pub fn record_metrics(&mut self, stats: &Stats, requests_served: u64) {
+        let gauges = [
+            ("pages", stats.pages),
+            ("pages.partial", stats.partial_pages),
+            ("memtable.entries", stats.memtable_entries),
+            ("memtable.usage", stats.memtable_bytes),
+            ("disk.usage", stats.disk_bytes),
+        ];
+        for (name, value) in gauges.iter() {
+            let line = format!("{}.{}:{}|g", self.prefix, name, value);
+            self.lines.push(line);
+        }
+        for (name, cache) in stats.caches.iter() {
+            let counts = [
+                ("hits", cache.hits),
+                ("misses", cache.misses),
+                ("evictions", cache.evictions),
+                ("entries", cache.entries),
+                ("usage", cache.bytes),
+            ];
+            for (count, value) in counts.iter() {
+                let line = format!("{}.cache.{}.{}:{}|g", self.prefix, name, count, value);
+                self.lines.push(line);
+            }
+        }
+        let requests = requests_served.saturating_sub(self.requests_reported);
+        let line = format!("{}.server.requests:{}|c", self.prefix, requests);
+        self.lines.push(line);
+        self.requests_reported = requests_served;
+    }
+
+    /// Send every buffered metric, as many to a datagram as fit. Metrics that can't be sent
+    /// are dropped, as statsd clients do.
+    pub fn flush(&mut self) -> Result<()> {
+        self.last_flush = Instant::now();
+        let mut datagram = String::new();
+        let mut result = Ok(());
+        for line in mem::replace(&mut self.lines, Vec::new()) {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+                result = result.and(self.send(&datagram));
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            result = result.and(self.send(&datagram));
+        }
+        result
+    }
+
+    fn send(&self, datagram: &str) -> Result<()> {
+        self.socket.send(datagram.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+//! Socket activation: listening on sockets that systemd opened and passed in, so the server can
+//! be started by the first connection and restarted without refusing any.
+use kvs::{Error, Result};
+use std::env;
+use std::net::TcpListener;
+use std::process;
+
+/// The first file descriptor systemd passes, after stdin, stdout, and stderr.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The sockets systemd passed in, following `sd_listen_fds`: there are `LISTEN_FDS` of them,
+/// starting at descriptor 3, if `LISTEN_PID` is this process. The variables are removed so that
+/// child processes don't try to take the sockets too. Empty if the server wasn't socket
+/// activated, or isn't running on Unix.
+pub fn systemd_listeners() -> Result<Vec<TcpListener>> {
+    let pid = match env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Ok(vec![]),
+    };
+    let count = env::var("LISTEN_FDS").unwrap_or_default();
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    if pid.parse::<u32>().ok() != Some(process::id()) {
+        return Ok(vec![]);
+    }
+    let count: i32 = count
+        .parse()
+        .map_err(|_| Error::Message(format!("Invalid LISTEN_FDS: {:?}", count)))?;
+    Ok(listeners(count))
+}
+
+#[cfg(unix)]
+fn listeners(count: i32) -> Vec<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn listeners(_count: i32) -> Vec<TcpListener> {
+    vec![]
+}
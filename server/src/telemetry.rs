@@ -0,0 +1,310 @@
+//! Exports request traces and engine metrics to an OpenTelemetry collector using OTLP over HTTP
+//! with the JSON encoding, so no protobuf or gRPC stack is needed.
+use crate::clock::{Clock, SystemClock};
+use kvs::{Error, Result, Stats};
+use serde_json::{json, Value};
+use slog::Logger;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Spans are sent at least this often, and sooner if `MAX_BATCH_SIZE` are waiting.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+const MAX_BATCH_SIZE: usize = 512;
+
+/// How many spans and metric exports may wait for the exporter before new ones are dropped, so
+/// that an unreachable collector can't make the server's memory grow without bound.
+const QUEUE_CAPACITY: usize = 4 * MAX_BATCH_SIZE;
+
+/// How long connecting to, writing to, and reading from the collector may each take.
+const COLLECTOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SCOPE_NAME: &str = "kvs-server";
+
+/// A handle for recording telemetry, which is exported from a background thread.
+pub struct Telemetry {
+    sender: SyncSender<Export>,
+    clock: Arc<dyn Clock>,
+}
+
+enum Export {
+    Span(FinishedSpan),
+    Metrics(Vec<Value>),
+}
+
+/// A span that has been started but not yet finished.
+pub struct Span {
+    name: String,
+    trace_id: String,
+    span_id: String,
+    start: SystemTime,
+    attributes: Vec<(String, String)>,
+}
+
+struct FinishedSpan {
+    span: Span,
+    end: SystemTime,
+    error: Option<String>,
+}
+
+impl Span {
+    pub fn set_attribute(&mut self, key: &str, value: String) {
+        self.attributes.push((key.to_owned(), value));
+    }
+}
+
+impl Telemetry {
+    /// Start exporting to the collector at `endpoint`, e.g. `http://localhost:4318`.
+    pub fn start(endpoint: &str, service_name: &str, logger: &Logger) -> Result<Telemetry> {
+        Telemetry::start_with_clock(endpoint, service_name, logger, Arc::new(SystemClock))
+    }
+
+    /// Like `start`, but timestamping spans and metrics with `clock`.
+    pub fn start_with_clock(
+        endpoint: &str,
+        service_name: &str,
+        logger: &Logger,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Telemetry> {
+        let collector = Collector::parse(endpoint, service_name)?;
+        let slog = logger.new(o!("otlp-endpoint" => endpoint.to_owned()));
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        thread::Builder::new()
+            .name("otlp-exporter".to_owned())
+            .spawn(move || collector.run(receiver, slog))?;
+        Ok(Telemetry { sender, clock })
+    }
+
+    /// Start a span in a new trace.
+    pub fn start_span(&self, name: &str) -> Span {
+        Span {
+            name: name.to_owned(),
+            trace_id: hex(Uuid::new_v4().as_bytes()),
+            span_id: hex(&Uuid::new_v4().as_bytes()[..8]),
+            start: self.clock.now(),
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Finish the span, marking it as failed if there was an error. The span is dropped if the
+    /// exporter has fallen `QUEUE_CAPACITY` behind.
+    pub fn finish(&self, span: Span, error: Option<String>) {
+        let finished = FinishedSpan {
+            span,
+            end: self.clock.now(),
+            error,
+        };
+        let _ = self.sender.try_send(Export::Span(finished));
+    }
+
+    /// Record the engine's statistics and the server's request count as OTLP metrics.
+    pub fn record_metrics(&self, stats: &Stats, requests_served: u64) {
+        let now = unix_nanos(self.clock.now());
+        let gauge = |name: &str, unit: &str, value: u64| {
+            json!({
+                "name": name,
+                "unit": unit,
+                "gauge": { "dataPoints": [{ "asInt": value.to_string(), "timeUnixNano": now }] },
+            })
+        };
+        let mut metrics = vec![
+            gauge("kvs.pages", "{page}", stats.pages),
+            gauge("kvs.pages.partial", "{page}", stats.partial_pages),
+            gauge("kvs.memtable.entries", "{entry}", stats.memtable_entries),
+            gauge("kvs.memtable.usage", "By", stats.memtable_bytes),
+            gauge("kvs.disk.usage", "By", stats.disk_bytes),
+            json!({
+                "name": "kvs.server.requests",
+                "unit": "{request}",
+                "sum": {
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                    "dataPoints": [{ "asInt": requests_served.to_string(), "timeUnixNano": now }],
+                },
+            }),
+        ];
+        for (name, cache) in stats.caches.iter() {
+            let name = |metric: &str| format!("kvs.cache.{}.{}", name, metric);
+            metrics.push(gauge(&name("hits"), "{hit}", cache.hits));
+            metrics.push(gauge(&name("misses"), "{miss}", cache.misses));
+            metrics.push(gauge(&name("evictions"), "{eviction}", cache.evictions));
+            metrics.push(gauge(&name("entries"), "{entry}", cache.entries));
+            metrics.push(gauge(&name("usage"), "By", cache.bytes));
+        }
+        let _ = self.sender.try_send(Export::Metrics(metrics));
+    }
+}
+
+/// The address of the OTLP/HTTP collector and the resource we report as.
+struct Collector {
+    host: String,
+    base_path: String,
+    resource: Value,
+}
+
+impl Collector {
+    fn parse(endpoint: &str, service_name: &str) -> Result<Collector> {
+        if !endpoint.starts_with("http://") {
+            return Err(Error::Message(format!(
+                "Unsupported OTLP endpoint {:?}, expected http://HOST:PORT",
+                endpoint
+            )));
+        }
+        let rest = &endpoint["http://".len()..];
+        let (host, base_path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        Ok(Collector {
+            host: host.to_owned(),
+            base_path: base_path.to_owned(),
+            resource: json!({
+                "attributes": [
+                    attribute("service.name", service_name),
+                    attribute("service.version", env!("CARGO_PKG_VERSION")),
+                ],
+            }),
+        })
+    }
+
+    fn run(self, receiver: Receiver<Export>, slog: Logger) {
+        let mut spans = Vec::new();
+        let mut last_flush = Instant::now();
+        loop {
+            let timeout = FLUSH_INTERVAL
+                .checked_sub(last_flush.elapsed())
+                .unwrap_or_else(|| Duration::from_secs(0));
+            let disconnected = match receiver.recv_timeout(timeout) {
+                Ok(Export::Span(span)) => {
+                    spans.push(span_json(span));
+                    false
+                }
+                Ok(Export::Metrics(metrics)) => {
+                    let body = json!({
+                        "resourceMetrics": [{
+                            "resource": self.resource,
+                            "scopeMetrics": [{ "scope": { "name": SCOPE_NAME }, "metrics": metrics }],
+                        }],
+                    });
+                    if let Err(e) = self.post("/v1/metrics", &body) {
+                        warn!(slog, "Failed to export metrics: {}", e);
+                    }
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+
+            if !spans.is_empty()
+                && (disconnected
+                    || spans.len() >= MAX_BATCH_SIZE
+                    || last_flush.elapsed() >= FLUSH_INTERVAL)
+            {
+                let body = json!({
+                    "resourceSpans": [{
+                        "resource": self.resource,
+                        "scopeSpans": [{ "scope": { "name": SCOPE_NAME }, "spans": spans }],
+                    }],
+                });
+                match self.post("/v1/traces", &body) {
+                    Ok(()) => trace!(slog, "Exported {} spans", spans.len()),
+                    Err(e) => warn!(slog, "Failed to export {} spans: {}", spans.len(), e),
+                }
+                spans = Vec::new();
+            }
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                last_flush = Instant::now();
+            }
+            if disconnected {
+                return;
+            }
+        }
+    }
+
+    /// POST a JSON body to the collector and check for a successful status code.
+    fn post(&self, path: &str, body: &Value) -> Result<()> {
+        let body = body.to_string();
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(COLLECTOR_TIMEOUT))?;
+        stream.set_write_timeout(Some(COLLECTOR_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.base_path,
+            path,
+            self.host,
+            body.len(),
+            body
+        )?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(Error::Message(format!(
+                "Collector responded with {:?}",
+                status_line.trim()
+            ))),
+        }
+    }
+
+    /// Connect to the first of the host's addresses that answers within `COLLECTOR_TIMEOUT`.
+    fn connect(&self) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.host.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, COLLECTOR_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(match last_error {
+            Some(e) => e.into(),
+            None => Error::Message(format!("No addresses found for {}", self.host)),
+        })
+    }
+}
+
+fn span_json(finished: FinishedSpan) -> Value {
+    let span = finished.span;
+    let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| attribute(key, value))
+        .collect();
+    // Status codes are 1 for OK and 2 for ERROR; kind 2 is SERVER.
+    let status = match finished.error {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 1 }),
+    };
+    json!({
+        "traceId": span.trace_id,
+        "spanId": span.span_id,
+        "name": span.name,
+        "kind": 2,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(finished.end),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
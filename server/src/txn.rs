@@ -0,0 +1,209 @@
+//! Optimistic transactions: reads go straight to the store and note the version of each key
+//! they saw, writes are buffered, and committing fails with `Error::Conflict` if anything the
+//! transaction read has been written since.
+use crate::kv::{KvStore, StoreState};
+use kvs::{Error, Result, WriteBatch};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem;
+
+/// How many of the most recent writes are remembered. A transaction that began before the
+/// oldest of them can't be checked, so it conflicts.
+const VERSION_WINDOW: usize = 100_000;
+
+/// The sequence number of the latest write to each recently written key.
+#[derive(Default)]
+pub(crate) struct KeyVersions {
+    /// The sequence number of the latest write to any key.
+    sequence: u64,
+    /// The latest write to each key in the window, by key hash.
+    latest: HashMap<u64, u64>,
+    /// The writes in the window, oldest first.
+    window: VecDeque<(u64, u64)>,
+    /// Every write up to this sequence number has been forgotten.
+    forgotten: u64,
+}
+
+impl KeyVersions {
+    /// Continue numbering writes after `sequence`, without knowing which keys the earlier
+    /// writes touched.
+    pub(crate) fn starting_at(sequence: u64) -> KeyVersions {
+        KeyVersions {
+            sequence,
+            forgotten: sequence,
+            ..KeyVersions::default()
+        }
+    }
+
+    pub(crate) fn record(&mut self, hash: u64) {
+        self.sequence += 1;
+        self.latest.insert(hash, self.sequence);
+        self.window.push_back((self.sequence, hash));
+        if self.window.len() > VERSION_WINDOW {
+            let (sequence, hash) = self.window.pop_front().unwrap();
+            if self.latest.get(&hash) == Some(&sequence) {
+                self.latest.remove(&hash);
+            }
+            self.forgotten = sequence;
+        }
+    }
+
+    /// The sequence number of the latest write to the key, or `None` if it hasn't been written
+    /// since the oldest write that's remembered.
+    pub(crate) fn get(&self, hash: u64) -> Option<u64> {
+        self.latest.get(&hash).cloned()
+    }
+
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub(crate) fn forgotten(&self) -> u64 {
+        self.forgotten
+    }
+}
+
+/// A transaction started with `KvStore::begin`. It doesn't borrow the store, so other writes
+/// can be made while it's open; `KvStore::commit_transaction` applies its writes only if none of them
+/// touched a key it read.
+///
+/// A transaction that's neither committed nor aborted keeps its locks until the store is
+/// closed.
+///
+/// Only string values can be read and written. Keys are compared by hash, so in rare cases a
+/// write to a different key is taken for a conflict.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub(crate) id: u64,
+    /// The sequence number of the latest write when the transaction began.
+    pub(crate) start: u64,
+    /// The version of each key when it was first read, by key.
+    pub(crate) reads: HashMap<String, Option<u64>>,
+    /// The buffered writes, with `None` for removes.
+    pub(crate) writes: BTreeMap<String, Option<String>>,
+    /// The keys the transaction has locked.
+    pub(crate) locks: Vec<String>,
+    /// Whether the transaction was aborted to break a deadlock.
+    pub(crate) deadlocked: bool,
+}
+
+impl Transaction {
+    pub(crate) fn new(id: u64, start: u64) -> Transaction {
+        Transaction {
+            id,
+            start,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+            locks: Vec::new(),
+            deadlocked: false,
+        }
+    }
+
+    /// Read a key, seeing the transaction's own writes.
+    pub fn get(&mut self, store: &KvStore, key: String) -> Result<Option<String>> {
+        self.get_from(&mut *store.state()?, key)
+    }
+
+    fn get_from(&mut self, store: &mut StoreState, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let version = store.key_version(&key);
+        self.reads.entry(key.clone()).or_insert(version);
+        store.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Remove a key. Unlike `KvStore::remove`, it's not an error if the key doesn't exist.
+    pub fn remove(&mut self, key: String) {
+        self.writes.insert(key, None);
+    }
+
+    /// Lock the key until the transaction is committed or aborted. Fails with
+    /// `Error::LockHeld` if another transaction has it locked, in which case it can be tried
+    /// again later.
+    ///
+    /// If the other transaction is waiting for one of this one's keys, directly or through
+    /// others, this one is aborted instead: its locks are released and this and every later
+    /// `lock` or commit fails with `Error::Deadlock`.
+    pub fn lock(&mut self, store: &KvStore, key: String) -> Result<()> {
+        self.lock_in(&mut *store.state()?, key)
+    }
+
+    fn lock_in(&mut self, store: &mut StoreState, key: String) -> Result<()> {
+        if self.deadlocked {
+            return Err(Error::Deadlock);
+        }
+        match store.lock_key(&key, self.id) {
+            Ok(true) => self.locks.push(key),
+            Ok(false) => {}
+            Err(Error::Deadlock) => {
+                self.deadlocked = true;
+                store.release_locks(self);
+                return Err(Error::Deadlock);
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn take_writes(&mut self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for (key, value) in mem::replace(&mut self.writes, BTreeMap::new()) {
+            match value {
+                Some(value) => {
+                    batch.set(key, value);
+                }
+                None => {
+                    batch.remove(key);
+                }
+            }
+        }
+        batch
+    }
+}
+
+/// A transaction run by `KvStore::transaction`, which commits it once the closure returns.
+pub struct ScopedTransaction<'a> {
+    pub(crate) store: &'a KvStore,
+    pub(crate) txn: Transaction,
+}
+
+impl<'a> ScopedTransaction<'a> {
+    /// Read a key, seeing the transaction's own writes.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.txn.get(self.store, key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.txn.set(key, value)
+    }
+
+    /// Remove a key. Unlike `KvStore::remove`, it's not an error if the key doesn't exist.
+    pub fn remove(&mut self, key: String) {
+        self.txn.remove(key)
+    }
+
+    /// Lock the key until the transaction ends.
+    pub fn lock(&mut self, key: String) -> Result<()> {
+        self.txn.lock(self.store, key)
+    }
+}
+
+/// Check that no key the transaction read has been written since it read it.
+pub(crate) fn validate(txn: &Transaction, store: &StoreState) -> Result<()> {
+    if txn.deadlocked {
+        return Err(Error::Deadlock);
+    }
+    if txn.start < store.forgotten_versions() {
+        return Err(Error::Conflict);
+    }
+    for (key, version) in txn.reads.iter() {
+        if store.key_version(key) != *version {
+            return Err(Error::Conflict);
+        }
+    }
+    Ok(())
+}